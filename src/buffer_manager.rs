@@ -1,6 +1,8 @@
 use anyhow::Result;
+use std::collections::HashMap;
 
-use crate::config::{BufferId, PageId};
+use crate::config::{BufferId, PageId, StoreId};
+use crate::error::StoreError;
 
 // Flags for page state
 pub const PAGE_RAW: u16 = 1; // buffer content is uninitialized
@@ -11,6 +13,7 @@ pub const PAGE_SYNCED: u16 = 16; // dirty pages was saved to log
 
 #[derive(Clone, Copy, Default)]
 pub struct Buffer {
+    pub store: StoreId, // store owning the page (pages of stores sharing buffer pool are distinguished by it)
     pub pid: PageId,
     collision: BufferId, // collision chain
     // LRU l2-list
@@ -26,25 +29,147 @@ impl Buffer {
     }
 }
 
+///
+/// Callback invoked when page of the store is evicted from cache (see `Store::set_eviction_hook`)
+///
+pub type EvictionHook = Box<dyn Fn(PageId, bool) + Send + Sync>;
+
+//
+// Buffers of current transaction of the store registered in buffer manager
+//
+#[derive(Default)]
+pub struct StoreBuffers {
+    pub meta: BufferId,        // pinned buffer with metadata page of the store
+    pub registered: bool,      // slot is used by opened store
+    pub dirty_pages: BufferId, // L2-list of dirty pages
+    pub next_sync: BufferId,   // next page to be written to WAL
+    pub dirtied: BufferId,     // amount of dirty pages
+    pub required: usize,       // buffers needed by the store: B-Tree path, pages created by its split and free buffers
+
+    pub spilled: HashMap<PageId, u64>, // WAL positions of pages spilled by current transaction
+
+    pub eviction_hook: Option<EvictionHook>, // callback notified about pages of the store evicted from cache
+}
+
 pub struct BufferManager {
     // LRU l2-list
     pub head: BufferId,
     pub tail: BufferId,
 
-    pub free_pages: BufferId,  // L1-list of free pages
-    pub dirty_pages: BufferId, // L2-list of dirty pages
-    pub next_sync: BufferId,   // next page to be written to WAL
+    pub free_pages: BufferId, // L1-list of free pages
+
+    pub used: BufferId,   // used part of page pool
+    pub pinned: BufferId, // amount of pinned pages
+    pub cached: BufferId, // amount of cached pages
 
-    pub used: BufferId,    // used part of page pool
-    pub pinned: BufferId,  // amount of pinned pages
-    pub dirtied: BufferId, // amount of dirty pages
-    pub cached: BufferId,  // amount of cached pages
+    pub stores: Vec<StoreBuffers>, // stores using this buffer manager, indexed by store identifier
 
     pub hash_table: Vec<BufferId>, // array containing indexes of collision chains
     pub pages: Vec<Buffer>,    // page data
 }
 
 impl BufferManager {
+    //
+    // Create buffer manager with empty cache of the specified size (buffer 0 holding metadata page
+    // of the first registered store is always pinned, it is also used as terminator of lists)
+    //
+    pub fn new(cache_size: usize) -> BufferManager {
+        BufferManager {
+            head: 0,
+            tail: 0,
+            free_pages: 0,
+            used: 1, // pinned metadata page
+            cached: 1,
+            pinned: 1,
+            stores: Vec::new(),
+            hash_table: vec![0; cache_size],
+            pages: vec![Buffer::new(); cache_size],
+        }
+    }
+
+    //
+    // Register store in buffer manager: returns identifier of the store and buffer for its metadata page.
+    // Slots of closed stores are reused together with their metadata buffers.
+    //
+    pub fn register(&mut self) -> Result<(StoreId, BufferId)> {
+        let id = match self.stores.iter().position(|s| !s.registered) {
+            Some(id) => id,
+            None => {
+                let meta = if self.stores.is_empty() { 0 } else { self.alloc_buffer()? };
+                self.stores.push(StoreBuffers {
+                    meta,
+                    ..StoreBuffers::default()
+                });
+                self.stores.len() - 1
+            }
+        };
+        self.stores[id].registered = true;
+        let meta = self.stores[id].meta;
+        self.pages[meta as usize].store = id as StoreId;
+        self.pages[meta as usize].pid = 0;
+        self.pages[meta as usize].state = 0;
+        Ok((id as StoreId, meta))
+    }
+
+    //
+    // Unregister closed store, throwing away its pages from cache
+    //
+    pub fn unregister(&mut self, store: StoreId) {
+        self.purge(store);
+        self.stores[store as usize].registered = false;
+        self.stores[store as usize].eviction_hook = None;
+        self.stores[store as usize].required = 0;
+    }
+
+    //
+    // Throw away all cached pages of the store (including dirty pages) and forget its current transaction
+    //
+    pub fn purge(&mut self, store: StoreId) {
+        for id in 1..self.used {
+            let buf = self.pages[id as usize];
+            if buf.store == store && self.find(store, buf.pid) == id {
+                if buf.access_count == 0 {
+                    self.pin(id);
+                }
+                self.pages[id as usize].access_count = 1;
+                self.throw_buffer(id);
+            }
+        }
+        self.clear_dirty(store);
+        self.stores[store as usize].spilled.clear();
+    }
+
+    //
+    // Forget dirty pages of the store after they were written or thrown away at the end of transaction
+    //
+    pub fn clear_dirty(&mut self, store: StoreId) {
+        let s = &mut self.stores[store as usize];
+        s.dirty_pages = 0;
+        s.next_sync = 0;
+        s.dirtied = 0;
+    }
+
+    //
+    // Buffers of the registered store
+    //
+    pub fn store(&self, store: StoreId) -> &StoreBuffers {
+        &self.stores[store as usize]
+    }
+
+    //
+    // Mutable buffers of the registered store
+    //
+    pub fn store_mut(&mut self, store: StoreId) -> &mut StoreBuffers {
+        &mut self.stores[store as usize]
+    }
+
+    //
+    // Hash of page of the store
+    //
+    fn hash(&self, store: StoreId, pid: PageId) -> usize {
+        (pid as usize).wrapping_add((store as usize).wrapping_mul(0x9E37_79B9)) % self.hash_table.len()
+    }
+
     //
     // Link buffer to the head of LRU list (make it acceptable for eviction)
     //
@@ -86,7 +211,7 @@ impl BufferManager {
     // Insert page in hash table
     //
     fn insert(&mut self, id: BufferId) {
-        let h = self.pages[id as usize].pid as usize % self.hash_table.len();
+        let h = self.hash(self.pages[id as usize].store, self.pages[id as usize].pid);
         self.pages[id as usize].collision = self.hash_table[h];
         self.hash_table[h] = id;
     }
@@ -95,7 +220,7 @@ impl BufferManager {
     // Remove page from hash table
     //
     fn remove(&mut self, id: BufferId) {
-        let h = self.pages[id as usize].pid as usize % self.hash_table.len();
+        let h = self.hash(self.pages[id as usize].store, self.pages[id as usize].pid);
         let mut p = self.hash_table[h];
         if p == id {
             self.hash_table[h] = self.pages[id as usize].collision;
@@ -111,6 +236,11 @@ impl BufferManager {
     // Throw away buffer from cache (used by transaction rollback)
     //
     pub fn throw_buffer(&mut self, id: BufferId) {
+        debug_assert!(self.pages[id as usize].access_count != 0);
+        // dirty buffer is pinned: unpin it before placing in free list
+        self.pages[id as usize].access_count = 0;
+        self.pages[id as usize].state = 0;
+        self.pinned -= 1;
         self.remove(id);
         self.pages[id as usize].next = self.free_pages;
         self.free_pages = id;
@@ -126,18 +256,21 @@ impl BufferManager {
         wal_flush_threshold: BufferId,
     ) -> Result<Option<(BufferId, PageId)>> {
         debug_assert!(self.pages[id as usize].access_count > 0);
+        let store = self.pages[id as usize].store as usize;
         let mut next_sync: Option<(BufferId, PageId)> = None;
         if (self.pages[id as usize].state & PAGE_DIRTY) == 0 {
+            // page will be written to WAL once again, so its spilled image is not needed any more
+            self.stores[store].spilled.remove(&self.pages[id as usize].pid);
             self.pages[id as usize].access_count += 1; // pin dirty page in memory
             self.pages[id as usize].state = PAGE_DIRTY;
-            self.dirtied += 1;
-            if self.dirtied > wal_flush_threshold {
-                let mut sync = self.next_sync;
+            self.stores[store].dirtied += 1;
+            if self.stores[store].dirtied > wal_flush_threshold {
+                let mut sync = self.stores[store].next_sync;
                 while sync != 0 {
                     assert_eq!(self.pages[sync as usize].state, PAGE_DIRTY);
                     if self.pages[sync as usize].access_count == 1 {
                         self.pages[sync as usize].state |= PAGE_SYNCED;
-                        self.next_sync = self.pages[sync as usize].prev;
+                        self.stores[store].next_sync = self.pages[sync as usize].prev;
                         let pid = self.pages[sync as usize].pid;
                         next_sync = Some((sync, pid));
                         break;
@@ -158,8 +291,8 @@ impl BufferManager {
             }
 
             // If this page was scheduled for flush, then use previous page instead
-            if self.next_sync == id {
-                self.next_sync = prev;
+            if self.stores[store].next_sync == id {
+                self.stores[store].next_sync = prev;
             }
 
             // unlink page
@@ -170,18 +303,82 @@ impl BufferManager {
             }
         }
         // link to the beginning of dirty list
-        if self.dirty_pages != 0 {
-            self.pages[self.dirty_pages as usize].prev = id;
+        let dirty_pages = self.stores[store].dirty_pages;
+        if dirty_pages != 0 {
+            self.pages[dirty_pages as usize].prev = id;
         }
-        if self.next_sync == 0 {
-            self.next_sync = id;
+        if self.stores[store].next_sync == 0 {
+            self.stores[store].next_sync = id;
         }
-        self.pages[id as usize].next = self.dirty_pages;
+        self.pages[id as usize].next = dirty_pages;
         self.pages[id as usize].prev = 0;
-        self.dirty_pages = id;
+        self.stores[store].dirty_pages = id;
         Ok(next_sync)
     }
 
+    //
+    // Find least recently modified dirty page of the store not yet written to WAL which is not used by anybody else.
+    // Returns 0 if there is no such page.
+    //
+    pub fn spill_candidate(&self, store: StoreId) -> BufferId {
+        let mut id = self.stores[store as usize].next_sync;
+        while id != 0 && self.pages[id as usize].access_count != 1 {
+            id = self.pages[id as usize].prev;
+        }
+        id
+    }
+
+    //
+    // Exclude page from dirty list and unpin it, so that it can be evicted before end of transaction.
+    // Page image should be already saved in WAL at the specified position.
+    //
+    pub fn spill_buffer(&mut self, id: BufferId, wal_pos: u64) {
+        debug_assert!(self.pages[id as usize].access_count == 1);
+        debug_assert!(self.pages[id as usize].state == PAGE_DIRTY);
+        let store = self.pages[id as usize].store as usize;
+        let next = self.pages[id as usize].next;
+        let prev = self.pages[id as usize].prev;
+        if self.stores[store].next_sync == id {
+            self.stores[store].next_sync = prev;
+        }
+        if prev == 0 {
+            self.stores[store].dirty_pages = next;
+        } else {
+            self.pages[prev as usize].next = next;
+        }
+        if next != 0 {
+            self.pages[next as usize].prev = prev;
+        }
+        self.pages[id as usize].state = 0;
+        self.stores[store].dirtied -= 1;
+        self.stores[store].spilled.insert(self.pages[id as usize].pid, wal_pos);
+        self.unpin(id);
+    }
+
+    //
+    // Find buffer containing specified page of the store. Returns 0 if page is not cached.
+    //
+    pub fn find(&self, store: StoreId, pid: PageId) -> BufferId {
+        let mut h = self.hash_table[self.hash(store, pid)];
+        while h != 0 && (self.pages[h as usize].pid != pid || self.pages[h as usize].store != store) {
+            h = self.pages[h as usize].collision;
+        }
+        h
+    }
+
+    //
+    // Throw away page from cache if it is cached (used by rollback to discard spilled pages)
+    //
+    pub fn discard_page(&mut self, store: StoreId, pid: PageId) {
+        let h = self.find(store, pid);
+        if h != 0 {
+            debug_assert!(self.pages[h as usize].access_count == 0);
+            self.pin(h);
+            self.pages[h as usize].access_count = 1;
+            self.throw_buffer(h);
+        }
+    }
+
     //
     // Decrement buffer's access counter and release buffer if it is last reference
     //
@@ -196,25 +393,34 @@ impl BufferManager {
     }
 
     //
-    // Find buffer with specified page or allocate new buffer
+    // Find buffer with specified page of the store or allocate new buffer
     //
-    pub fn get_buffer(&mut self, pid: PageId) -> Result<BufferId> {
-        let hash = pid as usize % self.hash_table.len();
-        let mut h = self.hash_table[hash];
-        while h != 0 {
-            if self.pages[h as usize].pid == pid {
-                let access_count = self.pages[h as usize].access_count;
-                debug_assert!(access_count < u16::MAX - 1);
-                if access_count == 0 {
-                    self.pin(h);
-                }
-                self.pages[h as usize].access_count = access_count + 1;
-                return Ok(h);
+    pub fn get_buffer(&mut self, store: StoreId, pid: PageId) -> Result<BufferId> {
+        let h = self.find(store, pid);
+        if h != 0 {
+            let access_count = self.pages[h as usize].access_count;
+            debug_assert!(access_count < u16::MAX - 1);
+            if access_count == 0 {
+                self.pin(h);
             }
-            h = self.pages[h as usize].collision;
+            self.pages[h as usize].access_count = access_count + 1;
+            return Ok(h);
         }
         // page not found in cache
-        h = self.free_pages;
+        let h = self.alloc_buffer()?;
+        self.pages[h as usize].access_count = 1;
+        self.pages[h as usize].store = store;
+        self.pages[h as usize].pid = pid;
+        self.pages[h as usize].state = PAGE_RAW;
+        self.insert(h);
+        Ok(h)
+    }
+
+    //
+    // Allocate pinned buffer: take free buffer, use new part of pool or evict least recently used page
+    //
+    fn alloc_buffer(&mut self) -> Result<BufferId> {
+        let mut h = self.free_pages;
         if h != 0 {
             // has some free pages
             self.free_pages = self.pages[h as usize].next;
@@ -229,18 +435,91 @@ impl BufferManager {
             } else {
                 // Replace least recently used page
                 let victim = self.tail;
-                anyhow::ensure!(victim != 0);
+                if victim == 0 {
+                    // all buffers are pinned by B-Tree path or dirty pages: stores sharing the pool
+                    // can pin their paths at the same time
+                    anyhow::bail!(StoreError::CacheTooSmall {
+                        cache_size: self.pages.len(),
+                        required: self.stores.iter().filter(|s| s.registered).map(|s| s.required).sum(),
+                    });
+                }
                 debug_assert!(self.pages[victim as usize].access_count == 0);
                 debug_assert!((self.pages[victim as usize].state & PAGE_DIRTY) == 0);
                 self.pin(victim);
                 self.remove(victim);
+                self.report_eviction(victim);
                 h = victim;
             }
         }
-        self.pages[h as usize].access_count = 1;
-        self.pages[h as usize].pid = pid;
-        self.pages[h as usize].state = PAGE_RAW;
-        self.insert(h);
         Ok(h)
     }
-}
\ No newline at end of file
+
+    //
+    // Evict clean unpinned pages in LRU order until number of cached pages drops to the target
+    // (or there are no more such pages). Returns number of evicted pages.
+    //
+    pub fn trim(&mut self, target: BufferId) -> usize {
+        let mut evicted = 0;
+        let mut id = self.tail;
+        while id != 0 && self.cached > target {
+            let prev = self.pages[id as usize].prev;
+            if !self.is_spilled(id) {
+                self.pin(id);
+                self.pages[id as usize].access_count = 1;
+                self.report_eviction(id);
+                self.throw_buffer(id);
+                evicted += 1;
+            }
+            id = prev;
+        }
+        evicted
+    }
+
+    //
+    // Page image in buffer is newer than in data file: it is spilled to WAL by current transaction
+    //
+    fn is_spilled(&self, id: BufferId) -> bool {
+        let buf = &self.pages[id as usize];
+        self.stores[buf.store as usize].spilled.contains_key(&buf.pid)
+    }
+
+    //
+    // Notify store owning the page about its eviction. Hook is called while holding buffer manager lock.
+    //
+    fn report_eviction(&self, id: BufferId) {
+        let buf = &self.pages[id as usize];
+        if let Some(hook) = &self.stores[buf.store as usize].eviction_hook {
+            hook(buf.pid, !self.is_spilled(id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exhausted_cache_reports_required_size() {
+        let mut bm = BufferManager::new(8);
+        let (first, _) = bm.register().unwrap();
+        let (second, _) = bm.register().unwrap();
+        bm.store_mut(first).required = 6;
+        bm.store_mut(second).required = 5;
+        // pin all buffers: metadata buffers of both stores are already pinned
+        for pid in 1..7 {
+            bm.get_buffer(first, pid).unwrap();
+        }
+        let err = bm.get_buffer(second, 1).unwrap_err();
+        match err.downcast::<StoreError>().unwrap() {
+            StoreError::CacheTooSmall { cache_size, required } => assert_eq!((cache_size, required), (8, 11)),
+            err => panic!("unexpected error {err}"),
+        }
+        // requirement of closed store is not counted
+        bm.unregister(second);
+        let err = bm.get_buffer(first, 7).unwrap_err();
+        assert!(matches!(
+            err.downcast::<StoreError>().unwrap(),
+            StoreError::CacheTooSmall { required: 6, .. }
+        ));
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::Result;
+use std::iter;
+use std::sync::{Condvar, Mutex, OnceLock, PoisonError, RwLock};
+
+use crate::buffer_manager::BufferManager;
+use crate::config::{BufferId, MIN_FREE_BUFFERS, N_BUSY_EVENTS};
+use crate::pagedata::PageData;
+
+///
+/// Buffer pool which can be shared by multiple stores opened by `Store::open_with_pool`.
+/// Pages of all stores are cached in the same pool and evicted in LRU order regardless of the store they belong to,
+/// so memory used by the stores is bounded by size of the pool rather than by sum of their `StoreConfig::cache_size`.
+/// Each opened store pins one buffer with its metadata page.
+///
+pub struct SharedBufferPool {
+    pub(crate) buf_mgr: Mutex<BufferManager>,
+    pub(crate) busy_events: Vec<Condvar>,
+    // Content of buffers. Pages are allocated on first use of buffer, so memory is not committed for unused part of cache.
+    pool: Vec<OnceLock<Box<RwLock<PageData>>>>,
+    // Pool is created by store for itself
+    private: bool,
+}
+
+impl SharedBufferPool {
+    ///
+    /// Create buffer pool of the specified size in pages
+    ///
+    pub fn new(size: usize) -> Result<SharedBufferPool> {
+        anyhow::ensure!(size > MIN_FREE_BUFFERS, "Buffer pool should contain more than {} pages", MIN_FREE_BUFFERS);
+        Ok(Self::create(size, N_BUSY_EVENTS, false))
+    }
+
+    //
+    // Create pool used only by one store
+    //
+    pub(crate) fn private(size: usize, busy_events: usize) -> SharedBufferPool {
+        Self::create(size, busy_events, true)
+    }
+
+    fn create(size: usize, busy_events: usize, private: bool) -> SharedBufferPool {
+        SharedBufferPool {
+            buf_mgr: Mutex::new(BufferManager::new(size)),
+            busy_events: iter::repeat_with(Condvar::new).take(busy_events).collect(),
+            pool: iter::repeat_with(OnceLock::new).take(size).collect(),
+            private,
+        }
+    }
+
+    ///
+    /// Size of the pool in pages
+    ///
+    pub fn size(&self) -> usize {
+        self.pool.len()
+    }
+
+    ///
+    /// Number of pages for which memory was allocated (pages are allocated on first use of buffer)
+    ///
+    pub fn allocated_pages(&self) -> usize {
+        self.pool.iter().filter(|page| page.get().is_some()).count()
+    }
+
+    ///
+    /// Number of pages of all stores currently cached in the pool
+    ///
+    pub fn cached_pages(&self) -> usize {
+        self.buf_mgr.lock().unwrap_or_else(PoisonError::into_inner).cached as usize
+    }
+
+    ///
+    /// Number of opened stores using the pool
+    ///
+    pub fn store_count(&self) -> usize {
+        let bm = self.buf_mgr.lock().unwrap_or_else(PoisonError::into_inner);
+        bm.stores.iter().filter(|s| s.registered).count()
+    }
+
+    //
+    // Get content of buffer, allocating it on first access
+    //
+    pub(crate) fn page(&self, buf: BufferId) -> &RwLock<PageData> {
+        self.pool[buf as usize].get_or_init(|| Box::new(RwLock::new(PageData::new())))
+    }
+
+    //
+    // Content of buffers which were already allocated
+    //
+    pub(crate) fn allocated(&self) -> impl Iterator<Item = &RwLock<PageData>> {
+        self.pool.iter().filter_map(|page| page.get().map(|page| &**page))
+    }
+
+    pub(crate) fn is_private(&self) -> bool {
+        self.private
+    }
+}
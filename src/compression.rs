@@ -0,0 +1,646 @@
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::Mutex;
+
+use snap::raw::{max_compress_len, Decoder, Encoder};
+
+use crate::config::{page_offset, PageId, COMPRESSED_PAGE_MARKER, FS_BLOCK_SIZE, PAGE_SIZE};
+use crate::storage::Storage;
+
+const RECORD_HEADER_SIZE: usize = 28; // marker, page identifier, sequence number, length of image and checksum
+const MAX_RECORD_BLOCKS: usize = (RECORD_HEADER_SIZE + PAGE_SIZE).div_ceil(FS_BLOCK_SIZE); // page stored uncompressed
+const SCAN_CHUNK_BLOCKS: usize = 256; // number of blocks read at once when page map is reconstructed
+
+//
+// Location of the newest image of the page
+//
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Slot {
+    block: u64,    // first block of the record
+    blocks: usize, // number of blocks occupied by the record
+    seq: u64,      // sequence number of the record
+}
+
+//
+// Header of page record
+//
+struct RecordHeader {
+    pid: PageId,
+    seq: u64,
+    len: usize, // length of compressed image (PAGE_SIZE if page is stored uncompressed)
+}
+
+impl RecordHeader {
+    fn blocks(&self) -> usize {
+        (RECORD_HEADER_SIZE + self.len).div_ceil(FS_BLOCK_SIZE)
+    }
+}
+
+struct PageMap {
+    slots: HashMap<PageId, Slot>,
+    n_pages: PageId,          // virtual size of storage in pages
+    end: u64,                 // number of blocks following metadata page
+    free: Vec<BTreeSet<u64>>, // free[n - 1] contains first blocks of free extents of n blocks
+    released: Vec<Slot>,      // records of replaced images: they can be reused only after the next sync
+    seq: u64,                 // sequence number of the last allocated record
+}
+
+impl PageMap {
+    //
+    // Find free extent with the smallest position which can hold record of the specified number of blocks.
+    // Returns its first block and size.
+    //
+    fn find_free(&self, blocks: usize) -> Option<(u64, usize)> {
+        (blocks..=MAX_RECORD_BLOCKS)
+            .filter_map(|n| self.free[n - 1].first().map(|&block| (block, n)))
+            .min()
+    }
+
+    //
+    // Allocate extent for the record of the specified number of blocks. Extents at the beginning of the file
+    // are preferred, so that space at the end of the file can be reclaimed by truncation.
+    //
+    fn allocate(&mut self, blocks: usize) -> Slot {
+        self.seq += 1;
+        let block = if let Some((block, n)) = self.find_free(blocks) {
+            self.free[n - 1].remove(&block);
+            if n > blocks {
+                self.free_extent(block + blocks as u64, n - blocks);
+            }
+            block
+        } else {
+            let block = self.end;
+            self.end += blocks as u64;
+            block
+        };
+        Slot {
+            block,
+            blocks,
+            seq: self.seq,
+        }
+    }
+
+    //
+    // Make written record the current image of the page, previous image is released
+    //
+    fn install(&mut self, pid: PageId, slot: Slot) {
+        match self.slots.entry(pid) {
+            Entry::Occupied(mut e) if e.get().seq < slot.seq => self.released.push(e.insert(slot)),
+            Entry::Occupied(_) => self.released.push(slot),
+            Entry::Vacant(e) => {
+                e.insert(slot);
+            }
+        }
+        self.n_pages = self.n_pages.max(pid + 1);
+    }
+
+    fn free_extent(&mut self, block: u64, blocks: usize) {
+        self.free[blocks - 1].insert(block);
+    }
+
+    //
+    // Remove free extents located at the end of the file
+    //
+    fn trim(&mut self) {
+        let mut extents: Vec<(u64, usize)> = Vec::new();
+        for (i, list) in self.free.iter_mut().enumerate() {
+            extents.extend(std::mem::take(list).into_iter().map(|block| (block, i + 1)));
+        }
+        extents.sort_unstable();
+        while let Some(&(block, blocks)) = extents.last() {
+            if block + blocks as u64 != self.end {
+                break;
+            }
+            self.end = block;
+            extents.pop();
+        }
+        for (block, blocks) in extents {
+            self.free_extent(block, blocks);
+        }
+    }
+}
+
+//
+// Storage wrapper compressing B-Tree pages written to the data file.
+// Compressed pages have different sizes, so page can not be located at `pid * PAGE_SIZE`.
+// Instead, the file after metadata page (page 0, which is never compressed) consists of blocks of FS_BLOCK_SIZE bytes
+// and each page image is stored in a record occupying one or more consecutive blocks: header (marker, page identifier,
+// sequence number, length of compressed image and checksum) followed by compressed image.
+// Page map (pid -> location of the newest record) is kept in memory. It is not saved in the file:
+// it is reconstructed on open by scanning all records. For each page the record with the largest sequence
+// number is used, records with wrong checksum (torn by crash) are ignored.
+// Records are never overwritten in place: new image of the page is written to free blocks (or appended
+// to the file) and blocks of the previous image can be reused only after the next sync makes new image durable.
+// So crash can not damage both images of the page. Compression mode is chosen when store is created
+// (`META_FLAG_PAGE_COMPRESSION`), since layout of the file is different.
+//
+pub struct CompressedStorage {
+    storage: Box<dyn Storage>,
+    map: Mutex<PageMap>,
+}
+
+impl CompressedStorage {
+    pub fn open(storage: Box<dyn Storage>) -> io::Result<CompressedStorage> {
+        let map = Self::scan(storage.as_ref())?;
+        Ok(CompressedStorage {
+            storage,
+            map: Mutex::new(map),
+        })
+    }
+
+    //
+    // Reconstruct page map after underlying storage is replaced
+    //
+    pub fn reload(&self) -> io::Result<()> {
+        let map = Self::scan(self.storage.as_ref())?;
+        *self.map.lock().unwrap() = map;
+        Ok(())
+    }
+
+    fn block_offset(block: u64) -> u64 {
+        PAGE_SIZE as u64 + block * FS_BLOCK_SIZE as u64
+    }
+
+    //
+    // Check if I/O request is for the whole B-Tree page
+    //
+    fn is_page(len: usize, offs: u64) -> bool {
+        len == PAGE_SIZE && offs != 0 && offs.is_multiple_of(PAGE_SIZE as u64)
+    }
+
+    fn unaligned() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "compressed storage supports only I/O of whole pages",
+        )
+    }
+
+    //
+    // Parse header of the record, returns None if it is not a valid record header
+    //
+    fn parse_header(buf: &[u8]) -> Option<RecordHeader> {
+        if buf.len() < RECORD_HEADER_SIZE || u32::from_be_bytes(buf[0..4].try_into().unwrap()) != COMPRESSED_PAGE_MARKER {
+            return None;
+        }
+        let pid = u64::from_be_bytes(buf[4..12].try_into().unwrap());
+        let seq = u64::from_be_bytes(buf[12..20].try_into().unwrap());
+        let len = u32::from_be_bytes(buf[20..24].try_into().unwrap()) as usize;
+        let pid = PageId::try_from(pid).ok()?;
+        if pid == 0 || len > PAGE_SIZE {
+            return None;
+        }
+        Some(RecordHeader {
+            pid,
+            seq,
+            len,
+        })
+    }
+
+    //
+    // Parse record located at the beginning of the buffer and check its checksum
+    //
+    fn parse_record(buf: &[u8]) -> Option<RecordHeader> {
+        let hdr = Self::parse_header(buf)?;
+        let end = RECORD_HEADER_SIZE + hdr.len;
+        if end > buf.len() {
+            return None;
+        }
+        let crc = u32::from_be_bytes(buf[24..28].try_into().unwrap());
+        let computed = crc32c::crc32c_append(crc32c::crc32c(&buf[..24]), &buf[RECORD_HEADER_SIZE..end]);
+        (crc == computed).then_some(hdr)
+    }
+
+    //
+    // Scan all records and build page map
+    //
+    fn scan(storage: &dyn Storage) -> io::Result<PageMap> {
+        let file_size = storage.size()?;
+        // partially written last block can not contain complete record
+        let n_blocks = file_size.saturating_sub(PAGE_SIZE as u64) / FS_BLOCK_SIZE as u64;
+        let mut slots: HashMap<PageId, Slot> = HashMap::new();
+        let mut chunk = vec![0u8; SCAN_CHUNK_BLOCKS * FS_BLOCK_SIZE];
+        let mut chunk_start = 0u64;
+        let mut chunk_end = 0u64;
+        let mut max_seq = 0u64;
+        let mut stale = false;
+        let mut block = 0u64;
+        while block < n_blocks {
+            if chunk_end < n_blocks && block + MAX_RECORD_BLOCKS as u64 > chunk_end {
+                chunk_start = block;
+                chunk_end = n_blocks.min(block + SCAN_CHUNK_BLOCKS as u64);
+                let len = (chunk_end - chunk_start) as usize * FS_BLOCK_SIZE;
+                storage.read_exact_at(&mut chunk[..len], Self::block_offset(block))?;
+            }
+            let from = (block - chunk_start) as usize * FS_BLOCK_SIZE;
+            let to = (chunk_end - chunk_start) as usize * FS_BLOCK_SIZE;
+            let Some(hdr) = Self::parse_record(&chunk[from..to]) else {
+                block += 1;
+                continue;
+            };
+            let slot = Slot {
+                block,
+                blocks: hdr.blocks(),
+                seq: hdr.seq,
+            };
+            max_seq = max_seq.max(hdr.seq);
+            match slots.entry(hdr.pid) {
+                Entry::Occupied(mut e) => {
+                    stale = true;
+                    if e.get().seq < slot.seq {
+                        e.insert(slot);
+                    }
+                }
+                Entry::Vacant(e) => {
+                    e.insert(slot);
+                }
+            }
+            block += slot.blocks as u64;
+        }
+        if stale {
+            // blocks of stale images are reused, so the newest images should be durable
+            storage.sync_data()?;
+        }
+        let mut used = vec![false; n_blocks as usize];
+        for slot in slots.values() {
+            used[slot.block as usize..slot.block as usize + slot.blocks].fill(true);
+        }
+        let end = used.iter().rposition(|&used| used).map_or(0, |last| last + 1);
+        let mut map = PageMap {
+            n_pages: slots.keys().map(|&pid| pid + 1).max().unwrap_or(0),
+            slots,
+            end: end as u64,
+            free: vec![BTreeSet::new(); MAX_RECORD_BLOCKS],
+            released: Vec::new(),
+            seq: max_seq,
+        };
+        let mut block = 0;
+        while block < end {
+            if used[block] {
+                block += 1;
+            } else {
+                let blocks = used[block..end]
+                    .iter()
+                    .take(MAX_RECORD_BLOCKS)
+                    .take_while(|&&used| !used)
+                    .count();
+                map.free_extent(block as u64, blocks);
+                block += blocks;
+            }
+        }
+        Ok(map)
+    }
+
+    //
+    // Read record and unpack page image from it. Returns false if record doesn't contain expected image
+    // (it was reused after the page map was looked up).
+    //
+    fn read_record(&self, pid: PageId, slot: Slot, buf: &mut [u8]) -> io::Result<bool> {
+        let mut record = vec![0u8; slot.blocks * FS_BLOCK_SIZE];
+        self.storage.read_exact_at(&mut record, Self::block_offset(slot.block))?;
+        let Some(hdr) = Self::parse_record(&record).filter(|hdr| hdr.pid == pid && hdr.seq == slot.seq) else {
+            return Ok(false);
+        };
+        let image = &record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + hdr.len];
+        if hdr.len == PAGE_SIZE {
+            buf.copy_from_slice(image);
+        } else {
+            let size = Decoder::new()
+                .decompress(image, buf)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if size != PAGE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid size of decompressed page",
+                ));
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_page(&self, buf: &mut [u8], pid: PageId) -> io::Result<usize> {
+        loop {
+            let slot = {
+                let map = self.map.lock().unwrap();
+                if pid >= map.n_pages {
+                    return Ok(0);
+                }
+                map.slots.get(&pid).copied()
+            };
+            let Some(slot) = slot else {
+                // page was never written
+                buf.fill(0);
+                return Ok(PAGE_SIZE);
+            };
+            if self.read_record(pid, slot, buf)? {
+                return Ok(PAGE_SIZE);
+            }
+            if self.map.lock().unwrap().slots.get(&pid) == Some(&slot) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("record of compressed page {} is damaged", pid),
+                ));
+            }
+        }
+    }
+
+    #[allow(clippy::unnecessary_cast)] // PageId is u64 with `large-pages` feature
+    fn write_page(&self, buf: &[u8], pid: PageId) -> io::Result<()> {
+        let mut record = vec![0u8; (RECORD_HEADER_SIZE + max_compress_len(PAGE_SIZE)).max(MAX_RECORD_BLOCKS * FS_BLOCK_SIZE)];
+        let mut len = Encoder::new()
+            .compress(buf, &mut record[RECORD_HEADER_SIZE..])
+            .map_err(io::Error::other)?;
+        if len >= PAGE_SIZE {
+            // page is not compressible
+            record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + PAGE_SIZE].copy_from_slice(buf);
+            len = PAGE_SIZE;
+        }
+        let blocks = (RECORD_HEADER_SIZE + len).div_ceil(FS_BLOCK_SIZE);
+        let slot = self.map.lock().unwrap().allocate(blocks);
+        record[0..4].copy_from_slice(&COMPRESSED_PAGE_MARKER.to_be_bytes());
+        record[4..12].copy_from_slice(&(pid as u64).to_be_bytes());
+        record[12..20].copy_from_slice(&slot.seq.to_be_bytes());
+        record[20..24].copy_from_slice(&(len as u32).to_be_bytes());
+        let crc = crc32c::crc32c_append(crc32c::crc32c(&record[..24]), &record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len]);
+        record[24..28].copy_from_slice(&crc.to_be_bytes());
+        let end = RECORD_HEADER_SIZE + len;
+        record[end..blocks * FS_BLOCK_SIZE].fill(0);
+        if let Err(err) = self
+            .storage
+            .write_all_at(&record[..blocks * FS_BLOCK_SIZE], Self::block_offset(slot.block))
+        {
+            // content of the extent is unknown: reuse it only after sync
+            self.map.lock().unwrap().released.push(slot);
+            return Err(err);
+        }
+        self.map.lock().unwrap().install(pid, slot);
+        Ok(())
+    }
+
+    //
+    // Move records from the end of the file to free extents preceding them
+    //
+    fn relocate_tail(&self) -> io::Result<()> {
+        let mut slots: Vec<(PageId, Slot)> = {
+            let map = self.map.lock().unwrap();
+            map.slots.iter().map(|(&pid, &slot)| (pid, slot)).collect()
+        };
+        slots.sort_unstable_by_key(|(_, slot)| Reverse(slot.block));
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for (pid, slot) in slots {
+            if self.map.lock().unwrap().find_free(slot.blocks).is_none_or(|(block, _)| block > slot.block) {
+                break;
+            }
+            self.read_page(&mut buf, pid)?;
+            self.write_page(&buf, pid)?;
+        }
+        Ok(())
+    }
+
+    //
+    // Sync underlying storage and make blocks of replaced images available for reuse
+    //
+    fn sync(&self, sync: impl FnOnce(&dyn Storage) -> io::Result<()>) -> io::Result<()> {
+        let released = std::mem::take(&mut self.map.lock().unwrap().released);
+        if let Err(err) = sync(self.storage.as_ref()) {
+            self.map.lock().unwrap().released.extend(released);
+            return Err(err);
+        }
+        let mut map = self.map.lock().unwrap();
+        for slot in released {
+            map.free_extent(slot.block, slot.blocks);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for CompressedStorage {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        if offs + buf.len() as u64 <= PAGE_SIZE as u64 {
+            self.storage.read_at(buf, offs)
+        } else if Self::is_page(buf.len(), offs) {
+            self.read_page(buf, (offs / PAGE_SIZE as u64) as PageId)
+        } else {
+            Err(Self::unaligned())
+        }
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        if offs + buf.len() as u64 <= PAGE_SIZE as u64 {
+            self.storage.write_all_at(buf, offs)
+        } else if Self::is_page(buf.len(), offs) {
+            self.write_page(buf, (offs / PAGE_SIZE as u64) as PageId)
+        } else {
+            Err(Self::unaligned())
+        }
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.sync(|storage| storage.sync_all())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.sync(|storage| storage.sync_data())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        if size < PAGE_SIZE as u64 {
+            // only (part of) metadata page is left
+            self.storage.set_len(size)?;
+            let mut map = self.map.lock().unwrap();
+            map.slots.clear();
+            map.free.iter_mut().for_each(BTreeSet::clear);
+            map.released.clear();
+            map.n_pages = 0;
+            map.end = 0;
+            return Ok(());
+        }
+        if !size.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(Self::unaligned());
+        }
+        let n_pages = (size / PAGE_SIZE as u64) as PageId;
+        let truncated: Vec<Slot> = {
+            let mut map = self.map.lock().unwrap();
+            if n_pages >= map.n_pages {
+                map.n_pages = n_pages;
+                return Ok(());
+            }
+            map.n_pages = n_pages;
+            let pids: Vec<PageId> = map.slots.keys().copied().filter(|&pid| pid >= n_pages).collect();
+            pids.iter().map(|pid| map.slots.remove(pid).unwrap()).collect()
+        };
+        for slot in &truncated {
+            // invalidate record, so that truncated page is not restored by scan of the file
+            self.storage
+                .write_all_at(&[0u8; RECORD_HEADER_SIZE], Self::block_offset(slot.block))?;
+        }
+        {
+            let mut map = self.map.lock().unwrap();
+            for slot in truncated {
+                map.free_extent(slot.block, slot.blocks);
+            }
+        }
+        self.relocate_tail()?;
+        // blocks of relocated records can be reclaimed only when their new images are durable
+        self.sync_data()?;
+        let end = {
+            let mut map = self.map.lock().unwrap();
+            map.trim();
+            map.end
+        };
+        if self.storage.size()? > Self::block_offset(end) {
+            self.storage.set_len(Self::block_offset(end))?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let n_pages = self.map.lock().unwrap().n_pages;
+        Ok(page_offset(n_pages).max(self.storage.size()?.min(PAGE_SIZE as u64)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+
+    // in-memory storage shared with test, so that file can be reopened
+    #[derive(Clone, Default)]
+    struct MemStorage(Arc<RwLock<Vec<u8>>>);
+
+    impl Storage for MemStorage {
+        fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+            let data = self.0.read().unwrap();
+            let offs = (offs as usize).min(data.len());
+            let n = buf.len().min(data.len() - offs);
+            buf[..n].copy_from_slice(&data[offs..offs + n]);
+            Ok(n)
+        }
+
+        fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+            let mut data = self.0.write().unwrap();
+            let end = offs as usize + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[offs as usize..end].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn sync_all(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn sync_data(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_len(&self, size: u64) -> io::Result<()> {
+            self.0.write().unwrap().resize(size as usize, 0);
+            Ok(())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.0.read().unwrap().len() as u64)
+        }
+    }
+
+    fn page(seed: u8) -> Vec<u8> {
+        (0..PAGE_SIZE).map(|i| seed.wrapping_add((i / 64) as u8)).collect()
+    }
+
+    fn read(storage: &CompressedStorage, pid: PageId) -> Vec<u8> {
+        let mut buf = vec![0u8; PAGE_SIZE];
+        storage.read_exact_at(&mut buf, page_offset(pid)).unwrap();
+        buf
+    }
+
+    #[test]
+    fn newest_image_is_found_after_reopen() {
+        let mem = MemStorage::default();
+        let storage = CompressedStorage::open(Box::new(mem.clone())).unwrap();
+        storage.write_all_at(&[1u8; PAGE_SIZE], 0).unwrap();
+        for seed in 0..3 {
+            for pid in 1..10 {
+                storage.write_all_at(&page(seed * 10 + pid as u8), page_offset(pid)).unwrap();
+            }
+        }
+        assert_eq!(storage.size().unwrap(), 10 * PAGE_SIZE as u64);
+        // old images are kept until sync, so reopened storage has to choose the newest one
+        let reopened = CompressedStorage::open(Box::new(mem.clone())).unwrap();
+        for pid in 1..10 {
+            assert_eq!(read(&reopened, pid), page(20 + pid as u8));
+        }
+        assert_eq!(reopened.size().unwrap(), 10 * PAGE_SIZE as u64);
+    }
+
+    #[test]
+    fn released_blocks_are_reused_after_sync() {
+        let mem = MemStorage::default();
+        let storage = CompressedStorage::open(Box::new(mem.clone())).unwrap();
+        storage.write_all_at(&page(1), PAGE_SIZE as u64).unwrap();
+        let size = mem.size().unwrap();
+        storage.write_all_at(&page(2), PAGE_SIZE as u64).unwrap();
+        assert!(mem.size().unwrap() > size, "image should not be overwritten before sync");
+        storage.sync_data().unwrap();
+        let size = mem.size().unwrap();
+        storage.write_all_at(&page(3), PAGE_SIZE as u64).unwrap();
+        assert_eq!(mem.size().unwrap(), size);
+        assert_eq!(read(&storage, 1), page(3));
+    }
+
+    #[test]
+    fn torn_record_is_ignored() {
+        let mem = MemStorage::default();
+        let storage = CompressedStorage::open(Box::new(mem.clone())).unwrap();
+        storage.write_all_at(&page(1), PAGE_SIZE as u64).unwrap();
+        storage.sync_data().unwrap();
+        let offs = mem.size().unwrap();
+        storage.write_all_at(&page(2), PAGE_SIZE as u64).unwrap();
+        // damage compressed image of the newest record
+        mem.0.write().unwrap()[offs as usize + RECORD_HEADER_SIZE] ^= 0xFF;
+        let reopened = CompressedStorage::open(Box::new(mem)).unwrap();
+        assert_eq!(read(&reopened, 1), page(1));
+    }
+
+    #[test]
+    fn uncompressible_page_is_stored_as_is() {
+        let mem = MemStorage::default();
+        let storage = CompressedStorage::open(Box::new(mem.clone())).unwrap();
+        let mut seed = 1u32;
+        let data: Vec<u8> = (0..PAGE_SIZE)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        storage.write_all_at(&data, 2 * PAGE_SIZE as u64).unwrap();
+        let reopened = CompressedStorage::open(Box::new(mem)).unwrap();
+        assert_eq!(read(&reopened, 2), data);
+        // page which was never written is read as zeros, pages beyond the end are not available
+        assert_eq!(read(&reopened, 1), vec![0u8; PAGE_SIZE]);
+        let mut buf = vec![0u8; PAGE_SIZE];
+        assert!(reopened.read_exact_at(&mut buf, 3 * PAGE_SIZE as u64).is_err());
+    }
+
+    #[test]
+    fn truncated_pages_are_not_restored() {
+        let mem = MemStorage::default();
+        let storage = CompressedStorage::open(Box::new(mem.clone())).unwrap();
+        storage.write_all_at(&[1u8; PAGE_SIZE], 0).unwrap();
+        for pid in 1..10 {
+            storage.write_all_at(&page(pid as u8), page_offset(pid)).unwrap();
+        }
+        let size = mem.size().unwrap();
+        storage.set_len(5 * PAGE_SIZE as u64).unwrap();
+        assert_eq!(storage.size().unwrap(), 5 * PAGE_SIZE as u64);
+        assert!(mem.size().unwrap() < size);
+        let reopened = CompressedStorage::open(Box::new(mem)).unwrap();
+        assert_eq!(reopened.size().unwrap(), 5 * PAGE_SIZE as u64);
+        for pid in 1..5 {
+            assert_eq!(read(&reopened, pid), page(pid as u8));
+        }
+    }
+}
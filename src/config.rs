@@ -1,33 +1,102 @@
 // PageId (u32) takes 4 bytes to store
+#[cfg(not(feature = "large-pages"))]
 pub const PID_SIZE: usize = 4;
+// PageId (u64) takes 8 bytes to store
+#[cfg(feature = "large-pages")]
+pub const PID_SIZE: usize = 8;
 // 8 KB
 pub const PAGE_SIZE: usize = 8192;
-// 64 bit target
-pub const USIZE_SIZE: usize = 8;
 
-// just the number of items in the page
-pub const PAGE_HEADER_SIZE: usize = 2;
+// number of items in the page (u16), page type (u8) and reserved byte
+pub const PAGE_HEADER_SIZE: usize = 4;
+pub const PAGE_TYPE_OFFS: usize = 2; // offset of page type in page header
 
+#[cfg(not(feature = "large-pages"))]
 pub type PageId = u32;
+#[cfg(feature = "large-pages")]
+pub type PageId = u64;
+#[cfg(not(feature = "large-pages"))]
+pub type AtomicPageId = std::sync::atomic::AtomicU32;
+#[cfg(feature = "large-pages")]
+pub type AtomicPageId = std::sync::atomic::AtomicU64;
 pub type BufferId = u32;
+pub type StoreId = u32; // identifier of store in buffer pool
 // offset within page, actually only 16 bits is enough, but use usize to avoid type casts when used as an index
 pub type ItemPointer = usize;
 
 // #[derive(Default)]
 // pub type PageData = [u8; PAGE_SIZE];
 
+// Offset of the page in data file
+#[allow(clippy::unnecessary_cast)] // PageId is u64 with `large-pages` feature
+pub const fn page_offset(pid: PageId) -> u64 {
+    pid as u64 * PAGE_SIZE as u64
+}
+
 pub const META_PID: PageId = 0;
-// the maximum pgnum that is used by the db for its own purposes. For now, only page 0 is used as the
-// header page. It means all other page numbers can be used.
-pub const MAX_NON_DATA_PID: PageId = 0;
 
 pub type Key = Vec<u8>;
 pub type Value = Vec<u8>;
 
 pub const N_BUSY_EVENTS: usize = 8; // number of condition variables used for waiting read completion
 
-pub const METADATA_SIZE: usize = 4 * 4;
+pub const METADATA_FIXED_SIZE: usize = 3 * PID_SIZE + 3 * 4 + 8; // fields known to all versions
+// space reserved for extension fields (TLV records) following fixed part of metadata
+pub const METADATA_EXT_SIZE: usize = 224;
+pub const METADATA_SIZE: usize = METADATA_FIXED_SIZE + METADATA_EXT_SIZE;
+pub const META_EXT_HEADER_SIZE: usize = 4; // type (u16) and length (u16) of extension field
+pub const META_EXT_FORMAT_VERSION: u16 = 1; // extension field containing version of store format (u32)
+pub const META_EXT_WAL_END: u16 = 2; // extension field containing size of WAL needed to recover the data file (u64)
+pub const META_EXT_STORE_UUID: u16 = 3; // extension field containing random identifier of the store assigned at creation
+pub const STORE_UUID_SIZE: usize = 16;
+
+// Version of store format:
+// 0 - metadata without signature: free, size, root and height only (LEGACY_METADATA_SIZE bytes)
+// 1 - metadata with signature but without format version field (any layout preceding typed pages)
+// 2 - format version field in metadata extension area and page type in page header
+// 3 - compressed pages are stored in records located through page map instead of hole-punched page slots
+pub const FORMAT_VERSION: u32 = 3;
+pub const LEGACY_METADATA_SIZE: usize = 4 * 4; // size of metadata of version 0 stores
+// signatures stored at the beginning of metadata to recognize store files: they also encode width of page identifiers,
+// since layout of metadata, WAL records and internal pages depends on it
+pub const STORE_MAGIC_32: u32 = u32::from_be_bytes(*b"SKV\0");
+pub const STORE_MAGIC_64: u32 = u32::from_be_bytes(*b"SKV8");
+#[cfg(not(feature = "large-pages"))]
+pub const STORE_MAGIC: u32 = STORE_MAGIC_32;
+#[cfg(feature = "large-pages")]
+pub const STORE_MAGIC: u32 = STORE_MAGIC_64;
+// offset of shadow copy of metadata (and its checksum) within page 0, it should be located in different disk sector
+pub const META_SHADOW_OFFS: usize = PAGE_SIZE / 2;
 
 
 pub const MAX_VALUE_LEN: usize = PAGE_SIZE / 4; // assume that pages may fit at least 3 items
-pub const MAX_KEY_LEN: usize = u8::MAX as usize; // should fit in one byte
\ No newline at end of file
+pub const MAX_KEY_LEN: usize = u8::MAX as usize; // should fit in one byte
+
+pub const MERGE_BATCH_SIZE: usize = 1024; // maximal number of keys copied by one transaction of merge
+pub const MIN_FREE_BUFFERS: usize = 3; // buffers needed besides path and split pages of B-Tree: metadata, new root and spare one
+
+pub const FS_BLOCK_SIZE: usize = 4096; // granularity of file system space allocation (unit of space allocation for compressed pages)
+pub const DIRECT_IO_ALIGNMENT: usize = 4096; // alignment of buffers, offsets and lengths of direct I/O (`PageData` is aligned to it)
+pub const COMPRESSED_PAGE_MARKER: u32 = u32::MAX; // marker starting record with compressed page
+
+pub const IO_RETRY_DELAY_MS: u64 = 1; // delay before first retry of I/O operation failed with transient error (doubled for each next retry)
+
+pub const META_FLAG_VALUE_LOG: u32 = 1; // values are stored in value log, B-Tree contains references to them
+pub const VALUE_REF_SIZE: usize = 16; // reference to the value in value log: offset, length and checksum
+pub const MAX_LOGGED_VALUE_LEN: usize = u32::MAX as usize; // maximal length of value stored in value log
+
+pub const META_FLAG_KEY_DICTIONARY: u32 = 2; // keys are encoded using key dictionary stored in dictionary page
+pub const DICTIONARY_PID: PageId = 1; // page containing key dictionary (allocated when store is created)
+pub const MAX_DICTIONARY_SIZE: usize = 127; // maximal number of key dictionary components (codes should fit in one byte)
+
+pub const META_FLAG_TOMBSTONES: u32 = 4; // removed keys are kept as tombstones, values stored in B-Tree are tagged
+pub const VALUE_TAG_LIVE: u8 = 0; // tag of value of existing key
+pub const VALUE_TAG_TOMBSTONE: u8 = 1; // tag of tombstone: it is followed by sequence number of commit which removed key
+pub const TOMBSTONE_SIZE: usize = 9; // tag and commit sequence number
+
+pub const META_FLAG_PAGE_COMPRESSION: u32 = 8; // pages are compressed and located through page map (see compression.rs)
+
+// WAL records with metadata are marked by page identifier which can not belong to B-Tree page (0 marks commit record)
+pub const WAL_PREPARE_MARK: PageId = PageId::MAX; // transaction is prepared by two-phase commit
+pub const WAL_ABORT_MARK: PageId = PageId::MAX - 1; // prepared transaction is rolled back
+pub const WAL_META_RECORD_SIZE: usize = PID_SIZE + METADATA_SIZE + 4; // mark, metadata and checksum of transaction
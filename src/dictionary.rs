@@ -0,0 +1,201 @@
+use anyhow::Result;
+
+use crate::config::{Key, MAX_DICTIONARY_SIZE};
+
+///
+/// Dictionary of recurring key components (see `StoreConfig::key_dictionary`). Keys are treated as tuples of components
+/// delimited by `separator`: components found in the dictionary are replaced with one byte code, other components
+/// are stored as is prefixed with one byte code. Codes are assigned in order of components, so encoding preserves
+/// order of keys: it requires separator to be smaller than all bytes of components (for example zero byte or '/'
+/// for alphanumeric components), keys containing other bytes below separator are rejected.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyDictionary {
+    separator: u8,
+    components: Vec<Key>, // sorted components: component `i` is encoded as `2*i+1`
+}
+
+//
+// Encoding of key component: component equal to `i`-th dictionary component is encoded as `2*i+1`,
+// other component is encoded as `2*j` followed by component itself, where `j` is number of dictionary components
+// smaller than this component. So codes of components are ordered in the same way as components,
+// and components with the same code are ordered by their bytes.
+//
+impl KeyDictionary {
+    ///
+    /// Create dictionary of the specified components (at most `MAX_DICTIONARY_SIZE` non-empty components, each of them
+    /// should contain only bytes greater than separator)
+    ///
+    pub fn new<C: AsRef<[u8]>>(separator: u8, components: impl IntoIterator<Item = C>) -> Result<KeyDictionary> {
+        let mut components: Vec<Key> = components.into_iter().map(|c| c.as_ref().to_vec()).collect();
+        components.sort();
+        components.dedup();
+        anyhow::ensure!(
+            components.len() <= MAX_DICTIONARY_SIZE,
+            "Key dictionary can contain at most {} components",
+            MAX_DICTIONARY_SIZE
+        );
+        for component in &components {
+            anyhow::ensure!(!component.is_empty(), "Key dictionary component should be non-empty");
+            anyhow::ensure!(
+                component.len() <= u8::MAX as usize,
+                "Key dictionary component can not be longer than {} bytes",
+                u8::MAX
+            );
+            Self::check_component(separator, component)?;
+        }
+        Ok(KeyDictionary { separator, components })
+    }
+
+    ///
+    /// Separator of key components
+    ///
+    pub fn separator(&self) -> u8 {
+        self.separator
+    }
+
+    ///
+    /// Dictionary components in ascending order
+    ///
+    pub fn components(&self) -> &[Key] {
+        &self.components
+    }
+
+    ///
+    /// Encode key: replace its components found in dictionary with their codes
+    ///
+    pub fn encode(&self, key: &[u8]) -> Result<Key> {
+        let mut encoded = Vec::with_capacity(key.len() + 1);
+        for (i, component) in key.split(|b| *b == self.separator).enumerate() {
+            Self::check_component(self.separator, component)?;
+            if i != 0 {
+                encoded.push(self.separator);
+            }
+            match self.components.binary_search_by(|c| c.as_slice().cmp(component)) {
+                Ok(pos) => encoded.push((pos * 2 + 1) as u8),
+                Err(pos) => {
+                    encoded.push((pos * 2) as u8);
+                    encoded.extend_from_slice(component);
+                }
+            }
+        }
+        Ok(encoded)
+    }
+
+    //
+    // Length of key encoded by `encode`, computed without building encoded key
+    //
+    pub(crate) fn encoded_len(&self, key: &[u8]) -> Result<usize> {
+        let mut len = 0;
+        for (i, component) in key.split(|b| *b == self.separator).enumerate() {
+            Self::check_component(self.separator, component)?;
+            if i != 0 {
+                len += 1;
+            }
+            len += 1;
+            if self.components.binary_search_by(|c| c.as_slice().cmp(component)).is_err() {
+                len += component.len();
+            }
+        }
+        Ok(len)
+    }
+
+    ///
+    /// Decode key encoded by `encode`
+    ///
+    pub fn decode(&self, encoded: &[u8]) -> Result<Key> {
+        let mut key = Vec::with_capacity(encoded.len());
+        let mut pos = 0;
+        loop {
+            anyhow::ensure!(pos < encoded.len(), "Encoded key is truncated");
+            let code = encoded[pos] as usize;
+            pos += 1;
+            if code % 2 == 1 {
+                let component = self
+                    .components
+                    .get(code / 2)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown key dictionary code {}", code))?;
+                key.extend_from_slice(component);
+            } else {
+                // literal component is terminated by separator or end of key
+                let len = encoded[pos..]
+                    .iter()
+                    .position(|b| *b == self.separator)
+                    .unwrap_or(encoded.len() - pos);
+                key.extend_from_slice(&encoded[pos..pos + len]);
+                pos += len;
+            }
+            if pos == encoded.len() {
+                return Ok(key);
+            }
+            anyhow::ensure!(encoded[pos] == self.separator, "Encoded key component is not terminated by separator");
+            key.push(self.separator);
+            pos += 1;
+        }
+    }
+
+    //
+    // Serialize dictionary: separator, number of components and components prefixed with their lengths
+    //
+    pub(crate) fn pack(&self, buf: &mut [u8]) -> Result<()> {
+        let size = 2 + self.components.iter().map(|c| 1 + c.len()).sum::<usize>();
+        anyhow::ensure!(size <= buf.len(), "Key dictionary does not fit in page");
+        buf[0] = self.separator;
+        buf[1] = self.components.len() as u8;
+        let mut pos = 2;
+        for component in &self.components {
+            buf[pos] = component.len() as u8;
+            buf[pos + 1..pos + 1 + component.len()].copy_from_slice(component);
+            pos += 1 + component.len();
+        }
+        Ok(())
+    }
+
+    //
+    // Deserialize dictionary packed by `pack`
+    //
+    pub(crate) fn unpack(buf: &[u8]) -> Result<KeyDictionary> {
+        anyhow::ensure!(buf.len() >= 2, "Key dictionary is truncated");
+        let separator = buf[0];
+        let mut components = Vec::with_capacity(buf[1] as usize);
+        let mut pos = 2;
+        for _ in 0..buf[1] {
+            anyhow::ensure!(pos < buf.len(), "Key dictionary is truncated");
+            let len = buf[pos] as usize;
+            anyhow::ensure!(pos + 1 + len <= buf.len(), "Key dictionary is truncated");
+            components.push(buf[pos + 1..pos + 1 + len].to_vec());
+            pos += 1 + len;
+        }
+        let dict = KeyDictionary::new(separator, &components)?;
+        anyhow::ensure!(dict.components == components, "Key dictionary is not sorted");
+        Ok(dict)
+    }
+
+    //
+    // Check that component contains only bytes greater than separator (otherwise encoding does not preserve order)
+    //
+    fn check_component(separator: u8, component: &[u8]) -> Result<()> {
+        anyhow::ensure!(
+            component.iter().all(|b| *b > separator),
+            "Key component contains byte which is not greater than separator {}",
+            separator
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_len_matches_encode() {
+        let dict = KeyDictionary::new(b'/', ["orders", "users", "v1"]).unwrap();
+        for key in [&b"users"[..], b"users/42", b"v1/orders/2024", b"tenant/users/x", b"a//b", b"/", b"v1/"] {
+            assert_eq!(dict.encoded_len(key).unwrap(), dict.encode(key).unwrap().len(), "{:?}", key);
+        }
+        // key with byte below separator is rejected in the same way
+        assert!(dict.encode(b"users/\x01").is_err());
+        assert!(dict.encoded_len(b"users/\x01").is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::config::{PageId, PID_SIZE};
+
+///
+/// Errors detected by the store itself. They are reported wrapped in `anyhow::Error`,
+/// so use `downcast_ref::<StoreError>()` to distinguish them from other (for example I/O) errors.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreError {
+    /// Page can not be accessed as B-Tree page (for example it is metadata page)
+    InvalidPage(PageId),
+    /// File is not a store file (signature is missing)
+    NotAStore,
+    /// Store was created by version of library using different store format
+    UnsupportedFormat { version: u32 },
+    /// File is locked by another process (data file opened by another store of this process is reported
+    /// as `AlreadyOpen` instead)
+    Locked { path: PathBuf },
+    /// File is already opened by another store in this process
+    AlreadyOpen { path: PathBuf },
+    /// Buffer cache can not hold path from root to leaf of B-Tree: `StoreConfig::cache_size` should be increased
+    CacheTooSmall { cache_size: usize, required: usize },
+    /// B-Tree consistency check failed (message describes detected violation)
+    Corrupted(String),
+    /// Operation was cancelled by setting its cancellation flag
+    Cancelled,
+    /// Value is longer than maximal value length (see `StoreConfig::max_value_len`)
+    ValueTooLong { len: usize, max: usize },
+    /// WAL is missing or truncated, while data file was not synced after the last commit, so committed changes
+    /// may be lost (see `StoreConfig::allow_missing_wal`)
+    WalMissing { expected: u64 },
+    /// WAL belongs to another store (identifier of the store in WAL doesn't match identifier in data file),
+    /// so it is not replayed
+    WalMismatch,
+    /// Store was created with different width of page identifiers (in bits): stores created with and without
+    /// `large-pages` feature are not compatible
+    PageIdWidthMismatch { width: usize },
+    /// Start of key range is greater than its end (use `range_unchecked` if such range should be treated as empty)
+    InvalidRange,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::InvalidPage(pid) => write!(f, "Page {} is not a B-Tree page", pid),
+            StoreError::NotAStore => write!(f, "File is not a store file"),
+            StoreError::UnsupportedFormat { version } => {
+                write!(f, "Store format version {} is not supported", version)
+            }
+            StoreError::Locked { path } => write!(f, "File {} is locked", path.display()),
+            StoreError::AlreadyOpen { path } => {
+                write!(f, "File {} is already opened by another store in this process", path.display())
+            }
+            StoreError::CacheTooSmall {
+                cache_size,
+                required,
+            } => write!(
+                f,
+                "Cache size {} is too small for B-Tree height: set StoreConfig::cache_size to at least {} pages",
+                cache_size, required
+            ),
+            StoreError::Corrupted(msg) => write!(f, "Store is corrupted: {}", msg),
+            StoreError::Cancelled => write!(f, "Operation is cancelled"),
+            StoreError::ValueTooLong { len, max } => {
+                write!(f, "Value length {} exceeds maximal value length {}", len, max)
+            }
+            StoreError::WalMissing { expected } => write!(
+                f,
+                "WAL is missing or truncated: data file was not synced and requires {} bytes of WAL",
+                expected
+            ),
+            StoreError::WalMismatch => write!(f, "WAL belongs to another store"),
+            StoreError::InvalidRange => write!(f, "Start of key range is greater than its end"),
+            StoreError::PageIdWidthMismatch { width } => write!(
+                f,
+                "Store was created with {}-bit page identifiers, but library uses {}-bit ones",
+                width,
+                PID_SIZE * 8
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
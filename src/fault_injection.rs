@@ -0,0 +1,213 @@
+use std::io;
+use std::sync::Mutex;
+
+use crate::storage::Storage;
+
+#[derive(Default)]
+struct Faults {
+    writes: u64,              // number of writes performed so far
+    fail_write: Option<u64>,  // number of write which should fail
+    transient_failures: Option<(u64, io::ErrorKind)>, // number of next writes which should fail with transient error
+    fail_sync: bool,          // whether sync should fail
+    crash_write: Option<u64>, // number of write starting from which all writes are lost
+    unsynced: Option<Vec<(u64, Vec<u8>)>>, // writes not yet synced (if write-back caching is simulated)
+}
+
+impl Faults {
+    fn crashed(&self) -> bool {
+        matches!(self.crash_write, Some(n) if self.writes >= n)
+    }
+}
+
+///
+/// Storage wrapper injecting I/O faults, used to test recovery and durability.
+/// It can fail particular write or sync, or simulate crash (power failure):
+/// after crash point all writes, syncs and truncations are silently ignored,
+/// so underlying storage preserves its state at the moment of crash.
+/// It can also simulate write-back cache of the device, which can persist unsynced writes in any order.
+///
+pub struct FaultInjector {
+    storage: Box<dyn Storage>,
+    faults: Mutex<Faults>,
+}
+
+impl FaultInjector {
+    pub fn new(storage: Box<dyn Storage>) -> FaultInjector {
+        FaultInjector {
+            storage,
+            faults: Mutex::new(Faults::default()),
+        }
+    }
+
+    ///
+    /// Fail n-th write (starting from 1) counting from now
+    ///
+    pub fn fail_nth_write(&self, n: u64) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.fail_write = Some(faults.writes + n);
+    }
+
+    ///
+    /// Fail next `n` writes with error of the specified kind (for example `io::ErrorKind::Interrupted`),
+    /// simulating transient I/O errors
+    ///
+    pub fn fail_next_writes(&self, n: u64, kind: io::ErrorKind) {
+        self.faults.lock().unwrap().transient_failures = if n != 0 { Some((n, kind)) } else { None };
+    }
+
+    ///
+    /// Make all subsequent syncs fail (or succeed again)
+    ///
+    pub fn fail_sync(&self, fail: bool) {
+        self.faults.lock().unwrap().fail_sync = fail;
+    }
+
+    ///
+    /// Simulate crash after `n` more writes: all writes starting from (n+1)-th are lost
+    ///
+    pub fn crash_after_writes(&self, n: u64) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.crash_write = Some(faults.writes + n);
+    }
+
+    ///
+    /// Simulate write-back cache: subsequent writes are kept in memory until sync (they are visible to reads)
+    ///
+    pub fn cache_writes(&self) {
+        self.faults.lock().unwrap().unsynced.get_or_insert_with(Vec::new);
+    }
+
+    ///
+    /// Simulate crash of device with write-back cache which has persisted only the last unsynced write:
+    /// other unsynced writes are lost, as well as all subsequent writes
+    ///
+    pub fn crash_persisting_last_write(&self) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        if let Some((offs, data)) = faults.unsynced.take().and_then(|mut writes| writes.pop()) {
+            self.storage.write_all_at(&data, offs)?;
+        }
+        faults.crash_write = Some(faults.writes);
+        Ok(())
+    }
+
+    //
+    // Write unsynced writes to the underlying storage
+    //
+    fn write_back(&self, faults: &mut Faults) -> io::Result<()> {
+        if let Some(unsynced) = &mut faults.unsynced {
+            for (offs, data) in unsynced.drain(..) {
+                self.storage.write_all_at(&data, offs)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Check whether crash point is reached
+    ///
+    pub fn crashed(&self) -> bool {
+        self.faults.lock().unwrap().crashed()
+    }
+
+    ///
+    /// Number of writes performed through this storage (including lost and failed ones)
+    ///
+    pub fn writes(&self) -> u64 {
+        self.faults.lock().unwrap().writes
+    }
+}
+
+impl Storage for FaultInjector {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        let faults = self.faults.lock().unwrap();
+        let mut len = self.storage.read_at(buf, offs)?;
+        // apply unsynced writes overlapping with read fragment
+        for (pos, data) in faults.unsynced.iter().flatten() {
+            let start = offs.max(*pos);
+            let end = (offs + buf.len() as u64).min(pos + data.len() as u64);
+            if start < end {
+                buf[(start - offs) as usize..(end - offs) as usize]
+                    .copy_from_slice(&data[(start - pos) as usize..(end - pos) as usize]);
+                len = len.max((end - offs) as usize);
+            }
+        }
+        Ok(len)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        let crashed = faults.crashed();
+        faults.writes += 1;
+        if faults.fail_write == Some(faults.writes) {
+            return Err(io::Error::other("injected write fault"));
+        }
+        if let Some((n, kind)) = faults.transient_failures {
+            faults.transient_failures = if n > 1 { Some((n - 1, kind)) } else { None };
+            return Err(io::Error::new(kind, "injected transient write fault"));
+        }
+        if crashed {
+            Ok(())
+        } else if let Some(unsynced) = &mut faults.unsynced {
+            unsynced.push((offs, buf.to_vec()));
+            Ok(())
+        } else {
+            self.storage.write_all_at(buf, offs)
+        }
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        if faults.fail_sync {
+            Err(io::Error::other("injected sync fault"))
+        } else if faults.crashed() {
+            Ok(())
+        } else {
+            self.write_back(&mut faults)?;
+            self.storage.sync_all()
+        }
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        if faults.fail_sync {
+            Err(io::Error::other("injected sync fault"))
+        } else if faults.crashed() {
+            Ok(())
+        } else {
+            self.write_back(&mut faults)?;
+            self.storage.sync_data()
+        }
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        if faults.crashed() {
+            Ok(())
+        } else {
+            self.write_back(&mut faults)?;
+            self.storage.set_len(size)
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let faults = self.faults.lock().unwrap();
+        let end = faults.unsynced.iter().flatten().map(|(offs, data)| offs + data.len() as u64).max();
+        Ok(self.storage.size()?.max(end.unwrap_or(0)))
+    }
+
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        if self.crashed() {
+            Ok(())
+        } else {
+            self.storage.allocate(size)
+        }
+    }
+
+    fn punch_hole(&self, offs: u64, len: u64) -> io::Result<()> {
+        if self.crashed() {
+            Ok(())
+        } else {
+            self.storage.punch_hole(offs, len)
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::store::Store;
+
+///
+/// Cheaply cloneable handle of the store. All clones of the handle refer to the same `Store`:
+/// they share its buffer pool, file lock and transaction lock (so write transactions started through
+/// different handles are still serialized). Store is closed when the last handle is dropped.
+/// Handle dereferences to `Store`, so it provides the whole store API.
+///
+#[derive(Clone)]
+pub struct StoreHandle {
+    store: Arc<Store>,
+}
+
+impl StoreHandle {
+    ///
+    /// Wrap opened store in handle
+    ///
+    pub fn new(store: Store) -> StoreHandle {
+        StoreHandle {
+            store: Arc::new(store),
+        }
+    }
+
+    ///
+    /// Number of existing handles of the store (including this one)
+    ///
+    pub fn handle_count(&self) -> usize {
+        Arc::strong_count(&self.store)
+    }
+}
+
+impl From<Store> for StoreHandle {
+    fn from(store: Store) -> StoreHandle {
+        StoreHandle::new(store)
+    }
+}
+
+impl Deref for StoreHandle {
+    type Target = Store;
+
+    fn deref(&self) -> &Store {
+        &self.store
+    }
+}
@@ -1,12 +1,35 @@
 mod config;
-mod disk_manager;
+mod error;
 mod buffer_manager;
-mod freelist;
 mod meta;
 mod pagedata;
 mod transaction;
 mod store;
+mod storage;
+mod compression;
+mod retry;
+mod writer;
+mod wal_syncer;
+mod handle;
+mod dictionary;
+mod buffer_pool;
+mod snapshot;
+#[cfg(feature = "fault-injection")]
+mod fault_injection;
+#[cfg(feature = "metrics")]
+mod metrics;
 
-pub use store::{Store, StoreConfig};
+pub use store::{Store, StoreConfig, StoreState, ConflictPolicy, Durability, AllocationPolicy, ItemSizeHint, StructureSummary, PrefixStats, LevelSummary, CacheStats, UpsertOutcome, RecoveryReport, ScrubReport, RepairReport};
 //pub use transaction::Transaction;
-pub use config::{Key, Value};
\ No newline at end of file
+pub use config::{Key, Value};
+pub use error::StoreError;
+pub use handle::StoreHandle;
+pub use snapshot::Snapshot;
+pub use dictionary::KeyDictionary;
+pub use buffer_pool::SharedBufferPool;
+pub use buffer_manager::EvictionHook;
+pub use storage::Storage;
+#[cfg(feature = "fault-injection")]
+pub use fault_injection::FaultInjector;
+#[cfg(feature = "diagnostics")]
+pub use store::{DirtyReport, PinReport};
\ No newline at end of file
@@ -1,10 +1,8 @@
-use crate::config::{PageId, PID_SIZE, METADATA_SIZE};
-use crate::pagedata::PageData;
+use anyhow::Result;
+use crc32c::crc32c;
 
-// #[derive(Default)]
-pub struct Meta {
-    pub freelist_pid: PageId,
-}
+use crate::config::{PageId, PID_SIZE, METADATA_SIZE, METADATA_FIXED_SIZE, METADATA_EXT_SIZE, META_EXT_HEADER_SIZE, STORE_MAGIC, STORE_MAGIC_32, STORE_MAGIC_64, LEGACY_METADATA_SIZE, META_EXT_FORMAT_VERSION};
+use crate::error::StoreError;
 
 #[derive(Copy, Clone)]
 pub struct Metadata {
@@ -12,6 +10,11 @@ pub struct Metadata {
     pub size: PageId, // size of database (pages)
     pub root: PageId, // B-Tree root page
     pub height: u32,  // height of B-Tree
+    pub commit_seq: u64, // sequence number of the last commit which changed database
+    pub flags: u32,   // bitmask of META_FLAG_* describing store format
+    // Extension fields: sequence of TLV records (type, length, value) terminated by zero type.
+    // It is preserved as is, so fields unknown to this version are not lost when metadata is updated.
+    pub ext: [u8; METADATA_EXT_SIZE],
 }
 
 impl Metadata {
@@ -22,7 +25,8 @@ impl Metadata {
         //     )
         // }
 
-        let mut pos: usize = 0;
+        // skip signature
+        let mut pos: usize = 4;
 
         // unwrap is safe since this always returns Ok
         let free = PageId::from_be_bytes(page[pos..pos + PID_SIZE].try_into().unwrap());
@@ -34,15 +38,26 @@ impl Metadata {
         let root = PageId::from_be_bytes(page[pos..pos + PID_SIZE].try_into().unwrap());
         pos += PID_SIZE;
 
-        // u32
-        let height = PageId::from_be_bytes(page[pos..pos + 4].try_into().unwrap());
+        let height = u32::from_be_bytes(page[pos..pos + 4].try_into().unwrap());
         pos += 4;
 
+        let commit_seq = u64::from_be_bytes(page[pos..pos + 8].try_into().unwrap());
+        pos += 8;
+
+        let flags = u32::from_be_bytes(page[pos..pos + 4].try_into().unwrap());
+        pos += 4;
+
+        debug_assert!(pos == METADATA_FIXED_SIZE);
+        let ext = page[pos..pos + METADATA_EXT_SIZE].try_into().unwrap();
+
         Self {
             free,
             size,
             root,
             height,
+            commit_seq,
+            flags,
+            ext,
         }
     }
 
@@ -51,6 +66,9 @@ impl Metadata {
         let mut page = [0u8; METADATA_SIZE];
         let mut pos: usize = 0;
 
+        page[pos..pos + 4].copy_from_slice(&STORE_MAGIC.to_be_bytes());
+        pos += 4;
+
         page[pos..pos + PID_SIZE].copy_from_slice(&self.free.to_be_bytes());
         pos += PID_SIZE;
 
@@ -64,30 +82,247 @@ impl Metadata {
         page[pos..pos + 4].copy_from_slice(&self.height.to_be_bytes());
         pos += 4;
 
+        page[pos..pos + 8].copy_from_slice(&self.commit_seq.to_be_bytes());
+        pos += 8;
+
+        page[pos..pos + 4].copy_from_slice(&self.flags.to_be_bytes());
+        pos += 4;
+
+        page[pos..pos + METADATA_EXT_SIZE].copy_from_slice(&self.ext);
+
         page
     }
-}
 
-impl Meta {
-    pub fn serialize(&self) -> PageData {
-        let mut page = PageData::new();
-        let mut pos: usize = 0;
+    //
+    // Locate extension field with the specified type: returns its offset and length within `ext`.
+    // Also returns offset of the end of the TLV sequence (where new field can be appended).
+    // Records which do not fit in `ext` are treated as end of sequence.
+    //
+    fn locate_ext(&self, tag: u16) -> (Option<(usize, usize)>, usize) {
+        let mut pos = 0;
+        while pos + META_EXT_HEADER_SIZE <= METADATA_EXT_SIZE {
+            let typ = u16::from_be_bytes(self.ext[pos..pos + 2].try_into().unwrap());
+            let len = u16::from_be_bytes(self.ext[pos + 2..pos + 4].try_into().unwrap()) as usize;
+            if typ == 0 || pos + META_EXT_HEADER_SIZE + len > METADATA_EXT_SIZE {
+                break;
+            }
+            if typ == tag {
+                return (Some((pos, META_EXT_HEADER_SIZE + len)), pos);
+            }
+            pos += META_EXT_HEADER_SIZE + len;
+        }
+        (None, pos)
+    }
 
-        page.data[pos..pos + PID_SIZE].copy_from_slice(&self.freelist_pid.to_be_bytes());
-        pos += PID_SIZE;
+    //
+    // Get value of extension field with the specified type (None if there is no such field)
+    //
+    pub fn get_ext(&self, tag: u16) -> Option<&[u8]> {
+        self.locate_ext(tag)
+            .0
+            .map(|(pos, len)| &self.ext[pos + META_EXT_HEADER_SIZE..pos + len])
+    }
 
-        page
+    //
+    // Set value of extension field with the specified type replacing its previous value (if any).
+    // Other fields (including unknown ones) are preserved.
+    //
+    pub fn set_ext(&mut self, tag: u16, value: &[u8]) -> Result<()> {
+        anyhow::ensure!(tag != 0, "Type of metadata extension field should be non-zero");
+        self.remove_ext(tag);
+        let (_, end) = self.locate_ext(tag);
+        anyhow::ensure!(
+            end + META_EXT_HEADER_SIZE + value.len() <= METADATA_EXT_SIZE,
+            "No space for metadata extension field {}",
+            tag
+        );
+        self.ext[end..end + 2].copy_from_slice(&tag.to_be_bytes());
+        self.ext[end + 2..end + 4].copy_from_slice(&(value.len() as u16).to_be_bytes());
+        self.ext[end + META_EXT_HEADER_SIZE..end + META_EXT_HEADER_SIZE + value.len()].copy_from_slice(value);
+        Ok(())
     }
 
-    pub fn deserialize(page: &PageData) -> Self {
-        let mut pos: usize = 0;
+    //
+    // Remove extension field with the specified type. Returns false if there is no such field.
+    //
+    pub fn remove_ext(&mut self, tag: u16) -> bool {
+        if let (Some((pos, len)), _) = self.locate_ext(tag) {
+            self.ext.copy_within(pos + len.., pos);
+            self.ext[METADATA_EXT_SIZE - len..].fill(0);
+            true
+        } else {
+            false
+        }
+    }
 
-        // unwrap is safe since this always returns Ok
-        let freelist_pid = PageId::from_be_bytes(page.data[pos..pos + PID_SIZE].try_into().unwrap());
-        pos += PID_SIZE;
+    //
+    // Check that buffer starts with store signature
+    //
+    pub fn has_magic(page: &[u8]) -> bool {
+        page[0..4] == STORE_MAGIC.to_be_bytes()
+    }
 
-        Self {
-            freelist_pid
+    //
+    // Check that buffer starts with store signature: store created with different width of page identifiers
+    // is reported separately, since its files can not be accessed by this build
+    //
+    pub fn check_magic(page: &[u8]) -> Result<()> {
+        if Self::has_magic(page) {
+            return Ok(());
         }
+        let width = match u32::from_be_bytes(page[0..4].try_into().unwrap()) {
+            STORE_MAGIC_32 => 32,
+            STORE_MAGIC_64 => 64,
+            _ if Self::is_legacy(page) => anyhow::bail!(StoreError::UnsupportedFormat { version: 0 }),
+            _ => anyhow::bail!(StoreError::NotAStore),
+        };
+        anyhow::bail!(StoreError::PageIdWidthMismatch { width })
+    }
+
+    //
+    // Check whether buffer contains metadata of store created before signature was introduced (version 0):
+    // it consists of free list head, size, root and height of B-Tree followed by zeros
+    //
+    fn is_legacy(page: &[u8]) -> bool {
+        let field = |i: usize| u32::from_be_bytes(page[i * 4..i * 4 + 4].try_into().unwrap());
+        let (free, size, root) = (field(0), field(1), field(2));
+        size >= 1
+            && free < size
+            && root < size
+            && page[LEGACY_METADATA_SIZE..METADATA_SIZE + 4].iter().all(|&b| b == 0)
+    }
+
+    //
+    // Version of store format: metadata without format version field belongs to version 1
+    //
+    pub fn format_version(&self) -> u32 {
+        self.get_ext(META_EXT_FORMAT_VERSION)
+            .and_then(|version| version.try_into().ok())
+            .map_or(1, u32::from_be_bytes)
+    }
+
+    //
+    // Checksum of packed metadata. It is stored right after metadata to detect torn write of metadata.
+    //
+    pub fn checksum(page: &[u8]) -> u32 {
+        crc32c(&page[0..METADATA_SIZE])
+    }
+
+    //
+    // Check that metadata at the beginning of the buffer matches checksum stored after it
+    //
+    pub fn is_intact(page: &[u8]) -> bool {
+        let crc = u32::from_be_bytes(page[METADATA_SIZE..METADATA_SIZE + 4].try_into().unwrap());
+        crc == Self::checksum(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            free: 1,
+            size: 10,
+            root: 2,
+            height: 3,
+            commit_seq: 4,
+            flags: 5,
+            ext: [0u8; METADATA_EXT_SIZE],
+        }
+    }
+
+    #[test]
+    fn ext_fields_round_trip() {
+        let mut meta = metadata();
+        meta.set_ext(1, &7u32.to_be_bytes()).unwrap();
+        meta.set_ext(100, b"unknown field").unwrap();
+        meta.set_ext(3, &[0xAB; 16]).unwrap();
+
+        let unpacked = Metadata::unpack(&meta.pack());
+        assert_eq!((unpacked.free, unpacked.size, unpacked.root), (1, 10, 2));
+        assert_eq!((unpacked.height, unpacked.commit_seq, unpacked.flags), (3, 4, 5));
+        assert_eq!(unpacked.get_ext(1), Some(&7u32.to_be_bytes()[..]));
+        assert_eq!(unpacked.get_ext(100), Some(&b"unknown field"[..]));
+        assert_eq!(unpacked.get_ext(3), Some(&[0xAB; 16][..]));
+        assert_eq!(unpacked.get_ext(2), None);
+        assert_eq!(unpacked.format_version(), 7);
+    }
+
+    #[test]
+    fn update_preserves_other_ext_fields() {
+        let mut meta = metadata();
+        meta.set_ext(1, &[1]).unwrap();
+        meta.set_ext(2, &[2, 2]).unwrap();
+        meta.set_ext(3, &[3, 3, 3]).unwrap();
+
+        // replacing field with value of different length moves it to the end of sequence
+        meta.set_ext(1, &[1, 1, 1, 1]).unwrap();
+        assert!(meta.remove_ext(2));
+        assert!(!meta.remove_ext(2));
+
+        let unpacked = Metadata::unpack(&meta.pack());
+        assert_eq!(unpacked.get_ext(1), Some(&[1, 1, 1, 1][..]));
+        assert_eq!(unpacked.get_ext(2), None);
+        assert_eq!(unpacked.get_ext(3), Some(&[3, 3, 3][..]));
+        assert_eq!(unpacked.ext, meta.ext);
+    }
+
+    #[test]
+    fn ext_area_overflow() {
+        let mut meta = metadata();
+        let value = [0u8; METADATA_EXT_SIZE - META_EXT_HEADER_SIZE];
+        meta.set_ext(1, &value).unwrap();
+        assert!(meta.set_ext(2, &[]).is_err());
+        assert!(meta.set_ext(0, &[]).is_err());
+        // field is replaced in place when there is no space for both values
+        meta.set_ext(1, &value[META_EXT_HEADER_SIZE..]).unwrap();
+        assert_eq!(meta.get_ext(1).map(<[u8]>::len), Some(value.len() - META_EXT_HEADER_SIZE));
+        meta.set_ext(2, &[]).unwrap();
+        assert_eq!(meta.get_ext(2), Some(&[][..]));
+    }
+
+    #[test]
+    fn checksum_detects_torn_write() {
+        let mut page = [0u8; METADATA_SIZE + 4];
+        page[..METADATA_SIZE].copy_from_slice(&metadata().pack());
+        let crc = Metadata::checksum(&page);
+        page[METADATA_SIZE..].copy_from_slice(&crc.to_be_bytes());
+        assert!(Metadata::is_intact(&page));
+        assert!(Metadata::has_magic(&page));
+        page[METADATA_FIXED_SIZE - 1] ^= 1;
+        assert!(!Metadata::is_intact(&page));
+    }
+
+    #[test]
+    fn page_ids_of_configured_width_round_trip() {
+        // identifiers which do not fit in 32 bits are used only by `large-pages` build
+        let large = if PID_SIZE == 8 { u32::MAX as PageId + 7 } else { PageId::MAX - 1 };
+        let meta = Metadata {
+            free: large,
+            size: PageId::MAX,
+            root: large - 1,
+            ..metadata()
+        };
+        let packed = meta.pack();
+        assert_eq!(packed[4..4 + PID_SIZE], large.to_be_bytes());
+        let unpacked = Metadata::unpack(&packed);
+        assert_eq!((unpacked.free, unpacked.size, unpacked.root), (large, PageId::MAX, large - 1));
+        assert_eq!((unpacked.height, unpacked.commit_seq, unpacked.flags), (3, 4, 5));
+    }
+
+    #[test]
+    fn metadata_of_other_page_id_width_is_rejected() {
+        let mut page = metadata().pack();
+        assert!(Metadata::check_magic(&page).is_ok());
+        let (other_magic, other_width) = if PID_SIZE == 8 { (STORE_MAGIC_32, 32) } else { (STORE_MAGIC_64, 64) };
+        page[0..4].copy_from_slice(&other_magic.to_be_bytes());
+        assert!(!Metadata::has_magic(&page));
+        let err = Metadata::check_magic(&page).unwrap_err().downcast::<StoreError>().unwrap();
+        assert!(matches!(err, StoreError::PageIdWidthMismatch { width } if width == other_width));
+        page[0..4].copy_from_slice(b"XYZ\0");
+        let err = Metadata::check_magic(&page).unwrap_err().downcast::<StoreError>().unwrap();
+        assert!(matches!(err, StoreError::NotAStore));
     }
 }
@@ -0,0 +1,52 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::store::CacheStats;
+
+//
+// Counters of store activity exported by `Store::metrics_text`. They are updated by relaxed atomic operations,
+// so metrics can be read without waiting for locks of the store.
+//
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub cache_hits: AtomicU64,   // pages found in buffer cache
+    pub cache_misses: AtomicU64, // pages read from storage
+    pub commits: AtomicU64,      // committed transactions
+    pub wal_bytes: AtomicU64,    // bytes of page images and metadata records written to WAL
+    pub checkpoints: AtomicU64,  // checkpoints (syncs of data file allowing to restart WAL)
+    pub height: AtomicU32,       // height of B-Tree in the last committed state
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, delta: u64) {
+        counter.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    //
+    // Format metrics in Prometheus text exposition format
+    //
+    pub fn format(&self, cache: &CacheStats) -> String {
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let misses = self.cache_misses.load(Ordering::Relaxed);
+        let hit_ratio = if hits + misses != 0 {
+            hits as f64 / (hits + misses) as f64
+        } else {
+            1.0
+        };
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            // writing to String can not fail
+            let _ = write!(text, "# HELP skv_{name} {help}\n# TYPE skv_{name} {kind}\nskv_{name} {value}\n");
+        };
+        metric("cache_hits_total", "counter", "Pages found in buffer cache", &hits);
+        metric("cache_misses_total", "counter", "Pages read from storage", &misses);
+        metric("cache_hit_ratio", "gauge", "Fraction of page accesses served from buffer cache", &hit_ratio);
+        metric("cached_pages", "gauge", "Pages in buffer cache", &cache.cached_pages);
+        metric("dirty_pages", "gauge", "Dirty pages of current transaction", &cache.dirty_pages);
+        metric("tree_height", "gauge", "Height of B-Tree", &self.height.load(Ordering::Relaxed));
+        metric("commits_total", "counter", "Committed transactions", &self.commits.load(Ordering::Relaxed));
+        metric("wal_bytes_written_total", "counter", "Bytes written to WAL", &self.wal_bytes.load(Ordering::Relaxed));
+        metric("checkpoints_total", "counter", "Checkpoints performed", &self.checkpoints.load(Ordering::Relaxed));
+        text
+    }
+}
@@ -1,7 +1,81 @@
 use std::cmp::Ordering;
+use std::fmt;
 
-use crate::config::{PAGE_SIZE, PAGE_HEADER_SIZE, PageId, Key, Value, ItemPointer};
+use crate::config::{PAGE_SIZE, PAGE_HEADER_SIZE, PAGE_TYPE_OFFS, PageId, PID_SIZE, Key, Value, ItemPointer};
 
+//
+// Type of page stored in page header. Metadata page has its own layout and is recognized by its position.
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageType {
+    Untyped = 0,    // allocated page which is not yet initialized
+    Leaf = 1,       // B-Tree leaf page containing key-value pairs
+    Internal = 2,   // B-Tree internal page containing references to child pages
+    Free = 3,       // page in free list: header is followed by next free page
+    Dictionary = 4, // page containing key dictionary: header is followed by packed dictionary
+}
+
+impl PageType {
+    fn from_u8(tag: u8) -> Option<PageType> {
+        match tag {
+            0 => Some(PageType::Untyped),
+            1 => Some(PageType::Leaf),
+            2 => Some(PageType::Internal),
+            3 => Some(PageType::Free),
+            4 => Some(PageType::Dictionary),
+            _ => None,
+        }
+    }
+}
+
+//
+// Right-most item of internal page can be +inf separator: it references child containing the largest keys
+// and is greater than any key. Separator is marked by zero key length, so empty keys can not be stored
+// in B-Tree and are rejected by `Store::check_item`. Marker is kept in the item itself rather than in a separate
+// flag: all values of key length byte are used by keys (`MAX_KEY_LEN` is `u8::MAX`), and flag in page header
+// would have to be maintained by split, merge and removal of the last child (which moves its key to the previous
+// item), while item carries its marker with it. It also preserves format of existing stores.
+//
+const INF_KEY_LEN: u8 = 0;
+
+//
+// Violation of page layout invariants detected by `PageData::validate` (or by update of the page)
+//
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageError {
+    TooManyItems(ItemPointer),     // array of item offsets doesn't fit in page
+    OverlappedItem(ItemPointer),   // item overlaps array of item offsets
+    UnorderedItem(ItemPointer),    // item is not located before previous item
+    InvalidKeyLength(ItemPointer), // key doesn't fit in item
+    InvalidType(u8),               // page type is unknown
+    MissingChild(ItemPointer),     // item of internal page has no space for child reference
+}
+
+impl fmt::Display for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageError::TooManyItems(n) => write!(f, "{} items do not fit in page", n),
+            PageError::OverlappedItem(ip) => write!(f, "item {} overlaps item offsets", ip),
+            PageError::UnorderedItem(ip) => write!(f, "item {} is not located before previous item", ip),
+            PageError::InvalidKeyLength(ip) => write!(f, "key of item {} doesn't fit in item", ip),
+            PageError::InvalidType(tag) => write!(f, "unknown page type {}", tag),
+            PageError::MissingChild(ip) => write!(f, "item {} doesn't contain child reference", ip),
+        }
+    }
+}
+
+impl std::error::Error for PageError {}
+
+//
+// B-Tree page layout: header (number of items and page type) is followed by array of item offsets,
+// items themselves are allocated from the end of the page. Item `i` occupies space from its offset
+// till offset of item `i-1` (or end of page for the first item), so items are always tightly packed:
+// insert and remove shift items to preserve this invariant. As a result page can not be fragmented
+// and `insert_item` fails only if total size of items and offsets really exceeds page size.
+// Pages are aligned to `DIRECT_IO_ALIGNMENT`, so that they can be read and written by direct I/O as is.
+//
+#[derive(Clone)]
+#[repr(C, align(4096))]
 pub struct PageData {
     pub data: [u8; PAGE_SIZE],
 }
@@ -23,16 +97,52 @@ impl PageData {
         self.set_u16(PAGE_HEADER_SIZE + ip * 2, offs as u16)
     }
 
+    //
+    // Get type of page (None if it is unknown)
+    //
+    pub fn get_page_type(&self) -> Option<PageType> {
+        PageType::from_u8(self.data[PAGE_TYPE_OFFS])
+    }
+
+    pub fn set_page_type(&mut self, page_type: PageType) {
+        self.data[PAGE_TYPE_OFFS] = page_type as u8;
+    }
+
+    //
+    // Initialize empty B-Tree page of the specified type
+    //
+    pub fn init(&mut self, page_type: PageType) {
+        self.set_n_items(0);
+        self.set_page_type(page_type);
+    }
+
+    //
+    // Get reference to the next page of free list (only for free page)
+    //
+    pub fn get_next_free(&self) -> PageId {
+        self.get_pid(PAGE_HEADER_SIZE)
+    }
+
+    //
+    // Turn page into free page referencing the next page of free list
+    //
+    pub fn set_next_free(&mut self, next: PageId) {
+        self.set_n_items(0);
+        self.set_page_type(PageType::Free);
+        self.set_pid(PAGE_HEADER_SIZE, next);
+    }
+
     pub fn get_child(&self, ip: ItemPointer) -> PageId {
+        debug_assert_eq!(self.get_page_type(), Some(PageType::Internal));
         let offs = self.get_offs(ip);
         let key_len = self.data[offs] as usize;
-        self.get_u32(offs + key_len + 1)
+        self.get_pid(offs + key_len + 1)
     }
 
     pub fn get_key(&self, ip: ItemPointer) -> Key {
         let offs = self.get_offs(ip);
         let key_len = self.data[offs] as usize;
-        self.data[offs + 1..offs + 1 + key_len].to_vec()
+        self.get_bytes(offs + 1, key_len).to_vec()
     }
 
     pub fn get_last_key(&self) -> Key {
@@ -41,6 +151,7 @@ impl PageData {
     }
 
     pub fn get_item(&self, ip: ItemPointer) -> (Key, Value) {
+        debug_assert_eq!(self.get_page_type(), Some(PageType::Leaf));
         let (item_offs, item_len) = self.get_item_offs_len(ip);
         let key_len = self.data[item_offs] as usize;
         (
@@ -49,6 +160,142 @@ impl PageData {
         )
     }
 
+    ///
+    /// Borrow value of leaf page item without copying it (unlike `get_item`, which returns owned key and value)
+    ///
+    pub fn get_value(&self, ip: ItemPointer) -> &[u8] {
+        debug_assert_eq!(self.get_page_type(), Some(PageType::Leaf));
+        let (item_offs, item_len) = self.get_item_offs_len(ip);
+        let key_len = self.data[item_offs] as usize;
+        &self.data[item_offs + 1 + key_len..item_offs + item_len]
+    }
+
+    ///
+    /// Iterate over items of the page in stored order, yielding borrowed key and value of each item
+    /// (value of internal page item is reference to child page, key of +inf separator is empty)
+    ///
+    pub fn items(&self) -> impl ExactSizeIterator<Item = (&[u8], &[u8])> + '_ {
+        // items are allocated from the end of page, so each item ends at offset of the previous one
+        let mut end = PAGE_SIZE;
+        (0..self.get_n_items()).map(move |ip| {
+            let offs = self.get_offs(ip);
+            debug_assert!(end > offs);
+            let key_len = self.data[offs] as usize;
+            let item = &self.data[offs..end];
+            end = offs;
+            (&item[1..1 + key_len], &item[1 + key_len..])
+        })
+    }
+
+    ///
+    /// Iterate over children of internal page in stored order
+    ///
+    pub fn children(&self) -> impl ExactSizeIterator<Item = PageId> + '_ {
+        debug_assert_eq!(self.get_page_type(), Some(PageType::Internal));
+        self.items()
+            .map(|(_, child)| PageId::from_be_bytes(child[..PID_SIZE].try_into().unwrap()))
+    }
+
+    //
+    // Check that page layout is consistent: page type is known, item offsets fit in page, items are tightly packed
+    // from the end of page in order of their offsets and do not overlap offsets array, key of each item fits in item.
+    //
+    pub fn validate(&self) -> Result<(), PageError> {
+        if self.get_page_type().is_none() {
+            return Err(PageError::InvalidType(self.data[PAGE_TYPE_OFFS]));
+        }
+        let n_items = self.get_n_items();
+        let items_origin = PAGE_HEADER_SIZE + n_items * 2;
+        if items_origin > PAGE_SIZE {
+            return Err(PageError::TooManyItems(n_items));
+        }
+        let mut next_offs = PAGE_SIZE;
+        for ip in 0..n_items {
+            let offs = self.get_offs(ip);
+            if offs >= next_offs {
+                return Err(PageError::UnorderedItem(ip));
+            }
+            if offs < items_origin {
+                return Err(PageError::OverlappedItem(ip));
+            }
+            if offs + 1 + self.data[offs] as usize > next_offs {
+                return Err(PageError::InvalidKeyLength(ip));
+            }
+            next_offs = offs;
+        }
+        Ok(())
+    }
+
+    //
+    // Check that keys of leaf page are strictly ascending. It is called in debug builds after each mutation of leaf page
+    // to catch ordering bugs where they are introduced rather than by later traversal.
+    //
+    pub fn assert_sorted(&self) {
+        debug_assert_eq!(self.get_page_type(), Some(PageType::Leaf));
+        let mut prev: Option<&[u8]> = None;
+        for (ip, (key, _)) in self.items().enumerate() {
+            assert!(
+                prev.is_none_or(|prev| prev < key),
+                "Key of item {} is not greater than key of previous item",
+                ip
+            );
+            prev = Some(key);
+        }
+    }
+
+    //
+    // Check that each item of internal page contains child reference following the key
+    // (page layout should be already checked by `validate`)
+    //
+    pub fn validate_internal(&self) -> Result<(), PageError> {
+        for ip in 0..self.get_n_items() {
+            let (item_offs, item_len) = self.get_item_offs_len(ip);
+            if item_len < 1 + self.data[item_offs] as usize + PID_SIZE {
+                return Err(PageError::MissingChild(ip));
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Replace value of item with new value of the same length
+    //
+    pub fn replace_value(&mut self, ip: ItemPointer, value: &[u8]) {
+        let (item_offs, item_len) = self.get_item_offs_len(ip);
+        let key_len = self.data[item_offs] as usize;
+        debug_assert!(item_len == 1 + key_len + value.len());
+        self.copy(item_offs + 1 + key_len, value);
+        debug_assert_eq!(self.validate(), Ok(()));
+    }
+
+    //
+    // Replace value of item with new value of arbitrary length, preserving position of the item.
+    // Items located before it are shifted to keep items tightly packed.
+    // Returns false (leaving page unchanged) if larger value doesn't fit in page.
+    //
+    pub fn replace_value_in_place(&mut self, ip: ItemPointer, value: &[u8]) -> bool {
+        let n_items = self.get_n_items();
+        let size = self.get_size();
+        let (item_offs, item_len) = self.get_item_offs_len(ip);
+        let key_len = self.data[item_offs] as usize;
+        let new_item_len = 1 + key_len + value.len();
+        if n_items * 2 + size - item_len + new_item_len > PAGE_SIZE - PAGE_HEADER_SIZE {
+            return false;
+        }
+        // move key of this item together with all items located before it
+        let items_origin = PAGE_SIZE - size;
+        self.data.copy_within(
+            items_origin..item_offs + 1 + key_len,
+            items_origin + item_len - new_item_len,
+        );
+        for i in ip..n_items {
+            self.set_offs(i, self.get_offs(i) + item_len - new_item_len);
+        }
+        self.copy(item_offs + item_len - value.len(), value);
+        debug_assert_eq!(self.validate(), Ok(()));
+        true
+    }
+
     fn get_item_offs_len(&self, ip: ItemPointer) -> (usize, usize) {
         let offs = self.get_offs(ip);
         let next_offs = if ip == 0 {
@@ -72,8 +319,27 @@ impl PageData {
         u16::from_be_bytes(self.data[offs..offs + 2].try_into().unwrap())
     }
 
-    pub fn get_u32(&self, offs: usize) -> u32 {
-        u32::from_be_bytes(self.data[offs..offs + 4].try_into().unwrap())
+    // 64-bit accessors for inline counters and timestamps (not used by B-Tree items themselves)
+    #[allow(dead_code)]
+    pub fn set_u64(&mut self, offs: usize, data: u64) {
+        self.copy(offs, &data.to_be_bytes());
+    }
+
+    #[allow(dead_code)]
+    pub fn get_u64(&self, offs: usize) -> u64 {
+        u64::from_be_bytes(self.data[offs..offs + 8].try_into().unwrap())
+    }
+
+    pub fn get_pid(&self, offs: usize) -> PageId {
+        PageId::from_be_bytes(self.data[offs..offs + PID_SIZE].try_into().unwrap())
+    }
+
+    pub fn set_pid(&mut self, offs: usize, pid: PageId) {
+        self.copy(offs, &pid.to_be_bytes());
+    }
+
+    pub fn get_bytes(&self, offs: usize, len: usize) -> &[u8] {
+        &self.data[offs..offs + len]
     }
 
     pub fn get_n_items(&self) -> ItemPointer {
@@ -89,26 +355,67 @@ impl PageData {
         }
     }
 
+    ///
+    /// Space (in bytes) available for new items including their offsets
+    ///
+    pub fn free_space(&self) -> usize {
+        (PAGE_SIZE - PAGE_HEADER_SIZE).saturating_sub(self.get_n_items() * 2 + self.get_size())
+    }
+
+    ///
+    /// Fraction of page space (excluding header) occupied by items and their offsets: 0 for empty page, 1 for full page
+    ///
+    pub fn fill_factor(&self) -> f32 {
+        1.0 - self.free_space() as f32 / (PAGE_SIZE - PAGE_HEADER_SIZE) as f32
+    }
+
     pub fn set_n_items(&mut self, n_items: ItemPointer) {
         self.set_u16(0, n_items as u16)
     }
 
     fn copy(&mut self, offs: usize, data: &[u8]) {
         let len = data.len();
-        self.data[offs..offs + len].copy_from_slice(&data);
+        self.data[offs..offs + len].copy_from_slice(data);
     }
 
-    pub fn compare_key(&self, ip: ItemPointer, key: &Key) -> Ordering {
-        let offs = self.get_offs(ip);
-        let key_len = self.data[offs] as usize;
-        if key_len == 0 {
-            // special handling of +inf in right-most internal nodes
+    //
+    // Check if item is +inf separator
+    //
+    pub fn is_inf_item(&self, ip: ItemPointer) -> bool {
+        self.data[self.get_offs(ip)] == INF_KEY_LEN
+    }
+
+    pub fn compare_key(&self, ip: ItemPointer, key: &[u8]) -> Ordering {
+        if self.is_inf_item(ip) {
+            // +inf separator is greater than any key
             Ordering::Less
         } else {
-            key[..].cmp(&self.data[offs + 1..offs + 1 + key_len])
+            let offs = self.get_offs(ip);
+            let key_len = self.data[offs] as usize;
+            key.cmp(self.get_bytes(offs + 1, key_len))
         }
     }
 
+    //
+    // Locate the first item with key greater or equal than the specified key: position of the key (or where it should be inserted)
+    // in leaf page, position of the child which may contain the key in internal page.
+    // Returns number of items if all keys are smaller than the specified key.
+    //
+    pub fn locate_key(&self, key: &[u8]) -> ItemPointer {
+        let mut l: ItemPointer = 0;
+        let mut r = self.get_n_items();
+        while l < r {
+            let m = (l + r) >> 1;
+            if self.compare_key(m, key) == Ordering::Greater {
+                l = m + 1;
+            } else {
+                r = m;
+            }
+        }
+        debug_assert!(l == r);
+        r
+    }
+
     pub fn remove_key(&mut self, ip: ItemPointer, leaf: bool) {
         let n_items = self.get_n_items();
         let size = self.get_size();
@@ -118,30 +425,64 @@ impl PageData {
         }
         let items_origin = PAGE_SIZE - size;
         if !leaf && n_items > 1 && ip + 1 == n_items {
-            // If we are removing last child of internal page then copy it's key to the previous item
+            // If we are removing last child of internal page then copy it's key to the previous item.
+            // Child of the previous item stays in place, so only key (with its length) is copied and
+            // start of the previous item is shifted by difference of key lengths.
             let prev_item_offs = item_offs + item_len;
             let key_len = self.data[item_offs] as usize;
             let prev_key_len = self.data[prev_item_offs] as usize;
             let new_offs = prev_item_offs + prev_key_len - key_len;
             self.set_offs(ip - 1, new_offs);
             self.data
-                .copy_within(item_offs..item_offs + prev_key_len + 1, new_offs);
+                .copy_within(item_offs..item_offs + key_len + 1, new_offs);
         } else {
             self.data
                 .copy_within(items_origin..item_offs, items_origin + item_len);
         }
         self.set_n_items(n_items - 1);
+        debug_assert_eq!(self.validate(), Ok(()));
+    }
+
+    //
+    // Insert item on the page is there is enough free space, otherwise return false.
+    // Key should be non-empty: empty key is reserved for +inf separator.
+    // Error is returned if layout of the page is found broken.
+    //
+    pub fn insert_item(&mut self, ip: ItemPointer, key: &[u8], value: &[u8]) -> Result<bool, PageError> {
+        debug_assert!(key.len() != INF_KEY_LEN as usize);
+        self.insert_raw_item(ip, key, value)
     }
 
     //
-    // Insert item on the page is there is enough free space, otherwise return false
+    // Insert +inf separator referencing child page with the largest keys
     //
-    pub fn insert_item(&mut self, ip: ItemPointer, key: &Key, value: &[u8]) -> bool {
+    pub fn insert_inf_item(&mut self, ip: ItemPointer, child: PageId) -> Result<bool, PageError> {
+        self.insert_raw_item(ip, &[], &child.to_be_bytes())
+    }
+
+    fn insert_raw_item(&mut self, ip: ItemPointer, key: &[u8], value: &[u8]) -> Result<bool, PageError> {
         let n_items = self.get_n_items();
+        if PAGE_HEADER_SIZE + n_items * 2 > PAGE_SIZE {
+            return Err(PageError::TooManyItems(n_items));
+        }
+        // Page can be loaded without validation (for example by recovery from partially written WAL),
+        // so offsets of the items which are shifted are checked rather than trusted: they should be decreasing
+        // and not overlap offsets array, otherwise computing new offsets could overflow.
+        let mut next_offs = PAGE_SIZE;
+        for i in ip.saturating_sub(1)..n_items {
+            let offs = self.get_offs(i);
+            if offs >= next_offs {
+                return Err(PageError::UnorderedItem(i));
+            }
+            if offs < PAGE_HEADER_SIZE + n_items * 2 {
+                return Err(PageError::OverlappedItem(i));
+            }
+            next_offs = offs;
+        }
         let size = self.get_size();
         let key_len = key.len();
         let item_len = 1 + key_len + value.len();
-        if (n_items + 1) * 2 + size + item_len <= PAGE_SIZE - PAGE_HEADER_SIZE {
+        if 2 + item_len <= self.free_space() {
             // fit in page
             for i in (ip..n_items).rev() {
                 self.set_offs(i + 1, self.get_offs(i) - item_len);
@@ -156,22 +497,33 @@ impl PageData {
             self.data
                 .copy_within(items_origin..item_offs + item_len, items_origin - item_len);
             self.data[item_offs] = key_len as u8;
-            self.data[item_offs + 1..item_offs + 1 + key_len].copy_from_slice(&key);
-            self.data[item_offs + 1 + key_len..item_offs + item_len].copy_from_slice(&value);
+            self.data[item_offs + 1..item_offs + 1 + key_len].copy_from_slice(key);
+            self.data[item_offs + 1 + key_len..item_offs + item_len].copy_from_slice(value);
             self.set_n_items(n_items + 1);
-            true
+            debug_assert_eq!(self.validate(), Ok(()));
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
     //
     // Split page into two approximately equal parts. Smallest keys are moved to the new page,
-    // largest - left on original page.
+    // largest - left on original page. If `min_part_size` is specified, then page is split at insert position `ip`
+    // (so that runs of adjacent keys fill pages), but each part keeps at least `min_part_size` bytes of items.
+    // Split position is adjusted if needed, so that the part receiving new item of `item_len` bytes inserted at `ip`
+    // (new page if `ip` is not greater than split position, original page otherwise) has enough space for it.
     // Returns split position
     //
-    pub fn split(&mut self, new_page: &mut PageData, ip: ItemPointer) -> ItemPointer {
+    pub fn split(
+        &mut self,
+        new_page: &mut PageData,
+        ip: ItemPointer,
+        item_len: usize,
+        min_part_size: Option<usize>,
+    ) -> ItemPointer {
         let n_items = self.get_n_items();
+        debug_assert!(n_items >= 2);
         let size = self.get_size();
         let mut r = n_items;
 
@@ -179,6 +531,16 @@ impl PageData {
             // Optimization for insert of sequential keys: move all data to new page,
             // leaving original page empty. It will cause complete filling of B-Tree pages.
             r -= 1;
+        } else if let Some(min_size) = min_part_size {
+            // Items before insert position are moved to the new page, then split position is adjusted
+            // to keep enough items on both pages (items are allocated from right to left)
+            r = ip.saturating_sub(1);
+            while r + 2 < n_items && PAGE_SIZE - self.get_offs(r) < min_size {
+                r += 1;
+            }
+            while r > 0 && size - (PAGE_SIZE - self.get_offs(r)) < min_size {
+                r -= 1;
+            }
         } else {
             // Divide page in two approximately equal parts.
             let margin = PAGE_SIZE - size / 2;
@@ -193,6 +555,25 @@ impl PageData {
                 }
             }
             debug_assert!(l == r);
+            // Last item can be larger than half of the page data: it should still be left on original page,
+            // otherwise original page becomes empty
+            r = r.min(n_items - 2);
+        }
+        if ip != n_items {
+            // Large items near split position can leave not enough space for new item in the part receiving it:
+            // then move split position towards insert position. Items are not larger than a quarter of the page,
+            // so one of the parts adjacent to new item can always hold it.
+            let capacity = PAGE_SIZE - PAGE_HEADER_SIZE;
+            let moved_used = |r: ItemPointer| PAGE_SIZE - self.get_offs(r) + (r + 1) * 2;
+            loop {
+                if ip <= r && r > 0 && moved_used(r) + item_len + 2 > capacity {
+                    r -= 1;
+                } else if ip > r && r + 2 < n_items && size + n_items * 2 - moved_used(r) + item_len + 2 > capacity {
+                    r += 1;
+                } else {
+                    break;
+                }
+            }
         }
         // Move first r+1 elements to the new page
         let moved_size = PAGE_SIZE - self.get_offs(r);
@@ -211,7 +592,554 @@ impl PageData {
         let src = PAGE_SIZE - size;
         self.data.copy_within(src..dst, src + moved_size);
         new_page.set_n_items(r + 1);
+        new_page.data[PAGE_TYPE_OFFS] = self.data[PAGE_TYPE_OFFS];
         self.set_n_items(n_items - r - 1);
+        debug_assert_eq!(self.validate(), Ok(()));
+        debug_assert_eq!(new_page.validate(), Ok(()));
         r
     }
-}
\ No newline at end of file
+
+    //
+    // Move all items of the left neighbour page (which keys are smaller than keys of this page) to the beginning
+    // of this page, reverting `split`. Returns false (and does nothing) if items of both pages do not fit in one page.
+    //
+    pub fn merge(&mut self, left: &PageData) -> bool {
+        let n_items = self.get_n_items();
+        let size = self.get_size();
+        let n_moved = left.get_n_items();
+        let moved_size = left.get_size();
+        if n_moved * 2 + moved_size > self.free_space() {
+            return false;
+        }
+        // Items of this page are shifted to free space at the end of the page for items of left page,
+        // which are copied to the same positions as they occupy on left page
+        let src = PAGE_SIZE - size;
+        self.data.copy_within(src..PAGE_SIZE, src - moved_size);
+        let dst = PAGE_SIZE - moved_size;
+        self.data[dst..].copy_from_slice(&left.data[dst..]);
+        for i in (0..n_items).rev() {
+            self.set_offs(i + n_moved, self.get_offs(i) - moved_size);
+        }
+        self.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + n_moved * 2]
+            .copy_from_slice(&left.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + n_moved * 2]);
+        self.set_n_items(n_items + n_moved);
+        debug_assert_eq!(self.validate(), Ok(()));
+        true
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MAX_KEY_LEN, MAX_VALUE_LEN};
+
+    fn internal_page(keys: &[&[u8]]) -> PageData {
+        let mut page = PageData::new();
+        page.init(PageType::Internal);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(page.insert_item(i, key, &(i as PageId + 1).to_be_bytes()), Ok(true));
+        }
+        assert_eq!(page.insert_inf_item(keys.len(), keys.len() as PageId + 1), Ok(true));
+        page
+    }
+
+    #[test]
+    fn inf_separator_is_greater_than_any_key() {
+        let page = internal_page(&[b"b", b"d"]);
+        assert!(page.is_inf_item(2));
+        assert!(!page.is_inf_item(1));
+        let largest = [u8::MAX; 255];
+        for key in [&b"\0"[..], b"a", b"c", b"e", &largest] {
+            assert_eq!(page.compare_key(2, key), Ordering::Less);
+        }
+        assert_eq!(page.locate_key(b"a"), 0);
+        assert_eq!(page.locate_key(b"d"), 1);
+        assert_eq!(page.locate_key(b"e"), 2);
+        assert_eq!(page.locate_key(&largest), 2);
+        assert_eq!(page.get_child(2), 3);
+    }
+
+    #[test]
+    fn removal_of_last_child_moves_inf_separator() {
+        let mut page = internal_page(&[b"b", b"d"]);
+        page.remove_key(2, false);
+        assert_eq!(page.get_n_items(), 2);
+        assert!(page.is_inf_item(1));
+        assert_eq!(page.get_child(1), 2);
+        assert_eq!(page.locate_key(b"z"), 1);
+        assert_eq!(page.validate_internal(), Ok(()));
+    }
+
+    #[test]
+    fn inf_separator_stays_on_right_part_of_split() {
+        let keys: Vec<Vec<u8>> = (0..200u32).map(|i| format!("key{i:04}").into_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+        let mut page = internal_page(&keys);
+        let mut left = PageData::new();
+        let r = page.split(&mut left, 0, 0, None);
+        assert!((0..=r).all(|i| !left.is_inf_item(i)));
+        assert!(page.is_inf_item(page.get_n_items() - 1));
+        assert!(page.merge(&left));
+        assert_eq!(page.get_n_items(), keys.len() + 1);
+        assert!(page.is_inf_item(keys.len()));
+    }
+
+    #[test]
+    fn u64_round_trip() {
+        let mut page = PageData::new();
+        let values = [0u64, 1, 0x0102_0304_0506_0708, u64::MAX - 1, u64::MAX];
+        for offs in [0, 1, 3, 7, PAGE_SIZE / 2 + 5, PAGE_SIZE - 9, PAGE_SIZE - 8] {
+            for value in values {
+                page.set_u64(offs, value);
+                assert_eq!(page.get_u64(offs), value, "offset {offs}");
+            }
+        }
+        // big-endian like other accessors
+        page.set_u64(PAGE_SIZE - 8, 0x0102_0304_0506_0708);
+        assert_eq!(page.get_bytes(PAGE_SIZE - 8, 8), &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(page.get_u16(PAGE_SIZE - 2), 0x0708);
+    }
+
+    #[test]
+    fn u64_does_not_touch_neighbours() {
+        let mut page = PageData::new();
+        page.data.fill(0xA5);
+        page.set_u64(PAGE_SIZE - 9, 0);
+        assert_eq!(page.data[PAGE_SIZE - 10], 0xA5);
+        assert_eq!(page.data[PAGE_SIZE - 1], 0xA5);
+        assert_eq!(page.get_u64(PAGE_SIZE - 9), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn u64_past_page_end_panics() {
+        PageData::new().get_u64(PAGE_SIZE - 7);
+    }
+
+    #[test]
+    fn locate_key_in_leaf() {
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        for (i, key) in [b"b", b"d", b"f"].iter().enumerate() {
+            assert_eq!(page.insert_item(i, *key, b"v"), Ok(true));
+        }
+        // equal to item
+        assert_eq!(page.locate_key(b"b"), 0);
+        assert_eq!(page.locate_key(b"d"), 1);
+        assert_eq!(page.locate_key(b"f"), 2);
+        // between items: position of the next greater one
+        assert_eq!(page.locate_key(b"c"), 1);
+        assert_eq!(page.locate_key(b"dd"), 2);
+        // smaller and larger than all
+        assert_eq!(page.locate_key(b""), 0);
+        assert_eq!(page.locate_key(b"a"), 0);
+        assert_eq!(page.locate_key(b"g"), 3);
+        let mut empty = PageData::new();
+        empty.init(PageType::Leaf);
+        assert_eq!(empty.locate_key(b"a"), 0);
+    }
+
+    //
+    // Remove children of internal page in the specified order, checking page against the model after each removal:
+    // removal of the right-most child moves its key to the previous item (keeping child of the previous item)
+    //
+    fn check_child_removal(keys: &[Vec<u8>], with_inf: bool, positions: impl Fn(usize) -> usize) {
+        let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+        let mut page = if with_inf {
+            internal_page(&keys)
+        } else {
+            let mut page = PageData::new();
+            page.init(PageType::Internal);
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(page.insert_item(i, key, &(i as PageId + 1).to_be_bytes()), Ok(true));
+            }
+            page
+        };
+        let mut model: Vec<(Vec<u8>, PageId)> = page.items().map(|(key, _)| key.to_vec()).zip(1..).collect();
+        let used = PAGE_SIZE - page.free_space();
+        while !model.is_empty() {
+            let ip = positions(model.len());
+            let removed = model.remove(ip);
+            if ip == model.len() && ip > 0 {
+                model[ip - 1].0 = removed.0;
+            }
+            page.remove_key(ip, false);
+            assert_eq!(page.validate(), Ok(()));
+            assert_eq!(page.validate_internal(), Ok(()));
+            assert_eq!(page.get_n_items(), model.len());
+            for (i, (key, child)) in model.iter().enumerate() {
+                assert_eq!(page.get_key(i), *key, "item {i}");
+                assert_eq!(page.get_child(i), *child, "item {i}");
+            }
+            // items remain tightly packed
+            let items_size: usize = model.iter().map(|(key, _)| 1 + key.len() + PID_SIZE + 2).sum();
+            assert!(PAGE_SIZE - page.free_space() <= used);
+            assert_eq!(PAGE_SIZE - page.free_space() - PAGE_HEADER_SIZE, items_size);
+        }
+    }
+
+    #[test]
+    fn removal_of_children_with_equal_keys() {
+        let equal: Vec<Vec<u8>> = (0..50u32).map(|i| format!("key{i:04}").into_bytes()).collect();
+        let duplicates: Vec<Vec<u8>> = (0..50u32).map(|i| format!("key{:04}", i / 2).into_bytes()).collect();
+        for keys in [equal, duplicates] {
+            for with_inf in [false, true] {
+                check_child_removal(&keys, with_inf, |_| 0);
+                check_child_removal(&keys, with_inf, |n| n / 2);
+                check_child_removal(&keys, with_inf, |n| n - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn removal_of_children_with_varying_keys() {
+        let growing: Vec<Vec<u8>> = (1..60usize).map(|i| vec![b'a' + (i % 26) as u8; i]).collect();
+        let mut shrinking = growing.clone();
+        shrinking.reverse();
+        let mixed: Vec<Vec<u8>> = (0..60usize).map(|i| vec![i as u8 + 1; 1 + (i * 37) % 200]).collect();
+        for keys in [growing, shrinking, mixed] {
+            for with_inf in [false, true] {
+                check_child_removal(&keys, with_inf, |_| 0);
+                check_child_removal(&keys, with_inf, |n| n / 2);
+                check_child_removal(&keys, with_inf, |n| n - 1);
+                check_child_removal(&keys, with_inf, |n| (n * 7 + 3) % n);
+            }
+        }
+    }
+
+    #[test]
+    fn leaf_is_not_fragmented_by_insert_and_remove() {
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        for round in 0..2000usize {
+            let key = format!("key{:05}", (round * 7919) % 10007).into_bytes();
+            let ip = page.locate_key(&key);
+            if ip < page.get_n_items() && page.compare_key(ip, &key) == Ordering::Equal {
+                continue;
+            }
+            // values of varying size, remove some item when page is full
+            let value = vec![round as u8; round % 97];
+            if !page.insert_item(ip, &key, &value).unwrap() {
+                let victim = (round * 31) % page.get_n_items();
+                page.remove_key(victim, true);
+                keys.remove(victim);
+            } else {
+                keys.insert(ip, key);
+            }
+            // free space is exactly what is not occupied by items and their offsets
+            let used: usize = page.items().map(|(key, value)| 1 + key.len() + value.len() + 2).sum();
+            assert_eq!(page.free_space(), PAGE_SIZE - PAGE_HEADER_SIZE - used);
+        }
+        assert_eq!(page.items().map(|(key, _)| key.to_vec()).collect::<Vec<_>>(), keys);
+        // item of exactly free space size fits without repacking, one byte larger doesn't
+        let key = b"key99999";
+        let value_len = page.free_space() - 2 - 1 - key.len();
+        let n_items = page.get_n_items();
+        assert!(!page.insert_item(n_items, key, &vec![0u8; value_len + 1]).unwrap());
+        assert!(page.insert_item(n_items, key, &vec![0u8; value_len]).unwrap());
+        assert_eq!(page.free_space(), 0);
+        assert_eq!(page.validate(), Ok(()));
+    }
+
+    #[test]
+    fn value_is_replaced_in_place() {
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        let keys: Vec<Vec<u8>> = (0..10).map(|i| format!("key{i}").into_bytes()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(page.insert_item(i, key, &[i as u8; 10]), Ok(true));
+        }
+        let check = |page: &PageData, replaced: &[u8]| {
+            assert_eq!(page.validate(), Ok(()));
+            assert_eq!(page.get_n_items(), keys.len());
+            for (i, key) in keys.iter().enumerate() {
+                let value = if i == 5 { replaced.to_vec() } else { vec![i as u8; 10] };
+                assert_eq!(page.get_item(i), (key.clone(), value), "item {i}");
+            }
+        };
+        // same size, smaller, empty and larger value
+        for value in [vec![20u8; 10], vec![21u8; 3], Vec::new(), vec![22u8; 100]] {
+            let free_space = page.free_space() + page.get_value(5).len();
+            assert!(page.replace_value_in_place(5, &value));
+            assert_eq!(page.free_space(), free_space - value.len());
+            check(&page, &value);
+        }
+        // value which doesn't fit leaves page unchanged, value of exactly available size fits
+        let max_len = page.free_space() + page.get_value(5).len();
+        let copy = page.clone();
+        assert!(!page.replace_value_in_place(5, &vec![23u8; max_len + 1]));
+        assert_eq!(page.data, copy.data);
+        assert!(page.replace_value_in_place(5, &vec![23u8; max_len]));
+        assert_eq!(page.free_space(), 0);
+        check(&page, &vec![23u8; max_len]);
+        // first and last items
+        assert!(page.replace_value_in_place(0, &[0u8; 10]));
+        assert!(page.replace_value_in_place(9, &[9u8; 10]));
+        check(&page, &vec![23u8; max_len]);
+    }
+
+    #[test]
+    fn page_type_is_kept_in_header() {
+        let mut page = internal_page(&[b"b"]);
+        assert_eq!(page.get_page_type(), Some(PageType::Internal));
+        page.init(PageType::Leaf);
+        assert_eq!((page.get_page_type(), page.get_n_items()), (Some(PageType::Leaf), 0));
+        page.set_next_free(7);
+        assert_eq!((page.get_page_type(), page.get_next_free()), (Some(PageType::Free), 7));
+        page.data[PAGE_TYPE_OFFS] = 99;
+        assert_eq!(page.get_page_type(), None);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn leaf_page_is_not_accessed_as_internal() {
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        assert_eq!(page.insert_item(0, b"key", &1u32.to_be_bytes()), Ok(true));
+        page.get_child(0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn internal_page_is_not_accessed_as_leaf() {
+        internal_page(&[b"b"]).get_item(0);
+    }
+
+    #[test]
+    fn validate_hand_crafted_pages() {
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        assert_eq!(page.validate(), Ok(()));
+        for (i, key) in [&b"a"[..], b"bb", b"ccc"].iter().enumerate() {
+            assert_eq!(page.insert_item(i, key, b"value"), Ok(true));
+        }
+        assert_eq!(page.validate(), Ok(()));
+
+        let corrupted = |f: &dyn Fn(&mut PageData)| {
+            let mut copy = page.clone();
+            f(&mut copy);
+            copy.validate()
+        };
+        assert_eq!(corrupted(&|p| p.data[PAGE_TYPE_OFFS] = 99), Err(PageError::InvalidType(99)));
+        assert_eq!(corrupted(&|p| p.set_n_items(5000)), Err(PageError::TooManyItems(5000)));
+        // item located after the previous one
+        assert_eq!(corrupted(&|p| p.set_offs(1, p.get_offs(0))), Err(PageError::UnorderedItem(1)));
+        assert_eq!(corrupted(&|p| p.set_offs(0, PAGE_SIZE)), Err(PageError::UnorderedItem(0)));
+        // item inside array of offsets
+        assert_eq!(corrupted(&|p| p.set_offs(2, PAGE_HEADER_SIZE + 2)), Err(PageError::OverlappedItem(2)));
+        // more items than were inserted: offsets of extra items are zeros
+        assert_eq!(corrupted(&|p| p.set_n_items(4)), Err(PageError::OverlappedItem(3)));
+        // key length exceeds item
+        assert_eq!(corrupted(&|p| p.data[p.get_offs(0)] = 200), Err(PageError::InvalidKeyLength(0)));
+        assert_eq!(corrupted(&|p| p.data[p.get_offs(1)] = 8), Err(PageError::InvalidKeyLength(1)));
+        assert_eq!(corrupted(&|p| p.data[p.get_offs(1)] = 7), Ok(()));
+
+        // internal page item without child reference
+        let mut page = internal_page(&[b"b", b"d"]);
+        assert_eq!(page.validate_internal(), Ok(()));
+        let n_items = page.get_n_items();
+        assert_eq!(page.insert_item(n_items, b"zz", &[1]), Ok(true));
+        assert_eq!(page.validate(), Ok(()));
+        assert_eq!(page.validate_internal(), Err(PageError::MissingChild(n_items)));
+    }
+
+    #[test]
+    fn insert_into_malformed_page_is_rejected() {
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        for (i, key) in [b"b", b"d", b"f"].iter().enumerate() {
+            assert_eq!(page.insert_item(i, *key, b"value"), Ok(true));
+        }
+        let malformed = |f: &dyn Fn(&mut PageData), ip: ItemPointer| {
+            let mut copy = page.clone();
+            f(&mut copy);
+            let before = copy.data;
+            let res = copy.insert_item(ip, b"c", b"value");
+            // rejected page is left untouched
+            assert!(res.is_ok() || copy.data == before);
+            res
+        };
+        // offset of shifted item is smaller than length of inserted item: shifting it would underflow
+        assert_eq!(malformed(&|p| p.set_offs(2, 1), 1), Err(PageError::OverlappedItem(2)));
+        assert_eq!(malformed(&|p| p.set_offs(1, PAGE_HEADER_SIZE + 2), 0), Err(PageError::OverlappedItem(1)));
+        assert_eq!(malformed(&|p| p.set_offs(2, p.get_offs(1)), 1), Err(PageError::UnorderedItem(2)));
+        assert_eq!(malformed(&|p| p.set_offs(0, PAGE_SIZE), 0), Err(PageError::UnorderedItem(0)));
+        assert_eq!(malformed(&|p| p.set_n_items(5000), 1), Err(PageError::TooManyItems(5000)));
+        // garbage offsets of extra items
+        assert_eq!(malformed(&|p| p.set_n_items(4), 3), Err(PageError::OverlappedItem(3)));
+        // items preceding insert position (except the previous one) are not shifted and so not checked
+        assert_eq!(malformed(&|p| p.set_offs(2, 1), 3), Err(PageError::OverlappedItem(2)));
+        assert_eq!(malformed(&|_| {}, 1), Ok(true));
+    }
+
+    #[test]
+    fn items_iterator_matches_item_accessors() {
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        assert_eq!(page.items().len(), 0);
+        // insert keys in scrambled order, so that stored order differs from order of allocation
+        for i in 0..100usize {
+            let key = format!("key{:03}", (i * 37) % 100).into_bytes();
+            let ip = page.locate_key(&key);
+            assert_eq!(page.insert_item(ip, &key, &vec![i as u8; i % 13]), Ok(true));
+        }
+        page.remove_key(50, true);
+        page.remove_key(0, true);
+        let items: Vec<(Key, Value)> = page.items().map(|(key, value)| (key.to_vec(), value.to_vec())).collect();
+        assert_eq!(items.len(), page.get_n_items());
+        for (ip, item) in items.iter().enumerate() {
+            assert_eq!(*item, page.get_item(ip), "item {ip}");
+        }
+        assert!(items.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let page = internal_page(&[b"b", b"d", b"f"]);
+        let children: Vec<PageId> = page.children().collect();
+        assert_eq!(children, (0..page.get_n_items()).map(|ip| page.get_child(ip)).collect::<Vec<_>>());
+        assert_eq!(children, [1, 2, 3, 4]);
+        // key of +inf separator is empty
+        let keys: Vec<&[u8]> = page.items().map(|(key, _)| key).collect();
+        assert_eq!(keys, [&b"b"[..], b"d", b"f", b""]);
+    }
+
+    #[test]
+    fn unsorted_leaf_keys_are_detected() {
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        for (ip, key) in [b"b", b"d", b"f"].iter().enumerate() {
+            assert_eq!(page.insert_item(ip, *key, b"v"), Ok(true));
+            page.assert_sorted();
+        }
+        // duplicate and out of order keys inserted at wrong positions
+        for (ip, key) in [(1, b"b"), (3, b"e"), (0, b"z")] {
+            let mut copy = page.clone();
+            assert_eq!(copy.insert_item(ip, key, b"v"), Ok(true));
+            let res = std::panic::catch_unwind(|| copy.assert_sorted());
+            assert!(res.is_err(), "insert of {key:?} at {ip}");
+        }
+    }
+
+    #[test]
+    fn child_references_of_configured_width_round_trip() {
+        let large = if PID_SIZE == 8 { u32::MAX as PageId + 1 } else { PageId::MAX };
+        let mut page = PageData::new();
+        page.init(PageType::Internal);
+        assert_eq!(page.insert_item(0, b"b", &(large - 1).to_be_bytes()), Ok(true));
+        assert_eq!(page.insert_inf_item(1, large), Ok(true));
+        assert_eq!(page.validate_internal(), Ok(()));
+        assert_eq!(page.children().collect::<Vec<_>>(), [large - 1, large]);
+        assert_eq!((page.get_child(0), page.get_child(1)), (large - 1, large));
+        // item of internal page consists of key length, key and child reference
+        let used = PAGE_SIZE - PAGE_HEADER_SIZE - page.free_space();
+        assert_eq!(used, (1 + 1 + PID_SIZE + 2) + (1 + PID_SIZE + 2));
+    }
+
+    #[test]
+    fn free_space_and_fill_factor_at_various_fill_levels() {
+        const CAPACITY: usize = PAGE_SIZE - PAGE_HEADER_SIZE;
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        assert_eq!(page.free_space(), CAPACITY);
+        assert_eq!(page.fill_factor(), 0.0);
+        // each item takes 100 bytes including its offset
+        let value = [0u8; 100 - 2 - 1 - 4];
+        let n_fitting = CAPACITY / 100;
+        for i in 0..n_fitting {
+            assert_eq!(page.insert_item(i, format!("{i:04}").as_bytes(), &value), Ok(true));
+            assert_eq!(page.free_space(), CAPACITY - (i + 1) * 100);
+            let fill = ((i + 1) * 100) as f32 / CAPACITY as f32;
+            assert!((page.fill_factor() - fill).abs() < 1e-6, "{} items", i + 1);
+        }
+        let half = page.clone();
+        // fill the rest of the page exactly
+        let rest = page.free_space() - 2 - 1 - 4;
+        assert_eq!(page.insert_item(n_fitting, b"9999", &vec![0u8; rest]), Ok(true));
+        assert_eq!(page.free_space(), 0);
+        assert_eq!(page.fill_factor(), 1.0);
+        assert_eq!(page.insert_item(0, b"0", b""), Ok(false));
+        // removal of items frees their space
+        page.remove_key(n_fitting, true);
+        assert_eq!(page.free_space(), half.free_space());
+        for i in (0..n_fitting).rev() {
+            page.remove_key(i, true);
+        }
+        assert_eq!(page.free_space(), CAPACITY);
+        assert_eq!(page.fill_factor(), 0.0);
+    }
+
+    //
+    // Key of item with the specified number padded to the specified length: order of keys is order of numbers
+    //
+    fn padded_key(n: usize, len: usize) -> Vec<u8> {
+        let mut key = format!("{n:06}").into_bytes();
+        key.resize(len.max(key.len()), b'x');
+        key
+    }
+
+    //
+    // Split page to insert item at position `ip` like `Store::btree_insert_in_page` does and check that both parts are
+    // well-formed and contain exactly the expected keys
+    //
+    fn check_split(page: &PageData, ip: ItemPointer, key: &[u8], value: &[u8], min_part_size: Option<usize>) {
+        let item_len = 1 + key.len() + value.len();
+        let n_items = page.get_n_items();
+        let context = format!("{n_items} items, insert at {ip}, item of {item_len} bytes, {min_part_size:?}");
+        let mut expected: Vec<Key> = page.items().map(|(key, _)| key.to_vec()).collect();
+        expected.insert(ip, key.to_vec());
+        let mut page = page.clone();
+        let mut new_page = PageData::new();
+        let split = page.split(&mut new_page, ip, item_len, min_part_size);
+        assert!(split < expected.len() - 1, "{context}: split at {split}");
+        let inserted = if ip > split {
+            page.insert_item(ip - split - 1, key, value)
+        } else {
+            new_page.insert_item(ip, key, value)
+        };
+        assert_eq!(inserted, Ok(true), "{context}: split at {split}");
+        assert_eq!(page.validate(), Ok(()), "{context}");
+        assert_eq!(new_page.validate(), Ok(()), "{context}");
+        assert!(page.get_n_items() > 0 && new_page.get_n_items() > 0, "{context}: split at {split}");
+        let keys: Vec<Key> = new_page.items().chain(page.items()).map(|(key, _)| key.to_vec()).collect();
+        assert_eq!(keys, expected, "{context}: split at {split}");
+        page.assert_sorted();
+        new_page.assert_sorted();
+    }
+
+    #[test]
+    fn split_at_every_position_and_fill_level() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(961);
+        // (maximal key length, maximal value length) of items filling the page
+        let sizes = [(8, 16), (20, 200), (MAX_KEY_LEN, MAX_VALUE_LEN), (MAX_KEY_LEN, MAX_VALUE_LEN / 8)];
+        for round in 0..100 {
+            let (max_key, max_value) = sizes[round % sizes.len()];
+            let mut page = PageData::new();
+            page.init(PageType::Leaf);
+            // existing items have odd numbers, so that new item with even number can be inserted at any position
+            let mut n = 0;
+            loop {
+                let key = padded_key(n * 2 + 1, rng.gen_range(6..=max_key));
+                let value = vec![n as u8; rng.gen_range(0..=max_value)];
+                if !page.insert_item(n, &key, &value).unwrap() {
+                    break;
+                }
+                n += 1;
+                // check splits of pages at various fill levels, but mostly of full pages (which are really split)
+                if n >= 2 && rng.gen_ratio(1, 8) {
+                    let ip = rng.gen_range(0..=n);
+                    check_split(&page, ip, &padded_key(ip * 2, 6), &[0u8; 10], None);
+                }
+            }
+            for ip in 0..=n {
+                // new item of any size, including maximal one
+                let key = padded_key(ip * 2, rng.gen_range(6..=MAX_KEY_LEN));
+                let value = vec![0xFFu8; if ip % 3 == 0 { MAX_VALUE_LEN } else { rng.gen_range(0..=MAX_VALUE_LEN) }];
+                check_split(&page, ip, &key, &value, None);
+                for min_part_size in [1, 100, PAGE_SIZE / 4] {
+                    check_split(&page, ip, &key, &value, Some(min_part_size.max(3 + key.len() + value.len())));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::IO_RETRY_DELAY_MS;
+use crate::storage::Storage;
+
+//
+// Storage wrapper retrying operations failed with transient errors (interrupted or would block)
+// at most `retries` times with exponential backoff. Other errors are reported immediately.
+// Retry of positional operation is safe because it doesn't depend on result of failed attempt.
+//
+pub struct RetryStorage {
+    storage: Box<dyn Storage>,
+    retries: u32,
+}
+
+impl RetryStorage {
+    //
+    // Wrap storage if retries are enabled
+    //
+    pub fn wrap(storage: Box<dyn Storage>, retries: u32) -> Box<dyn Storage> {
+        if retries == 0 {
+            storage
+        } else {
+            Box::new(RetryStorage { storage, retries })
+        }
+    }
+
+    fn retry<T>(&self, mut op: impl FnMut(&dyn Storage) -> io::Result<T>) -> io::Result<T> {
+        let mut delay = Duration::from_millis(IO_RETRY_DELAY_MS);
+        let mut attempt = 0u32;
+        loop {
+            match op(&*self.storage) {
+                Err(err)
+                    if attempt < self.retries
+                        && matches!(
+                            err.kind(),
+                            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                        ) =>
+                {
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Storage for RetryStorage {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        self.retry(|storage| storage.read_at(buf, offs))
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        self.retry(|storage| storage.write_all_at(buf, offs))
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.retry(|storage| storage.sync_all())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.retry(|storage| storage.sync_data())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.retry(|storage| storage.set_len(size))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.retry(|storage| storage.size())
+    }
+
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        self.retry(|storage| storage.allocate(size))
+    }
+
+    fn punch_hole(&self, offs: u64, len: u64) -> io::Result<()> {
+        self.retry(|storage| storage.punch_hole(offs, len))
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offs: u64) -> io::Result<()> {
+        self.retry(|storage| storage.read_exact_at(buf, offs))
+    }
+}
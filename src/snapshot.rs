@@ -0,0 +1,92 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{Key, PageId, Value};
+use crate::pagedata::PageData;
+use crate::store::Store;
+
+///
+/// Read snapshot of the last committed state of the store (see `Store::snapshot`). All reads through the snapshot
+/// see the same committed state. Pages of B-Tree are updated in place (there is no copy-on-write), so while snapshot
+/// exists commit saves committed images of pages it modifies and reads through the snapshot use these images.
+/// Snapshot doesn't block write transactions, but memory used by saved images grows with amount of changes
+/// committed since snapshot was taken, so snapshot should not be kept for a long time.
+/// Snapshot becomes invalid if store is reloaded (for example by `Store::replace_with` or `Store::try_reopen`),
+/// compacted by `Store::compact_in_place` or its value log is collected by `Store::collect_value_log`.
+///
+pub struct Snapshot<'a> {
+    store: &'a Store,
+    root: PageId,
+    height: u32,
+    pages: Arc<SnapshotPages>,
+}
+
+//
+// Committed images of pages modified after snapshot was taken (they are saved by commit)
+//
+pub(crate) struct SnapshotPages {
+    pub size: PageId,       // size of the store when snapshot was taken: pages allocated later are not saved
+    pub generation: u64,    // generation of the store state (it is changed when store is reloaded)
+    pub images: Mutex<HashMap<PageId, Arc<PageData>>>,
+}
+
+impl SnapshotPages {
+    pub(crate) fn new(size: PageId, generation: u64) -> SnapshotPages {
+        SnapshotPages {
+            size,
+            generation,
+            images: Mutex::new(HashMap::new()),
+        }
+    }
+
+    //
+    // Saved image of the page, if page was modified after snapshot was taken
+    //
+    pub(crate) fn image(&self, pid: PageId) -> Option<Arc<PageData>> {
+        self.images.lock().unwrap().get(&pid).cloned()
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    pub(crate) fn new(store: &'a Store, (root, height): (PageId, u32), pages: Arc<SnapshotPages>) -> Snapshot<'a> {
+        Snapshot {
+            store,
+            root,
+            height,
+            pages,
+        }
+    }
+
+    ///
+    /// Lookup key in the snapshot
+    ///
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Value>> {
+        self.store.read_snapshot(&self.pages, || {
+            self.store.lookup_committed((self.root, self.height), key.as_ref(), Some(&self.pages))
+        })
+    }
+
+    ///
+    /// Return items of the snapshot with keys from `start` till `end` (exclusive) in key order.
+    /// Returns `StoreError::InvalidRange` if `start` is greater than `end`.
+    ///
+    pub fn range(&self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<Vec<(Key, Value)>> {
+        Store::check_range(start.as_ref(), end.as_ref())?;
+        self.range_unchecked(start, end)
+    }
+
+    ///
+    /// Like `range`, but range with `start` greater than `end` is treated as empty rather than reported as error
+    ///
+    pub fn range_unchecked(&self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<Vec<(Key, Value)>> {
+        self.store.read_snapshot(&self.pages, || {
+            self.store.scan_committed(
+                (self.root, self.height),
+                start.as_ref(),
+                end.as_ref(),
+                Some(&self.pages),
+            )
+        })
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+#[cfg(target_os = "linux")]
+use crate::config::DIRECT_IO_ALIGNMENT;
+
+///
+/// Positional I/O interface used by store to access data and WAL files.
+/// It is implemented for `File`, but custom implementation can be provided to `Store::open_with_storage`
+/// (for example to inject I/O faults in tests).
+///
+pub trait Storage: Send + Sync {
+    /// Read data at the specified offset. Returns number of read bytes (0 at the end of file).
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize>;
+
+    /// Write the whole buffer at the specified offset
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()>;
+
+    /// Flush data and metadata to the disk
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// Flush data (but not necessarily metadata) to the disk
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Truncate or extend storage to the specified size
+    fn set_len(&self, size: u64) -> io::Result<()>;
+
+    /// Current size of storage
+    fn size(&self) -> io::Result<u64>;
+
+    /// Allocate disk space for storage of the specified size (extending it if needed), so that following
+    /// writes within this size do not change file metadata. By default storage is just extended.
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        if self.size()? < size {
+            self.set_len(size)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Deallocate space in the specified range without changing storage size (range is read as zeros).
+    /// Used only to reclaim space of value log, so by default it does nothing.
+    fn punch_hole(&self, _offs: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Read exactly `buf.len()` bytes at the specified offset
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offs: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offs) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offs += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for File {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offs)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        FileExt::write_all_at(self, buf, offs)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        fs2::FileExt::allocate(self, size)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn punch_hole(&self, offs: u64, len: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        let rc = unsafe {
+            libc::fallocate(
+                self.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offs as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if rc == 0 {
+            Ok(())
+        } else {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                Ok(()) // file system doesn't support holes: just do not reclaim space
+            } else {
+                Err(err)
+            }
+        }
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offs: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buf, offs)
+    }
+}
+
+// Allows caller to keep reference to the storage passed to the store (for example to control fault injection)
+impl<T: Storage + ?Sized> Storage for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offs)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        (**self).write_all_at(buf, offs)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        (**self).sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        (**self).sync_data()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        (**self).set_len(size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        (**self).allocate(size)
+    }
+
+    fn punch_hole(&self, offs: u64, len: u64) -> io::Result<()> {
+        (**self).punch_hole(offs, len)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offs: u64) -> io::Result<()> {
+        (**self).read_exact_at(buf, offs)
+    }
+}
+
+//
+// Storage which underlying file can be replaced while it is used by the store (see `Store::replace_with`).
+// Operations hold read lock, so replacement waits for completion of I/O in progress.
+//
+pub(crate) struct ReplaceableStorage {
+    storage: RwLock<Box<dyn Storage>>,
+}
+
+impl ReplaceableStorage {
+    pub(crate) fn new(storage: Box<dyn Storage>) -> ReplaceableStorage {
+        ReplaceableStorage {
+            storage: RwLock::new(storage),
+        }
+    }
+
+    //
+    // Replace underlying storage: previous storage is closed
+    //
+    pub(crate) fn replace(&self, storage: Box<dyn Storage>) {
+        *self.storage.write().unwrap_or_else(PoisonError::into_inner) = storage;
+    }
+
+    fn get(&self) -> RwLockReadGuard<'_, Box<dyn Storage>> {
+        self.storage.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Storage for ReplaceableStorage {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        self.get().read_at(buf, offs)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        self.get().write_all_at(buf, offs)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.get().sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.get().sync_data()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.get().set_len(size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.get().size()
+    }
+
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        self.get().allocate(size)
+    }
+
+    fn punch_hole(&self, offs: u64, len: u64) -> io::Result<()> {
+        self.get().punch_hole(offs, len)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offs: u64) -> io::Result<()> {
+        self.get().read_exact_at(buf, offs)
+    }
+}
+
+//
+// Data file opened with O_DIRECT (see `StoreConfig::direct_io`). Direct I/O requires buffer address, offset and length
+// to be aligned to the block size of the device. Pages are aligned, so their I/O is performed as is, other requests
+// (for example of metadata or compressed pages) go through aligned bounce buffer with read-modify-write of partial blocks.
+//
+#[cfg(target_os = "linux")]
+pub(crate) struct DirectStorage {
+    file: File,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; DIRECT_IO_ALIGNMENT]);
+
+#[cfg(target_os = "linux")]
+impl DirectStorage {
+    //
+    // Switch file to direct I/O mode (file system should support O_DIRECT)
+    //
+    pub(crate) fn new(file: File) -> io::Result<DirectStorage> {
+        let fd = file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(DirectStorage { file })
+    }
+
+    fn is_aligned(buf: &[u8], offs: u64) -> bool {
+        (buf.as_ptr() as usize | buf.len() | offs as usize).is_multiple_of(DIRECT_IO_ALIGNMENT)
+    }
+
+    //
+    // Read blocks covering the specified range in aligned buffer: returns buffer, offset of the range in it
+    // and number of bytes read in buffer (smaller than buffer size at the end of file)
+    //
+    fn read_blocks(&self, len: usize, offs: u64) -> io::Result<(Vec<AlignedBlock>, usize, usize)> {
+        let start = offs - offs % DIRECT_IO_ALIGNMENT as u64;
+        let skip = (offs - start) as usize;
+        let n_blocks = (skip + len).div_ceil(DIRECT_IO_ALIGNMENT);
+        let mut blocks = vec![AlignedBlock([0u8; DIRECT_IO_ALIGNMENT]); n_blocks];
+        let buf = Self::as_bytes(&mut blocks);
+        let mut read = 0;
+        while read < buf.len() {
+            match FileExt::read_at(&self.file, &mut buf[read..], start + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((blocks, skip, read))
+    }
+
+    fn as_bytes(blocks: &mut [AlignedBlock]) -> &mut [u8] {
+        // blocks are arrays of bytes without padding
+        unsafe { std::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut u8, blocks.len() * DIRECT_IO_ALIGNMENT) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Storage for DirectStorage {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        if Self::is_aligned(buf, offs) {
+            return FileExt::read_at(&self.file, buf, offs);
+        }
+        let (mut blocks, skip, read) = self.read_blocks(buf.len(), offs)?;
+        let len = buf.len().min(read.saturating_sub(skip));
+        buf[..len].copy_from_slice(&Self::as_bytes(&mut blocks)[skip..skip + len]);
+        Ok(len)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        if Self::is_aligned(buf, offs) {
+            return FileExt::write_all_at(&self.file, buf, offs);
+        }
+        let size = self.size()?;
+        let (mut blocks, skip, _) = self.read_blocks(buf.len(), offs)?;
+        let bytes = Self::as_bytes(&mut blocks);
+        bytes[skip..skip + buf.len()].copy_from_slice(buf);
+        let start = offs - skip as u64;
+        FileExt::write_all_at(&self.file, bytes, start)?;
+        // whole blocks are written, so file extended by this write is truncated to the end of written data
+        let new_size = size.max(offs + buf.len() as u64);
+        if new_size < start + bytes.len() as u64 {
+            self.file.set_len(new_size)?;
+        }
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Storage::size(&self.file)
+    }
+
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        Storage::allocate(&self.file, size)
+    }
+
+    fn punch_hole(&self, offs: u64, len: u64) -> io::Result<()> {
+        Storage::punch_hole(&self.file, offs, len)
+    }
+}
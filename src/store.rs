@@ -1,20 +1,40 @@
-use std::fs::{File, OpenOptions};
-// use std::os::unix::fs::FileExt;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::{Entry, RandomState};
+use std::hash::{BuildHasher, Hasher};
+use std::fs::{self, File, OpenOptions};
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
 use std::cmp::Ordering;
 use fs2::FileExt;
-use std::sync::{Condvar, Mutex, RwLock};
-use std::os::unix::prelude::FileExt as UnixFileExt;
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering as AtomicOrdering};
 use crc32c::*;
-use std::iter;
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 
+use crate::error::StoreError;
 use crate::meta::Metadata;
-use crate::buffer_manager::{BufferManager, PAGE_RAW, PAGE_BUSY, PAGE_WAIT, PAGE_DIRTY, PAGE_SYNCED, Buffer};
-use crate::config::{N_BUSY_EVENTS, BufferId, PageId, PAGE_SIZE, METADATA_SIZE, Key, Value, ItemPointer, MAX_KEY_LEN, MAX_VALUE_LEN};
-use crate::pagedata::PageData;
+use crate::buffer_manager::{BufferManager, EvictionHook, PAGE_RAW, PAGE_BUSY, PAGE_WAIT, PAGE_DIRTY, PAGE_SYNCED};
+use crate::config::{WAL_PREPARE_MARK, WAL_ABORT_MARK, WAL_META_RECORD_SIZE, PID_SIZE, page_offset, N_BUSY_EVENTS, AtomicPageId, BufferId, PageId, StoreId, PAGE_SIZE, METADATA_SIZE, METADATA_EXT_SIZE, META_SHADOW_OFFS, Key, Value, ItemPointer, MAX_KEY_LEN, MAX_VALUE_LEN, MERGE_BATCH_SIZE, MIN_FREE_BUFFERS, META_PID, META_FLAG_VALUE_LOG, VALUE_REF_SIZE, MAX_LOGGED_VALUE_LEN, META_EXT_FORMAT_VERSION, META_EXT_WAL_END, META_EXT_STORE_UUID, STORE_UUID_SIZE, FORMAT_VERSION, META_FLAG_KEY_DICTIONARY, DICTIONARY_PID, PAGE_HEADER_SIZE, META_FLAG_TOMBSTONES, META_FLAG_PAGE_COMPRESSION, VALUE_TAG_LIVE, VALUE_TAG_TOMBSTONE, TOMBSTONE_SIZE};
+use crate::pagedata::{PageData, PageType};
+#[cfg(target_os = "linux")]
+use crate::storage::DirectStorage;
+use crate::storage::{ReplaceableStorage, Storage};
+use crate::snapshot::{Snapshot, SnapshotPages};
+use crate::compression::CompressedStorage;
+use crate::retry::RetryStorage;
+use crate::writer::BackgroundWriter;
+use crate::wal_syncer::WalSyncer;
 use crate::transaction::{TransactionStatus, Transaction};
+use crate::handle::StoreHandle;
+use crate::dictionary::KeyDictionary;
+use crate::buffer_pool::SharedBufferPool;
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
 
 #[derive(PartialEq)]
 enum AccessMode {
@@ -22,6 +42,15 @@ enum AccessMode {
     WriteOnly,
 }
 
+//
+// Underflow of B-Tree page after remove of key
+//
+#[derive(PartialEq)]
+enum Underflow {
+    Empty,  // page became empty and is moved to free list
+    Sparse, // fill factor of page dropped below `StoreConfig::merge_threshold`, so it can be merged with neighbour
+}
+
 struct PageGuard<'a> {
     buf: BufferId,
     pid: PageId,
@@ -34,63 +63,605 @@ impl<'a> Drop for PageGuard<'a> {
     }
 }
 
-#[derive(PartialEq, Copy, Clone, Debug)]
-enum DatabaseState {
+//
+// Path from B-Tree root to leaf: page and item position at each level
+//
+type TreePath = Vec<(PageId, ItemPointer)>;
+
+//
+// Key-value pairs in ascending key order
+//
+type KeyValues = Vec<(Key, Value)>;
+
+///
+/// State of the store (see `Store::state`)
+///
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum StoreState {
+    /// Store is recovered from WAL
     InRecovery,
+    /// Store is opened and can be used
     Opened,
+    /// Store is closed or shut down
     Closed,
+    /// Store was found corrupted: all operations report `StoreError::Corrupted` until `Store::try_reopen` succeeds
     Corrupted,
 }
+
+impl StoreState {
+    fn from_u8(state: u8) -> StoreState {
+        match state {
+            0 => StoreState::InRecovery,
+            1 => StoreState::Opened,
+            2 => StoreState::Closed,
+            _ => StoreState::Corrupted,
+        }
+    }
+}
+
 pub struct Database {
     pub meta: Metadata,           // cached metadata (stored in root page)
     meta_updated: bool,       // whether metadata was updated
-    state: DatabaseState,     // database state
+    modified: bool,           // whether current transaction has modified some pages
     wal_pos: u64,             // current position in log file
     tx_crc: u32,              // accumulated CRC of the current transaction
     tx_size: usize,           // current transaction size
+    vlog_pos: u64,            // end of value log (position of next appended value)
+    vlog_dirty: bool,         // whether values were appended to value log by current transaction
+    prepared: bool,           // whether current transaction is prepared (its pages and metadata are saved in WAL)
+    in_doubt: Option<InDoubt>, // prepared transaction restored by recovery which outcome is not known yet
+    batched_since: Option<Instant>, // commit time of the first batched transaction for which WAL is not synced yet
+}
+
+impl Database {
+    //
+    // Whether current transaction has changed the database, so that its changes should be rolled back
+    //
+    pub(crate) fn is_modified(&self) -> bool {
+        self.modified || self.meta_updated
+    }
+}
+
+//
+// Prepared transaction restored by recovery: its pages are not loaded, but read from WAL when accessed
+//
+struct InDoubt {
+    meta: Metadata,                // metadata of prepared transaction
+    pages: HashMap<PageId, u64>,   // WAL positions of pages modified by transaction
+    crc: u32,                      // checksum of transaction including prepare record
+}
+
+//
+// Leaf page of B-Tree remembered by insert cursor together with range of stored keys which belong to it
+//
+pub(crate) struct LeafAnchor {
+    pid: PageId,
+    low: Option<Key>,  // keys of the leaf are greater than this key (None if there is no lower bound)
+    high: Option<Key>, // keys of the leaf are not greater than this key (None for the right-most leaf)
+}
+
+impl LeafAnchor {
+    fn contains(&self, key: &[u8]) -> bool {
+        self.low.as_ref().is_none_or(|low| key > &low[..]) && self.high.as_ref().is_none_or(|high| key <= &high[..])
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct StoreConfig {
-    /// Buffer pool (pages)
+    /// Buffer pool (pages). It should contain at least `2*height + 3` pages for B-Tree of the given height,
+    /// otherwise `StoreError::CacheTooSmall` is reported on open or when B-Tree grows.
+    /// In no-WAL mode pages modified by transaction are pinned in cache until commit, so cache should also
+    /// hold them: if all buffers are pinned, then operation fails with `StoreError::CacheTooSmall`.
+    /// Memory for buffers is allocated when they are used first time, not when store is opened.
     pub cache_size: usize,
     /// Maximal size of WAL. When it is reached, database file is synced and WAL is rotated
     /// (write starts from the beginning)
     pub checkpoint_interval: u64,
     /// Threshold for flushing dirty pages to WAL (to reduce commit time)
     pub wal_flush_threshold: BufferId,
+    /// Write copy of metadata to the shadow slot before updating it, so that torn write of metadata
+    /// can be recovered. Used only in no-WAL mode (with WAL metadata is restored from the log).
+    pub meta_double_write: bool,
+    /// Prefix implicitly prepended to all keys, so that store (or several stores sharing the same file)
+    /// can be split into isolated namespaces. Keys returned by scans are stripped of this prefix.
+    pub key_prefix: Option<Key>,
+    /// Write committed pages to the data file in background thread (used only in WAL mode).
+    /// Commit still writes and syncs WAL, so it remains durable. Checkpoint waits until
+    /// background thread writes all committed pages and syncs the data file.
+    pub background_flush: bool,
+    /// Maximal number of updated keys buffered by transaction before applying them to B-Tree (0 disables buffering).
+    /// Repeated updates of the same key within transaction are coalesced, so B-Tree is updated only once per key.
+    /// Buffered updates are applied in key order when limit is reached or on commit.
+    pub write_cache_size: usize,
+    /// Lock data and WAL files to prevent concurrent access to them by several processes.
+    /// Disable it only if file system doesn't support locking and exclusive access is guaranteed in some other way:
+    /// opening the same store twice without locking will corrupt it.
+    pub file_locking: bool,
+    /// Compress B-Tree pages written to the data file. Compressed pages are stored in records of variable size
+    /// located through page map, which is kept in memory and reconstructed by scanning the data file on open.
+    /// This mode is chosen when store is created and can not be changed later.
+    pub page_compression: bool,
+    /// Store values out-of-line in value log file (`.vlog` file next to the data file),
+    /// keeping in B-Tree only references to them. It makes B-Tree more compact for large values
+    /// and allows values longer than page. Space of overwritten and removed values is reclaimed
+    /// by `Store::collect_value_log`. This mode is chosen when store is created and can not be changed later.
+    pub value_log: bool,
+    /// Number of condition variables used to wait for completion of page reads by other threads.
+    /// Buffers are mapped to them by modulo, so increasing it reduces spurious wakeups when many threads
+    /// concurrently load different pages.
+    pub busy_events: usize,
+    /// Maximal number of dirty pages pinned in cache by transaction (used only in WAL mode).
+    /// When it is exceeded, least recently modified pages are written to WAL and unpinned,
+    /// so that transaction can modify more pages than fit in cache. Evicted pages are reloaded from WAL.
+    /// By default it is 3/4 of `cache_size`. Limit is reduced if needed to leave place for B-Tree path in cache.
+    pub dirty_pages_limit: Option<usize>,
+    /// Number of times I/O operation failed with transient error (interrupted or would block) is retried
+    /// (with exponential backoff) before error is reported. Other errors are reported immediately.
+    pub io_retries: u32,
+    /// Check B-Tree invariants (as `Transaction::verify` does) on open after recovery.
+    /// If check fails, then open reports `StoreError::Corrupted` instead of returning broken store.
+    /// It requires reading all B-Tree pages, so open of large store takes more time.
+    pub verify_on_open: bool,
+    /// Maximal number of values cached by `Store::get_shared` (0 disables caching).
+    /// Repeated reads of the same key share the cached value instead of copying it from page.
+    /// Cache is cleared by each commit which changed the database and when it becomes full.
+    pub value_cache_size: usize,
+    /// Policy of choosing page allocated for B-Tree: reuse free page or extend the store.
+    pub allocation: AllocationPolicy,
+    /// Maximal length of value: longer values are rejected with `StoreError::ValueTooLong`.
+    /// By default it is the largest value which can be stored: `PAGE_SIZE/4` for values stored in B-Tree pages
+    /// and 4Gb in value log mode. Limit can be reduced, but can not exceed this default.
+    pub max_value_len: Option<usize>,
+    /// Allocate space for WAL of `checkpoint_interval` size when it is reset (on open and by checkpoint
+    /// performed by `Store::compact_in_place`), so that WAL size is not changed by commits and WAL can be synced
+    /// by `sync_data` instead of `sync_all`, avoiding flush of file metadata by each commit.
+    pub preallocate_wal: bool,
+    /// Encode keys using dictionary of recurring key components, making keys shorter and increasing fanout of B-Tree.
+    /// Dictionary is saved in the store when it is created and can not be changed: store created with dictionary
+    /// can be opened only with the same dictionary, and store created without it can not be opened with dictionary.
+    /// Keys are encoded and decoded transparently (key prefix is not encoded).
+    pub key_dictionary: Option<KeyDictionary>,
+    /// Open store even if its WAL is missing or truncated while data file was not synced after the last commit
+    /// (for example WAL was deleted after crash). By default open reports `StoreError::WalMissing` in this case,
+    /// because changes of transactions committed only in WAL are lost and data file may be inconsistent.
+    pub allow_missing_wal: bool,
+    /// Maximal delay of WAL sync for transactions committed with `Durability::Batched` (used only in WAL mode).
+    /// WAL is synced by background thread when this delay expires after commit of the first unsynced batched
+    /// transaction (or by commit of transaction exceeding it), so that several batched transactions share one sync.
+    /// It is also synced by commit of `Durability::Immediate` transaction, by `Store::sync_batched` and on close.
+    pub batched_sync_interval: Duration,
+    /// Keep removed keys as tombstones, so that change tracking consumers can see deletes (see `Store::scan_tombstones`).
+    /// Tombstoned keys are treated as absent by lookups and scans, they are physically removed by `Store::purge_tombstones`.
+    /// This mode is chosen when store is created and can not be changed later.
+    pub tombstones: bool,
+    /// Expected sizes of keys and values. If specified, page split is adjusted for bulk loads where keys are inserted
+    /// in runs of adjacent keys (for example in descending order): instead of dividing page in two halves,
+    /// page is split at the insert position, keeping on each part at least a quarter of items of the hinted size
+    /// fitting in the page. It reduces number of pages left half-empty by such loads, but may decrease
+    /// fill of pages for random inserts.
+    pub item_size_hint: Option<ItemSizeHint>,
+    /// Truncate WAL on checkpoint to reclaim its disk space. By default WAL is not truncated (it is rewritten from
+    /// the beginning) to avoid overhead of file extension, so WAL file keeps its peak size. If `preallocate_wal`
+    /// is also set, then WAL is shrunk to the preallocated size.
+    pub shrink_wal_on_checkpoint: bool,
+    /// Fill factor (fraction of page space occupied by items) below which B-Tree page is merged with its neighbour
+    /// after remove of key, if items of both pages fit in one page. Zero (default) disables merging: page is reclaimed
+    /// only when it becomes empty, so delete-heavy workload can leave pages sparsely filled.
+    pub merge_threshold: f32,
+    /// Access data file with direct I/O (`O_DIRECT`) bypassing OS page cache, so that pages are cached only
+    /// in buffer pool of the store. Supported only on Linux by stores opened by `Store::open`, file system
+    /// should support direct I/O. WAL and value log are still accessed through page cache.
+    pub direct_io: bool,
+}
+
+///
+/// Expected sizes of keys and values (see `StoreConfig::item_size_hint`)
+///
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct ItemSizeHint {
+    /// Expected length of key (including key prefix)
+    pub key_len: usize,
+    /// Expected length of value
+    pub value_len: usize,
 }
 
-impl StoreConfig {
-    pub fn default() -> StoreConfig {
+impl Default for StoreConfig {
+    fn default() -> StoreConfig {
         StoreConfig {
-            cache_size: 128 * 1024,                         // 1Gb
-            checkpoint_interval: 1u64 * 1024 * 1024 * 1024, // 1Gb
+            cache_size: 128 * 1024,                    // 1Gb
+            checkpoint_interval: 1024 * 1024 * 1024, // 1Gb
             wal_flush_threshold: BufferId::MAX,
+            meta_double_write: false,
+            key_prefix: None,
+            background_flush: false,
+            write_cache_size: 0,
+            file_locking: true,
+            page_compression: false,
+            value_log: false,
+            busy_events: N_BUSY_EVENTS,
+            dirty_pages_limit: None,
+            io_retries: 0,
+            verify_on_open: false,
+            value_cache_size: 0,
+            allocation: AllocationPolicy::ReuseFirst,
+            max_value_len: None,
+            preallocate_wal: false,
+            key_dictionary: None,
+            allow_missing_wal: false,
+            batched_sync_interval: Duration::from_millis(10),
+            tombstones: false,
+            item_size_hint: None,
+            shrink_wal_on_checkpoint: false,
+            merge_threshold: 0.0,
+            direct_io: false,
         }
     }
 }
 
+///
+/// Policy of allocating pages for B-Tree (see `StoreConfig::allocation`)
+///
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum AllocationPolicy {
+    /// Reuse pages from free list before extending the store: keeps store compact
+    ReuseFirst,
+    /// Extend the store and reuse free pages only when store size reaches `max_size` pages.
+    /// New pages are placed sequentially at the end of the file, which improves locality of ascending inserts,
+    /// while space of removed pages is not reclaimed until then (or until `Store::compact_in_place`).
+    AppendFirst { max_size: PageId },
+}
+
+///
+/// Durability of transaction commit (see `Transaction::set_durability`)
+///
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Durability {
+    /// Commit syncs WAL, so transaction is durable when commit returns
+    #[default]
+    Immediate,
+    /// Commit writes WAL but delays its sync (see `StoreConfig::batched_sync_interval`), so transaction
+    /// can be lost by crash after commit. Changes of transaction are written to the data file only after sync,
+    /// so lost transaction doesn't corrupt the store.
+    Batched,
+}
+
+///
+/// Policy of resolving conflicts when key being merged from other store already exists in this store
+///
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ConflictPolicy {
+    /// Replace existing value with the value from other store
+    Overwrite,
+    /// Preserve existing value
+    KeepExisting,
+    /// Abort merge with error
+    Error,
+}
+
+///
+/// Result of `Transaction::put_reporting`
+///
+#[derive(PartialEq, Clone, Debug)]
+pub enum UpsertOutcome {
+    /// New key was inserted
+    Inserted,
+    /// Value of existing key was replaced
+    Updated {
+        /// Previous value of the key
+        old: Value,
+    },
+}
+
+///
+/// Statistic of B-Tree pages at one level
+///
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LevelSummary {
+    /// Number of pages at this level
+    pub pages: u64,
+    /// Minimal number of items in page
+    pub min_items: usize,
+    /// Average number of items in page
+    pub avg_items: f64,
+    /// Maximal number of items in page
+    pub max_items: usize,
+}
+
+///
+/// Structural summary of B-Tree
+///
+#[derive(Clone, Debug, Default)]
+pub struct StructureSummary {
+    /// Height of B-Tree
+    pub height: u32,
+    /// Statistic for each level of B-Tree, starting from root
+    pub levels: Vec<LevelSummary>,
+    /// Number of internal pages which right-most item is +inf separator
+    pub inf_separators: u64,
+}
+
+///
+/// Space used by keys with the given prefix (see `Store::prefix_stats`)
+///
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    /// Number of keys with the prefix
+    pub keys: u64,
+    /// Total length of keys (without key prefix of the store)
+    pub key_bytes: u64,
+    /// Total length of values (including values stored in value log)
+    pub value_bytes: u64,
+    /// Number of leaf pages containing keys with the prefix
+    pub pages: u64,
+}
+
+///
+/// Statistic of buffer cache usage
+///
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CacheStats {
+    /// Number of pages in cache
+    pub cached_pages: usize,
+    /// Number of pages pinned in cache (accessed by some thread or modified by current transaction)
+    pub pinned_pages: usize,
+    /// Number of dirty pages pinned until commit of current transaction
+    pub dirty_pages: usize,
+    /// Number of pages spilled to WAL by current transaction
+    pub spilled_pages: usize,
+}
+
+///
+/// Snapshot of the list of dirty pages of current transaction (see `Store::dirty_page_report`)
+///
+#[cfg(feature = "diagnostics")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DirtyReport {
+    /// Dirty pages in order of dirty list: from most recently to least recently modified
+    pub dirty: Vec<PageId>,
+    /// Dirty pages which images are already written to WAL (subset of `dirty`)
+    pub synced: Vec<PageId>,
+    /// Page which will be written to WAL next when `StoreConfig::wal_flush_threshold` is exceeded
+    pub next_sync: Option<PageId>,
+    /// Number of dirty pages maintained by buffer manager (it should be equal to length of `dirty`)
+    pub dirtied: usize,
+}
+
+///
+/// Pinned buffers of the cache which are not explained by current transaction (see `Store::pin_audit`)
+///
+#[cfg(feature = "diagnostics")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PinReport {
+    /// Pinned pages and number of their pins (excluding pin of dirty page held until end of transaction)
+    pub pinned: Vec<(PageId, u16)>,
+}
+
+///
+/// Summary of recovery performed when store is opened (see `Store::open_with_report`)
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// Number of committed transactions replayed from WAL
+    pub transactions: u64,
+    /// Number of WAL bytes occupied by replayed transactions
+    pub replayed_bytes: u64,
+    /// Number of WAL bytes following the last replayed transaction which were discarded:
+    /// incomplete or corrupted transaction, or remains of WAL written before its restart by checkpoint
+    pub discarded_bytes: u64,
+    /// Whether replay was stopped because of checksum mismatch
+    pub crc_mismatch: bool,
+    /// Sequence number of the last committed transaction after recovery
+    pub commit_seq: u64,
+    /// Size of the store in pages after recovery
+    pub size: PageId,
+    /// Height of B-Tree after recovery
+    pub height: u32,
+    /// Whether WAL ends with prepared transaction which was neither committed nor rolled back.
+    /// It should be resolved using `Store::prepared_transaction` before other transactions can be committed.
+    pub prepared: bool,
+}
+
+///
+/// Result of checking pages of the store by `Store::scrub`
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScrubReport {
+    /// Number of checked pages: all pages of the data file including unreachable ones
+    pub checked_pages: u64,
+    /// Pages which can not be read, have broken layout or contain invalid references to other pages (in ascending order)
+    pub bad_pages: Vec<PageId>,
+    /// Pages which are neither reachable from B-Tree root nor included in free list (in ascending order):
+    /// space of these pages is leaked. Unreachable pages which are also damaged are reported in `bad_pages` too.
+    pub unreachable_pages: Vec<PageId>,
+}
+
+///
+/// Result of rebuilding store from leaf pages of damaged store (see `Store::repair`)
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RepairReport {
+    /// Number of scanned pages of source store (excluding metadata page)
+    pub scanned_pages: u64,
+    /// Number of leaf pages which items were copied to the new store
+    pub salvaged_pages: u64,
+    /// Number of pages which can not be read or have invalid layout
+    pub unreadable_pages: u64,
+    /// Number of keys loaded in the new store
+    pub keys: u64,
+    /// Number of discarded items with duplicate keys
+    pub duplicates: u64,
+}
+
+///
+/// Persistent key-value store.
+///
+/// `Store` is `Send + Sync`, so it can be shared between threads by wrapping it in `Arc<Store>`.
+/// Each thread should start its own transactions: write transactions are serialized
+/// (transaction holds exclusive lock until it is committed or rolled back),
+/// while `get` can be executed concurrently by multiple threads. `get` reads the last committed state
+/// and is not blocked by active write transaction, while scans wait for its completion.
+///
 pub struct Store {
     db: RwLock<Database>,
-    buf_mgr: Mutex<BufferManager>,
-    busy_events: [Condvar; N_BUSY_EVENTS],
-    pool: Vec<RwLock<PageData>>,
-    conf: StoreConfig,
-    file: File,
-    log: Option<File>,
+    // Root and height of B-Tree in the last committed state. It is locked for write while commit or rollback
+    // updates buffers, so `get` holding read lock sees consistent committed state.
+    committed: RwLock<(PageId, u32)>,
+    // Size of the store in the last committed state (it is updated together with `committed`)
+    committed_size: AtomicPageId,
+    // Snapshots taken by `snapshot`: commit saves committed images of pages it modifies for them
+    snapshots: Mutex<Vec<Weak<SnapshotPages>>>,
+    // Generation of the store state: it is incremented when store is reloaded, invalidating existing snapshots
+    generation: AtomicU64,
+    // State of the store (`StoreState`). It is changed while holding write lock on `db`, but is kept outside of it,
+    // so that `get` can check it without waiting for completion of active write transaction.
+    state: AtomicU8,
+    // Values returned by `get_shared`. It is cleared by commit while holding write lock on `committed`,
+    // so it contains only values from the last committed state.
+    value_cache: Mutex<HashMap<Key, Arc<[u8]>>>,
+    // Buffer pool: private pool of `StoreConfig::cache_size` pages or pool shared with other stores
+    buffers: Arc<SharedBufferPool>,
+    store_id: StoreId, // identifier of the store in buffer pool
+    meta_buf: BufferId, // pinned buffer with metadata page
+    pub(crate) conf: StoreConfig,
+    file: Arc<dyn Storage>,
+    // Page map of the data file with compressed pages (`file` refers to the same storage)
+    compression: Option<Arc<CompressedStorage>>,
+    log: Option<Arc<dyn Storage>>,
+    vlog: Option<Box<dyn Storage>>,
+    writer: Option<BackgroundWriter>,
+    // Background sync of WAL written by batched transactions (started by the first batched commit)
+    syncer: OnceLock<WalSyncer>,
+    // Images of pages committed by batched transactions: they are written to the data file after WAL is synced
+    deferred: Mutex<HashMap<PageId, Arc<PageData>>>,
+    // Files of store opened by path, which can be replaced by `replace_with`
+    replaceable: Option<ReplaceableFiles>,
+    // Registration of data file opened by path: it is dropped after files are closed
+    _open_path: Option<OpenPath>,
+    #[cfg(feature = "metrics")]
+    metrics: Metrics,
+}
+
+// Canonical paths of data files of stores opened in this process. File lock does not necessarily
+// prevent opening the same file once again through another descriptor of the same process.
+static OPEN_PATHS: LazyLock<Mutex<HashSet<PathBuf>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+//
+// Data file registered in `OPEN_PATHS` until store is dropped
+//
+struct OpenPath(PathBuf);
+
+impl OpenPath {
+    //
+    // Register opened file, reporting `StoreError::AlreadyOpen` if it is already opened by another store
+    //
+    fn register(path: &Path) -> Result<OpenPath> {
+        let path = path.canonicalize()?;
+        if !OPEN_PATHS.lock().unwrap_or_else(PoisonError::into_inner).insert(path.clone()) {
+            anyhow::bail!(StoreError::AlreadyOpen { path });
+        }
+        Ok(OpenPath(path))
+    }
+}
+
+impl Drop for OpenPath {
+    fn drop(&mut self) {
+        OPEN_PATHS.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.0);
+    }
+}
+
+//
+// Data file and WAL of store opened by path: paths are needed to replace files by `Store::replace_with`
+//
+struct ReplaceableFiles {
+    db_path: PathBuf,
+    log_path: Option<PathBuf>,
+    file: Arc<ReplaceableStorage>,
+    log: Option<Arc<ReplaceableStorage>>,
+}
+
+//
+// Generate random identifier of new store (hasher of standard library is seeded with random keys)
+//
+fn generate_store_uuid() -> [u8; STORE_UUID_SIZE] {
+    let mut uuid = [0u8; STORE_UUID_SIZE];
+    for chunk in uuid.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos()));
+        hasher.write_u32(std::process::id());
+        chunk.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    uuid
 }
 
+// Store is shared between threads by reference: check it at compile time
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Store>();
+};
+
 //
 // Storage internal methods implementations
 //
 impl Store {
+    //
+    // Get content of buffer, allocating it on first access
+    //
+    fn pool_page(&self, buf: BufferId) -> &RwLock<PageData> {
+        self.buffers.page(buf)
+    }
+
+    //
+    // Get content of buffer with metadata page
+    //
+    fn meta_page(&self) -> &RwLock<PageData> {
+        self.pool_page(self.meta_buf)
+    }
+
+    //
+    // Lock buffer manager. Mutex is poisoned if some thread panicked while holding it: state of buffers
+    // can be inconsistent in this case, so `StoreError::Corrupted` is reported instead of propagating panic.
+    //
+    fn lock_buf_mgr(&self) -> Result<MutexGuard<'_, BufferManager>> {
+        Self::lock_pool(&self.buffers)
+    }
+
+    //
+    // Lock buffer manager of the pool (like `lock_buf_mgr`, but before the store is constructed)
+    //
+    fn lock_pool(buffers: &SharedBufferPool) -> Result<MutexGuard<'_, BufferManager>> {
+        buffers
+            .buf_mgr
+            .lock()
+            .map_err(|_| StoreError::Corrupted("buffer manager lock is poisoned".to_string()).into())
+    }
+
+    //
+    // Change state of the store (caller should hold write lock on `db`)
+    //
+    fn set_state(&self, state: StoreState) {
+        self.state.store(state as u8, AtomicOrdering::Release);
+    }
+
+    //
+    // Report `StoreError::Corrupted` if store was found corrupted, rather than accessing its inconsistent buffers
+    //
+    pub(crate) fn check_state(&self) -> Result<()> {
+        if self.state() == StoreState::Corrupted {
+            anyhow::bail!(StoreError::Corrupted(
+                "store was marked as corrupted by failed operation".to_string()
+            ));
+        }
+        Ok(())
+    }
+
     //
     // Unpin page (called by PageGuard)
     //
     fn release_page(&self, buf: BufferId) {
-        let mut bm = self.buf_mgr.lock().unwrap();
-        bm.release_buffer(buf);
+        // PageGuard can be dropped while unwinding panic which has poisoned the lock
+        if let Ok(mut bm) = self.buffers.buf_mgr.lock() {
+            bm.release_buffer(buf);
+        }
     }
 
     //
@@ -98,32 +669,46 @@ impl Store {
     //
     fn new_page(&self, db: &mut Database) -> Result<PageGuard<'_>> {
         let free = db.meta.free;
-        let buf;
-        let mut bm = self.buf_mgr.lock().unwrap();
-        if free != 0 {
-            buf = bm.get_buffer(free)?;
-            let mut page = self.pool[buf as usize].write().unwrap();
-            if (bm.pages[buf as usize].state & PAGE_RAW) != 0 {
-                self.file
-                    .read_exact_at(&mut page.data, free as u64 * PAGE_SIZE as u64)?;
-            }
-            db.meta.free = page.get_u32(0);
+        db.meta_updated = true;
+        let reuse = match self.conf.allocation {
+            AllocationPolicy::ReuseFirst => true,
+            AllocationPolicy::AppendFirst { max_size } => db.meta.size >= max_size,
+        };
+        if free != 0 && reuse {
+            // Page removed from B-Tree by current transaction can be concurrently read by `Store::get`,
+            // so it is loaded by get_page and locked without holding buffer manager lock after marking it as modified.
+            anyhow::ensure!(free < db.meta.size, "Free list is corrupted at page {}", free);
+            let pin = self.get_page(free, AccessMode::ReadOnly)?;
+            let next = {
+                // check page before it is marked as modified
+                let page = self.pool_page(pin.buf).read().unwrap();
+                let next = page.get_next_free();
+                anyhow::ensure!(
+                    page.get_page_type() == Some(PageType::Free) && next != free && next < db.meta.size,
+                    "Free list is corrupted at page {}",
+                    free
+                );
+                next
+            };
+            self.modify_page(db, pin.buf)?;
+            let mut page = self.pool_page(pin.buf).write().unwrap();
+            db.meta.free = next;
             page.data.fill(0u8);
+            drop(page);
+            Ok(pin)
         } else {
             // extend store
-            buf = bm.get_buffer(db.meta.size)?;
+            let mut bm = self.lock_buf_mgr()?;
+            let buf = bm.get_buffer(self.store_id, db.meta.size)?;
             db.meta.size += 1;
-            let mut page = self.pool[buf as usize].write().unwrap();
-            page.data.fill(0u8);
+            self.pool_page(buf).write().unwrap().data.fill(0u8);
+            self.modify_buffer(db, &mut bm, buf)?;
+            Ok(PageGuard {
+                buf,
+                pid: bm.pages[buf as usize].pid,
+                store: self,
+            })
         }
-        db.meta_updated = true;
-        self.modify_buffer(db, &mut bm, buf)?;
-
-        Ok(PageGuard {
-            buf,
-            pid: bm.pages[buf as usize].pid,
-            store: &self,
-        })
     }
 
     //
@@ -131,37 +716,43 @@ impl Store {
     // Buffer will be automatically released on exiting from scope
     //
     fn get_page(&self, pid: PageId, mode: AccessMode) -> Result<PageGuard<'_>> {
-        let mut bm = self.buf_mgr.lock().unwrap();
-        let buf = bm.get_buffer(pid)?;
-        if (bm.pages[buf as usize].state & PAGE_BUSY) != 0 {
-            // Some other thread is loading buffer: just wait until it done
+        let mut bm = self.lock_buf_mgr()?;
+        let buf = bm.get_buffer(self.store_id, pid)?;
+        let busy_event = &self.buffers.busy_events[buf as usize % self.buffers.busy_events.len()];
+        while (bm.pages[buf as usize].state & PAGE_BUSY) != 0 {
+            // Some other thread is loading buffer: just wait until it done.
+            // Condition variable is shared by several buffers, so wakeup may be caused by other buffer.
             bm.pages[buf as usize].state |= PAGE_WAIT;
-            loop {
-                debug_assert!((bm.pages[buf as usize].state & PAGE_WAIT) != 0);
-                bm = self.busy_events[buf as usize % N_BUSY_EVENTS]
-                    .wait(bm)
-                    .unwrap();
-                if (bm.pages[buf as usize].state & PAGE_BUSY) == 0 {
-                    break;
-                }
-            }
-        } else if (bm.pages[buf as usize].state & PAGE_RAW) != 0 {
+            bm = busy_event.wait(bm).unwrap();
+        }
+        if (bm.pages[buf as usize].state & PAGE_RAW) != 0 {
             if mode != AccessMode::WriteOnly {
                 // Read buffer if not in write-only mode
                 bm.pages[buf as usize].state = PAGE_BUSY;
+                let spilled = bm.store(self.store_id).spilled.get(&pid).copied();
                 drop(bm); // read page without holding lock
-                {
-                    let mut page = self.pool[buf as usize].write().unwrap();
-                    self.file
-                        .read_exact_at(&mut page.data, pid as u64 * PAGE_SIZE as u64)?;
-                }
-                bm = self.buf_mgr.lock().unwrap();
+                let res = {
+                    let mut page = self.pool_page(buf).write().unwrap();
+                    self.load_page(pid, spilled, &mut page)
+                };
+                bm = self.lock_buf_mgr()?;
                 if (bm.pages[buf as usize].state & PAGE_WAIT) != 0 {
                     // Somebody is waiting for us
-                    self.busy_events[buf as usize % N_BUSY_EVENTS].notify_all();
+                    busy_event.notify_all();
                 }
+                if let Err(err) = res {
+                    // leave buffer raw, so that waiting threads try to read it themselves
+                    bm.pages[buf as usize].state = PAGE_RAW;
+                    bm.release_buffer(buf);
+                    return Err(err);
+                }
+                #[cfg(feature = "metrics")]
+                Metrics::add(&self.metrics.cache_misses, 1);
             }
             bm.pages[buf as usize].state = 0;
+        } else {
+            #[cfg(feature = "metrics")]
+            Metrics::add(&self.metrics.cache_hits, 1);
         }
         if mode != AccessMode::ReadOnly {
             bm.modify_buffer(buf, BufferId::MAX)?;
@@ -169,7 +760,7 @@ impl Store {
         Ok(PageGuard {
             buf,
             pid,
-            store: &self,
+            store: self,
         })
     }
 
@@ -183,10 +774,62 @@ impl Store {
         bm: &mut BufferManager,
         buf: BufferId,
     ) -> Result<()> {
+        db.modified = true;
         if let Some((sync_buf, sync_pid)) = bm.modify_buffer(buf, self.conf.wal_flush_threshold)? {
             assert_eq!(bm.pages[sync_buf as usize].state, PAGE_DIRTY | PAGE_SYNCED);
             self.write_page_to_wal(db, sync_buf, sync_pid)?;
         }
+        if self.log.is_some() {
+            self.spill_buffers(db, bm)?;
+        }
+        Ok(())
+    }
+
+    //
+    // Check that cache can hold pages pinned by update of B-Tree of the specified height:
+    // split at each level of path pins path page and new page
+    //
+    fn check_cache_size(&self, height: u32) -> Result<()> {
+        let required = height as usize * 2 + MIN_FREE_BUFFERS;
+        // requirement is reported if buffers are exhausted later
+        self.lock_buf_mgr()?.store_mut(self.store_id).required = required;
+        if self.buffers.size() < required {
+            anyhow::bail!(StoreError::CacheTooSmall {
+                cache_size: self.buffers.size(),
+                required,
+            });
+        }
+        Ok(())
+    }
+
+    //
+    // Maximal number of dirty pages pinned in cache. It is limited to leave place for path in B-Tree
+    // of the specified height, otherwise there may be no page to evict while traversing B-Tree.
+    //
+    fn dirty_pages_limit(&self, height: u32) -> BufferId {
+        let limit = self
+            .conf
+            .dirty_pages_limit
+            .unwrap_or(self.buffers.size() - self.buffers.size() / 4);
+        let reserved = height as usize + MIN_FREE_BUFFERS;
+        limit.min(self.buffers.size().saturating_sub(reserved)).max(1) as BufferId
+    }
+
+    //
+    // If number of dirty pages exceeds limit, then write least recently modified pages to WAL
+    // and unpin them, so that they can be evicted from cache before commit
+    //
+    fn spill_buffers(&self, db: &mut Database, bm: &mut BufferManager) -> Result<()> {
+        let limit = self.dirty_pages_limit(db.meta.height);
+        while bm.store(self.store_id).dirtied > limit {
+            let buf = bm.spill_candidate(self.store_id);
+            if buf == 0 {
+                break;
+            }
+            let wal_pos = db.wal_pos + PID_SIZE as u64; // skip page identifier
+            self.write_page_to_wal(db, buf, bm.pages[buf as usize].pid)?;
+            bm.spill_buffer(buf, wal_pos);
+        }
         Ok(())
     }
 
@@ -194,7 +837,7 @@ impl Store {
     // Mark page as dirty and pin it in-memory until end of transaction
     //
     fn modify_page(&self, db: &mut Database, buf: BufferId) -> Result<()> {
-        let mut bm = self.buf_mgr.lock().unwrap();
+        let mut bm = self.lock_buf_mgr()?;
         self.modify_buffer(db, &mut bm, buf)
     }
 
@@ -203,282 +846,1099 @@ impl Store {
             status: TransactionStatus::InProgress,
             store: self,
             db: self.db.write().unwrap(),
+            write_cache: BTreeMap::new(),
+            durability: Durability::Immediate,
+        }
+    }
+
+    ///
+    /// Get prepared transaction restored by recovery (see `RecoveryReport::prepared`), so that coordinator
+    /// of distributed transaction can complete it by `Transaction::commit_prepared` or `Transaction::rollback_prepared`.
+    /// Returns None if there is no such transaction. Dropping returned transaction rolls it back.
+    ///
+    pub fn prepared_transaction(&self) -> Result<Option<Transaction<'_>>> {
+        let mut db = self.db.write().unwrap();
+        if db.in_doubt.is_none() {
+            return Ok(None);
         }
+        let (meta, crc) = {
+            // Pages of transaction are read from WAL, so their committed images should not be taken from cache.
+            // Readers are excluded, so that no page is pinned.
+            let _committed = self.committed.write().unwrap();
+            let mut bm = self.lock_buf_mgr()?;
+            // transaction is taken only when buffer manager is locked, so that it is not lost on error
+            let in_doubt = db.in_doubt.take().unwrap();
+            for pid in in_doubt.pages.keys() {
+                bm.discard_page(self.store_id, *pid);
+            }
+            bm.store_mut(self.store_id).spilled = in_doubt.pages;
+            (in_doubt.meta, in_doubt.crc)
+        };
+        db.meta = meta;
+        self.meta_page().write().unwrap().data[0..METADATA_SIZE].copy_from_slice(&db.meta.pack());
+        db.meta_updated = true;
+        db.tx_crc = crc;
+        db.prepared = true;
+        Ok(Some(Transaction {
+            status: TransactionStatus::Prepared,
+            store: self,
+            db,
+            write_cache: BTreeMap::new(),
+            durability: Durability::Immediate,
+        }))
     }
 
     fn write_page_to_wal(&self, db: &mut Database, buf: BufferId, pid: PageId) -> Result<()> {
         if let Some(log) = &self.log {
-            let mut tx_buf = [0u8; PAGE_SIZE + 4];
-            let page = self.pool[buf as usize].read().unwrap();
-            tx_buf[0..4].copy_from_slice(&pid.to_be_bytes());
-            tx_buf[4..].copy_from_slice(&page.data);
+            let mut tx_buf = [0u8; PID_SIZE + PAGE_SIZE];
+            let page = self.pool_page(buf).read().unwrap();
+            tx_buf[0..PID_SIZE].copy_from_slice(&pid.to_be_bytes());
+            tx_buf[PID_SIZE..].copy_from_slice(&page.data);
             db.tx_crc = crc32c_append(db.tx_crc, &tx_buf);
             log.write_all_at(&tx_buf, db.wal_pos)?;
-            db.wal_pos += (4 + PAGE_SIZE) as u64;
-            db.tx_size += 4 + PAGE_SIZE;
+            db.wal_pos += (PID_SIZE + PAGE_SIZE) as u64;
+            db.tx_size += PID_SIZE + PAGE_SIZE;
+            #[cfg(feature = "metrics")]
+            Metrics::add(&self.metrics.wal_bytes, (PID_SIZE + PAGE_SIZE) as u64);
         }
         Ok(())
     }
 
-    pub fn commit(&self, db: &mut Database) -> Result<()> {
-        let mut bm = self.buf_mgr.lock().unwrap();
+    //
+    // Make values appended to value log by current transaction durable: they should be durable before references to them
+    //
+    fn sync_value_log(&self, db: &mut Database) -> Result<()> {
+        if db.vlog_dirty {
+            self.vlog.as_ref().unwrap().sync_data()?;
+            db.vlog_dirty = false;
+        }
+        Ok(())
+    }
 
-        if db.meta_updated {
-            let meta = db.meta.pack();
-            let mut page = self.pool[0].write().unwrap();
-            page.data[0..METADATA_SIZE].copy_from_slice(&meta);
+    //
+    // Update metadata if current transaction changed database and write its dirty pages to WAL (if any).
+    // Returns true if database is changed.
+    //
+    fn save_transaction(&self, db: &mut Database, bm: &mut BufferManager) -> Result<bool> {
+        // metadata can be changed without modification of pages (for example by truncation of free pages)
+        let changed = bm.store(self.store_id).dirty_pages != 0 || db.meta_updated;
+        if changed {
+            anyhow::ensure!(
+                db.in_doubt.is_none(),
+                "Prepared transaction should be resolved before commit of other transactions"
+            );
+            // transaction changed database
+            db.meta.commit_seq += 1;
+            db.meta_updated = true;
         }
-        if let Some(log) = &self.log {
+        if self.log.is_some() {
             // Write dirty pages to log file
-            let mut dirty = bm.dirty_pages;
-            while dirty != 0 && (bm.pages[dirty as usize].state & PAGE_SYNCED) == 0 {
-                assert_eq!(bm.pages[dirty as usize].state, PAGE_DIRTY);
-                self.write_page_to_wal(db, dirty, bm.pages[dirty as usize].pid)?;
+            let mut dirty = bm.store(self.store_id).dirty_pages;
+            while dirty != 0 {
+                // pages pinned by other references at the moment of flush may be left unsynced behind synced ones
+                if (bm.pages[dirty as usize].state & PAGE_SYNCED) == 0 {
+                    assert_eq!(bm.pages[dirty as usize].state, PAGE_DIRTY);
+                    self.write_page_to_wal(db, dirty, bm.pages[dirty as usize].pid)?;
+                }
                 dirty = bm.pages[dirty as usize].next;
             }
-            if bm.dirty_pages != 0 {
-                let mut buf = [0u8; METADATA_SIZE + 8];
-                {
-                    let page = self.pool[0].read().unwrap();
-                    buf[4..4 + METADATA_SIZE].copy_from_slice(&page.data[0..METADATA_SIZE]);
-                }
-                let crc = crc32c_append(db.tx_crc, &buf[..4 + METADATA_SIZE]);
-                buf[4 + METADATA_SIZE..].copy_from_slice(&crc.to_be_bytes());
-                log.write_all_at(&buf, db.wal_pos)?;
-                db.wal_pos += (8 + METADATA_SIZE) as u64;
-                log.sync_all()?;
-                db.tx_crc = 0;
-                db.tx_size = 0;
+            if changed {
+                // data file written by this transaction can not be recovered without this part of WAL
+                db.meta.set_ext(META_EXT_WAL_END, &db.wal_pos.to_be_bytes())?;
+            }
+        }
+        if db.meta_updated {
+            let meta = db.meta.pack();
+            let mut page = self.meta_page().write().unwrap();
+            page.data[0..METADATA_SIZE].copy_from_slice(&meta);
+        }
+        Ok(changed)
+    }
 
-                // Write pages to the data file
-                self.flush_buffers(&mut bm, db.meta_updated)?;
+    //
+    // Remove WAL end marker from metadata in the data file. It should be called when data file is synced,
+    // before WAL is truncated or rewritten from the beginning: data file does not depend on WAL any more.
+    //
+    fn clear_wal_end(&self, db: &mut Database) -> Result<()> {
+        let mut page = self.meta_page().write().unwrap();
+        // data file is synced, so it contains the last committed metadata
+        self.file.read_exact_at(&mut page.data, 0)?;
+        let mut meta = Metadata::unpack(&page.data);
+        if meta.remove_ext(META_EXT_WAL_END) {
+            page.data[0..METADATA_SIZE].copy_from_slice(&meta.pack());
+            let crc = Metadata::checksum(&page.data);
+            page.set_u32(METADATA_SIZE, crc);
+            self.file.write_all_at(&page.data, 0)?;
+            self.file.sync_data()?;
+        }
+        db.meta.remove_ext(META_EXT_WAL_END);
+        Ok(())
+    }
 
-                if db.wal_pos >= self.conf.checkpoint_interval {
-                    // Sync data file and restart from the beginning of WAL.
-                    // So not truncate WAL to avoid file extension overhead.
-                    self.file.sync_all()?;
-                    db.wal_pos = 0;
-                }
-            }
-        } else {
-            // No WAL mode: just write dirty pages to the disk
-            self.flush_buffers(&mut bm, db.meta_updated)?;
+    //
+    // Truncate WAL and preallocate space for it if `StoreConfig::preallocate_wal` is set
+    //
+    fn reset_wal(&self, log: &dyn Storage) -> Result<()> {
+        log.set_len(0)?;
+        if self.conf.preallocate_wal {
+            log.allocate(self.conf.checkpoint_interval)?;
         }
-        db.meta_updated = false;
         Ok(())
     }
 
     //
-    // Flush dirty pages to the disk. Return true if database is changed.
+    // Write record of the specified type (commit, prepare or abort) with metadata of current transaction to WAL
+    // and sync it if requested. Returns checksum of transaction including this record.
     //
-    fn flush_buffers(&self, bm: &mut BufferManager, save_meta: bool) -> Result<bool> {
-        let mut dirty = bm.dirty_pages;
-        if save_meta {
-            assert!(dirty != 0); // if we changed meta, then we should change or create at least one page
-            let page = self.pool[0].read().unwrap();
-            self.file.write_all_at(&page.data, 0)?;
+    fn write_meta_record(&self, db: &mut Database, mark: PageId, sync: bool) -> Result<u32> {
+        let log = self.log.as_ref().unwrap();
+        let mut buf = [0u8; WAL_META_RECORD_SIZE];
+        buf[0..PID_SIZE].copy_from_slice(&mark.to_be_bytes());
+        {
+            let page = self.meta_page().read().unwrap();
+            buf[PID_SIZE..PID_SIZE + METADATA_SIZE].copy_from_slice(&page.data[0..METADATA_SIZE]);
         }
-        while dirty != 0 {
-            let pid = bm.pages[dirty as usize].pid;
-            let file_offs = pid as u64 * PAGE_SIZE as u64;
-            let page = self.pool[dirty as usize].read().unwrap();
-            let next = bm.pages[dirty as usize].next;
-            self.file.write_all_at(&page.data, file_offs)?;
-            debug_assert!((bm.pages[dirty as usize].state & PAGE_DIRTY) != 0);
-            bm.pages[dirty as usize].state = 0;
-            bm.unpin(dirty);
-            dirty = next;
+        let crc = crc32c_append(db.tx_crc, &buf[..PID_SIZE + METADATA_SIZE]);
+        buf[PID_SIZE + METADATA_SIZE..].copy_from_slice(&crc.to_be_bytes());
+        log.write_all_at(&buf, db.wal_pos)?;
+        db.wal_pos += WAL_META_RECORD_SIZE as u64;
+        db.tx_size += WAL_META_RECORD_SIZE;
+        #[cfg(feature = "metrics")]
+        Metrics::add(&self.metrics.wal_bytes, WAL_META_RECORD_SIZE as u64);
+        if sync {
+            self.sync_wal(log.as_ref())?;
         }
-        if bm.dirty_pages != 0 {
-            bm.dirty_pages = 0;
-            bm.dirtied = 0;
-            bm.next_sync = 0;
-            Ok(true)
+        Ok(crc)
+    }
+
+    //
+    // Sync WAL
+    //
+    fn sync_wal(&self, log: &dyn Storage) -> Result<()> {
+        if self.conf.preallocate_wal {
+            // WAL size is not changed, so it is enough to sync data
+            log.sync_data()?;
         } else {
-            Ok(false)
+            log.sync_all()?;
         }
+        Ok(())
     }
 
     //
-    // Rollback current transaction
+    // Sync WAL and write pages of batched transactions to the data file
     //
-    pub fn rollback(&self, db: &mut Database) -> Result<()> {
-        let mut bm = self.buf_mgr.lock().unwrap();
-        let mut dirty = bm.dirty_pages;
-        // Just throw away all dirty pages from buffer cache to force reloading of original pages
-        while dirty != 0 {
-            debug_assert!((bm.pages[dirty as usize].state & PAGE_DIRTY) != 0);
-            debug_assert!(bm.pages[dirty as usize].access_count == 1);
-            let next = bm.pages[dirty as usize].next;
-            bm.throw_buffer(dirty);
-            dirty = next;
+    fn sync_batched_transactions(&self, db: &mut Database) -> Result<()> {
+        self.check_background_sync()?;
+        if let Some(log) = &self.log {
+            self.sync_wal(log.as_ref())?;
         }
-        bm.dirty_pages = 0;
-        bm.dirtied = 0;
-        bm.next_sync = 0;
-        db.wal_pos -= db.tx_size as u64;
-        db.tx_crc = 0;
-        db.tx_size = 0;
+        self.write_deferred(db)
+            .inspect_err(|_| self.set_state(StoreState::Corrupted))
+    }
 
-        if db.meta_updated {
-            // reread metadata from disk
-            let mut page = self.pool[0].write().unwrap();
-            self.file.read_exact_at(&mut page.data, 0)?;
-            db.meta = Metadata::unpack(&page.data);
-            db.meta_updated = false;
+    //
+    // Report failure of background sync of WAL: batched transactions committed before may be lost
+    //
+    fn check_background_sync(&self) -> Result<()> {
+        if let Some(err) = self.syncer.get().and_then(WalSyncer::take_error) {
+            return Err(err.into());
         }
         Ok(())
     }
 
-    ///
-    /// Open database store. If store file doesn't exist, then it is created.
-    /// If path to transaction log is not specified, then WAL (write-ahead-log) is not used.
-    /// It will significantly increase performance but can cause database corruption in case of power failure or system crash.
-    ///
-    pub fn open(db_path: &Path, log_path: Option<&Path>, conf: StoreConfig) -> Result<Store> {
-        let mut buf = [0u8; PAGE_SIZE];
-        let (file, meta) = if let Ok(file) = OpenOptions::new().write(true).read(true).open(db_path)
-        {
-            // open existed file
-            file.try_lock_exclusive()?;
-            file.read_exact_at(&mut buf, 0)?;
-            let meta = Metadata::unpack(&buf);
-            anyhow::ensure!(meta.size >= 1);
-            (file, meta)
+    //
+    // Write pages of batched transactions to the data file (WAL containing them should be already synced)
+    //
+    fn write_deferred(&self, db: &mut Database) -> Result<()> {
+        // readers wait until images are written, so they never see stale page in the data file
+        let mut deferred = self.deferred.lock().unwrap();
+        let images: Vec<(PageId, Arc<PageData>)> = deferred.drain().collect();
+        if let Some(writer) = &self.writer {
+            writer.write(images)?;
         } else {
-            let file = OpenOptions::new()
-                .write(true)
-                .read(true)
-                .create(true)
-                .open(db_path)?;
-            file.try_lock_exclusive()?;
-            // create new file
-            let meta = Metadata {
-                free: 0,
-                size: 1,
-                root: 0,
-                height: 0,
-            };
-            let metadata = meta.pack();
-            buf[0..METADATA_SIZE].copy_from_slice(&metadata);
-            file.write_all_at(&mut buf, 0)?;
-            (file, meta)
-        };
+            for (pid, image) in images {
+                self.file.write_all_at(&image.data, page_offset(pid))?;
+            }
+        }
+        db.batched_since = None;
+        if let Some(syncer) = self.syncer.get() {
+            syncer.cancel();
+        }
+        Ok(())
+    }
+
+    //
+    // Sync data file and restart from the beginning of WAL
+    //
+    fn checkpoint(&self, db: &mut Database) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.sync()?;
+        } else {
+            self.file.sync_all()?;
+        }
+        self.clear_wal_end(db)?;
+        db.wal_pos = 0;
+        // Do not truncate WAL unless requested to avoid file extension overhead.
+        if self.conf.shrink_wal_on_checkpoint {
+            if let Some(log) = &self.log {
+                self.reset_wal(log.as_ref())?;
+            }
+        }
+        #[cfg(feature = "metrics")]
+        Metrics::add(&self.metrics.checkpoints, 1);
+        Ok(())
+    }
+
+    //
+    // Commit current transaction and return its sequence number.
+    // If `checkpoint` is true, then checkpoint is performed after commit even if WAL size is below checkpoint interval.
+    //
+    pub(crate) fn commit(&self, db: &mut Database, durability: Durability, checkpoint: bool) -> Result<u64> {
+        self.check_background_sync()?;
+        self.sync_value_log(db)?;
+        let mut committed = self.committed.write().unwrap();
+        let mut bm = self.lock_buf_mgr().inspect_err(|_| self.set_state(StoreState::Corrupted))?;
+
+        // committed images are saved before pages of transaction are written to the data file
+        self.save_snapshot_pages(&bm)
+            .inspect_err(|_| self.set_state(StoreState::Corrupted))?;
+
+        // pages and metadata of prepared transaction are already saved in WAL
+        let changed = db.prepared || self.save_transaction(db, &mut bm)?;
+        if self.log.is_some() {
+            if changed {
+                let sync = durability == Durability::Immediate
+                    || checkpoint
+                    || db.wal_pos + WAL_META_RECORD_SIZE as u64 >= self.conf.checkpoint_interval
+                    || db
+                        .batched_since
+                        .is_some_and(|since| since.elapsed() >= self.conf.batched_sync_interval);
+                self.write_meta_record(db, 0, sync)?;
+                db.prepared = false;
+                db.tx_crc = 0;
+                db.tx_size = 0;
+
+                // Write pages to the data file. Transaction is already committed in WAL, so if it fails,
+                // then buffers can not be rolled back and store can be used only after recovery.
+                // Pages of transaction for which WAL is not synced are kept in memory: they can not be written
+                // to the data file before WAL, otherwise crash could leave partially applied transaction.
+                let written = if !sync {
+                    let deferred = self.collect_images(&mut bm, db.meta_updated);
+                    let since = *db.batched_since.get_or_insert_with(Instant::now);
+                    self.syncer
+                        .get_or_init(|| WalSyncer::start(self.log.clone().unwrap(), self.conf.preallocate_wal))
+                        .schedule(since + self.conf.batched_sync_interval);
+                    deferred.map(|images| self.deferred.lock().unwrap().extend(images))
+                } else if let Some(writer) = &self.writer {
+                    self.write_deferred(db)
+                        .and_then(|_| self.submit_buffers(&mut bm, db.meta_updated, writer))
+                } else {
+                    self.write_deferred(db)
+                        .and_then(|_| self.flush_buffers(&mut bm, db.meta_updated).map(|_| ()))
+                };
+                written.inspect_err(|_| self.set_state(StoreState::Corrupted))?;
+            } else if checkpoint && db.batched_since.is_some() {
+                self.sync_batched_transactions(db)?;
+            }
+            if db.wal_pos != 0 && (checkpoint || db.wal_pos >= self.conf.checkpoint_interval) {
+                self.checkpoint(db)?;
+            }
+        } else {
+            // No WAL mode: just write dirty pages to the disk (failure leaves data file partially updated)
+            self.flush_buffers(&mut bm, db.meta_updated)
+                .inspect_err(|_| self.set_state(StoreState::Corrupted))?;
+        }
+        db.meta_updated = false;
+        db.modified = false;
+        if changed {
+            self.value_cache.lock().unwrap().clear();
+        }
+        *committed = (db.meta.root, db.meta.height);
+        self.committed_size.store(db.meta.size, AtomicOrdering::Relaxed);
+        #[cfg(feature = "metrics")]
+        {
+            Metrics::add(&self.metrics.commits, 1);
+            self.metrics.height.store(db.meta.height, AtomicOrdering::Relaxed);
+        }
+        Ok(db.meta.commit_seq)
+    }
+
+    //
+    // Prepare current transaction for two-phase commit: write its dirty pages and metadata to WAL and sync it,
+    // so that transaction can be committed (or rolled back) after crash
+    //
+    pub(crate) fn prepare(&self, db: &mut Database) -> Result<()> {
+        anyhow::ensure!(self.log.is_some(), "Two-phase commit requires WAL");
+        self.sync_value_log(db)?;
+        let mut bm = self.lock_buf_mgr().inspect_err(|_| self.set_state(StoreState::Corrupted))?;
+        if self.save_transaction(db, &mut bm)? {
+            db.tx_crc = self.write_meta_record(db, WAL_PREPARE_MARK, true)?;
+            db.prepared = true;
+        }
+        Ok(())
+    }
+
+    //
+    // Rollback prepared transaction. Rollback is recorded in WAL, so that transaction is not restored by recovery.
+    //
+    pub(crate) fn rollback_prepared(&self, db: &mut Database) -> Result<()> {
+        if db.prepared {
+            self.write_meta_record(db, WAL_ABORT_MARK, true)?;
+            db.prepared = false;
+            db.tx_size = 0; // preserve records of transaction in WAL
+        }
+        self.rollback(db)
+    }
+
+    //
+    // Flush dirty pages to the disk. Return true if database is changed.
+    //
+    fn flush_buffers(&self, bm: &mut BufferManager, save_meta: bool) -> Result<bool> {
+        let mut dirty = bm.store(self.store_id).dirty_pages;
+        for (pid, wal_pos) in mem::take(&mut bm.store_mut(self.store_id).spilled) {
+            let mut page = PageData::new();
+            self.load_page(pid, Some(wal_pos), &mut page)?;
+            self.file
+                .write_all_at(&page.data, page_offset(pid))?;
+        }
+        while dirty != 0 {
+            let pid = bm.pages[dirty as usize].pid;
+            let file_offs = page_offset(pid);
+            let page = self.pool_page(dirty).read().unwrap();
+            let next = bm.pages[dirty as usize].next;
+            self.file.write_all_at(&page.data, file_offs)?;
+            debug_assert!((bm.pages[dirty as usize].state & PAGE_DIRTY) != 0);
+            bm.pages[dirty as usize].state = 0;
+            bm.unpin(dirty);
+            dirty = next;
+        }
+        // Metadata is written after all other pages: in no-WAL mode crash in the middle of flush
+        // should not leave metadata referencing pages which were not written
+        if save_meta {
+            if self.log.is_none() {
+                // written pages should be durable before metadata referencing them: otherwise they can be
+                // reordered with metadata write and lost on crash
+                self.file.sync_data()?;
+            }
+            let mut page = self.meta_page().write().unwrap();
+            let crc = Metadata::checksum(&page.data);
+            page.set_u32(METADATA_SIZE, crc);
+            if self.log.is_none() && self.conf.meta_double_write {
+                // Make shadow copy of metadata durable before overwriting the primary one
+                page.data
+                    .copy_within(0..METADATA_SIZE + 4, META_SHADOW_OFFS);
+                self.file.write_all_at(
+                    &page.data[META_SHADOW_OFFS..META_SHADOW_OFFS + METADATA_SIZE + 4],
+                    META_SHADOW_OFFS as u64,
+                )?;
+                self.file.sync_data()?;
+            }
+            self.file.write_all_at(&page.data, 0)?;
+        }
+        if bm.store(self.store_id).dirty_pages != 0 {
+            bm.clear_dirty(self.store_id);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    //
+    // Pass images of dirty pages to the background writer and release their buffers
+    //
+    fn submit_buffers(
+        &self,
+        bm: &mut BufferManager,
+        save_meta: bool,
+        writer: &BackgroundWriter,
+    ) -> Result<()> {
+        let images = self.collect_images(bm, save_meta)?;
+        // buffer manager is still locked, so released pages can not be evicted and reloaded before images are registered
+        writer.write(images)
+    }
+
+    //
+    // Copy images of dirty pages (and metadata if it was updated) and release their buffers
+    //
+    fn collect_images(&self, bm: &mut BufferManager, save_meta: bool) -> Result<Vec<(PageId, Arc<PageData>)>> {
+        let mut images = Vec::new();
+        if save_meta {
+            let mut page = self.meta_page().write().unwrap();
+            let crc = Metadata::checksum(&page.data);
+            page.set_u32(METADATA_SIZE, crc);
+            images.push((META_PID, Arc::new(page.clone())));
+        }
+        for (pid, wal_pos) in mem::take(&mut bm.store_mut(self.store_id).spilled) {
+            let mut page = PageData::new();
+            self.load_page(pid, Some(wal_pos), &mut page)?;
+            images.push((pid, Arc::new(page)));
+        }
+        let mut dirty = bm.store(self.store_id).dirty_pages;
+        while dirty != 0 {
+            let page = self.pool_page(dirty).read().unwrap();
+            images.push((bm.pages[dirty as usize].pid, Arc::new(page.clone())));
+            let next = bm.pages[dirty as usize].next;
+            debug_assert!((bm.pages[dirty as usize].state & PAGE_DIRTY) != 0);
+            bm.pages[dirty as usize].state = 0;
+            bm.unpin(dirty);
+            dirty = next;
+        }
+        bm.clear_dirty(self.store_id);
+        Ok(images)
+    }
+
+    //
+    // Lock file, reporting StoreError::Locked if it is already locked
+    //
+    fn lock(file: &File, path: &Path) -> Result<()> {
+        match file.try_lock_exclusive() {
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                anyhow::bail!(StoreError::Locked {
+                    path: path.to_path_buf()
+                })
+            }
+            res => Ok(res?),
+        }
+    }
+
+    //
+    // Lock file, tolerating lock already held through the same or other descriptor
+    //
+    fn try_lock(file: &File) -> Result<()> {
+        match file.try_lock_exclusive() {
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    //
+    // Read page from the data file (or take its committed image not yet written by background writer)
+    //
+    fn read_page(&self, pid: PageId, page: &mut PageData) -> Result<()> {
+        if let Some(image) = self.deferred.lock().unwrap().get(&pid) {
+            page.data.copy_from_slice(&image.data);
+            return Ok(());
+        }
+        if let Some(writer) = &self.writer {
+            if writer.read(pid, page) {
+                return Ok(());
+            }
+        }
+        self.file
+            .read_exact_at(&mut page.data, page_offset(pid))?;
+        Ok(())
+    }
+
+    //
+    // Load page image: from WAL if page was spilled by current transaction, otherwise from the data file
+    //
+    fn load_page(&self, pid: PageId, spilled: Option<u64>, page: &mut PageData) -> Result<()> {
+        match (spilled, &self.log) {
+            (Some(wal_pos), Some(log)) => Ok(log.read_exact_at(&mut page.data, wal_pos)?),
+            _ => self.read_page(pid, page),
+        }
+    }
+
+    //
+    // Rollback current transaction
+    //
+    pub(crate) fn rollback(&self, db: &mut Database) -> Result<()> {
+        let _committed = self.committed.write().unwrap();
+        self.discard_transaction(db)
+    }
+
+    //
+    // Throw away changes of current transaction (caller should hold write lock on `committed`)
+    //
+    fn discard_transaction(&self, db: &mut Database) -> Result<()> {
+        let mut bm = self.lock_buf_mgr().inspect_err(|_| self.set_state(StoreState::Corrupted))?;
+        let mut dirty = bm.store(self.store_id).dirty_pages;
+        // Just throw away all dirty pages from buffer cache to force reloading of original pages.
+        // Pages allocated by this transaction (including new root created by split) are always dirty or spilled,
+        // so they are thrown away too, and rereading metadata restores root, height, size and free list of the store.
+        while dirty != 0 {
+            debug_assert!((bm.pages[dirty as usize].state & PAGE_DIRTY) != 0);
+            debug_assert!(bm.pages[dirty as usize].access_count == 1);
+            let next = bm.pages[dirty as usize].next;
+            bm.throw_buffer(dirty);
+            dirty = next;
+        }
+        for pid in mem::take(&mut bm.store_mut(self.store_id).spilled).into_keys() {
+            bm.discard_page(self.store_id, pid);
+        }
+        bm.clear_dirty(self.store_id);
+        db.wal_pos -= db.tx_size as u64;
+        db.tx_crc = 0;
+        db.tx_size = 0;
+        db.modified = false;
+
+        if db.meta_updated {
+            // reread metadata from disk
+            let mut page = self.meta_page().write().unwrap();
+            self.read_page(META_PID, &mut page)?;
+            db.meta = Metadata::unpack(&page.data);
+            db.meta_updated = false;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Open database store. If store file doesn't exist, then it is created.
+    /// If path to transaction log is not specified, then WAL (write-ahead-log) is not used.
+    /// It will significantly increase performance but can cause database corruption in case of power failure or system crash.
+    ///
+    pub fn open(db_path: &Path, log_path: Option<&Path>, conf: StoreConfig) -> Result<Store> {
+        Ok(Self::open_with_report(db_path, log_path, conf)?.0)
+    }
+
+    ///
+    /// Open database store like `Store::open` and return also summary of recovery performed by open:
+    /// number of transactions replayed from WAL and discarded part of WAL.
+    ///
+    pub fn open_with_report(
+        db_path: &Path,
+        log_path: Option<&Path>,
+        conf: StoreConfig,
+    ) -> Result<(Store, RecoveryReport)> {
+        Self::open_path(db_path, log_path, conf, None)
+    }
+
+    ///
+    /// Open database store like `Store::open`, but cache its pages in buffer pool shared with other stores
+    /// instead of allocating its own pool: `StoreConfig::cache_size` and `StoreConfig::busy_events` are ignored.
+    /// Store can be opened with shared pool only by this method or by `Store::open_with_pool_and_report`.
+    /// Statistics of cached and pinned pages returned by `Store::stats` refer to the whole pool.
+    ///
+    pub fn open_with_pool(
+        db_path: &Path,
+        log_path: Option<&Path>,
+        conf: StoreConfig,
+        pool: Arc<SharedBufferPool>,
+    ) -> Result<Store> {
+        Ok(Self::open_with_pool_and_report(db_path, log_path, conf, pool)?.0)
+    }
+
+    ///
+    /// Open database store with shared buffer pool like `Store::open_with_pool` and return also summary
+    /// of recovery performed by open (like `Store::open_with_report`).
+    ///
+    pub fn open_with_pool_and_report(
+        db_path: &Path,
+        log_path: Option<&Path>,
+        conf: StoreConfig,
+        pool: Arc<SharedBufferPool>,
+    ) -> Result<(Store, RecoveryReport)> {
+        Self::open_path(db_path, log_path, conf, Some(pool))
+    }
+
+    //
+    // Open store files at the specified paths and open store using either shared or private buffer pool
+    //
+    fn open_path(
+        db_path: &Path,
+        log_path: Option<&Path>,
+        conf: StoreConfig,
+        pool: Option<Arc<SharedBufferPool>>,
+    ) -> Result<(Store, RecoveryReport)> {
+        let file = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(false)
+            .open(db_path)?;
+        let open_path = OpenPath::register(db_path)?;
+        if conf.file_locking {
+            Self::lock(&file, db_path)?;
+        }
         let log = if let Some(path) = log_path {
             let log = OpenOptions::new()
                 .write(true)
                 .read(true)
                 .create(true)
+                .truncate(false)
                 .open(path)?;
-            log.try_lock_exclusive()?;
-            Some(log)
+            if conf.file_locking {
+                Self::lock(&log, path)?;
+            }
+            Some(Arc::new(ReplaceableStorage::new(Box::new(log))))
         } else {
             None
         };
-        let store = Store {
-            busy_events: [(); N_BUSY_EVENTS].map(|_| Condvar::new()),
-            buf_mgr: Mutex::new(BufferManager {
-                head: 0,
-                tail: 0,
-                free_pages: 0,
-                dirty_pages: 0,
-                next_sync: 0,
-                used: 1, // pinned root page
-                cached: 1,
-                pinned: 1,
-                dirtied: 0,
-                hash_table: vec![0; conf.cache_size],
-                pages: vec![Buffer::new(); conf.cache_size],
-            }),
-            pool: iter::repeat_with(|| RwLock::new(PageData::new()))
-                .take(conf.cache_size)
-                .collect(),
+        let replaceable = ReplaceableFiles {
+            db_path: db_path.to_path_buf(),
+            log_path: log_path.map(Path::to_path_buf),
+            file: Arc::new(ReplaceableStorage::new(Self::data_storage(file, &conf)?)),
+            log: log.clone(),
+        };
+        let vlog = if conf.value_log {
+            let path = db_path.with_extension("vlog");
+            let vlog = OpenOptions::new()
+                .write(true)
+                .read(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            if conf.file_locking {
+                Self::lock(&vlog, &path)?;
+            }
+            Some(Box::new(vlog) as Box<dyn Storage>)
+        } else {
+            None
+        };
+        Self::open_storage(
+            Box::new(replaceable.file.clone()),
+            log.map(|log| Box::new(log) as Box<dyn Storage>),
+            vlog,
+            conf,
+            pool,
+            Some(open_path),
+            Some(replaceable),
+        )
+    }
+
+    //
+    // Storage of data file opened by path: direct I/O is enabled for it if requested by configuration
+    //
+    fn data_storage(file: File, conf: &StoreConfig) -> Result<Box<dyn Storage>> {
+        if !conf.direct_io {
+            return Ok(Box::new(file));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            Ok(Box::new(DirectStorage::new(file)?))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            anyhow::bail!("Direct I/O is supported only on Linux")
+        }
+    }
+
+    ///
+    /// Open database store using already opened files (for example received from sandbox supervisor).
+    /// Files should be opened for read and write. Store tries to lock them, but if files are already locked
+    /// (presumably by the caller), then it is not considered as error. Locking is skipped if `file_locking` is disabled.
+    ///
+    pub fn open_with_files(file: File, log: Option<File>, conf: StoreConfig) -> Result<Store> {
+        if conf.file_locking {
+            Self::try_lock(&file)?;
+        }
+        let log = if let Some(log) = log {
+            if conf.file_locking {
+                Self::try_lock(&log)?;
+            }
+            Some(Box::new(log) as Box<dyn Storage>)
+        } else {
+            None
+        };
+        Self::open_with_storage(Box::new(file), log, conf)
+    }
+
+    ///
+    /// Open database store on top of custom storage. If data storage is empty, then new store is created in it.
+    /// If WAL storage is not specified, then WAL (write-ahead-log) is not used.
+    /// Caller is responsible for preventing concurrent access to the storage.
+    /// Value log and direct I/O modes are not supported: use `Store::open` for them.
+    ///
+    pub fn open_with_storage(
+        file: Box<dyn Storage>,
+        log: Option<Box<dyn Storage>>,
+        conf: StoreConfig,
+    ) -> Result<Store> {
+        anyhow::ensure!(!conf.value_log, "Value log requires Store::open");
+        anyhow::ensure!(!conf.direct_io, "Direct I/O requires Store::open");
+        Ok(Self::open_storage(file, log, None, conf, None, None, None)?.0)
+    }
+
+    //
+    // Open store on top of the specified data, WAL and value log storages
+    //
+    fn open_storage(
+        file: Box<dyn Storage>,
+        log: Option<Box<dyn Storage>>,
+        vlog: Option<Box<dyn Storage>>,
+        conf: StoreConfig,
+        pool: Option<Arc<SharedBufferPool>>,
+        open_path: Option<OpenPath>,
+        replaceable: Option<ReplaceableFiles>,
+    ) -> Result<(Store, RecoveryReport)> {
+        anyhow::ensure!(conf.busy_events > 0, "At least one busy event is required");
+        anyhow::ensure!(conf.dirty_pages_limit != Some(0), "Dirty pages limit should be positive");
+        let value_len_limit = if vlog.is_some() { MAX_LOGGED_VALUE_LEN } else { MAX_VALUE_LEN };
+        anyhow::ensure!(
+            conf.max_value_len.is_none_or(|len| len <= value_len_limit),
+            "Maximal value length can not exceed {}",
+            value_len_limit
+        );
+        anyhow::ensure!(
+            (0.0..1.0).contains(&conf.merge_threshold),
+            "Merge threshold should be in range [0, 1)"
+        );
+        anyhow::ensure!(
+            conf.item_size_hint.is_none_or(|hint| hint.key_len <= MAX_KEY_LEN && hint.value_len <= MAX_VALUE_LEN),
+            "Item size hint exceeds maximal length of key or value"
+        );
+        let vlog_flag = if vlog.is_some() { META_FLAG_VALUE_LOG } else { 0 };
+        let dict_flag = if conf.key_dictionary.is_some() { META_FLAG_KEY_DICTIONARY } else { 0 };
+        let tombstones_flag = if conf.tombstones { META_FLAG_TOMBSTONES } else { 0 };
+        let compression_flag = if conf.page_compression { META_FLAG_PAGE_COMPRESSION } else { 0 };
+        let file = RetryStorage::wrap(file, conf.io_retries);
+        let log: Option<Arc<dyn Storage>> = log.map(|log| Arc::from(RetryStorage::wrap(log, conf.io_retries)));
+        let vlog = vlog.map(|vlog| RetryStorage::wrap(vlog, conf.io_retries));
+        let mut buf = [0u8; PAGE_SIZE];
+        // layout of the data file depends on compression mode chosen when store was created
+        let compressed = if file.size()? >= PAGE_SIZE as u64 {
+            file.read_exact_at(&mut buf, 0)?;
+            // primary copy of metadata may be torn: then it is restored from the shadow copy below
+            let page = if !Metadata::is_intact(&buf) && Metadata::is_intact(&buf[META_SHADOW_OFFS..]) {
+                &buf[META_SHADOW_OFFS..]
+            } else {
+                &buf[..]
+            };
+            Metadata::has_magic(page) && Metadata::unpack(page).flags & META_FLAG_PAGE_COMPRESSION != 0
+        } else {
+            conf.page_compression
+        };
+        let (file, compression): (Arc<dyn Storage>, _) = if compressed {
+            let compression = Arc::new(CompressedStorage::open(file)?);
+            (compression.clone(), Some(compression))
+        } else {
+            (Arc::from(file), None)
+        };
+        let file_size = file.size()?;
+        let meta = if file_size != 0 {
+            // open existed file
+            if file_size < PAGE_SIZE as u64 {
+                anyhow::bail!(StoreError::NotAStore);
+            }
+            file.read_exact_at(&mut buf, 0)?;
+            if conf.meta_double_write
+                && !Metadata::is_intact(&buf)
+                && Metadata::is_intact(&buf[META_SHADOW_OFFS..])
+            {
+                // Write of metadata was torn: restore it from the shadow copy
+                buf.copy_within(META_SHADOW_OFFS..META_SHADOW_OFFS + METADATA_SIZE + 4, 0);
+                file.write_all_at(&buf, 0)?;
+                file.sync_data()?;
+            }
+            Metadata::check_magic(&buf)?;
+            let meta = Metadata::unpack(&buf);
+            let version = meta.format_version();
+            if version != FORMAT_VERSION {
+                anyhow::bail!(StoreError::UnsupportedFormat { version });
+            }
+            anyhow::ensure!(meta.size >= 1);
+            anyhow::ensure!(
+                meta.flags & META_FLAG_VALUE_LOG == vlog_flag,
+                "Store was created with different value log mode"
+            );
+            anyhow::ensure!(
+                meta.flags & META_FLAG_KEY_DICTIONARY == dict_flag,
+                "Store was created with different key dictionary mode"
+            );
+            anyhow::ensure!(
+                meta.flags & META_FLAG_TOMBSTONES == tombstones_flag,
+                "Store was created with different tombstone mode"
+            );
+            anyhow::ensure!(
+                meta.flags & META_FLAG_PAGE_COMPRESSION == compression_flag,
+                "Store was created with different page compression mode"
+            );
+            if let (Some(log), Some(wal_end)) = (&log, meta.get_ext(META_EXT_WAL_END)) {
+                let wal_end = wal_end.try_into().map_or(0, u64::from_be_bytes);
+                if !conf.allow_missing_wal && !Self::has_wal_end_record(log.as_ref(), &meta, wal_end)? {
+                    let expected = wal_end + WAL_META_RECORD_SIZE as u64;
+                    anyhow::bail!(StoreError::WalMissing { expected });
+                }
+            }
+            if let Some(dict) = &conf.key_dictionary {
+                let mut page = PageData::new();
+                file.read_exact_at(&mut page.data, page_offset(DICTIONARY_PID))?;
+                anyhow::ensure!(
+                    page.get_page_type() == Some(PageType::Dictionary),
+                    StoreError::Corrupted("key dictionary page is damaged".to_string())
+                );
+                anyhow::ensure!(
+                    KeyDictionary::unpack(&page.data[PAGE_HEADER_SIZE..])? == *dict,
+                    "Store was created with different key dictionary"
+                );
+            }
+            meta
+        } else {
+            // create new file
+            let mut meta = Metadata {
+                free: 0,
+                size: 1,
+                root: 0,
+                height: 0,
+                commit_seq: 0,
+                flags: vlog_flag | dict_flag | tombstones_flag | compression_flag,
+                ext: [0u8; METADATA_EXT_SIZE],
+            };
+            meta.set_ext(META_EXT_FORMAT_VERSION, &FORMAT_VERSION.to_be_bytes())?;
+            // identifier is propagated to WAL records with metadata, so that WAL of another store is not replayed
+            meta.set_ext(META_EXT_STORE_UUID, &generate_store_uuid())?;
+            if let Some(dict) = &conf.key_dictionary {
+                // dictionary page is written before metadata referring to it
+                let mut page = PageData::new();
+                page.init(PageType::Dictionary);
+                dict.pack(&mut page.data[PAGE_HEADER_SIZE..])?;
+                file.write_all_at(&page.data, page_offset(DICTIONARY_PID))?;
+                meta.size = DICTIONARY_PID + 1;
+            }
+            let metadata = meta.pack();
+            buf[0..METADATA_SIZE].copy_from_slice(&metadata);
+            buf[METADATA_SIZE..METADATA_SIZE + 4]
+                .copy_from_slice(&Metadata::checksum(&metadata).to_be_bytes());
+            file.write_all_at(&buf, 0)?;
+            meta
+        };
+        let buffers = pool.unwrap_or_else(|| Arc::new(SharedBufferPool::private(conf.cache_size, conf.busy_events)));
+        let (store_id, meta_buf) = Self::lock_pool(&buffers)?.register()?;
+        let mut store = Store {
+            committed: RwLock::new((meta.root, meta.height)),
+            committed_size: AtomicPageId::new(meta.size),
+            snapshots: Mutex::new(Vec::new()),
+            generation: AtomicU64::new(0),
+            state: AtomicU8::new(StoreState::InRecovery as u8),
+            value_cache: Mutex::new(HashMap::new()),
+            buffers,
+            store_id,
+            meta_buf,
             file,
+            compression,
             log,
+            writer: None,
+            syncer: OnceLock::new(),
+            deferred: Mutex::new(HashMap::new()),
+            replaceable,
+            _open_path: open_path,
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::default(),
             conf,
             db: RwLock::new(Database {
                 meta,
                 meta_updated: false,
-                state: DatabaseState::InRecovery,
+                modified: false,
                 wal_pos: 0,
                 tx_crc: 0,
                 tx_size: 0,
+                vlog_pos: match &vlog {
+                    Some(vlog) => vlog.size()?,
+                    None => 0,
+                },
+                vlog_dirty: false,
+                prepared: false,
+                in_doubt: None,
+                batched_since: None,
             }),
+            vlog,
         };
-        store.recovery()?;
-        Ok(store)
+        let report = store.recovery()?;
+        store.check_cache_size(report.height)?;
+        if store.conf.background_flush && store.log.is_some() {
+            store.writer = Some(BackgroundWriter::start(store.file.clone()));
+        }
+        Ok((store, report))
+    }
+
+    //
+    // Check that WAL contains metadata record of the last transaction written to the data file: it is located at
+    // WAL end position saved in metadata. Size of WAL is not enough, since preallocated WAL is never truncated.
+    //
+    fn has_wal_end_record(log: &dyn Storage, meta: &Metadata, wal_end: u64) -> Result<bool> {
+        if log.size()? < wal_end + WAL_META_RECORD_SIZE as u64 {
+            return Ok(false);
+        }
+        let mut buf = [0u8; WAL_META_RECORD_SIZE];
+        log.read_exact_at(&mut buf, wal_end)?;
+        let mark = PageId::from_be_bytes(buf[0..PID_SIZE].try_into().unwrap());
+        let record = &buf[PID_SIZE..PID_SIZE + METADATA_SIZE];
+        if (mark != 0 && mark != WAL_PREPARE_MARK) || !Metadata::has_magic(record) {
+            return Ok(false);
+        }
+        let logged = Metadata::unpack(record);
+        Ok(logged.commit_seq == meta.commit_seq
+            && logged.get_ext(META_EXT_STORE_UUID) == meta.get_ext(META_EXT_STORE_UUID))
     }
 
     //
     // Recover database from WAL (if any)
     //
-    fn recovery(&self) -> Result<()> {
+    fn recovery(&self) -> Result<RecoveryReport> {
         let mut db = self.db.write().unwrap();
+        let mut committed = self.committed.write().unwrap();
+        self.recover(&mut db, &mut committed)
+    }
+
+    //
+    // Recover database from WAL (caller should hold write lock on `db`)
+    //
+    fn recover(&self, db: &mut Database, committed: &mut (PageId, u32)) -> Result<RecoveryReport> {
+        let mut report = RecoveryReport::default();
         if let Some(log) = &self.log {
-            let mut buf = [0u8; 4];
+            let mut buf = [0u8; PID_SIZE];
             let mut crc = 0u32;
             let mut wal_pos = 0u64;
+            let mut commit_seq = 0u64;
+            let mut positions: HashMap<PageId, u64> = HashMap::new(); // WAL positions of pages of current transaction
+            let mut prepared: Option<InDoubt> = None; // prepared transaction not followed by commit or abort record
+            let limit = self.dirty_pages_limit(db.meta.height);
             loop {
                 let len = log.read_at(&mut buf, wal_pos)?;
-                if len != 4 {
+                if len != PID_SIZE {
                     // end of log
                     break;
                 }
-                wal_pos += 4;
+                wal_pos += PID_SIZE as u64;
                 let pid = PageId::from_be_bytes(buf);
                 crc = crc32c_append(crc, &buf);
-                if pid != 0 {
-                    let pin = self.get_page(pid, AccessMode::WriteOnly)?;
-                    let mut page = self.pool[pin.buf as usize].write().unwrap();
-                    let len = log.read_at(&mut page.data, wal_pos)?;
-                    if len != PAGE_SIZE {
+                if pid != 0 && pid != WAL_PREPARE_MARK && pid != WAL_ABORT_MARK {
+                    if prepared.is_some() {
+                        // prepared transaction can not be changed: these are remains of WAL written before restart
                         break;
                     }
-                    wal_pos += len as u64;
-                    crc = crc32c_append(crc, &page.data);
+                    {
+                        let pin = self.get_page(pid, AccessMode::WriteOnly)?;
+                        let mut page = self.pool_page(pin.buf).write().unwrap();
+                        let len = log.read_at(&mut page.data, wal_pos)?;
+                        if len != PAGE_SIZE {
+                            break;
+                        }
+                        positions.insert(pid, wal_pos);
+                        wal_pos += len as u64;
+                        crc = crc32c_append(crc, &page.data);
+                    }
+                    // Transaction may be larger than cache: spill pages which images are already in WAL
+                    let mut bm = self.lock_buf_mgr()?;
+                    while bm.store(self.store_id).dirtied > limit {
+                        let buf = bm.spill_candidate(self.store_id);
+                        if buf == 0 {
+                            break;
+                        }
+                        let pos = positions[&bm.pages[buf as usize].pid];
+                        bm.spill_buffer(buf, pos);
+                    }
                 } else {
                     let mut meta_buf = [0u8; METADATA_SIZE];
                     let len = log.read_at(&mut meta_buf, wal_pos)?;
                     if len != METADATA_SIZE {
                         break;
                     }
+                    if !Metadata::has_magic(&meta_buf) {
+                        // unused (preallocated) part of WAL
+                        break;
+                    }
                     wal_pos += len as u64;
                     crc = crc32c_append(crc, &meta_buf);
-                    let len = log.read_at(&mut buf, wal_pos)?;
+                    let mut crc_buf = [0u8; 4];
+                    let len = log.read_at(&mut crc_buf, wal_pos)?;
                     if len != 4 {
                         break;
                     }
                     wal_pos += 4;
-                    if u32::from_be_bytes(buf) != crc {
+                    if u32::from_be_bytes(crc_buf) != crc {
                         // CRC mismatch
+                        report.crc_mismatch = true;
                         break;
                     }
-                    {
-                        let mut page = self.pool[0].write().unwrap();
-                        page.data[0..METADATA_SIZE].copy_from_slice(&meta_buf);
-                        db.meta_updated = true;
+                    if Metadata::unpack(&meta_buf).get_ext(META_EXT_STORE_UUID) != db.meta.get_ext(META_EXT_STORE_UUID) {
+                        // throw away loaded pages and prevent close from truncating WAL of another store
+                        self.discard_transaction(db)?;
+                        self.set_state(StoreState::Corrupted);
+                        anyhow::bail!(StoreError::WalMismatch);
+                    }
+                    if pid == WAL_ABORT_MARK {
+                        if prepared.take().is_none() {
+                            break;
+                        }
+                        // throw away pages of rolled back transaction
+                        self.discard_transaction(db)?;
+                    } else {
+                        // WAL is not truncated by checkpoint, so complete transactions written before it
+                        // may follow the last committed transaction: they are recognized by sequence number
+                        let seq = Metadata::unpack(&meta_buf).commit_seq;
+                        if report.transactions != 0 && seq != commit_seq + 1 {
+                            break;
+                        }
+                        if pid == WAL_PREPARE_MARK {
+                            if prepared.is_some() {
+                                break;
+                            }
+                            // transaction is committed or rolled back by the following record (if any)
+                            prepared = Some(InDoubt {
+                                meta: Metadata::unpack(&meta_buf),
+                                pages: positions.clone(),
+                                crc,
+                            });
+                            report.replayed_bytes = wal_pos;
+                            continue;
+                        }
+                        prepared = None;
+                        commit_seq = seq;
+                        {
+                            let mut page = self.meta_page().write().unwrap();
+                            page.data[0..METADATA_SIZE].copy_from_slice(&meta_buf);
+                            db.meta_updated = true;
+                        }
+                        let mut bm = self.lock_buf_mgr()?;
+                        self.flush_buffers(&mut bm, true)?;
+                        db.meta_updated = false;
+                        report.transactions += 1;
                     }
-                    let mut bm = self.buf_mgr.lock().unwrap();
-                    self.flush_buffers(&mut bm, true)?;
-                    db.meta_updated = false;
                     crc = 0u32;
+                    positions.clear();
+                    report.replayed_bytes = wal_pos;
                 }
             }
-            self.rollback(&mut db)?;
+            report.discarded_bytes = log.size()? - report.replayed_bytes;
+            // throw away pages of incomplete or prepared transaction
+            self.discard_transaction(db)?;
 
-            // reset WAL
             self.file.sync_all()?;
-            db.wal_pos = 0;
-            log.set_len(0)?; // truncate log
+            if let Some(in_doubt) = prepared {
+                // WAL is preserved until prepared transaction is resolved
+                db.wal_pos = report.replayed_bytes;
+                db.in_doubt = Some(in_doubt);
+                report.prepared = true;
+            } else {
+                // reset WAL
+                self.clear_wal_end(db)?;
+                db.wal_pos = 0;
+                self.reset_wal(log.as_ref())?;
+            }
         }
         // reread metadata
-        let mut page = self.pool[0].write().unwrap();
+        let mut page = self.meta_page().write().unwrap();
         self.file.read_exact_at(&mut page.data, 0)?;
         db.meta = Metadata::unpack(&page.data);
 
-        db.state = DatabaseState::Opened;
+        // B-Tree is empty if and only if its height is zero, otherwise root should be inside the store
+        if (db.meta.root == 0) != (db.meta.height == 0) || db.meta.root >= db.meta.size {
+            self.set_state(StoreState::Corrupted);
+            anyhow::bail!(StoreError::Corrupted(format!(
+                "B-Tree root {} with height {} is inconsistent with store size {}",
+                db.meta.root, db.meta.height, db.meta.size
+            )));
+        }
+        if self.conf.verify_on_open && db.meta.root != 0 {
+            // cache should be able to hold B-Tree path to traverse it
+            self.check_cache_size(db.meta.height)?;
+            let mut prev_key = Vec::new();
+            if let Err(err) = self.traverse(db.meta.root, &mut prev_key, db.meta.height, None) {
+                self.set_state(StoreState::Corrupted);
+                anyhow::bail!(StoreError::Corrupted(err.to_string()));
+            }
+        }
+        *committed = (db.meta.root, db.meta.height);
+        self.committed_size.store(db.meta.size, AtomicOrdering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.height.store(db.meta.height, AtomicOrdering::Relaxed);
+        self.set_state(StoreState::Opened);
 
-        Ok(())
+        report.commit_seq = db.meta.commit_seq;
+        report.size = db.meta.size;
+        report.height = db.meta.height;
+        Ok(report)
     }
 
     //
@@ -487,13 +1947,13 @@ impl Store {
     fn btree_allocate_leaf_page(
         &self,
         db: &mut Database,
-        key: &Key,
-        value: &Value,
+        key: &[u8],
+        value: &[u8],
     ) -> Result<PageId> {
         let pin = self.new_page(db)?;
-        let mut page = self.pool[pin.buf as usize].write().unwrap();
-        page.set_n_items(0);
-        page.insert_item(0, key, value);
+        let mut page = self.pool_page(pin.buf).write().unwrap();
+        page.init(PageType::Leaf);
+        page.insert_item(0, key, value)?;
         Ok(pin.pid)
     }
 
@@ -503,23 +1963,27 @@ impl Store {
     fn btree_allocate_internal_page(
         &self,
         db: &mut Database,
-        key: &Key,
+        key: &[u8],
         left_child: PageId,
         right_child: PageId,
     ) -> Result<PageId> {
         let pin = self.new_page(db)?;
-        let mut page = self.pool[pin.buf as usize].write().unwrap();
-        page.set_n_items(0);
+        let mut page = self.pool_page(pin.buf).write().unwrap();
+        page.init(PageType::Internal);
         debug_assert!(left_child != 0);
         debug_assert!(right_child != 0);
-        page.insert_item(0, key, &left_child.to_be_bytes().to_vec());
-        page.insert_item(1, &vec![], &right_child.to_be_bytes().to_vec());
+        page.insert_item(0, key, &left_child.to_be_bytes())?;
+        page.insert_inf_item(1, right_child)?;
         Ok(pin.pid)
     }
 
     //
     // Insert item at the specified position in B-Tree page.
-    // If B-Tree pages is full then split it, evenly distribute items between pages: smaller items moved to new page, larger items left on original page.
+    // If B-Tree page is full then split it: smaller items are moved to new page, larger items left on original page.
+    // Split point is chosen by `PageData::split`: by default at the middle of page data (but at least the last item is
+    // left on original page), at insert position keeping `split_min_part_size` margin in both parts if item size hint
+    // is specified, and all items are moved to new page when appending. Split point is then shifted if needed, so that
+    // the part receiving new item has space for it.
     // Value of largest key on new page and its identifiers are returned in case of overflow.
     //
     fn btree_insert_in_page(
@@ -527,268 +1991,2662 @@ impl Store {
         db: &mut Database,
         page: &mut PageData,
         ip: ItemPointer,
-        key: &Key,
-        value: &Value,
+        key: &[u8],
+        value: &[u8],
     ) -> Result<Option<(Key, PageId)>> {
-        if !page.insert_item(ip, key, value) {
+        let leaf = cfg!(debug_assertions) && page.get_page_type() == Some(PageType::Leaf);
+        if !page.insert_item(ip, key, value)? {
             // page is full then divide page
             let pin = self.new_page(db)?;
-            let mut new_page = self.pool[pin.buf as usize].write().unwrap();
-            let split = page.split(&mut new_page, ip);
+            let mut new_page = self.pool_page(pin.buf).write().unwrap();
+            // part receiving new item should free space for it
+            let min_part_size = self.split_min_part_size().map(|size| size.max(3 + key.len() + value.len()));
+            let split = page.split(&mut new_page, ip, 1 + key.len() + value.len(), min_part_size);
             let ok = if ip > split {
-                page.insert_item(ip - split - 1, key, value)
+                page.insert_item(ip - split - 1, key, value)?
             } else {
-                new_page.insert_item(ip, key, value)
+                new_page.insert_item(ip, key, value)?
             };
             anyhow::ensure!(ok);
+            if leaf {
+                page.assert_sorted();
+                new_page.assert_sorted();
+                debug_assert!(new_page.get_last_key() < page.get_key(0));
+            }
             Ok(Some((new_page.get_last_key(), pin.pid)))
+        } else {
+            if leaf {
+                page.assert_sorted();
+            }
+            Ok(None)
+        }
+    }
+
+    //
+    // Minimal size of items left on each part of split page computed from `StoreConfig::item_size_hint`:
+    // a quarter of items of the hinted size fitting in the page (at least one item)
+    //
+    fn split_min_part_size(&self) -> Option<usize> {
+        self.conf.item_size_hint.map(|hint| {
+            let item_size = 1 + hint.key_len + hint.value_len;
+            let capacity = (PAGE_SIZE - PAGE_HEADER_SIZE) / (item_size + 2);
+            (capacity / 4).max(1) * item_size
+        })
+    }
+
+    //
+    // Remove key from B-Tree. Recursively traverse B-Tree and return underflow of the page (if any).
+    // Empty page is moved to free list and removed from its parent, sparse page is merged with its neighbour
+    // by parent (if they fit in one page). Items are not redistributed between pages.
+    // If key is not found, then nothing is performed and no error is reported.
+    //
+    fn btree_remove(
+        &self,
+        db: &mut Database,
+        pid: PageId,
+        key: &[u8],
+        height: u32,
+        removed: &mut Option<Value>,
+    ) -> Result<Option<Underflow>> {
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let mut page = self.pool_page(pin.buf).write().unwrap();
+        let n = page.get_n_items();
+        let r = page.locate_key(key);
+        if height == 1 {
+            // leaf page
+            if r < n && page.compare_key(r, key) == Ordering::Equal {
+                self.modify_page(db, pin.buf)?;
+                *removed = Some(page.get_value(r).to_vec());
+                page.remove_key(r, true);
+                if cfg!(debug_assertions) {
+                    page.assert_sorted();
+                }
+            }
+        } else {
+            // recurse to next level
+            debug_assert!(r < n);
+            match self.btree_remove(db, page.get_child(r), key, height - 1, removed)? {
+                Some(Underflow::Empty) => {
+                    self.modify_page(db, pin.buf)?;
+                    page.remove_key(r, false);
+                }
+                Some(Underflow::Sparse) if n > 1 => {
+                    // merge sparse page with its right neighbour or, if they do not fit in one page, with the left one
+                    // (so that pages left sparse by removes in key order are merged too)
+                    let merged = r + 1 < n && self.btree_merge(db, &mut page, pin.buf, r)?;
+                    if !merged && r > 0 {
+                        self.btree_merge(db, &mut page, pin.buf, r - 1)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if page.get_n_items() == 0 {
+            self.free_btree_page(db, &mut page, pin.buf, pid)?;
+            Ok(Some(Underflow::Empty))
+        } else if page.fill_factor() < self.conf.merge_threshold {
+            Ok(Some(Underflow::Sparse))
         } else {
             Ok(None)
         }
     }
 
     //
-    // Remove key from B-Tree. Recursively traverse B-Tree and return true in case of underflow.
-    // Right now we do not redistribute nodes between pages or merge pages, underflow is reported only if page becomes empty.
-    // If key is not found, then nothing is performed and no error is reported.
+    // Merge child `left` of internal page into its right neighbour if items of both children fit in one page.
+    // Left child is moved to free list and removed from the parent. Returns false if pages do not fit in one page.
+    //
+    fn btree_merge(&self, db: &mut Database, parent: &mut PageData, parent_buf: BufferId, left: ItemPointer) -> Result<bool> {
+        let left_pid = parent.get_child(left);
+        let left_pin = self.get_page(left_pid, AccessMode::ReadOnly)?;
+        let mut left_page = self.pool_page(left_pin.buf).write().unwrap();
+        let right_pin = self.get_page(parent.get_child(left + 1), AccessMode::ReadOnly)?;
+        let mut right_page = self.pool_page(right_pin.buf).write().unwrap();
+        if PAGE_SIZE - PAGE_HEADER_SIZE - left_page.free_space() > right_page.free_space() {
+            return Ok(false);
+        }
+        self.modify_page(db, right_pin.buf)?;
+        let merged = right_page.merge(&left_page);
+        debug_assert!(merged);
+        self.modify_page(db, left_pin.buf)?;
+        self.free_btree_page(db, &mut left_page, left_pin.buf, left_pid)?;
+        self.modify_page(db, parent_buf)?;
+        parent.remove_key(left, false);
+        Ok(true)
+    }
+
+    //
+    // Move page removed from B-Tree to free list. Page should be already marked as modified by current transaction:
+    // it contains pointer to the next free page, so it has to be saved in WAL together with updated metadata
+    // to make it possible to reconstruct free list after crash.
+    //
+    fn free_btree_page(&self, db: &mut Database, page: &mut PageData, buf: BufferId, pid: PageId) -> Result<()> {
+        debug_assert!(self.lock_buf_mgr()?.pages[buf as usize].state & PAGE_DIRTY != 0);
+        page.set_next_free(db.meta.free);
+        db.meta.free = pid;
+        db.meta_updated = true;
+        Ok(())
+    }
+
+    //
+    // Insert item in B-Tree. Recursively traverse B-Tree and return position of new page in case of overflow.
+    //
+    fn btree_insert(
+        &self,
+        db: &mut Database,
+        pid: PageId,
+        key: &[u8],
+        value: &[u8],
+        height: u32,
+        replaced: &mut Option<Value>,
+    ) -> Result<Option<(Key, PageId)>> {
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let mut page = self.pool_page(pin.buf).write().unwrap();
+        let n = page.get_n_items();
+        let r = page.locate_key(key);
+        if height == 1 {
+            // leaf page
+            self.modify_page(db, pin.buf)?;
+            if r < n && page.compare_key(r, key) == Ordering::Equal {
+                // replace old value with new one: just remove old one and reinsert new key-value pair
+                *replaced = Some(page.get_value(r).to_vec());
+                page.remove_key(r, true);
+            }
+            self.btree_insert_in_page(db, &mut page, r, key, value)
+        } else {
+            // recurse to next level
+            debug_assert!(r < n);
+            let overflow =
+                self.btree_insert(db, page.get_child(r), key, value, height - 1, replaced)?;
+            if let Some((key, child)) = overflow {
+                // insert new page before original
+                self.modify_page(db, pin.buf)?;
+                debug_assert!(child != 0);
+                self.btree_insert_in_page(db, &mut page, r, &key, &child.to_be_bytes())
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    //
+    // Append item with key greater than all keys in B-Tree: descend along the right-most path
+    // without searching for the key. Returns position of new page in case of overflow.
+    //
+    fn btree_append(
+        &self,
+        db: &mut Database,
+        pid: PageId,
+        key: &[u8],
+        value: &[u8],
+        height: u32,
+    ) -> Result<Option<(Key, PageId)>> {
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let mut page = self.pool_page(pin.buf).write().unwrap();
+        let n = page.get_n_items();
+        if height == 1 {
+            // leaf page
+            anyhow::ensure!(
+                n == 0 || page.compare_key(n - 1, key) == Ordering::Greater,
+                "Appended key is not greater than the last key"
+            );
+            self.modify_page(db, pin.buf)?;
+            // insert at the end of page causes split leaving all existing items on new page
+            self.btree_insert_in_page(db, &mut page, n, key, value)
+        } else {
+            let overflow = self.btree_append(db, page.get_child(n - 1), key, value, height - 1)?;
+            if let Some((key, child)) = overflow {
+                // insert new page before original
+                self.modify_page(db, pin.buf)?;
+                debug_assert!(child != 0);
+                self.btree_insert_in_page(db, &mut page, n - 1, &key, &child.to_be_bytes())
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    //
+    // Check that start of key range is not greater than its end: reversed range is most likely a bug of the caller,
+    // so it is reported rather than treated as empty
+    //
+    pub(crate) fn check_range(start: &[u8], end: &[u8]) -> Result<()> {
+        anyhow::ensure!(start.cmp(end) != Ordering::Greater, StoreError::InvalidRange);
+        Ok(())
+    }
+
+    //
+    // Convert the user's key to the key stored in B-Tree: encode it using key dictionary (if any)
+    // and prepend key prefix (if any)
+    //
+    pub(crate) fn stored_key<'a>(&self, key: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let key = match &self.conf.key_dictionary {
+            Some(dict) => Cow::Owned(dict.encode(key)?),
+            None => Cow::Borrowed(key),
+        };
+        Ok(match &self.conf.key_prefix {
+            Some(prefix) => Cow::Owned([&prefix[..], &key].concat()),
+            None => key,
+        })
+    }
+
+    //
+    // Length of the key stored in B-Tree for the user's key (key is neither copied nor encoded)
+    //
+    fn stored_key_len(&self, key: &[u8]) -> Result<usize> {
+        let len = match &self.conf.key_dictionary {
+            Some(dict) => dict.encoded_len(key)?,
+            None => key.len(),
+        };
+        Ok(self.conf.key_prefix.as_ref().map_or(0, |prefix| prefix.len()) + len)
+    }
+
+    //
+    // Convert key stored in B-Tree to the user's key: strip key prefix of the specified length and decode key
+    //
+    fn user_key(&self, mut key: Key, prefix_len: usize) -> Result<Key> {
+        key.drain(..prefix_len);
+        match &self.conf.key_dictionary {
+            Some(dict) => dict.decode(&key),
+            None => Ok(key),
+        }
+    }
+
+    //
+    // Check that key can be stored: it is non-empty and fits in B-Tree item together with key prefix
+    // (and can be encoded if key dictionary is used)
+    //
+    pub(crate) fn check_key(&self, key: &[u8]) -> Result<()> {
+        anyhow::ensure!(!key.is_empty(), "Key should be non-empty: empty key is reserved for B-Tree separator");
+        anyhow::ensure!(self.stored_key_len(key)? <= MAX_KEY_LEN);
+        Ok(())
+    }
+
+    //
+    // Check that key and value can be stored
+    //
+    pub(crate) fn check_item(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_key(key)?;
+        let max = self.max_value_len();
+        if value.len() > max {
+            anyhow::bail!(StoreError::ValueTooLong { len: value.len(), max });
+        }
+        Ok(())
+    }
+
+    //
+    // Maximal length of value: configured limit or, by default, the largest value which can be stored
+    // (in value log mode values are not limited by page size)
+    //
+    fn max_value_len(&self) -> usize {
+        self.conf.max_value_len.unwrap_or(if self.vlog.is_some() {
+            MAX_LOGGED_VALUE_LEN
+        } else {
+            MAX_VALUE_LEN
+        })
+    }
+
+    //
+    // Read item from sorted stream: key and value, each preceded by its length (big-endian u32).
+    // Returns None at the end of stream.
+    //
+    fn read_sorted_item(&self, reader: &mut impl Read) -> Result<Option<(Key, Value)>> {
+        let mut len = [0u8; 4];
+        let mut filled = 0;
+        while filled < len.len() {
+            match reader.read(&mut len[filled..]) {
+                Ok(0) => {
+                    anyhow::ensure!(filled == 0, "Sorted stream is truncated");
+                    return Ok(None);
+                }
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let key_len = u32::from_be_bytes(len) as usize;
+        anyhow::ensure!(key_len <= MAX_KEY_LEN, "Key length {} in sorted stream is too large", key_len);
+        let mut key = vec![0u8; key_len];
+        reader.read_exact(&mut key)?;
+        reader.read_exact(&mut len)?;
+        let value_len = u32::from_be_bytes(len) as usize;
+        anyhow::ensure!(
+            value_len <= self.max_value_len(),
+            "Value length {} in sorted stream is too large",
+            value_len
+        );
+        let mut value = vec![0u8; value_len];
+        reader.read_exact(&mut value)?;
+        Ok(Some((key, value)))
+    }
+
+    //
+    // Append value to the value log and return reference to it
+    //
+    fn store_value(&self, db: &mut Database, value: &[u8]) -> Result<[u8; VALUE_REF_SIZE]> {
+        let vlog = self.vlog.as_ref().unwrap();
+        vlog.write_all_at(value, db.vlog_pos)?;
+        let mut value_ref = [0u8; VALUE_REF_SIZE];
+        value_ref[0..8].copy_from_slice(&db.vlog_pos.to_be_bytes());
+        value_ref[8..12].copy_from_slice(&(value.len() as u32).to_be_bytes());
+        value_ref[12..16].copy_from_slice(&crc32c(value).to_be_bytes());
+        db.vlog_pos += value.len() as u64;
+        db.vlog_dirty = true;
+        Ok(value_ref)
+    }
+
+    //
+    // Convert value to the form stored in B-Tree: append it to value log (if it is used) and tag it in tombstone mode
+    //
+    fn stored_value<'a>(&self, db: &mut Database, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let value = if self.vlog.is_some() {
+            Cow::Owned(self.store_value(db, value)?.to_vec())
+        } else {
+            Cow::Borrowed(value)
+        };
+        Ok(if self.conf.tombstones {
+            Cow::Owned([&[VALUE_TAG_LIVE][..], &value].concat())
+        } else {
+            value
+        })
+    }
+
+    //
+    // Tombstone stored in B-Tree instead of value of key removed by current transaction
+    //
+    fn tombstone(db: &Database) -> [u8; TOMBSTONE_SIZE] {
+        let mut tombstone = [VALUE_TAG_TOMBSTONE; TOMBSTONE_SIZE];
+        tombstone[1..].copy_from_slice(&(db.meta.commit_seq + 1).to_be_bytes());
+        tombstone
+    }
+
+    //
+    // Sequence number of commit which removed key if value stored in B-Tree is tombstone
+    //
+    fn tombstone_seq(&self, value: &[u8]) -> Option<u64> {
+        if self.conf.tombstones && value.first() == Some(&VALUE_TAG_TOMBSTONE) && value.len() == TOMBSTONE_SIZE {
+            Some(u64::from_be_bytes(value[1..].try_into().unwrap()))
+        } else {
+            None
+        }
+    }
+
+    //
+    // Get value stored in B-Tree item: in value log mode item contains reference to the value.
+    // Returns None if item is tombstone.
+    //
+    pub(crate) fn load_value(&self, mut value: Value) -> Result<Option<Value>> {
+        if self.conf.tombstones {
+            if self.tombstone_seq(&value).is_some() {
+                return Ok(None);
+            }
+            anyhow::ensure!(value.first() == Some(&VALUE_TAG_LIVE), "Value has unknown tag");
+            value.remove(0);
+        }
+        if let Some(vlog) = &self.vlog {
+            anyhow::ensure!(value.len() == VALUE_REF_SIZE);
+            let offs = u64::from_be_bytes(value[0..8].try_into().unwrap());
+            let len = u32::from_be_bytes(value[8..12].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(value[12..16].try_into().unwrap());
+            let mut buf = vec![0u8; len];
+            vlog.read_exact_at(&mut buf, offs)?;
+            anyhow::ensure!(
+                crc32c(&buf) == crc,
+                "Value at position {} of value log is corrupted",
+                offs
+            );
+            Ok(Some(buf))
+        } else {
+            Ok(Some(value))
+        }
+    }
+
+    //
+    // Insert or update key in the store.
+    // Returns replaced value as it is stored in B-Tree (reference to value log if it is used), see `load_value`.
+    //
+    pub(crate) fn do_upsert(&self, db: &mut Database, key: &[u8], value: &[u8]) -> Result<Option<Value>> {
+        self.check_item(key, value)?;
+        let key = &self.stored_key(key)?;
+        let value = &self.stored_value(db, value)?;
+        self.insert_item(db, key, value)
+    }
+
+    //
+    // Insert new key or update existed key in the leaf remembered by `anchor` if key belongs to its range
+    // and fits in the page, otherwise insert it from the root and anchor the leaf containing the key.
+    //
+    pub(crate) fn do_upsert_at(
+        &self,
+        db: &mut Database,
+        anchor: &mut Option<LeafAnchor>,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        self.check_item(key, value)?;
+        let key = &self.stored_key(key)?;
+        let value = &self.stored_value(db, value)?;
+        if let Some(leaf) = anchor {
+            if leaf.contains(key) && self.insert_in_leaf(db, leaf.pid, key, value)? {
+                return Ok(());
+            }
+        }
+        self.insert_item(db, key, value)?;
+        *anchor = self.locate_leaf(db, key)?;
+        Ok(())
+    }
+
+    //
+    // Insert new stored key or replace value of existed key in the leaf page without split.
+    // Returns false (and does nothing) if item doesn't fit in the page.
+    //
+    fn insert_in_leaf(&self, db: &mut Database, pid: PageId, key: &[u8], value: &[u8]) -> Result<bool> {
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let mut page = self.pool_page(pin.buf).write().unwrap();
+        let r = page.locate_key(key);
+        let exists = r < page.get_n_items() && page.compare_key(r, key) == Ordering::Equal;
+        let reclaimed = if exists { 3 + key.len() + page.get_value(r).len() } else { 0 };
+        if page.free_space() + reclaimed < 3 + key.len() + value.len() {
+            return Ok(false);
+        }
+        self.modify_page(db, pin.buf)?;
+        if exists {
+            page.remove_key(r, true);
+        }
+        anyhow::ensure!(page.insert_item(r, key, value)?);
+        if cfg!(debug_assertions) {
+            page.assert_sorted();
+        }
+        Ok(true)
+    }
+
+    //
+    // Descend B-Tree to the leaf page which may contain stored key and determine range of keys belonging to it
+    //
+    fn locate_leaf(&self, db: &Database, key: &[u8]) -> Result<Option<LeafAnchor>> {
+        if db.meta.root == 0 {
+            return Ok(None);
+        }
+        let mut leaf = LeafAnchor {
+            pid: db.meta.root,
+            low: None,
+            high: None,
+        };
+        for _ in 1..db.meta.height {
+            let pin = self.get_page(leaf.pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            let r = page.locate_key(key);
+            if r > 0 {
+                leaf.low = Some(page.get_key(r - 1));
+            }
+            if !page.is_inf_item(r) {
+                leaf.high = Some(page.get_key(r));
+            }
+            leaf.pid = page.get_child(r);
+        }
+        Ok(Some(leaf))
+    }
+
+    //
+    // Insert prefixed key with value as it is stored in B-Tree, growing B-Tree if needed.
+    // Returns replaced value as it is stored in B-Tree.
+    //
+    fn insert_item(&self, db: &mut Database, key: &[u8], value: &[u8]) -> Result<Option<Value>> {
+        let mut replaced = None;
+        if db.meta.root == 0 {
+            self.check_cache_size(1)?;
+            db.meta.root = self.btree_allocate_leaf_page(db, key, value)?;
+            db.meta.height = 1;
+            db.meta_updated = true;
+        } else if let Some((key, page)) =
+            self.btree_insert(db, db.meta.root, key, value, db.meta.height, &mut replaced)?
+        {
+            // overflow
+            self.check_cache_size(db.meta.height + 1)?;
+            db.meta.root = self.btree_allocate_internal_page(db, &key, page, db.meta.root)?;
+            db.meta.height += 1;
+            db.meta_updated = true;
+        }
+        Ok(replaced)
+    }
+
+    //
+    // Update value of existing key. Value is replaced in place if it fits in the leaf page,
+    // otherwise item is reinserted. Returns false if key is not found.
+    //
+    pub(crate) fn do_update(&self, db: &mut Database, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.check_item(key, value)?;
+        let key = &self.stored_key(key)?;
+        Ok(self.replace_item(db, key, |db| self.stored_value(db, value))?.is_some())
+    }
+
+    //
+    // Replace value of existing (not tombstoned) prefixed key with value as it is stored in B-Tree, which is produced
+    // by `value` only if key is found. Value is replaced in place if it fits in the leaf page, otherwise item is reinserted.
+    // Returns replaced value as it is stored in B-Tree or None if key is not found.
+    //
+    fn replace_item<'v>(
+        &self,
+        db: &mut Database,
+        key: &[u8],
+        value: impl FnOnce(&mut Database) -> Result<Cow<'v, [u8]>>,
+    ) -> Result<Option<Value>> {
+        if db.meta.root == 0 {
+            return Ok(None);
+        }
+        let path = self.locate(db.meta.root, key, db.meta.height)?;
+        let (pid, ip) = path[path.len() - 1];
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let mut page = self.pool_page(pin.buf).write().unwrap();
+        if ip == page.get_n_items() || page.compare_key(ip, key) != Ordering::Equal {
+            return Ok(None);
+        }
+        let old = page.get_value(ip).to_vec();
+        if self.tombstone_seq(&old).is_some() {
+            return Ok(None);
+        }
+        let value = value(db)?;
+        self.modify_page(db, pin.buf)?;
+        if !page.replace_value_in_place(ip, &value) {
+            drop(page);
+            drop(pin);
+            self.insert_item(db, key, &value)?;
+        }
+        Ok(Some(old))
+    }
+
+    //
+    // Append key greater than all keys in the store
+    //
+    pub(crate) fn do_append(&self, db: &mut Database, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_item(key, value)?;
+        let key = &self.stored_key(key)?;
+        let value = &self.stored_value(db, value)?;
+        if db.meta.root == 0 {
+            self.check_cache_size(1)?;
+            db.meta.root = self.btree_allocate_leaf_page(db, key, value)?;
+            db.meta.height = 1;
+            db.meta_updated = true;
+        } else if let Some((key, page)) =
+            self.btree_append(db, db.meta.root, key, value, db.meta.height)?
+        {
+            // overflow
+            self.check_cache_size(db.meta.height + 1)?;
+            db.meta.root = self.btree_allocate_internal_page(db, &key, page, db.meta.root)?;
+            db.meta.height += 1;
+            db.meta_updated = true;
+        }
+        Ok(())
+    }
+
+    //
+    // Check if key is greater than all keys in B-Tree (including keys of other prefixes),
+    // so that it can be inserted by `do_append`
+    //
+    fn is_after_last_key(&self, db: &Database, key: &[u8]) -> Result<bool> {
+        if db.meta.root == 0 {
+            return Ok(true);
+        }
+        let key = &self.stored_key(key)?;
+        let mut pid = db.meta.root;
+        let mut height = db.meta.height;
+        loop {
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            let n = page.get_n_items();
+            if height == 1 {
+                return Ok(n == 0 || page.compare_key(n - 1, key) == Ordering::Greater);
+            }
+            pid = page.get_child(n - 1);
+            height -= 1;
+        }
+    }
+
+    //
+    // Remove key from the store (in tombstone mode replace its value with tombstone). Does nothing it key not exists.
+    // Returns removed value as it is stored in B-Tree (reference to value log if it is used), see `load_value`.
+    //
+    pub(crate) fn do_remove(&self, db: &mut Database, key: &[u8]) -> Result<Option<Value>> {
+        self.check_key(key)?;
+        let key = &self.stored_key(key)?;
+        if self.conf.tombstones {
+            self.replace_item(db, key, |db| Ok(Cow::Owned(Self::tombstone(db).to_vec())))
+        } else {
+            self.remove_item(db, key)
+        }
+    }
+
+    //
+    // Physically remove prefixed key from B-Tree. Returns removed value as it is stored in B-Tree.
+    //
+    fn remove_item(&self, db: &mut Database, key: &[u8]) -> Result<Option<Value>> {
+        let mut removed = None;
+        if db.meta.root != 0 {
+            let underflow = self.btree_remove(db, db.meta.root, key, db.meta.height, &mut removed)?;
+            if underflow == Some(Underflow::Empty) {
+                db.meta.height = 0;
+                db.meta.root = 0;
+                db.meta_updated = true;
+            }
+        }
+        Ok(removed)
+    }
+
+    //
+    // Expected type of B-Tree page at the specified height
+    //
+    fn btree_page_type(height: u32) -> PageType {
+        if height == 1 {
+            PageType::Leaf
+        } else {
+            PageType::Internal
+        }
+    }
+
+    //
+    // Traverse B-Tree, check B-Tree invariants and return total number of keys in B-Tree.
+    // Cancellation flag (if specified) is checked before visiting each page.
+    //
+    pub(crate) fn traverse(
+        &self,
+        pid: PageId,
+        prev_key: &mut Key,
+        height: u32,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<u64> {
+        if pid == META_PID {
+            anyhow::bail!(StoreError::InvalidPage(pid));
+        }
+        Self::check_cancelled(cancel)?;
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let page = self.pool_page(pin.buf).read().unwrap();
+        Self::check_btree_page(pid, &page, height)?;
+        let n_items = page.get_n_items();
+        let mut count = 0u64;
+        if height == 1 {
+            for (key, _) in page.items() {
+                anyhow::ensure!(key > prev_key.as_slice());
+                prev_key.clear();
+                prev_key.extend_from_slice(key);
+            }
+            count += n_items as u64;
+        } else {
+            for (i, child) in page.children().enumerate() {
+                count += self.traverse(child, prev_key, height - 1, cancel)?;
+                let ord = page.compare_key(i, prev_key);
+                anyhow::ensure!(ord == Ordering::Less || ord == Ordering::Equal);
+            }
+        }
+        Ok(count)
+    }
+
+    //
+    // Like `traverse`, but visit only pages which may contain keys from `start` till `end` (exclusive)
+    // and return number of keys in this range. Visited pages are checked entirely.
+    // `prev_key` is initialized with separator preceding the visited subtree, so that lower bound of its keys is also checked.
+    //
+    pub(crate) fn traverse_range(
+        &self,
+        pid: PageId,
+        prev_key: &mut Key,
+        height: u32,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<u64> {
+        if pid == META_PID {
+            anyhow::bail!(StoreError::InvalidPage(pid));
+        }
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let page = self.pool_page(pin.buf).read().unwrap();
+        Self::check_btree_page(pid, &page, height)?;
+        let n_items = page.get_n_items();
+        let mut count = 0u64;
+        if height == 1 {
+            for i in 0..n_items {
+                anyhow::ensure!(page.compare_key(i, prev_key) == Ordering::Less);
+                if page.compare_key(i, start) != Ordering::Greater && page.compare_key(i, end) == Ordering::Greater {
+                    count += 1;
+                }
+                *prev_key = page.get_key(i);
+            }
+        } else {
+            let first = page.locate_key(start);
+            if first > 0 {
+                // keys of the skipped children are not greater than their separator
+                anyhow::ensure!(page.compare_key(first - 1, prev_key) == Ordering::Less);
+                *prev_key = page.get_key(first - 1);
+            }
+            for i in first..n_items {
+                count += self.traverse_range(page.get_child(i), prev_key, height - 1, start, end)?;
+                let ord = page.compare_key(i, prev_key);
+                anyhow::ensure!(ord == Ordering::Less || ord == Ordering::Equal);
+                if page.compare_key(i, end) != Ordering::Greater {
+                    // subtrees of the following items contain only keys greater or equal than the end of range
+                    break;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    //
+    // Check structure of B-Tree page located at the specified height (but not order of its keys)
+    //
+    fn check_btree_page(pid: PageId, page: &PageData, height: u32) -> Result<()> {
+        page.validate()
+            .map_err(|err| anyhow::anyhow!("Page {} is corrupted: {}", pid, err))?;
+        anyhow::ensure!(
+            page.get_page_type() == Some(Self::btree_page_type(height)),
+            "Page {} has type {:?} at height {}",
+            pid,
+            page.get_page_type(),
+            height
+        );
+        let n_items = page.get_n_items();
+        for i in 0..n_items {
+            if height == 1 {
+                anyhow::ensure!(!page.is_inf_item(i), "Leaf page {} contains separator", pid);
+            } else {
+                anyhow::ensure!(
+                    i + 1 == n_items || !page.is_inf_item(i),
+                    "Separator is not the last item of page {}",
+                    pid
+                );
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Convert store into cloneable handle, so that it can be shared by several owners (for example threads
+    /// or connection objects). All clones share this store: it is closed when the last of them is dropped.
+    ///
+    pub fn into_handle(self) -> StoreHandle {
+        StoreHandle::new(self)
+    }
+
+    ///
+    /// Close store. Close data and WAL files and truncate WAL file.
+    ///
+    pub fn close(&self) -> Result<()> {
+        if let Ok(mut db) = self.db.write() {
+            // avoid poisoned lock
+            if self.state() == StoreState::Opened {
+                let mut delayed_commit = false;
+                if let Ok(bm) = self.buffers.buf_mgr.lock() {
+                    // avoid poisoned mutex
+                    if bm.store(self.store_id).dirty_pages != 0 {
+                        delayed_commit = true;
+                    }
+                }
+                if delayed_commit {
+                    self.commit(&mut db, Durability::Immediate, false)?;
+                }
+                if db.batched_since.is_some() {
+                    self.sync_batched_transactions(&mut db)?;
+                }
+                // Sync data file and truncate log in case of normal shutdown
+                if let Some(writer) = &self.writer {
+                    writer.sync()?;
+                }
+                self.file.sync_all()?;
+                // unresolved prepared transaction is kept in WAL
+                if let (Some(log), None) = (&self.log, &db.in_doubt) {
+                    self.clear_wal_end(&mut db)?;
+                    log.set_len(0)?; // truncate WAL
+                }
+                self.set_state(StoreState::Closed);
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Locate position of the key in B-Tree with the specified root.
+    // Returns path from root to leaf: page and position of item at each level.
+    // Position at leaf page is the position of the first key greater or equal than the specified key,
+    // it can be equal to number of items in the leaf if all keys in this leaf are smaller.
+    //
+    fn locate(&self, root: PageId, key: &[u8], height: u32) -> Result<TreePath> {
+        if root == META_PID {
+            anyhow::bail!(StoreError::InvalidPage(root));
+        }
+        let mut path = TreePath::with_capacity(height as usize);
+        let mut pid = root;
+        for level in (1..=height).rev() {
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            let ip = page.locate_key(key);
+            path.push((pid, ip));
+            if level > 1 {
+                debug_assert!(ip < page.get_n_items());
+                pid = page.get_child(ip);
+                debug_assert!(pid != 0);
+            }
+        }
+        Ok(path)
+    }
+
+    //
+    // Move path to the first item of the next leaf page.
+    // Returns false if path is already at the last leaf page.
+    //
+    fn next_leaf(&self, path: &mut TreePath) -> Result<bool> {
+        let height = path.len();
+        let mut level = height - 1;
+        // find nearest ancestor having next child
+        let mut child = loop {
+            if level == 0 {
+                return Ok(false);
+            }
+            level -= 1;
+            let (pid, ip) = path[level];
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            if ip + 1 < page.get_n_items() {
+                path[level].1 = ip + 1;
+                break page.get_child(ip + 1);
+            }
+        };
+        // descend to the left-most leaf of this child
+        path.truncate(level + 1);
+        path.push((child, 0));
+        while path.len() < height {
+            let pin = self.get_page(child, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            child = page.get_child(0);
+            path.push((child, 0));
+        }
+        Ok(true)
+    }
+
+    //
+    // Lookup key in B-Tree with the specified root
+    //
+    pub(crate) fn find(&self, root: PageId, key: &[u8], height: u32) -> Result<Option<Value>> {
+        if root == 0 {
+            // empty tree
+            return Ok(None);
+        }
+        let key = &self.stored_key(key)?;
+        let path = self.locate(root, key, height)?;
+        let (pid, ip) = path[path.len() - 1];
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let page = self.pool_page(pin.buf).read().unwrap();
+        if ip < page.get_n_items() && page.compare_key(ip, key) == Ordering::Equal {
+            self.load_value(page.get_value(ip).to_vec())
+        } else {
+            Ok(None)
+        }
+    }
+
+    //
+    // Lookup key in the last committed state without waiting for completion of active write transaction.
+    // Pages modified by this transaction are updated in place, so their committed images are read from the data file.
+    // Page is checked for modification while it is locked, so transaction can not modify it after the check.
+    //
+    fn find_committed(&self, key: &[u8]) -> Result<Option<Value>> {
+        let committed = self.committed.read().unwrap();
+        self.lookup_committed(*committed, key, None)
+    }
+
+    //
+    // Lookup key in B-Tree with the specified committed root and height (caller should hold read lock on `committed`).
+    // Saved images of snapshot pages are used instead of current images of these pages.
+    //
+    pub(crate) fn lookup_committed(
+        &self,
+        (mut pid, mut height): (PageId, u32),
+        key: &[u8],
+        snapshot: Option<&SnapshotPages>,
+    ) -> Result<Option<Value>> {
+        self.check_state()?;
+        if pid == 0 {
+            // empty tree
+            return Ok(None);
+        }
+        // lookup in page either completes search or continues it in child page
+        enum Step {
+            Found(Option<Value>),
+            Child(PageId),
+        }
+        let key = &self.stored_key(key)?;
+        loop {
+            let step = self.with_committed_page(pid, snapshot, |page| {
+                let ip = page.locate_key(key);
+                if height == 1 {
+                    return if ip < page.get_n_items() && page.compare_key(ip, key) == Ordering::Equal {
+                        Ok(Step::Found(self.load_value(page.get_value(ip).to_vec())?))
+                    } else {
+                        Ok(Step::Found(None))
+                    };
+                }
+                debug_assert!(ip < page.get_n_items());
+                Ok(Step::Child(page.get_child(ip)))
+            })?;
+            match step {
+                Step::Found(value) => return Ok(value),
+                Step::Child(child) => pid = child,
+            }
+            height -= 1;
+        }
+    }
+
+    //
+    // Check if cached page is modified by active transaction, so that its committed image should be read from the data file
+    //
+    fn is_modified_page(&self, buf: BufferId, pid: PageId) -> Result<bool> {
+        let bm = self.lock_buf_mgr()?;
+        Ok((bm.pages[buf as usize].state & PAGE_DIRTY) != 0 || bm.store(self.store_id).spilled.contains_key(&pid))
+    }
+
+    //
+    // Read items with keys from `start` till `end` (exclusive) from B-Tree with the specified committed root and height
+    // (caller should hold read lock on `committed`). Key prefix (if any) is stripped from returned keys.
+    // Saved images of snapshot pages are used instead of current images of these pages.
+    //
+    pub(crate) fn scan_committed(
+        &self,
+        (root, height): (PageId, u32),
+        start: &[u8],
+        end: &[u8],
+        snapshot: Option<&SnapshotPages>,
+    ) -> Result<KeyValues> {
+        self.check_state()?;
+        let mut items = Vec::new();
+        if root != 0 && start < end {
+            let start = self.stored_key(start)?;
+            let end = self.stored_key(end)?;
+            self.collect_committed(root, height, &start, &end, snapshot, &mut items)?;
+        }
+        Ok(items)
+    }
+
+    //
+    // Collect items of committed subtree with keys from `start` till `end` (exclusive)
+    //
+    fn collect_committed(
+        &self,
+        pid: PageId,
+        height: u32,
+        start: &[u8],
+        end: &[u8],
+        snapshot: Option<&SnapshotPages>,
+        items: &mut KeyValues,
+    ) -> Result<()> {
+        let prefix_len = self.conf.key_prefix.as_ref().map_or(0, |prefix| prefix.len());
+        let children = self.with_committed_page(pid, snapshot, |page| {
+            let ip = page.locate_key(start);
+            let mut children = Vec::new();
+            if height == 1 {
+                for (key, value) in page.items().skip(ip) {
+                    if key >= end {
+                        break;
+                    }
+                    if let Some(value) = self.load_value(value.to_vec())? {
+                        items.push((self.user_key(key.to_vec(), prefix_len)?, value));
+                    }
+                }
+            } else {
+                // separator is the largest key of the child subtree
+                for i in ip..page.get_n_items() {
+                    children.push(page.get_child(i));
+                    if page.compare_key(i, end) != Ordering::Greater {
+                        break;
+                    }
+                }
+            }
+            Ok(children)
+        })?;
+        for child in children {
+            self.collect_committed(child, height - 1, start, end, snapshot, items)?;
+        }
+        Ok(())
+    }
+
+    //
+    // Access committed image of the page: image saved in snapshot if page was modified after snapshot was taken,
+    // cached page if it is not modified by active transaction, otherwise image read from the data file
+    // (caller should hold read lock on `committed`)
+    //
+    fn with_committed_page<R>(
+        &self,
+        pid: PageId,
+        snapshot: Option<&SnapshotPages>,
+        f: impl FnOnce(&PageData) -> Result<R>,
+    ) -> Result<R> {
+        if let Some(image) = snapshot.and_then(|snapshot| snapshot.image(pid)) {
+            return f(&image);
+        }
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let cached = self.pool_page(pin.buf).read().unwrap();
+        if self.is_modified_page(pin.buf, pid)? {
+            let mut image = PageData::new();
+            self.read_page(pid, &mut image)?;
+            f(&image)
+        } else {
+            f(&cached)
+        }
+    }
+
+    ///
+    /// Shutdown store. Unlike close it does't commit delayed transactions, flush data file and truncatate WAL.
+    ///
+    pub fn shutdown(&self) -> Result<()> {
+        let _db = self.db.write().unwrap();
+        anyhow::ensure!(self.state() == StoreState::Opened);
+        self.set_state(StoreState::Closed);
+        Ok(())
+    }
+
+    ///
+    /// Get state of the store. It doesn't wait for completion of active transaction.
+    /// Once store is found corrupted (for example write to the data file has failed in the middle of commit
+    /// or some thread panicked while updating buffers), all operations report `StoreError::Corrupted`
+    /// until store is recovered by `try_reopen`.
+    ///
+    pub fn state(&self) -> StoreState {
+        StoreState::from_u8(self.state.load(AtomicOrdering::Acquire))
+    }
+
+    ///
+    /// Try to recover corrupted store without closing it: cached pages are thrown away and store is recovered
+    /// from the data file and WAL like on open, so changes of transaction which was active when corruption was detected are lost.
+    /// If recovery succeeds, then store is opened again, otherwise it remains corrupted and error is returned.
+    ///
+    pub fn try_reopen(&self) -> Result<RecoveryReport> {
+        // locks can be poisoned by the panic which has corrupted the store
+        let mut db = self.db.write().unwrap_or_else(PoisonError::into_inner);
+        anyhow::ensure!(self.state() == StoreState::Corrupted, "Store is not corrupted");
+        self.db.clear_poison();
+        if let Some(writer) = &self.writer {
+            // committed images should not be written to the data file after recovery
+            writer.sync()?;
+        }
+        // wait until readers of committed state release buffers: new readers wait until store is recovered
+        let mut committed = self.committed.write().unwrap_or_else(PoisonError::into_inner);
+        self.committed.clear_poison();
+        self.reload(&mut db, &mut committed)
+    }
+
+    ///
+    /// Replace content of the store with another store (for example new version of data built aside) without
+    /// reconstructing `Store`: files of the new store are renamed over files of this store and store is reopened
+    /// from them, throwing away cached pages. Replacement waits for completion of active write transaction.
+    /// New store should be closed and created with the same configuration, it should have WAL if and only if
+    /// this store has WAL. Only store opened by path can be replaced and value log mode is not supported.
+    /// If replacement fails after files are renamed, then store becomes corrupted (see `try_reopen`).
+    ///
+    pub fn replace_with(&self, new_data: &Path, new_log: Option<&Path>) -> Result<()> {
+        let mut db = self.db.write().unwrap();
+        self.check_state()?;
+        let Some(files) = &self.replaceable else {
+            anyhow::bail!("Only store opened by path can be replaced");
+        };
+        anyhow::ensure!(self.vlog.is_none(), "Replacement of store with value log is not supported");
+        anyhow::ensure!(
+            db.in_doubt.is_none(),
+            "Prepared transaction should be resolved before replacement of the store"
+        );
+        anyhow::ensure!(
+            new_log.is_some() == files.log.is_some(),
+            "New store should have WAL if and only if replaced store has WAL"
+        );
+        // new store should not be opened by another store of this process
+        drop(OpenPath::register(new_data)?);
+        let file = OpenOptions::new().read(true).write(true).open(new_data)?;
+        if self.conf.file_locking {
+            Self::lock(&file, new_data)?;
+        }
+        self.check_replacement(&file, &db)?;
+        let log = if let Some(path) = new_log {
+            let log = OpenOptions::new().read(true).write(true).open(path)?;
+            if self.conf.file_locking {
+                Self::lock(&log, path)?;
+            }
+            Some(log)
+        } else {
+            None
+        };
+        // WAL of this store is made empty, so that crash during replacement doesn't replay it in new data file
+        if db.batched_since.is_some() {
+            self.sync_batched_transactions(&mut db)?;
+        }
+        if let Some(log) = &self.log {
+            self.checkpoint(&mut db)?;
+            self.reset_wal(log.as_ref())?;
+        }
+        let file = Self::data_storage(file, &self.conf)?;
+        // readers of committed state should not access new files until new root is installed by reload
+        let mut committed = self.committed.write().unwrap();
+        self.value_cache.lock().unwrap().clear();
+        fs::rename(new_data, &files.db_path)?;
+        files.file.replace(file);
+        let replaced = if let (Some(path), Some(log), Some(storage)) = (new_log, log, &files.log) {
+            fs::rename(path, files.log_path.as_ref().unwrap()).map(|_| storage.replace(Box::new(log)))
+        } else {
+            Ok(())
+        };
+        replaced
+            .and_then(|_| self.compression.as_ref().map_or(Ok(()), |compression| compression.reload()))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| self.reload(&mut db, &mut committed))
+            .inspect_err(|_| self.set_state(StoreState::Corrupted))?;
+        Ok(())
+    }
+
+    //
+    // Check that data file of store replacing this store has the same format
+    //
+    fn check_replacement(&self, file: &File, db: &Database) -> Result<()> {
+        let mut page = PageData::new();
+        anyhow::ensure!(file.metadata()?.len() >= PAGE_SIZE as u64, StoreError::NotAStore);
+        Storage::read_exact_at(file, &mut page.data, 0)?;
+        Metadata::check_magic(&page.data)?;
+        let meta = Metadata::unpack(&page.data);
+        anyhow::ensure!(
+            meta.format_version() == db.meta.format_version()
+                && meta.flags == db.meta.flags,
+            "New store was created with different format or configuration"
+        );
+        if let Some(dict) = &self.conf.key_dictionary {
+            if self.compression.is_some() {
+                CompressedStorage::open(Box::new(file.try_clone()?))?
+                    .read_exact_at(&mut page.data, page_offset(DICTIONARY_PID))?;
+            } else {
+                Storage::read_exact_at(file, &mut page.data, page_offset(DICTIONARY_PID))?;
+            }
+            anyhow::ensure!(
+                page.get_page_type() == Some(PageType::Dictionary)
+                    && KeyDictionary::unpack(&page.data[PAGE_HEADER_SIZE..])? == *dict,
+                "New store was created with different key dictionary"
+            );
+        }
+        Ok(())
+    }
+
+    //
+    // Throw away cached state of the store and recover it from the data file and WAL like on open
+    // (caller should hold write locks on `db` and `committed`). Store becomes corrupted if recovery fails.
+    //
+    fn reload(&self, db: &mut Database, committed: &mut (PageId, u32)) -> Result<RecoveryReport> {
+        // recovered state can differ from the state seen by existing snapshots
+        self.invalidate_snapshots();
+        self.value_cache.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        self.value_cache.clear_poison();
+        // committed images of batched transactions are restored from WAL by recovery
+        self.deferred.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        self.deferred.clear_poison();
+        {
+            let mut bm = self.buffers.buf_mgr.lock().unwrap_or_else(PoisonError::into_inner);
+            if self.buffers.is_private() {
+                // panic could leave buffer manager inconsistent, so it is recreated if it is not used by other stores
+                *bm = BufferManager::new(self.buffers.size());
+                bm.register()?;
+            } else {
+                bm.purge(self.store_id);
+            }
+        }
+        self.buffers.buf_mgr.clear_poison();
+        for page in self.buffers.allocated() {
+            page.clear_poison();
+        }
+        let mut buf = [0u8; METADATA_SIZE];
+        self.file.read_exact_at(&mut buf, 0)?;
+        Metadata::check_magic(&buf)?;
+        self.meta_page().write().unwrap().data[0..METADATA_SIZE].copy_from_slice(&buf);
+        *db = Database {
+            meta: Metadata::unpack(&buf),
+            meta_updated: false,
+            modified: false,
+            wal_pos: 0,
+            tx_crc: 0,
+            tx_size: 0,
+            vlog_pos: match &self.vlog {
+                Some(vlog) => vlog.size()?,
+                None => 0,
+            },
+            vlog_dirty: false,
+            prepared: false,
+            in_doubt: None,
+            batched_since: None,
+        };
+        let report = self
+            .recover(db, committed)
+            .and_then(|report| self.check_cache_size(report.height).map(|_| report))
+            .inspect_err(|_| self.set_state(StoreState::Corrupted))?;
+        Ok(report)
+    }
+
+    ///
+    /// Insert new key in the storage or update existed key in autocommit mode (as separate transaction)
+    ///
+    pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let mut trans = self.start_transaction();
+        trans.put(key, value)?;
+        trans.commit()?;
+        Ok(())
+    }
+
+    ///
+    /// Insert batch of items in autocommit mode (as single transaction).
+    /// If keys in the batch are in ascending order and all of them are greater than the last key in the storage
+    /// (typical for time-series ingestion), then items are appended along the right-most path of B-Tree
+    /// and pages are packed densely (like `Transaction::append`). Otherwise items are inserted one by one like by `put`.
+    ///
+    pub fn put_many_sorted<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, items: &[(K, V)]) -> Result<()> {
+        let mut trans = self.start_transaction();
+        let ascending = items
+            .windows(2)
+            .all(|pair| pair[0].0.as_ref() < pair[1].0.as_ref());
+        let append = ascending
+            && match items.first() {
+                Some((key, _)) => self.is_after_last_key(&trans.db, key.as_ref())?,
+                None => true,
+            };
+        for (key, value) in items {
+            if append {
+                self.do_append(&mut trans.db, key.as_ref(), value.as_ref())?;
+            } else {
+                trans.put(key, value)?;
+            }
+        }
+        trans.commit()?;
+        Ok(())
+    }
+
+    ///
+    /// Remove key from storage in autocommit mode (as separate transaction).
+    /// Does nothing if key not exist.
+    ///
+    pub fn remove(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let mut trans = self.start_transaction();
+        trans.remove(key)?;
+        trans.commit()?;
+        Ok(())
+    }
+
+    ///
+    /// Lookup key in the last committed state of the storage. It doesn't wait for completion of active
+    /// write transaction (even started by the same thread), so its updates are not visible: use `Transaction::get` to see them.
+    ///
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Value>> {
+        self.find_committed(key.as_ref())
+    }
+
+    ///
+    /// Return items of the last committed state with keys from `start` till `end` (exclusive) in key order,
+    /// which keys pass `filter`. Range is scanned in lexicographic order of keys, `filter` is applied to each key
+    /// in the range (without key prefix), so it can be used for range conditions in other space than byte order
+    /// of keys (for example numeric suffix). Items of the range are collected before `filter` is called,
+    /// so `filter` can access the store. Like `get`, it doesn't see updates of active write transaction.
+    /// Returns `StoreError::InvalidRange` if `start` is greater than `end`.
+    ///
+    pub fn range_filtered(
+        &self,
+        start: impl AsRef<[u8]>,
+        end: impl AsRef<[u8]>,
+        filter: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<(Key, Value)>> {
+        Self::check_range(start.as_ref(), end.as_ref())?;
+        let committed = self.committed.read().unwrap();
+        let mut items = self.scan_committed(*committed, start.as_ref(), end.as_ref(), None)?;
+        drop(committed);
+        items.retain(|(key, _)| filter(key));
+        Ok(items)
+    }
+
+    ///
+    /// Take read snapshot of the last committed state: reads through the snapshot see the same state without waiting
+    /// for write transactions and commits do not wait for snapshot (see `Snapshot`).
+    ///
+    pub fn snapshot(&self) -> Result<Snapshot<'_>> {
+        let committed = self.committed.read().unwrap();
+        self.check_state()?;
+        let size = self.committed_size.load(AtomicOrdering::Relaxed);
+        let pages = Arc::new(SnapshotPages::new(size, self.generation.load(AtomicOrdering::Relaxed)));
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.retain(|snapshot| snapshot.strong_count() != 0);
+        snapshots.push(Arc::downgrade(&pages));
+        Ok(Snapshot::new(self, *committed, pages))
+    }
+
+    //
+    // Perform read through snapshot: commit is not performed concurrently with it, so images of pages modified
+    // after snapshot was taken are either saved in snapshot or not yet written to the data file
+    //
+    pub(crate) fn read_snapshot<R>(&self, pages: &SnapshotPages, read: impl FnOnce() -> Result<R>) -> Result<R> {
+        let _committed = self.committed.read().unwrap();
+        anyhow::ensure!(
+            pages.generation == self.generation.load(AtomicOrdering::Relaxed),
+            "Snapshot is invalidated by reload of the store"
+        );
+        read()
+    }
+
+    //
+    // Invalidate existing snapshots when pages or values which they can access are discarded
+    // (caller should hold write lock on `committed`, so that there are no reads through snapshots in progress)
+    //
+    fn invalidate_snapshots(&self) {
+        self.generation.fetch_add(1, AtomicOrdering::Relaxed);
+        self.snapshots.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        self.snapshots.clear_poison();
+    }
+
+    //
+    // Save committed images of pages modified by current transaction in snapshots for which they are not saved yet
+    // (caller should hold write lock on `committed`)
+    //
+    fn save_snapshot_pages(&self, bm: &BufferManager) -> Result<()> {
+        let snapshots: Vec<Arc<SnapshotPages>> = {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            snapshots.retain(|snapshot| snapshot.strong_count() != 0);
+            snapshots.iter().filter_map(Weak::upgrade).collect()
+        };
+        if snapshots.is_empty() {
+            return Ok(());
+        }
+        let mut modified: Vec<PageId> = bm.store(self.store_id).spilled.keys().copied().collect();
+        let mut dirty = bm.store(self.store_id).dirty_pages;
+        while dirty != 0 {
+            modified.push(bm.pages[dirty as usize].pid);
+            dirty = bm.pages[dirty as usize].next;
+        }
+        for pid in modified {
+            let mut image: Option<Arc<PageData>> = None;
+            for snapshot in &snapshots {
+                if pid >= snapshot.size {
+                    continue; // page was allocated after snapshot was taken
+                }
+                if let Entry::Vacant(entry) = snapshot.images.lock().unwrap().entry(pid) {
+                    if image.is_none() {
+                        let mut page = PageData::new();
+                        self.read_page(pid, &mut page)?;
+                        image = Some(Arc::new(page));
+                    }
+                    entry.insert(image.clone().unwrap());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Lookup key in the last committed state of the storage like `get`, returning `default` if key doesn't exist.
+    ///
+    pub fn get_or(&self, key: impl AsRef<[u8]>, default: Value) -> Result<Value> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    ///
+    /// Lookup key in the last committed state of the storage like `get`, returning result of `default`
+    /// if key doesn't exist (`default` is called only in this case).
+    ///
+    pub fn get_or_else(&self, key: impl AsRef<[u8]>, default: impl FnOnce() -> Value) -> Result<Value> {
+        Ok(self.get(key)?.unwrap_or_else(default))
+    }
+
+    ///
+    /// Check if key exists in the last committed state of the storage (like `get`, it doesn't see updates
+    /// of active write transaction).
+    ///
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    ///
+    /// Lookup several keys in the last committed state of the storage and pass result of each lookup to `sink`
+    /// as soon as it is completed, so that results can be streamed without collecting them.
+    /// All keys are looked up in the same committed state: commits are blocked until all lookups are done,
+    /// so `sink` should not commit transactions of this store.
+    ///
+    pub fn get_stream<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+        mut sink: impl FnMut(K, Result<Option<Value>>),
+    ) {
+        let committed = self.committed.read().unwrap();
+        for key in keys {
+            let value = self.lookup_committed(*committed, key.as_ref(), None);
+            sink(key, value);
+        }
+    }
+
+    ///
+    /// Lookup key in the last committed state of the storage like `get`, but return shared value.
+    /// If `StoreConfig::value_cache_size` is not zero, then value is cached, so repeated reads of the key
+    /// return the same allocation until some transaction commits changes.
+    ///
+    pub fn get_shared(&self, key: impl AsRef<[u8]>) -> Result<Option<Arc<[u8]>>> {
+        let key = key.as_ref();
+        let committed = self.committed.read().unwrap();
+        if self.conf.value_cache_size == 0 {
+            return Ok(self.lookup_committed(*committed, key, None)?.map(Arc::from));
+        }
+        if let Some(value) = self.value_cache.lock().unwrap().get(key) {
+            return Ok(Some(value.clone()));
+        }
+        match self.lookup_committed(*committed, key, None)? {
+            Some(value) => {
+                let value: Arc<[u8]> = Arc::from(value);
+                let mut cache = self.value_cache.lock().unwrap();
+                if cache.len() >= self.conf.value_cache_size {
+                    cache.clear();
+                }
+                cache.insert(key.to_vec(), value.clone());
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Get statistic of buffer cache usage. It doesn't wait for completion of current transaction,
+    /// so it can be used to monitor number of dirty and spilled pages while transaction is in progress.
+    ///
+    pub fn stats(&self) -> CacheStats {
+        // statistics remain available even if buffer manager lock is poisoned
+        let bm = self.buffers.buf_mgr.lock().unwrap_or_else(PoisonError::into_inner);
+        CacheStats {
+            cached_pages: bm.cached as usize,
+            pinned_pages: bm.pinned as usize,
+            dirty_pages: bm.store(self.store_id).dirtied as usize,
+            spilled_pages: bm.store(self.store_id).spilled.len(),
+        }
+    }
+
+    ///
+    /// Set callback notified when page of this store is evicted from buffer cache to make room for another page
+    /// or by `trim_cache`. It receives identifier of evicted page and whether the page was clean: image of page
+    /// which is not clean was spilled to WAL by current transaction and is not yet written to the data file.
+    /// Hook is called while holding lock of buffer pool, so it should not access the store or stores sharing its pool.
+    /// `None` removes previously set hook.
+    ///
+    pub fn set_eviction_hook(&self, hook: Option<EvictionHook>) -> Result<()> {
+        let mut bm = self.lock_buf_mgr()?;
+        bm.store_mut(self.store_id).eviction_hook = hook;
+        Ok(())
+    }
+
+    ///
+    /// Voluntarily evict clean unpinned pages from buffer cache in LRU order until number of cached pages drops
+    /// to `target_pages`. Pinned, dirty and spilled pages are retained, so target may be not reached.
+    /// With shared buffer pool pages of all stores using the pool are evicted and number of cached pages
+    /// is counted for the whole pool. Memory of buffers is not returned to the system, but reused for pages
+    /// loaded later. Returns number of evicted pages.
+    ///
+    pub fn trim_cache(&self, target_pages: usize) -> Result<usize> {
+        let mut bm = self.lock_buf_mgr()?;
+        let target = target_pages.min(BufferId::MAX as usize) as BufferId;
+        Ok(bm.trim(target))
+    }
+
+    ///
+    /// Export metrics of the store (cache hit ratio, dirty pages, B-Tree height, number of commits, bytes written to WAL
+    /// and number of checkpoints) in Prometheus text exposition format. Counters are maintained by atomic operations,
+    /// so like `stats` it doesn't wait for completion of current transaction.
+    ///
+    #[cfg(feature = "metrics")]
+    pub fn metrics_text(&self) -> String {
+        self.metrics.format(&self.stats())
+    }
+
+    ///
+    /// Get snapshot of dirty pages of current transaction for debugging of commit and WAL flush behavior.
+    /// Like `stats`, it doesn't wait for completion of current transaction and holds buffer manager lock
+    /// only while traversing the list of dirty pages.
+    ///
+    #[cfg(feature = "diagnostics")]
+    pub fn dirty_page_report(&self) -> Result<DirtyReport> {
+        let bm = self.lock_buf_mgr()?;
+        let mut report = DirtyReport {
+            next_sync: if bm.store(self.store_id).next_sync != 0 {
+                Some(bm.pages[bm.store(self.store_id).next_sync as usize].pid)
+            } else {
+                None
+            },
+            dirtied: bm.store(self.store_id).dirtied as usize,
+            ..DirtyReport::default()
+        };
+        let mut dirty = bm.store(self.store_id).dirty_pages;
+        while dirty != 0 {
+            let page = &bm.pages[dirty as usize];
+            report.dirty.push(page.pid);
+            if (page.state & PAGE_SYNCED) != 0 {
+                report.synced.push(page.pid);
+            }
+            dirty = page.next;
+        }
+        Ok(report)
+    }
+
+    ///
+    /// Find pinned pages in cache to detect leaked pins. Pages are pinned while they are accessed
+    /// and dirty pages are pinned until end of transaction, so when no operation is in progress
+    /// only leaked pins (which will never be released) are reported. Leaked pins are not released:
+    /// they reduce number of buffers available for eviction.
+    ///
+    #[cfg(feature = "diagnostics")]
+    pub fn pin_audit(&self) -> Result<PinReport> {
+        let bm = self.lock_buf_mgr()?;
+        let mut report = PinReport::default();
+        for buf in 1..bm.used as usize {
+            let page = &bm.pages[buf];
+            if page.store != self.store_id {
+                continue; // page of other store sharing buffer pool
+            }
+            let dirty_pin = if (page.state & PAGE_DIRTY) != 0 { 1 } else { 0 };
+            if page.access_count > dirty_pin {
+                report.pinned.push((page.pid, page.access_count - dirty_pin));
+            }
+        }
+        report.pinned.sort_unstable();
+        Ok(report)
+    }
+
+    ///
+    /// Whether store is used in WAL mode (opened with log file)
+    ///
+    pub fn wal_enabled(&self) -> bool {
+        self.log.is_some()
+    }
+
+    ///
+    /// Sync WAL and write changes of transactions committed with `Durability::Batched` to the data file,
+    /// making them durable without waiting for `StoreConfig::batched_sync_interval`.
+    /// Waits for completion of current write transaction.
+    ///
+    pub fn sync_batched(&self) -> Result<()> {
+        let mut db = self.db.write().unwrap();
+        self.check_state()?;
+        if db.batched_since.is_some() {
+            self.sync_batched_transactions(&mut db)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Current position in WAL: amount of data (bytes) written to the log since the last checkpoint.
+    /// It is always 0 in no-WAL mode. Waits for completion of current write transaction.
+    ///
+    pub fn wal_position(&self) -> u64 {
+        self.db.read().unwrap().wal_pos
+    }
+
+    ///
+    /// WAL size (bytes) after reaching which checkpoint is performed by commit (see `StoreConfig::checkpoint_interval`).
+    /// Together with `wal_position` it can be used to estimate recovery cost.
+    ///
+    pub fn checkpoint_interval(&self) -> u64 {
+        self.conf.checkpoint_interval
+    }
+
+    ///
+    /// Get structural summary of B-Tree: number of pages and their fill at each level.
+    /// Please notice that pages are not merged on delete unless `StoreConfig::merge_threshold` is set,
+    /// so delete-heavy workload can cause low fill of pages.
+    ///
+    pub fn structure_summary(&self) -> Result<StructureSummary> {
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        let mut summary = StructureSummary {
+            height: db.meta.height,
+            ..Default::default()
+        };
+        let mut height = db.meta.height;
+        let mut level = if db.meta.root != 0 {
+            vec![db.meta.root]
+        } else {
+            Vec::new()
+        };
+        // level-order traversal of B-Tree
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            let mut stat = LevelSummary {
+                min_items: usize::MAX,
+                ..Default::default()
+            };
+            let mut total_items = 0u64;
+            for pid in level {
+                let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+                let page = self.pool_page(pin.buf).read().unwrap();
+                let n = page.get_n_items();
+                stat.pages += 1;
+                stat.min_items = stat.min_items.min(n);
+                stat.max_items = stat.max_items.max(n);
+                total_items += n as u64;
+                if height > 1 {
+                    // internal page
+                    next_level.extend(page.children());
+                    if n != 0 && page.is_inf_item(n - 1) {
+                        summary.inf_separators += 1;
+                    }
+                }
+            }
+            stat.avg_items = total_items as f64 / stat.pages as f64;
+            summary.levels.push(stat);
+            level = next_level;
+            height -= 1;
+        }
+        Ok(summary)
+    }
+
+    ///
+    /// Load upper (internal) levels of B-Tree in buffer cache, so that subsequent lookups read from the disk
+    /// only leaf pages. Levels are loaded starting from the root and loading stops when cache is filled
+    /// (leaving place for B-Tree path), so that loaded pages are not evicted by warmup itself.
+    /// Returns number of pages loaded in cache (pages which were already cached are not counted).
+    ///
+    pub fn warmup(&self) -> Result<u64> {
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        let mut loaded = 0u64;
+        let limit = self.warmup_limit(db.meta.height);
+        let mut height = db.meta.height;
+        let mut level = if height > 1 { vec![db.meta.root] } else { Vec::new() };
+        // level-order traversal of internal pages
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            for pid in level {
+                let pin = match self.warmup_page(pid, limit, &mut loaded)? {
+                    Some(pin) => pin,
+                    None => return Ok(loaded),
+                };
+                if height > 2 {
+                    let page = self.pool_page(pin.buf).read().unwrap();
+                    next_level.extend(page.children());
+                }
+            }
+            level = next_level;
+            height -= 1;
+        }
+        Ok(loaded)
+    }
+
+    ///
+    /// Load in buffer cache leaf pages containing keys from `start` till `end` (exclusive) and pages referencing them.
+    /// Like `Store::warmup`, loading stops when cache is filled. Returns number of pages loaded in cache.
+    /// Returns `StoreError::InvalidRange` if `start` is greater than `end`.
+    ///
+    pub fn warmup_range(&self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<u64> {
+        Self::check_range(start.as_ref(), end.as_ref())?;
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        let mut loaded = 0u64;
+        if db.meta.root != 0 && start.as_ref() < end.as_ref() {
+            let limit = self.warmup_limit(db.meta.height);
+            let start = self.stored_key(start.as_ref())?;
+            let end = self.stored_key(end.as_ref())?;
+            self.warmup_subtree(db.meta.root, db.meta.height, &start, &end, limit, &mut loaded)?;
+        }
+        Ok(loaded)
+    }
+
+    //
+    // Maximal number of cached pages after warmup: leave place for path in B-Tree of the specified height
+    //
+    fn warmup_limit(&self, height: u32) -> usize {
+        self.buffers
+            .size()
+            .saturating_sub(height as usize + MIN_FREE_BUFFERS)
+    }
+
+    //
+    // Pin page for warmup if it is cached or can be loaded without exceeding limit of cached pages.
+    // Returns None if limit is reached.
+    //
+    fn warmup_page(&self, pid: PageId, limit: usize, loaded: &mut u64) -> Result<Option<PageGuard<'_>>> {
+        {
+            let bm = self.lock_buf_mgr()?;
+            if bm.find(self.store_id, pid) == 0 {
+                if bm.cached as usize >= limit {
+                    return Ok(None);
+                }
+                *loaded += 1;
+            }
+        }
+        Ok(Some(self.get_page(pid, AccessMode::ReadOnly)?))
+    }
+
+    //
+    // Load pages of subtree containing keys from `start` till `end` (exclusive). Returns false if limit of cached pages
+    // is reached.
+    //
+    fn warmup_subtree(
+        &self,
+        pid: PageId,
+        height: u32,
+        start: &[u8],
+        end: &[u8],
+        limit: usize,
+        loaded: &mut u64,
+    ) -> Result<bool> {
+        let pin = match self.warmup_page(pid, limit, loaded)? {
+            Some(pin) => pin,
+            None => return Ok(false),
+        };
+        if height > 1 {
+            let page = self.pool_page(pin.buf).read().unwrap();
+            let n = page.get_n_items();
+            // child page contains keys not greater than its item key (last item has no key)
+            for i in page.locate_key(start)..n {
+                if !self.warmup_subtree(page.get_child(i), height - 1, start, end, limit, loaded)? {
+                    return Ok(false);
+                }
+                if page.compare_key(i, end) != Ordering::Greater {
+                    break;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    //
+    // Read next batch of items following `after` key (or starting from the smallest key if `after` is not specified).
+    // If key prefix is specified, then only keys with this prefix are returned (prefix is stripped).
+    //
+    fn scan_batch(&self, after: Option<&[u8]>, limit: usize) -> Result<KeyValues> {
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        let mut items = Vec::new();
+        if db.meta.root == 0 {
+            return Ok(items);
+        }
+        let prefix: &[u8] = self.conf.key_prefix.as_deref().unwrap_or_default();
+        // prefix itself is smaller than any (non-empty) user key
+        let after = match after {
+            Some(key) => self.stored_key(key)?,
+            None => Cow::Borrowed(prefix),
+        };
+        let mut path = self.locate(db.meta.root, &after, db.meta.height)?;
+        loop {
+            let (pid, mut ip) = path[path.len() - 1];
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            let n = page.get_n_items();
+            if ip < n && page.compare_key(ip, &after) == Ordering::Equal {
+                // skip `after` key itself
+                ip += 1;
+            }
+            for (key, value) in page.items().skip(ip) {
+                if !key.starts_with(prefix) || items.len() == limit {
+                    // end of prefix range or batch
+                    return Ok(items);
+                }
+                if let Some(value) = self.load_value(value.to_vec())? {
+                    items.push((self.user_key(key.to_vec(), prefix.len())?, value));
+                }
+            }
+            if items.len() == limit || !self.next_leaf(&mut path)? {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    ///
+    /// Read page of at most `limit` items with keys greater than `after` (or starting from the smallest key
+    /// if `after` is not specified). Returns these items and continuation key which should be passed as `after`
+    /// to get next page, or `None` if there are no more items. Each call is performed in separate read snapshot,
+    /// so no lock is held between calls.
+    ///
+    pub fn scan_page(&self, after: Option<&[u8]>, limit: usize) -> Result<(KeyValues, Option<Key>)> {
+        anyhow::ensure!(limit > 0);
+        // fetch one extra item to check if there are more items
+        let mut items = self.scan_batch(after, limit + 1)?;
+        let next = if items.len() > limit {
+            items.truncate(limit);
+            Some(items[limit - 1].0.clone())
+        } else {
+            None
+        };
+        Ok((items, next))
+    }
+
+    //
+    // Report error if operation is cancelled
+    //
+    fn check_cancelled(cancel: Option<&AtomicBool>) -> Result<()> {
+        if cancel.is_some_and(|cancel| cancel.load(AtomicOrdering::Relaxed)) {
+            anyhow::bail!(StoreError::Cancelled);
+        }
+        Ok(())
+    }
+
+    //
+    // Read items with keys from `start` till `end` (exclusive) from B-Tree with the specified root.
+    // Key prefix (if any) is stripped from returned keys. Cancellation flag (if specified) is checked
+    // before reading each leaf page.
+    //
+    pub(crate) fn scan_range(
+        &self,
+        root: PageId,
+        height: u32,
+        start: &[u8],
+        end: &[u8],
+        cancel: Option<&AtomicBool>,
+    ) -> Result<KeyValues> {
+        let mut items = Vec::new();
+        if root == 0 || start >= end {
+            return Ok(items);
+        }
+        let prefix_len = self.conf.key_prefix.as_ref().map_or(0, |prefix| prefix.len());
+        let start = self.stored_key(start)?;
+        let end = self.stored_key(end)?;
+        let mut path = self.locate(root, &start, height)?;
+        loop {
+            Self::check_cancelled(cancel)?;
+            let (pid, ip) = path[path.len() - 1];
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            for (key, value) in page.items().skip(ip) {
+                if key >= end.as_ref() {
+                    return Ok(items);
+                }
+                if let Some(value) = self.load_value(value.to_vec())? {
+                    items.push((self.user_key(key.to_vec(), prefix_len)?, value));
+                }
+            }
+            if !self.next_leaf(&mut path)? {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    ///
+    /// Traverse B-Tree of the last committed state, check B-Tree invariants and return total number of keys in B-Tree
+    /// (like `Transaction::verify`, but it waits for completion of active write transaction).
+    /// Traversal is aborted with `StoreError::Cancelled` error as soon as `cancel` flag is set
+    /// (it is checked before visiting each page), so long check of large store can be stopped from other thread.
+    ///
+    pub fn verify_cancellable(&self, cancel: &AtomicBool) -> Result<u64> {
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        if db.meta.root != 0 {
+            let mut prev_key = Vec::new();
+            self.traverse(db.meta.root, &mut prev_key, db.meta.height, Some(cancel))
+        } else {
+            Ok(0)
+        }
+    }
+
+    ///
+    /// Check all pages of the store to detect latent corruption: checksum of metadata, layout of B-Tree pages
+    /// and references to child pages, links of free list. Pages which are reachable neither from B-Tree root nor
+    /// from free list are checked standalone and reported as unreachable. Unlike `verify_cancellable`, check is not
+    /// stopped by the first bad page: all detected bad pages are reported. Pages are read directly from the data file
+    /// bypassing buffer cache, so scrub doesn't evict cached pages. It waits for completion of active write
+    /// transaction and blocks new ones until it is finished.
+    ///
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        let size = db.meta.size;
+        let mut report = ScrubReport::default();
+        let mut visited = vec![false; size as usize];
+        let mut page = PageData::new();
+        visited[META_PID as usize] = true;
+        report.checked_pages += 1;
+        if self.read_page(META_PID, &mut page).is_err()
+            || !Metadata::has_magic(&page.data)
+            || !Metadata::is_intact(&page.data)
+        {
+            report.bad_pages.push(META_PID);
+        }
+        if let Some(dict) = &self.conf.key_dictionary {
+            visited[DICTIONARY_PID as usize] = true;
+            report.checked_pages += 1;
+            if self.read_page(DICTIONARY_PID, &mut page).is_err()
+                || page.get_page_type() != Some(PageType::Dictionary)
+                || KeyDictionary::unpack(&page.data[PAGE_HEADER_SIZE..]).ok().as_ref() != Some(dict)
+            {
+                report.bad_pages.push(DICTIONARY_PID);
+            }
+        }
+        if db.meta.root != 0 {
+            self.scrub_subtree(db.meta.root, db.meta.height, &mut visited, &mut report);
+        }
+        // follow free list until it reaches its end or bad page
+        let mut free = db.meta.free;
+        while free != 0 && free < size && !visited[free as usize] {
+            visited[free as usize] = true;
+            report.checked_pages += 1;
+            if self.read_page(free, &mut page).is_err() || page.get_page_type() != Some(PageType::Free) {
+                report.bad_pages.push(free);
+                break;
+            }
+            let next = page.get_next_free();
+            if next >= size || (next != 0 && visited[next as usize]) {
+                report.bad_pages.push(free);
+                break;
+            }
+            free = next;
+        }
+        // pages not reached by traversal of B-Tree and free list are checked standalone
+        for pid in 0..size {
+            if !visited[pid as usize] {
+                report.checked_pages += 1;
+                report.unreachable_pages.push(pid);
+                if self.read_page(pid, &mut page).is_err() || !Self::is_valid_standalone_page(&page) {
+                    report.bad_pages.push(pid);
+                }
+            }
+        }
+        report.bad_pages.sort_unstable();
+        report.bad_pages.dedup();
+        Ok(report)
+    }
+
+    //
+    // Check layout of page which is not referenced by other pages, so that only its own content can be checked
     //
-    fn btree_remove(&self, db: &mut Database, pid: PageId, key: &Key, height: u32) -> Result<bool> {
-        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
-        let mut page = self.pool[pin.buf as usize].write().unwrap();
-        let mut l: ItemPointer = 0;
-        let n = page.get_n_items();
-        let mut r = n;
-        while l < r {
-            let m = (l + r) >> 1;
-            if page.compare_key(m, key) == Ordering::Greater {
-                l = m + 1;
-            } else {
-                r = m;
+    fn is_valid_standalone_page(page: &PageData) -> bool {
+        match page.get_page_type() {
+            Some(PageType::Leaf) => page.validate().is_ok(),
+            Some(PageType::Internal) => page.validate().is_ok() && page.validate_internal().is_ok(),
+            Some(PageType::Free) => true,
+            _ => false,
+        }
+    }
+
+    //
+    // Check pages of B-Tree subtree for `scrub`. Children of bad page are not visited.
+    //
+    fn scrub_subtree(&self, pid: PageId, height: u32, visited: &mut [bool], report: &mut ScrubReport) {
+        visited[pid as usize] = true;
+        report.checked_pages += 1;
+        let mut page = PageData::new();
+        if self.read_page(pid, &mut page).is_err()
+            || page.validate().is_err()
+            || page.get_page_type() != Some(Self::btree_page_type(height))
+            || page.get_n_items() == 0
+        {
+            report.bad_pages.push(pid);
+            return;
+        }
+        if height > 1 {
+            if page.validate_internal().is_err() {
+                report.bad_pages.push(pid);
+                return;
+            }
+            let children: Vec<PageId> = page.children().collect();
+            if children
+                .iter()
+                .any(|&child| child == META_PID || child as usize >= visited.len() || visited[child as usize])
+            {
+                report.bad_pages.push(pid);
+                return;
+            }
+            for child in children {
+                self.scrub_subtree(child, height - 1, visited, report);
             }
         }
-        debug_assert!(l == r);
-        if height == 1 {
-            // leaf page
-            if r < n && page.compare_key(r, key) == Ordering::Equal {
-                self.modify_page(db, pin.buf)?;
-                page.remove_key(r, true);
+    }
+
+    ///
+    /// Compute hash of store content for comparing replicas (for example backup with the original).
+    /// CRCs of all key-value pairs are folded in key order, so stores with the same logical content
+    /// produce the same hash regardless of their physical page layout. If key prefix is specified,
+    /// then only keys with this prefix are hashed. Hash is computed in one read snapshot.
+    ///
+    pub fn content_hash(&self) -> Result<u64> {
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        let mut hash = 0u64;
+        if db.meta.root == 0 {
+            return Ok(hash);
+        }
+        let prefix: &[u8] = self.conf.key_prefix.as_deref().unwrap_or_default();
+        let mut path = self.locate(db.meta.root, prefix, db.meta.height)?;
+        loop {
+            let (pid, ip) = path[path.len() - 1];
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            for (key, value) in page.items().skip(ip) {
+                if !key.starts_with(prefix) {
+                    // end of prefix range
+                    return Ok(hash);
+                }
+                let Some(value) = self.load_value(value.to_vec())? else {
+                    continue; // tombstone
+                };
+                let key = &self.user_key(key.to_vec(), prefix.len())?;
+                // key length separates key from value
+                let crc = crc32c_append(crc32c(&[key.len() as u8]), key);
+                let crc = crc32c_append(crc, &value);
+                hash = (hash ^ crc as u64).wrapping_mul(0x100000001b3);
             }
-        } else {
-            // recurse to next level
-            debug_assert!(r < n);
-            let underflow = self.btree_remove(db, page.get_child(r), key, height - 1)?;
-            if underflow {
-                self.modify_page(db, pin.buf)?;
-                page.remove_key(r, false);
+            if !self.next_leaf(&mut path)? {
+                break;
             }
         }
-        if page.get_n_items() == 0 {
-            // free page
-            page.set_u32(0, db.meta.free);
-            db.meta.free = pid;
-            db.meta_updated = true;
-            Ok(true)
+        Ok(hash)
+    }
+
+    ///
+    /// Get number and total size of keys and values with the specified prefix (for example to account usage of
+    /// namespaces sharing the store). Key prefix of the store is prepended to `prefix`. Stats are computed in one read
+    /// snapshot without loading values from value log, tombstones are not counted.
+    ///
+    pub fn prefix_stats(&self, prefix: &[u8]) -> Result<PrefixStats> {
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        let mut stats = PrefixStats::default();
+        if db.meta.root == 0 {
+            return Ok(stats);
+        }
+        let store_prefix: &[u8] = self.conf.key_prefix.as_deref().unwrap_or_default();
+        // encoded keys can be matched only after decoding
+        let range_prefix = if self.conf.key_dictionary.is_some() {
+            store_prefix.to_vec()
         } else {
-            Ok(false)
+            [store_prefix, prefix].concat()
+        };
+        let mut path = self.locate(db.meta.root, &range_prefix, db.meta.height)?;
+        loop {
+            let (pid, ip) = path[path.len() - 1];
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            let mut matched = false;
+            for (key, value) in page.items().skip(ip) {
+                if !key.starts_with(&range_prefix) {
+                    // end of prefix range
+                    stats.pages += matched as u64;
+                    return Ok(stats);
+                }
+                let key_len = if self.conf.key_dictionary.is_some() {
+                    let key = self.user_key(key.to_vec(), store_prefix.len())?;
+                    if !key.starts_with(prefix) {
+                        continue;
+                    }
+                    key.len()
+                } else {
+                    key.len() - store_prefix.len()
+                };
+                if let Some(value_len) = self.value_len(value) {
+                    stats.keys += 1;
+                    stats.key_bytes += key_len as u64;
+                    stats.value_bytes += value_len as u64;
+                    matched = true;
+                }
+            }
+            stats.pages += matched as u64;
+            if !self.next_leaf(&mut path)? {
+                break;
+            }
         }
+        Ok(stats)
     }
 
     //
-    // Insert item in B-Tree. Recursively traverse B-Tree and return position of new page in case of overflow.
+    // Length of value stored in B-Tree item without loading it from value log. Returns None for tombstone.
     //
-    fn btree_insert(
-        &self,
-        db: &mut Database,
-        pid: PageId,
-        key: &Key,
-        value: &Value,
-        height: u32,
-    ) -> Result<Option<(Key, PageId)>> {
-        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
-        let mut page = self.pool[pin.buf as usize].write().unwrap();
-        let mut l: ItemPointer = 0;
-        let n = page.get_n_items();
-        let mut r = n;
-        while l < r {
-            let m = (l + r) >> 1;
-            if page.compare_key(m, key) == Ordering::Greater {
-                l = m + 1;
-            } else {
-                r = m;
-            }
+    fn value_len(&self, value: &[u8]) -> Option<usize> {
+        if self.tombstone_seq(value).is_some() {
+            return None;
         }
-        debug_assert!(l == r);
-        if height == 1 {
-            // leaf page
-            self.modify_page(db, pin.buf)?;
-            if r < n && page.compare_key(r, key) == Ordering::Equal {
-                // replace old value with new one: just remove old one and reinsert new key-value pair
-                page.remove_key(r, true);
-            }
-            self.btree_insert_in_page(db, &mut page, r, key, value)
+        let value = if self.conf.tombstones { &value[1..] } else { value };
+        Some(if self.vlog.is_some() {
+            u32::from_be_bytes(value[8..12].try_into().unwrap()) as usize
         } else {
-            // recurse to next level
-            debug_assert!(r < n);
-            let overflow = self.btree_insert(db, page.get_child(r), key, value, height - 1)?;
-            if let Some((key, child)) = overflow {
-                // insert new page before original
-                self.modify_page(db, pin.buf)?;
-                debug_assert!(child != 0);
-                self.btree_insert_in_page(db, &mut page, r, &key, &child.to_be_bytes().to_vec())
-            } else {
-                Ok(None)
-            }
+            value.len()
+        })
+    }
+
+    ///
+    /// List keys removed by commits with sequence number greater than `after_seq` (returned by `Transaction::commit`),
+    /// which are still kept as tombstones (see `StoreConfig::tombstones`). Returns pairs of key and sequence number
+    /// of commit which removed it, in key order. If key prefix is specified, then only keys with this prefix are listed.
+    ///
+    pub fn scan_tombstones(&self, after_seq: u64) -> Result<Vec<(Key, u64)>> {
+        anyhow::ensure!(self.conf.tombstones, "Tombstones are not kept");
+        let db = self.db.read().unwrap();
+        self.check_state()?;
+        let prefix_len = self.conf.key_prefix.as_ref().map_or(0, |prefix| prefix.len());
+        self.collect_tombstones(&db, |seq| seq > after_seq)?
+            .into_iter()
+            .map(|(key, seq)| Ok((self.user_key(key, prefix_len)?, seq)))
+            .collect()
+    }
+
+    ///
+    /// Physically remove tombstones of keys removed by commits with sequence number smaller than `older_than`.
+    /// Tombstones are removed in one transaction. Returns number of removed tombstones.
+    ///
+    pub fn purge_tombstones(&self, older_than: u64) -> Result<u64> {
+        anyhow::ensure!(self.conf.tombstones, "Tombstones are not kept");
+        let mut trans = self.start_transaction();
+        let tombstones = self.collect_tombstones(&trans.db, |seq| seq < older_than)?;
+        for (key, _) in &tombstones {
+            self.remove_item(&mut trans.db, key)?;
         }
+        trans.commit()?;
+        Ok(tombstones.len() as u64)
     }
 
     //
-    // Insert or update key in the store
+    // Collect prefixed keys and sequence numbers of tombstones matching filter
     //
-    pub fn do_upsert(&self, db: &mut Database, key: &Key, value: &Value) -> Result<()> {
-        anyhow::ensure!(key.len() != 0 && key.len() <= MAX_KEY_LEN && value.len() <= MAX_VALUE_LEN);
+    fn collect_tombstones(&self, db: &Database, filter: impl Fn(u64) -> bool) -> Result<Vec<(Key, u64)>> {
+        let mut tombstones = Vec::new();
         if db.meta.root == 0 {
-            db.meta.root = self.btree_allocate_leaf_page(db, key, value)?;
-            db.meta.height = 1;
-            db.meta_updated = true;
-        } else if let Some((key, page)) =
-            self.btree_insert(db, db.meta.root, key, value, db.meta.height)?
-        {
-            // overflow
-            db.meta.root = self.btree_allocate_internal_page(db, &key, page, db.meta.root)?;
-            db.meta.height += 1;
-            db.meta_updated = true;
+            return Ok(tombstones);
         }
-        Ok(())
+        let prefix: &[u8] = self.conf.key_prefix.as_deref().unwrap_or_default();
+        let mut path = self.locate(db.meta.root, prefix, db.meta.height)?;
+        loop {
+            let (pid, ip) = path[path.len() - 1];
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            for (key, value) in page.items().skip(ip) {
+                if !key.starts_with(prefix) {
+                    // end of prefix range
+                    return Ok(tombstones);
+                }
+                if let Some(seq) = self.tombstone_seq(value).filter(|seq| filter(*seq)) {
+                    tombstones.push((key.to_vec(), seq));
+                }
+            }
+            if !self.next_leaf(&mut path)? {
+                break;
+            }
+        }
+        Ok(tombstones)
     }
 
-    //
-    // Remove key from the store. Does nothing it key not exists.
-    //
-    pub fn do_remove(&self, db: &mut Database, key: &Key) -> Result<()> {
-        if db.meta.root != 0 {
-            let underflow = self.btree_remove(db, db.meta.root, key, db.meta.height)?;
-            if underflow {
-                db.meta.height = 0;
-                db.meta.root = 0;
-                db.meta_updated = true;
+    ///
+    /// Merge all entries of other store into this store. Keys are copied in ascending order
+    /// in several transactions (each containing at most `MERGE_BATCH_SIZE` keys) to bound WAL growth.
+    /// If key already exists in this store, then conflict is resolved according to `on_conflict` policy.
+    /// Returns number of merged keys (keys preserved by `ConflictPolicy::KeepExisting` are not counted).
+    /// Please notice that if merge is aborted because of error, batches committed before it are not undone.
+    ///
+    pub fn merge_from(&self, other: &Store, on_conflict: ConflictPolicy) -> Result<u64> {
+        anyhow::ensure!(!std::ptr::eq(self, other));
+        let mut merged = 0u64;
+        let mut after: Option<Key> = None;
+        loop {
+            let mut batch = other.scan_batch(after.as_deref(), MERGE_BATCH_SIZE)?;
+            if batch.is_empty() {
+                break;
+            }
+            let mut trans = self.start_transaction();
+            for (key, value) in batch.iter() {
+                if on_conflict != ConflictPolicy::Overwrite && trans.get(key)?.is_some() {
+                    anyhow::ensure!(
+                        on_conflict == ConflictPolicy::KeepExisting,
+                        "Key {:?} already exists",
+                        key
+                    );
+                    continue;
+                }
+                trans.put(key, value)?;
+                merged += 1;
             }
+            trans.commit()?;
+            after = batch.pop().map(|(key, _)| key);
         }
-        Ok(())
+        Ok(merged)
     }
 
-    pub fn traverse(&self, pid: PageId, prev_key: &mut Key, height: u32) -> Result<u64> {
-        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
-        let page = self.pool[pin.buf as usize].read().unwrap();
-        let n_items = page.get_n_items();
-        let mut count = 0u64;
-        if height == 1 {
-            for i in 0..n_items {
-                anyhow::ensure!(page.compare_key(i, prev_key) == Ordering::Less);
-                *prev_key = page.get_key(i);
+    ///
+    /// Create new store and load it from stream of items sorted by key (for example to restore it from backup).
+    /// Each item is key followed by value, both preceded by their length (big-endian u32).
+    /// Items are appended to B-Tree like `Transaction::append` does, in several transactions
+    /// (each containing at most `MERGE_BATCH_SIZE` items), so B-Tree is built without searching for each key.
+    /// Returns error if store is not empty or if keys in stream are not strictly ascending (including duplicates).
+    /// Please notice that if load is aborted because of error, batches committed before it are not undone.
+    ///
+    pub fn create_from_sorted(
+        db_path: &Path,
+        log_path: Option<&Path>,
+        conf: StoreConfig,
+        mut reader: impl Read,
+    ) -> Result<Store> {
+        let store = Store::open(db_path, log_path, conf)?;
+        anyhow::ensure!(store.db.read().unwrap().meta.root == 0, "Store is not empty");
+        let mut end = false;
+        while !end {
+            let mut trans = store.start_transaction();
+            for _ in 0..MERGE_BATCH_SIZE {
+                match store.read_sorted_item(&mut reader)? {
+                    Some((key, value)) => trans.append(key, value)?,
+                    None => {
+                        end = true;
+                        break;
+                    }
+                }
             }
-            count += n_items as u64;
+            trans.commit()?;
+        }
+        Ok(store)
+    }
+
+    ///
+    /// Rebuild damaged store (for example with corrupted internal pages) from its leaf pages: all pages of `src`
+    /// data file are scanned, items of valid leaf pages are sorted and loaded in new store created at `dest`.
+    /// Keys are copied as is (including key prefixes), so `conf.key_prefix` is ignored. If source store uses key dictionary,
+    /// then `conf.key_dictionary` should specify the same dictionary and `conf.key_prefix` should specify key prefix
+    /// of source store (if any): encoded part of the key follows the prefix. WAL of source store is not used,
+    /// and value log is not supported. Items are collected in memory before loading.
+    /// Please notice that leaf pages do not contain versions of items: if the same key is found in several pages
+    /// (which is possible only in damaged store), then it is not known which value is the newest,
+    /// and value from the page with the smallest identifier is kept.
+    ///
+    pub fn repair(src: &Path, dest: &Path, conf: StoreConfig) -> Result<RepairReport> {
+        anyhow::ensure!(!conf.value_log, "Repair of store with value log is not supported");
+        anyhow::ensure!(!conf.tombstones, "Repair of store with tombstones is not supported");
+        let file = OpenOptions::new().read(true).open(src)?;
+        let mut report = RepairReport::default();
+        let mut page = PageData::new();
+        let compressed = Storage::read_exact_at(&file, &mut page.data, 0).is_ok()
+            && Metadata::has_magic(&page.data)
+            && (Metadata::unpack(&page.data).flags & META_FLAG_PAGE_COMPRESSION) != 0;
+        let file: Box<dyn Storage> = if compressed {
+            Box::new(CompressedStorage::open(Box::new(file))?)
         } else {
-            for i in 0..n_items {
-                count += self.traverse(page.get_child(i), prev_key, height - 1)?;
-                let ord = page.compare_key(i, prev_key);
-                anyhow::ensure!(ord == Ordering::Less || ord == Ordering::Equal);
+            Box::new(file)
+        };
+        if file.read_exact_at(&mut page.data, 0).is_ok()
+            && Metadata::has_magic(&page.data)
+            && (Metadata::unpack(&page.data).flags & META_FLAG_VALUE_LOG) != 0
+        {
+            anyhow::bail!("Repair of store with value log is not supported");
+        }
+        if file.read_exact_at(&mut page.data, 0).is_ok()
+            && Metadata::has_magic(&page.data)
+            && (Metadata::unpack(&page.data).flags & META_FLAG_TOMBSTONES) != 0
+        {
+            anyhow::bail!("Repair of store with tombstones is not supported");
+        }
+        let n_pages = file.size()? / PAGE_SIZE as u64;
+        let mut items: Vec<(Key, Value)> = Vec::new();
+        for pid in 1..n_pages {
+            report.scanned_pages += 1;
+            if file.read_exact_at(&mut page.data, pid * PAGE_SIZE as u64).is_err() || page.validate().is_err() {
+                report.unreadable_pages += 1;
+                continue;
+            }
+            if page.get_page_type() == Some(PageType::Leaf) && page.get_n_items() != 0 {
+                report.salvaged_pages += 1;
+                items.extend(page.items().map(|(key, value)| (key.to_vec(), value.to_vec())));
             }
         }
-        Ok(count)
+        // stable sort preserves order of pages for duplicates
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        let n_items = items.len();
+        items.dedup_by(|next, prev| next.0 == prev.0);
+        report.duplicates = (n_items - items.len()) as u64;
+
+        // with key dictionary keys are decoded and encoded again by new store, which prepends the same prefix
+        let key_prefix = if conf.key_dictionary.is_some() { conf.key_prefix.clone() } else { None };
+        let store = Store::open(dest, None, StoreConfig { key_prefix, ..conf })?;
+        anyhow::ensure!(store.db.read().unwrap().meta.root == 0, "Store is not empty");
+        for batch in items.chunks(MERGE_BATCH_SIZE) {
+            let mut trans = store.start_transaction();
+            for (key, value) in batch {
+                match &store.conf.key_dictionary {
+                    Some(dict) => {
+                        let prefix = store.conf.key_prefix.as_deref().unwrap_or_default();
+                        let Some(encoded) = key.strip_prefix(prefix) else {
+                            anyhow::bail!("Key of source store doesn't start with key prefix");
+                        };
+                        trans.append(dict.decode(encoded)?, value)?
+                    }
+                    None => trans.append(key, value)?,
+                }
+            }
+            trans.commit()?;
+        }
+        report.keys = items.len() as u64;
+        Ok(report)
     }
 
     ///
-    /// Close store. Close data and WAL files and truncate WAL file.
+    /// Reclaim space of overwritten and removed values in value log: all live values are copied
+    /// to the end of value log (in several transactions, each relocating at most `MERGE_BATCH_SIZE` values),
+    /// and then space occupied by original values is deallocated (if file system supports holes,
+    /// so size of value log file is not changed). Returns number of relocated values.
     ///
-    pub fn close(&self) -> Result<()> {
-        if let Ok(mut db) = self.db.write() {
-            // avoid poisoned lock
-            if db.state == DatabaseState::Opened {
-                let mut delayed_commit = false;
-                if let Ok(bm) = self.buf_mgr.lock() {
-                    // avoid poisoned mutex
-                    if bm.dirty_pages != 0 {
-                        delayed_commit = true;
+    pub fn collect_value_log(&self) -> Result<u64> {
+        anyhow::ensure!(self.vlog.is_some(), "Value log is not used");
+        let end = self.db.read().unwrap().vlog_pos;
+        let mut relocated = 0u64;
+        let mut after: Option<Key> = None;
+        loop {
+            let mut trans = self.start_transaction();
+            let (n, last) = self.relocate_values(&mut trans.db, after.as_deref(), end)?;
+            trans.commit()?;
+            relocated += n;
+            if last.is_none() {
+                break;
+            }
+            after = last;
+        }
+        if self.log.is_none() {
+            // references to relocated values should be durable
+            self.file.sync_all()?;
+        }
+        // values appended after start of collection are preserved, while snapshots can still reference
+        // original locations of relocated values
+        let _committed = self.committed.write().unwrap();
+        self.invalidate_snapshots();
+        self.vlog.as_ref().unwrap().punch_hole(0, end)?;
+        Ok(relocated)
+    }
+
+    //
+    // Copy values located before `end` position to the end of value log, starting from the key following `after`.
+    // Returns number of relocated values and last visited key (None if there are no more keys).
+    //
+    fn relocate_values(
+        &self,
+        db: &mut Database,
+        after: Option<&[u8]>,
+        end: u64,
+    ) -> Result<(u64, Option<Key>)> {
+        if db.meta.root == 0 {
+            return Ok((0, None));
+        }
+        let start = after.unwrap_or_default();
+        let mut path = self.locate(db.meta.root, start, db.meta.height)?;
+        let mut relocated = 0u64;
+        let mut visited = 0usize;
+        loop {
+            let (pid, mut ip) = path[path.len() - 1];
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let mut page = self.pool_page(pin.buf).write().unwrap();
+            let n = page.get_n_items();
+            if after.is_some() && ip < n && page.compare_key(ip, start) == Ordering::Equal {
+                ip += 1;
+            }
+            while ip < n {
+                let (key, value_ref) = page.get_item(ip);
+                visited += 1;
+                ip += 1;
+                // tombstone contains no reference to value log
+                if self.tombstone_seq(&value_ref).is_none() {
+                    // in tombstone mode reference follows tag
+                    let offs = u64::from_be_bytes(value_ref[value_ref.len() - VALUE_REF_SIZE..][0..8].try_into().unwrap());
+                    if offs < end {
+                        let value = self.load_value(value_ref)?.unwrap();
+                        let value_ref = self.stored_value(db, &value)?;
+                        self.modify_page(db, pin.buf)?;
+                        page.replace_value(ip - 1, &value_ref);
+                        relocated += 1;
                     }
                 }
-                if delayed_commit {
-                    self.commit(&mut db)?;
+                if visited == MERGE_BATCH_SIZE {
+                    return Ok((relocated, Some(key)));
                 }
-                // Sync data file and truncate log in case of normal shutdown
+            }
+            drop(page);
+            if !self.next_leaf(&mut path)? {
+                return Ok((relocated, None));
+            }
+        }
+    }
+
+    ///
+    /// Shrink data file in place: B-Tree pages located beyond the last live page are moved to free pages
+    /// preceding it, and then the file is truncated (in contrast to copying store to new file, no extra space is needed).
+    /// Pages are relocated in one transaction, so crash during compaction leaves store in its original state.
+    /// After commit data file is synced and WAL is truncated, so that images of truncated pages are not replayed.
+    /// Returns number of pages by which data file was shrunk. Please notice that in no-WAL mode all relocated pages
+    /// have to fit in cache.
+    ///
+    #[allow(clippy::unnecessary_cast)] // PageId is u64 with `large-pages` feature
+    pub fn compact_in_place(&self) -> Result<u64> {
+        let mut trans = self.start_transaction();
+        let size = trans.db.meta.size;
+        let new_size = self.relocate_pages(&mut trans.db)?;
+        trans.commit()?;
+        if new_size != size {
+            // relocated pages should be durable before truncation of their original locations
+            if let Some(writer) = &self.writer {
+                writer.sync()?;
+            } else {
                 self.file.sync_all()?;
-                if let Some(log) = &self.log {
-                    log.set_len(0)?; // truncate WAL
-                }
-                db.state = DatabaseState::Closed;
             }
+            if let Some(log) = &self.log {
+                self.clear_wal_end(&mut trans.db)?;
+                trans.db.wal_pos = 0;
+                self.reset_wal(log.as_ref())?;
+            }
+            // snapshots can still reference original locations of relocated pages
+            let _committed = self.committed.write().unwrap();
+            self.invalidate_snapshots();
+            self.file.set_len(page_offset(new_size))?;
         }
-        Ok(())
+        Ok((size - new_size) as u64)
     }
 
     //
-    // Locate greater or equal key.
-    // Returns true and initializes path to this element if such key is found,
-    // reset path and returns false otherwise.
+    // Move B-Tree pages located after the last live page to free pages preceding it, updating references to them.
+    // Free list is discarded, because all free pages either are reused or follow the new end of database.
+    // Returns new size of database (pages).
     //
-    pub fn find(&self, pid: PageId, key: &Key, height: u32) -> Result<Option<Value>> {
-        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
-        let page = self.pool[pin.buf as usize].read().unwrap();
-        let n = page.get_n_items();
-        let mut l: ItemPointer = 0;
-        let mut r = n;
+    fn relocate_pages(&self, db: &mut Database) -> Result<PageId> {
+        let mut live = vec![false; db.meta.size as usize];
+        live[META_PID as usize] = true;
+        if self.conf.key_dictionary.is_some() {
+            live[DICTIONARY_PID as usize] = true;
+        }
+        if db.meta.root != 0 {
+            self.mark_live_pages(db.meta.root, db.meta.height, &mut live)?;
+        }
+        let new_size = live.iter().filter(|used| **used).count() as PageId;
+        if new_size == db.meta.size {
+            // there are no free pages
+            return Ok(new_size);
+        }
+        // number of free pages preceding new end of database is equal to number of live pages following it
+        let mut free = (0..new_size).filter(|pid| !live[*pid as usize]);
+        if db.meta.root >= new_size {
+            db.meta.root = self.relocate_page(db, db.meta.root, free.next().unwrap())?;
+        }
+        if db.meta.height > 1 {
+            self.relocate_children(db, db.meta.root, db.meta.height, new_size, &mut free)?;
+        }
+        debug_assert!(free.next().is_none());
+        db.meta.free = 0;
+        db.meta.size = new_size;
+        db.meta_updated = true;
+        Ok(new_size)
+    }
 
-        while l < r {
-            let m = (l + r) >> 1;
-            if page.compare_key(m, key) == Ordering::Greater {
-                l = m + 1;
-            } else {
-                r = m;
+    //
+    // Mark pages of B-Tree with the specified root as used. Leaf pages are not read.
+    //
+    fn mark_live_pages(&self, pid: PageId, height: u32, live: &mut [bool]) -> Result<()> {
+        if pid == META_PID || pid as usize >= live.len() || live[pid as usize] {
+            anyhow::bail!(StoreError::InvalidPage(pid));
+        }
+        live[pid as usize] = true;
+        if height > 1 {
+            let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+            let page = self.pool_page(pin.buf).read().unwrap();
+            for child in page.children() {
+                self.mark_live_pages(child, height - 1, live)?;
             }
         }
-        debug_assert!(l == r);
-        if height == 1 {
-            // leaf page
-            if r < n {
-                let item = page.get_item(r);
-                if &item.0 == key {
-                    Ok(Some(item.1))
-                } else {
-                    Ok(None)
-                }
-            } else {
-                Ok(None)
+        Ok(())
+    }
+
+    //
+    // Relocate children of internal page which are located at or after `new_size`, taking target pages from `free`
+    //
+    fn relocate_children(
+        &self,
+        db: &mut Database,
+        pid: PageId,
+        height: u32,
+        new_size: PageId,
+        free: &mut impl Iterator<Item = PageId>,
+    ) -> Result<()> {
+        let pin = self.get_page(pid, AccessMode::ReadOnly)?;
+        let mut page = self.pool_page(pin.buf).write().unwrap();
+        for i in 0..page.get_n_items() {
+            let mut child = page.get_child(i);
+            if child >= new_size {
+                child = self.relocate_page(db, child, free.next().unwrap())?;
+                self.modify_page(db, pin.buf)?;
+                page.replace_value(i, &child.to_be_bytes());
             }
-        } else {
-            debug_assert!(r < n);
-            while r < n {
-                debug_assert!(page.get_child(r) != 0);
-                if let Some(val) = self.find(page.get_child(r), key, height - 1)? {
-                    return Ok(Some(val));
-                }
-                r += 1;
+            if height > 2 {
+                self.relocate_children(db, child, height - 1, new_size, free)?;
             }
-            Ok(None)
         }
+        Ok(())
     }
 
-    ///
-    /// Shutdown store. Unlike close it does't commit delayed transactions, flush data file and truncatate WAL.
-    ///
-    pub fn shutdown(&self) -> Result<()> {
-        let mut db = self.db.write().unwrap();
-        anyhow::ensure!(db.state == DatabaseState::Opened);
-        db.state = DatabaseState::Closed;
-        Ok(())
+    //
+    // Copy content of page to the free page and return identifier of the target page
+    //
+    fn relocate_page(&self, db: &mut Database, src: PageId, dst: PageId) -> Result<PageId> {
+        let src_pin = self.get_page(src, AccessMode::ReadOnly)?;
+        let dst_pin = self.get_page(dst, AccessMode::ReadOnly)?;
+        self.modify_page(db, dst_pin.buf)?;
+        let src_page = self.pool_page(src_pin.buf).read().unwrap();
+        let mut dst_page = self.pool_page(dst_pin.buf).write().unwrap();
+        dst_page.data.copy_from_slice(&src_page.data);
+        Ok(dst)
     }
+}
 
-    pub fn get(&self, key: &Key) -> Result<Option<Value>> {
-        let db = self.db.read().unwrap();
-        self.find(db.meta.root, &key, db.meta.height)
+impl fmt::Debug for Store {
+    // Locks are not waited for to avoid deadlock: if lock is held, then correspondent fields are reported as `<locked>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut dbg = f.debug_struct("Store");
+        dbg.field("conf", &self.conf);
+        dbg.field("wal", &self.log.is_some());
+        dbg.field("state", &self.state());
+        if let Ok(db) = self.db.try_read() {
+            dbg.field("size", &db.meta.size)
+                .field("height", &db.meta.height)
+                .field("wal_pos", &db.wal_pos);
+        } else {
+            dbg.field("db", &format_args!("<locked>"));
+        }
+        if let Ok(bm) = self.buffers.buf_mgr.try_lock() {
+            dbg.field("cached", &bm.cached)
+                .field("pinned", &bm.pinned)
+                .field("dirtied", &bm.store(self.store_id).dirtied);
+        } else {
+            dbg.field("buf_mgr", &format_args!("<locked>"));
+        }
+        dbg.finish()
     }
 }
 
 impl Drop for Store {
     fn drop(&mut self) {
         self.close().unwrap();
+        // release buffers of the store in the pool shared with other stores
+        if let Ok(mut bm) = self.buffers.buf_mgr.lock() {
+            bm.unregister(self.store_id);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    //
+    // Path of data file of test store in temporary directory: file left by previous run of the test is removed
+    //
+    fn test_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join("skv-tests");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{name}.db"));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    #[cfg(feature = "diagnostics")]
+    fn forgotten_page_guard_is_reported_by_pin_audit() {
+        let store = Store::open(&test_path("forgotten_page_guard_is_reported_by_pin_audit"), None, StoreConfig::default())
+            .unwrap();
+        let mut trans = store.start_transaction();
+        for i in 0..1000u32 {
+            trans.put(i.to_be_bytes(), [0u8; 100]).unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(store.pin_audit().unwrap(), PinReport::default());
+
+        // leak pins of the root page (transaction below modifies only leaf page)
+        let root = store.db.read().unwrap().meta.root;
+        mem::forget(store.get_page(root, AccessMode::ReadOnly).unwrap());
+        assert_eq!(store.pin_audit().unwrap().pinned, vec![(root, 1)]);
+        mem::forget(store.get_page(root, AccessMode::ReadOnly).unwrap());
+        assert_eq!(store.pin_audit().unwrap().pinned, vec![(root, 2)]);
+        // pin of dirty page held by transaction is not counted
+        let mut trans = store.start_transaction();
+        trans.put(0u32.to_be_bytes(), b"new value").unwrap();
+        assert_eq!(store.stats().dirty_pages, 1);
+        assert_eq!(store.pin_audit().unwrap().pinned, vec![(root, 2)]);
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(store.pin_audit().unwrap().pinned, vec![(root, 2)]);
+        assert_eq!(store.get(0u32.to_be_bytes()).unwrap(), Some(b"new value".to_vec()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn drop_of_read_only_transaction_does_not_lock_buffer_manager() {
+        let path = test_path("drop_of_read_only_transaction_does_not_lock_buffer_manager");
+        let log = path.with_extension("log");
+        let _ = fs::remove_file(&log);
+        let store = Store::open(&path, Some(&log), StoreConfig::default()).unwrap();
+        store.put(b"key", b"value").unwrap();
+        let wal_pos = store.wal_position();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        let store = &store;
+        thread::scope(|scope| {
+            let reader = scope.spawn(move || {
+                let trans = store.start_transaction();
+                assert_eq!(trans.get(b"key").unwrap(), Some(b"value".to_vec()));
+                assert_eq!(trans.range(b"a", b"z").unwrap().len(), 1);
+                ready_tx.send(()).unwrap();
+                locked_rx.recv().unwrap();
+                // buffer manager is locked by the main thread
+                drop(trans);
+                done_tx.send(()).unwrap();
+            });
+            ready_rx.recv().unwrap();
+            let bm = store.buffers.buf_mgr.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            let res = done_rx.recv_timeout(Duration::from_secs(10));
+            drop(bm);
+            reader.join().unwrap();
+            assert!(res.is_ok(), "drop of read-only transaction waits for buffer manager");
+        });
+        assert_eq!(store.wal_position(), wal_pos);
+        assert_eq!(store.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    //
+    // Insert at wrong position (like by buggy `locate_key` or `insert_item`) is detected by the insert itself
+    //
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "is not greater than key of previous item")]
+    fn misplaced_leaf_insert_is_caught() {
+        let store = Store::open(&test_path("misplaced_leaf_insert_is_caught"), None, StoreConfig::default()).unwrap();
+        let mut db = store.db.write().unwrap();
+        let mut page = PageData::new();
+        page.init(PageType::Leaf);
+        for (ip, key) in [b"b", b"d"].iter().enumerate() {
+            assert_eq!(store.btree_insert_in_page(&mut db, &mut page, ip, *key, b"v").unwrap(), None);
+        }
+        // "c" belongs between "b" and "d"
+        let _ = store.btree_insert_in_page(&mut db, &mut page, 0, b"c", b"v");
+    }
+}
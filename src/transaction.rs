@@ -1,14 +1,21 @@
 use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::ops::Bound;
 use std::sync::RwLockWriteGuard;
+use std::sync::atomic::AtomicBool;
+use std::thread;
 
-use crate::{store::{Store, Database}, config::{Key, Value}};
+use crate::{store::{Store, StoreState, Database, Durability, UpsertOutcome, LeafAnchor}, config::{Key, Value}};
 
 ///
 /// Status of transaction
 ///
-#[derive(PartialEq)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum TransactionStatus {
     InProgress,
+    Prepared,
     Committed,
     Aborted,
 }
@@ -21,25 +28,109 @@ pub struct Transaction<'a> {
     pub status: TransactionStatus,
     pub store: &'a Store,
     pub db: RwLockWriteGuard<'a, Database>,
+    pub(crate) write_cache: BTreeMap<Key, Option<Value>>, // buffered updates not yet applied to B-Tree (None for removed key)
+    pub(crate) durability: Durability, // whether commit syncs WAL
 }
 
-impl<'a> Transaction<'_> {
+impl<'a> Transaction<'a> {
     ///
-    /// Commit transaction
+    /// Commit transaction. Returns commit sequence number: it is incremented by each transaction
+    /// which changed the database. Read-only transaction returns sequence number of the last commit.
     ///
-    pub fn commit(&mut self) -> Result<()> {
-        anyhow::ensure!(self.status == TransactionStatus::InProgress);
-        self.store.commit(&mut self.db)?;
+    pub fn commit(&mut self) -> Result<u64> {
+        self.check_status(TransactionStatus::InProgress)?;
+        self.flush_write_cache()?;
+        let seq = self.store.commit(&mut self.db, self.durability, false)?;
         self.status = TransactionStatus::Committed;
+        Ok(seq)
+    }
+
+    ///
+    /// Commit transaction and perform checkpoint: changes are written to the data file which is synced,
+    /// and WAL is restarted from the beginning, so that recovery has nothing to replay.
+    /// Checkpoint is performed while the transaction lock is still held, which is cheaper than separate checkpoint.
+    /// Pages of batched transactions committed before are also written. In no-WAL mode it is the same as `commit`.
+    /// Returns commit sequence number.
+    ///
+    pub fn commit_and_checkpoint(&mut self) -> Result<u64> {
+        self.check_status(TransactionStatus::InProgress)?;
+        self.flush_write_cache()?;
+        let seq = self.store.commit(&mut self.db, self.durability, true)?;
+        self.status = TransactionStatus::Committed;
+        Ok(seq)
+    }
+
+    ///
+    /// Commit work done so far by long-running transaction and continue it in new transaction, so that readers and writers
+    /// waiting for the store lock can proceed in between. It sacrifices atomicity of the whole batch: changes committed
+    /// before the failure of the batch are not undone. Returns new transaction with the same durability,
+    /// which should be used instead of this one.
+    ///
+    pub fn checkpoint_progress(mut self) -> Result<Transaction<'a>> {
+        self.commit()?;
+        let (store, durability) = (self.store, self.durability);
+        drop(self);
+        // let threads waiting for the lock acquire it before it is taken again
+        thread::yield_now();
+        let mut trans = store.start_transaction();
+        trans.set_durability(durability);
+        Ok(trans)
+    }
+
+    ///
+    /// Set durability of transaction commit: `Durability::Immediate` (default) syncs WAL on commit,
+    /// while `Durability::Batched` delays sync, so that it is shared with other batched transactions.
+    /// It has no effect in no-WAL mode.
+    ///
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    ///
+    /// Prepare transaction for two-phase commit: its changes are written to WAL and synced, so that transaction
+    /// survives crash, but are not applied to the database until `commit_prepared` is called.
+    /// After crash prepared transaction which was neither committed nor rolled back is restored by recovery
+    /// and can be obtained by `Store::prepared_transaction`. Requires WAL.
+    ///
+    pub fn prepare(&mut self) -> Result<()> {
+        self.check_status(TransactionStatus::InProgress)?;
+        self.flush_write_cache()?;
+        self.store.prepare(&mut self.db)?;
+        self.status = TransactionStatus::Prepared;
         Ok(())
     }
 
     ///
-    /// Rollback transaction undoing all changes
+    /// Commit prepared transaction. Returns commit sequence number.
+    ///
+    pub fn commit_prepared(&mut self) -> Result<u64> {
+        self.check_status(TransactionStatus::Prepared)?;
+        let seq = self.store.commit(&mut self.db, self.durability, false)?;
+        self.status = TransactionStatus::Committed;
+        Ok(seq)
+    }
+
+    ///
+    /// Rollback prepared transaction undoing all changes. Rollback is recorded in WAL,
+    /// so that transaction is not restored by recovery.
+    ///
+    pub fn rollback_prepared(&mut self) -> Result<()> {
+        self.check_status(TransactionStatus::Prepared)?;
+        self.store.rollback_prepared(&mut self.db)?;
+        self.status = TransactionStatus::Aborted;
+        Ok(())
+    }
+
+    ///
+    /// Rollback transaction undoing all changes. B-Tree is restored to its state before the transaction
+    /// (even if transaction has split the root and increased B-Tree height) and pages allocated by the transaction are released.
     ///
     pub fn rollback(&mut self) -> Result<()> {
-        anyhow::ensure!(self.status == TransactionStatus::InProgress);
-        self.store.rollback(&mut self.db)?;
+        self.check_status(TransactionStatus::InProgress)?;
+        self.write_cache.clear();
+        if self.db.is_modified() {
+            self.store.rollback(&mut self.db)?;
+        }
         self.status = TransactionStatus::Aborted;
         Ok(())
     }
@@ -47,49 +138,464 @@ impl<'a> Transaction<'_> {
     ///
     /// Lookup key in the storage.
     ///
-    pub fn get(&self, key: &Key) -> Result<Option<Value>> {
-        anyhow::ensure!(self.status == TransactionStatus::InProgress);
-        self.store.find(self.db.meta.root, &key, self.db.meta.height)
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Value>> {
+        self.check_status(TransactionStatus::InProgress)?;
+        if let Some(value) = self.write_cache.get(key.as_ref()) {
+            return Ok(value.clone());
+        }
+        self.store
+            .find(self.db.meta.root, key.as_ref(), self.db.meta.height)
+    }
+
+    ///
+    /// Lookup key in the storage, returning `default` if key doesn't exist.
+    ///
+    pub fn get_or(&self, key: impl AsRef<[u8]>, default: Value) -> Result<Value> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    ///
+    /// Lookup key in the storage, returning result of `default` if key doesn't exist.
+    ///
+    pub fn get_or_else(&self, key: impl AsRef<[u8]>, default: impl FnOnce() -> Value) -> Result<Value> {
+        Ok(self.get(key)?.unwrap_or_else(default))
+    }
+
+    ///
+    /// Check if key exists in the storage, including updates made by this transaction.
+    ///
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    ///
+    /// Return items with keys from `start` till `end` (exclusive) in key order, including updates
+    /// made by this transaction. Items are collected before returning, so it is safe to update
+    /// or remove them in this transaction while iterating through the result.
+    /// Returns `StoreError::InvalidRange` if `start` is greater than `end`.
+    ///
+    pub fn range(&self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<Vec<(Key, Value)>> {
+        Store::check_range(start.as_ref(), end.as_ref())?;
+        self.scan_range(start.as_ref(), end.as_ref(), None)
+    }
+
+    ///
+    /// Like `range`, but range with `start` greater than `end` is treated as empty rather than reported as error.
+    ///
+    pub fn range_unchecked(&self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<Vec<(Key, Value)>> {
+        self.scan_range(start.as_ref(), end.as_ref(), None)
+    }
+
+    ///
+    /// Like `range`, but scan is aborted with `StoreError::Cancelled` error as soon as `cancel` flag is set
+    /// (it is checked before reading each leaf page), so it can be used to stop long scan from other thread.
+    ///
+    pub fn range_cancellable(
+        &self,
+        start: impl AsRef<[u8]>,
+        end: impl AsRef<[u8]>,
+        cancel: &AtomicBool,
+    ) -> Result<Vec<(Key, Value)>> {
+        Store::check_range(start.as_ref(), end.as_ref())?;
+        self.scan_range(start.as_ref(), end.as_ref(), Some(cancel))
+    }
+
+    //
+    // Scan range of keys merging B-Tree items with buffered updates
+    //
+    fn scan_range(&self, start: &[u8], end: &[u8], cancel: Option<&AtomicBool>) -> Result<Vec<(Key, Value)>> {
+        self.check_status(TransactionStatus::InProgress)?;
+        let items = self
+            .store
+            .scan_range(self.db.meta.root, self.db.meta.height, start, end, cancel)?;
+        if start >= end || self.write_cache.is_empty() {
+            return Ok(items);
+        }
+        // merge buffered updates which are not yet applied to B-Tree
+        let mut merged: BTreeMap<Key, Value> = items.into_iter().collect();
+        for (key, value) in self
+            .write_cache
+            .range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+        {
+            if let Some(value) = value {
+                merged.insert(key.clone(), value.clone());
+            } else {
+                merged.remove(key);
+            }
+        }
+        Ok(merged.into_iter().collect())
     }
 
     ///
     /// Insert new key in the storage or update existed key as part of this transaction.
     ///
-    pub fn put(&mut self, key: &Key, value: &Value) -> Result<()> {
-        anyhow::ensure!(self.status == TransactionStatus::InProgress);
-        self.store.do_upsert(&mut self.db, key, value)?;
-        Ok(())
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.check_status(TransactionStatus::InProgress)?;
+        if self.store.conf.write_cache_size == 0 {
+            self.store.do_upsert(&mut self.db, key.as_ref(), value.as_ref())?;
+            Ok(())
+        } else {
+            self.store.check_item(key.as_ref(), value.as_ref())?;
+            self.write_cache
+                .insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+            self.check_write_cache()
+        }
+    }
+
+    ///
+    /// Insert new key or update existed key as part of this transaction and report which of them was done
+    /// (with previous value in case of update).
+    ///
+    pub fn put_reporting(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<UpsertOutcome> {
+        self.check_status(TransactionStatus::InProgress)?;
+        let old = if self.store.conf.write_cache_size == 0 {
+            match self.store.do_upsert(&mut self.db, key.as_ref(), value.as_ref())? {
+                Some(old) => self.store.load_value(old)?,
+                None => None,
+            }
+        } else {
+            self.store.check_item(key.as_ref(), value.as_ref())?;
+            let old = self.get(key.as_ref())?;
+            self.write_cache
+                .insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+            self.check_write_cache()?;
+            old
+        };
+        Ok(match old {
+            Some(old) => UpsertOutcome::Updated { old },
+            None => UpsertOutcome::Inserted,
+        })
+    }
+
+    ///
+    /// Update value of existing key as part of this transaction. Unlike `put`, value is replaced in place
+    /// when it fits in the page, so item keeps its position in the page. Returns false (and does nothing)
+    /// if key not exists.
+    ///
+    pub fn update_value(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool> {
+        self.check_status(TransactionStatus::InProgress)?;
+        self.flush_write_cache()?;
+        self.store.do_update(&mut self.db, key.as_ref(), value.as_ref())
+    }
+
+    ///
+    /// Append key which is greater than all keys in the storage (including keys of other prefixes if key prefix is used).
+    /// It is faster than `put` for ascending keys and packs B-Tree pages densely.
+    /// Returns error if key is not greater than the last key.
+    ///
+    pub fn append(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.check_status(TransactionStatus::InProgress)?;
+        self.flush_write_cache()?;
+        self.store.do_append(&mut self.db, key.as_ref(), value.as_ref())
+    }
+
+    ///
+    /// Apply function to all items with keys from `start` till `end` (exclusive) as part of this transaction:
+    /// value of the item is replaced with value returned by `f`, or item is removed if `f` returns `None`.
+    /// Items are collected before they are updated (like by `range`), so updates do not affect the pass.
+    /// Returns number of updated and removed items. Returns `StoreError::InvalidRange` if `start` is greater than `end`.
+    ///
+    pub fn update_range(
+        &mut self,
+        start: impl AsRef<[u8]>,
+        end: impl AsRef<[u8]>,
+        mut f: impl FnMut(&Key, &Value) -> Option<Value>,
+    ) -> Result<u64> {
+        let items = self.range(start, end)?;
+        for (key, value) in &items {
+            match f(key, value) {
+                Some(new_value) => self.put(key, new_value)?,
+                None => self.remove(key)?,
+            }
+        }
+        Ok(items.len() as u64)
+    }
+
+    ///
+    /// Remove items with keys from `start` till `end` (exclusive) for which `pred` returns true as part of this transaction.
+    /// Items are collected before they are removed (like by `range`), so removals do not affect the pass.
+    /// Returns number of removed items. Returns `StoreError::InvalidRange` if `start` is greater than `end`.
+    ///
+    pub fn remove_range_where(
+        &mut self,
+        start: impl AsRef<[u8]>,
+        end: impl AsRef<[u8]>,
+        pred: impl Fn(&Key, &Value) -> bool,
+    ) -> Result<u64> {
+        let mut removed = 0u64;
+        for (key, value) in self.range(start, end)? {
+            if pred(&key, &value) {
+                self.remove(&key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    ///
+    /// Get entry of the key for read-modify-write: value of the key is looked up once and can be
+    /// updated by `Entry::and_modify` or inserted by `Entry::or_insert` within this transaction.
+    ///
+    pub fn entry<'t>(&'t mut self, key: impl AsRef<[u8]>) -> Result<Entry<'t, 'a>> {
+        let value = self.get(key.as_ref())?;
+        Ok(Entry {
+            trans: self,
+            key: key.as_ref().to_vec(),
+            value,
+            modified: false,
+        })
+    }
+
+    ///
+    /// Get cursor for bulk insert of keys which are close to each other (for example sorted keys merged from
+    /// another source): cursor remembers leaf page of the last inserted key, so that next keys belonging to this page
+    /// are inserted in it directly without descending B-Tree from the root.
+    ///
+    pub fn insert_cursor<'t>(&'t mut self) -> Result<InsertCursor<'t, 'a>> {
+        self.check_status(TransactionStatus::InProgress)?;
+        // buffered updates should be applied before B-Tree is modified bypassing write cache
+        self.flush_write_cache()?;
+        Ok(InsertCursor {
+            trans: self,
+            anchor: None,
+        })
     }
 
     ///
     /// Remove key from storage as part of this transaction.
     /// Does nothing if key not exist.
     ///
-    pub fn remove(&mut self, key: &Key) -> Result<()> {
-        anyhow::ensure!(self.status == TransactionStatus::InProgress);
-        self.store.do_remove(&mut self.db, key)?;
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.check_status(TransactionStatus::InProgress)?;
+        if self.store.conf.write_cache_size == 0 {
+            self.store.do_remove(&mut self.db, key.as_ref())?;
+            Ok(())
+        } else {
+            self.store.check_key(key.as_ref())?;
+            self.write_cache.insert(key.as_ref().to_vec(), None);
+            self.check_write_cache()
+        }
+    }
+
+    ///
+    /// Remove key from storage as part of this transaction and return its value,
+    /// or `None` if key not exist. It is cheaper than `get` followed by `remove`.
+    ///
+    pub fn remove_returning(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Value>> {
+        self.check_status(TransactionStatus::InProgress)?;
+        if self.store.conf.write_cache_size == 0 {
+            match self.store.do_remove(&mut self.db, key.as_ref())? {
+                Some(value) => self.store.load_value(value),
+                None => Ok(None),
+            }
+        } else {
+            self.store.check_key(key.as_ref())?;
+            let value = self.get(key.as_ref())?;
+            self.write_cache.insert(key.as_ref().to_vec(), None);
+            self.check_write_cache()?;
+            Ok(value)
+        }
+    }
+
+    //
+    // Check that transaction has the expected status and store is not corrupted
+    //
+    fn check_status(&self, status: TransactionStatus) -> Result<()> {
+        self.store.check_state()?;
+        anyhow::ensure!(self.status == status);
+        Ok(())
+    }
+
+    //
+    // Apply buffered updates to B-Tree if write cache is full
+    //
+    fn check_write_cache(&mut self) -> Result<()> {
+        if self.write_cache.len() >= self.store.conf.write_cache_size {
+            self.flush_write_cache()
+        } else {
+            Ok(())
+        }
+    }
+
+    //
+    // Apply buffered updates to B-Tree in key order
+    //
+    fn flush_write_cache(&mut self) -> Result<()> {
+        for (key, value) in mem::take(&mut self.write_cache) {
+            if let Some(value) = value {
+                self.store.do_upsert(&mut self.db, &key, &value)?;
+            } else {
+                self.store.do_remove(&mut self.db, &key)?;
+            }
+        }
         Ok(())
     }
 
     ///
-    /// Traverse B-Tree, check B-Tree invariants and return total number of keys in B-Tree
+    /// Traverse B-Tree, check B-Tree invariants and return total number of keys in B-Tree.
+    /// Updates buffered in write cache are not yet applied to B-Tree, so they are not taken in account.
     ///
     pub fn verify(&self) -> Result<u64> {
-        anyhow::ensure!(self.status == TransactionStatus::InProgress);
+        self.check_status(TransactionStatus::InProgress)?;
         if self.db.meta.root != 0 {
             let mut prev_key = Vec::new();
             self.store
-                .traverse(self.db.meta.root, &mut prev_key, self.db.meta.height)
+                .traverse(self.db.meta.root, &mut prev_key, self.db.meta.height, None)
+        } else {
+            Ok(0)
+        }
+    }
+
+    ///
+    /// Check B-Tree invariants like `verify`, but only for pages which may contain keys from `start` till `end` (exclusive),
+    /// and return number of keys in this range. It is much cheaper than `verify` for large B-Tree,
+    /// so it can be used to check part of B-Tree affected by recent updates.
+    /// Updates buffered in write cache are not yet applied to B-Tree, so they are not taken in account.
+    /// Returns `StoreError::InvalidRange` if `start` is greater than `end` (range with `start` equal to `end` is empty).
+    ///
+    pub fn verify_range(&self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<u64> {
+        self.check_status(TransactionStatus::InProgress)?;
+        Store::check_range(start.as_ref(), end.as_ref())?;
+        if self.db.meta.root != 0 && start.as_ref() < end.as_ref() {
+            let mut prev_key = Vec::new();
+            self.store.traverse_range(
+                self.db.meta.root,
+                &mut prev_key,
+                self.db.meta.height,
+                &self.store.stored_key(start.as_ref())?,
+                &self.store.stored_key(end.as_ref())?,
+            )
         } else {
             Ok(0)
         }
     }
 }
 
+///
+/// Entry of the key in transaction (see `Transaction::entry`). Changes made by `and_modify` are written
+/// to the storage by `or_insert`, `or_insert_with` or `apply`: entry dropped without calling them changes nothing.
+///
+pub struct Entry<'t, 'a> {
+    trans: &'t mut Transaction<'a>,
+    key: Key,
+    value: Option<Value>,
+    modified: bool,
+}
+
+impl Entry<'_, '_> {
+    ///
+    /// Key of the entry
+    ///
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    ///
+    /// Current value of the entry (None if key doesn't exist)
+    ///
+    pub fn get(&self) -> Option<&Value> {
+        self.value.as_ref()
+    }
+
+    ///
+    /// Modify value if key exists. Does nothing if key doesn't exist.
+    ///
+    pub fn and_modify(mut self, f: impl FnOnce(&mut Value)) -> Self {
+        if let Some(value) = &mut self.value {
+            f(value);
+            self.modified = true;
+        }
+        self
+    }
+
+    ///
+    /// Insert `default` value if key doesn't exist and return value of the key
+    /// (saving value changed by `and_modify` if key exists).
+    ///
+    pub fn or_insert(self, default: impl AsRef<[u8]>) -> Result<Value> {
+        self.or_insert_with(|| default.as_ref().to_vec())
+    }
+
+    ///
+    /// Insert value produced by `default` if key doesn't exist and return value of the key
+    /// (saving value changed by `and_modify` if key exists). `default` is not called if key exists.
+    ///
+    pub fn or_insert_with(mut self, default: impl FnOnce() -> Value) -> Result<Value> {
+        if self.value.is_none() {
+            self.value = Some(default());
+            self.modified = true;
+        }
+        self.apply()?;
+        Ok(self.value.unwrap())
+    }
+
+    ///
+    /// Save value changed by `and_modify`
+    ///
+    pub fn apply(&mut self) -> Result<()> {
+        if self.modified {
+            if let Some(value) = &self.value {
+                self.trans.put(&self.key, value)?;
+            }
+            self.modified = false;
+        }
+        Ok(())
+    }
+}
+
+///
+/// Cursor inserting keys in the leaf page of the previously inserted key (see `Transaction::insert_cursor`).
+/// If key doesn't belong to this page or doesn't fit in it, then key is inserted from the root of B-Tree
+/// (splitting pages if needed) and cursor is moved to the leaf page containing this key.
+///
+pub struct InsertCursor<'t, 'a> {
+    trans: &'t mut Transaction<'a>,
+    anchor: Option<LeafAnchor>,
+}
+
+impl InsertCursor<'_, '_> {
+    ///
+    /// Insert new key or update existed key as part of the transaction
+    ///
+    pub fn insert(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        self.trans.check_status(TransactionStatus::InProgress)?;
+        self.trans
+            .store
+            .do_upsert_at(&mut self.trans.db, &mut self.anchor, key.as_ref(), value.as_ref())
+    }
+}
+
+impl fmt::Debug for Transaction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("status", &self.status)
+            .field("root", &self.db.meta.root)
+            .field("height", &self.db.meta.height)
+            .field("cached", &self.write_cache.len())
+            .finish()
+    }
+}
+
 impl<'a> Drop for Transaction<'a> {
     fn drop(&mut self) {
-        if self.status == TransactionStatus::InProgress {
-            self.store.rollback(&mut self.db).unwrap();
+        // nothing can be rolled back in corrupted store: error is already reported by the failed operation
+        if self.store.state() == StoreState::Corrupted {
+            return;
+        }
+        let res = match self.status {
+            // read-only transaction has nothing to roll back: just release the lock
+            TransactionStatus::InProgress if !self.db.is_modified() => Ok(()),
+            TransactionStatus::InProgress => self.store.rollback(&mut self.db),
+            TransactionStatus::Prepared => self.store.rollback_prepared(&mut self.db),
+            _ => Ok(()),
+        };
+        // rollback can fail because of detected corruption
+        if self.store.state() != StoreState::Corrupted {
+            res.unwrap();
         }
     }
 }
@@ -0,0 +1,106 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crate::storage::Storage;
+
+struct SyncState {
+    deadline: Option<Instant>, // time by which WAL written by batched transactions should be synced
+    error: Option<io::Error>,  // first sync error (reported by next commit)
+    stop: bool,
+}
+
+//
+// Background sync of WAL written by transactions committed with `Durability::Batched`.
+// Commit of batched transaction schedules sync, so that it is durable after `StoreConfig::batched_sync_interval`
+// even if no other transaction is committed. Pages of synced transactions remain deferred in memory:
+// they are written to the data file by the next commit performing sync or by `Store::sync_batched`.
+//
+pub struct WalSyncer {
+    state: Arc<(Mutex<SyncState>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WalSyncer {
+    //
+    // Start syncer thread. If `sync_data` is true, then WAL is synced by `sync_data` instead of `sync_all`
+    // (WAL is preallocated).
+    //
+    pub fn start(log: Arc<dyn Storage>, sync_data: bool) -> WalSyncer {
+        let state = Arc::new((
+            Mutex::new(SyncState {
+                deadline: None,
+                error: None,
+                stop: false,
+            }),
+            Condvar::new(),
+        ));
+        let thread = {
+            let state = state.clone();
+            thread::spawn(move || {
+                let (lock, cvar) = &*state;
+                let mut state = lock.lock().unwrap();
+                while !state.stop {
+                    match state.deadline {
+                        None => state = cvar.wait(state).unwrap(),
+                        Some(deadline) if deadline > Instant::now() => {
+                            state = cvar.wait_timeout(state, deadline - Instant::now()).unwrap().0;
+                        }
+                        Some(_) => {
+                            // WAL written after this point schedules one more sync
+                            state.deadline = None;
+                            drop(state);
+                            let res = if sync_data { log.sync_data() } else { log.sync_all() };
+                            state = lock.lock().unwrap();
+                            if let Err(err) = res {
+                                state.error.get_or_insert(err);
+                            }
+                        }
+                    }
+                }
+            })
+        };
+        WalSyncer {
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    //
+    // Schedule sync of WAL by the specified time (unless it is already scheduled earlier)
+    //
+    pub fn schedule(&self, deadline: Instant) {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if state.deadline.is_none_or(|scheduled| scheduled > deadline) {
+            state.deadline = Some(deadline);
+            cvar.notify_one();
+        }
+    }
+
+    //
+    // Cancel scheduled sync: WAL was synced by commit
+    //
+    pub fn cancel(&self) {
+        self.state.0.lock().unwrap().deadline = None;
+    }
+
+    //
+    // Take error of background sync (if any)
+    //
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.state.0.lock().unwrap().error.take()
+    }
+}
+
+impl Drop for WalSyncer {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().stop = true;
+        cvar.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::Result;
+
+use crate::config::{page_offset, PageId};
+use crate::pagedata::PageData;
+use crate::storage::Storage;
+
+enum Request {
+    Write(Vec<(PageId, Arc<PageData>)>), // write committed page images to the data file
+    Sync(Sender<io::Result<()>>),        // sync data file after all previous writes are completed
+}
+
+//
+// Images of pages committed but not yet written to the data file
+//
+type PendingPages = Arc<Mutex<HashMap<PageId, Arc<PageData>>>>;
+
+//
+// Background writer of committed pages to the data file.
+// Committed pages are already durable in WAL, so write of them to the data file can be delayed.
+// Until page image is written, it is kept in the map of pending pages and should be used instead of
+// (stale) page from the data file.
+//
+pub struct BackgroundWriter {
+    pending: PendingPages,
+    error: Arc<Mutex<Option<io::Error>>>, // first write error (reported by sync)
+    sender: Option<Sender<Request>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    pub fn start(file: Arc<dyn Storage>) -> BackgroundWriter {
+        let (sender, receiver) = channel::<Request>();
+        let pending: PendingPages = Arc::new(Mutex::new(HashMap::new()));
+        let error = Arc::new(Mutex::new(None));
+        let thread = {
+            let pending = pending.clone();
+            let error = error.clone();
+            thread::spawn(move || {
+                for req in receiver {
+                    match req {
+                        Request::Write(images) => {
+                            for (pid, image) in images {
+                                if let Err(err) =
+                                    file.write_all_at(&image.data, page_offset(pid))
+                                {
+                                    // leave image in pending map, so that it is still visible to readers
+                                    error.lock().unwrap().get_or_insert(err);
+                                    continue;
+                                }
+                                let mut pending = pending.lock().unwrap();
+                                // remove image unless it was replaced with more recent one
+                                if matches!(pending.get(&pid), Some(last) if Arc::ptr_eq(last, &image)) {
+                                    pending.remove(&pid);
+                                }
+                            }
+                        }
+                        Request::Sync(ack) => {
+                            let res = match error.lock().unwrap().take() {
+                                Some(err) => Err(err),
+                                None => file.sync_all(),
+                            };
+                            let _ = ack.send(res);
+                        }
+                    }
+                }
+            })
+        };
+        BackgroundWriter {
+            pending,
+            error,
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    //
+    // Schedule write of page images. They are visible through `read` until written.
+    //
+    pub fn write(&self, images: Vec<(PageId, Arc<PageData>)>) -> Result<()> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            for (pid, image) in images.iter() {
+                pending.insert(*pid, image.clone());
+            }
+        }
+        self.sender.as_ref().unwrap().send(Request::Write(images))?;
+        Ok(())
+    }
+
+    //
+    // Copy image of page not yet written to the data file. Returns false if there is no such image.
+    //
+    pub fn read(&self, pid: PageId, page: &mut PageData) -> bool {
+        if let Some(image) = self.pending.lock().unwrap().get(&pid) {
+            page.data.copy_from_slice(&image.data);
+            true
+        } else {
+            false
+        }
+    }
+
+    //
+    // Wait until all scheduled writes are completed and sync data file
+    //
+    pub fn sync(&self) -> Result<()> {
+        if let Some(err) = self.error.lock().unwrap().take() {
+            return Err(err.into());
+        }
+        let (ack, done) = channel();
+        self.sender.as_ref().unwrap().send(Request::Sync(ack))?;
+        done.recv()??;
+        Ok(())
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        // closing channel stops the thread after all scheduled writes are completed
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
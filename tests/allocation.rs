@@ -0,0 +1,109 @@
+mod common;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use common::{fill, key, verify, TestFiles};
+use skv::{AllocationPolicy, Storage, Store, StoreConfig};
+
+const PAGE_SIZE: u64 = 8192;
+const N_KEYS: u32 = 50000;
+
+fn value(i: u32) -> Vec<u8> {
+    vec![i as u8; 100]
+}
+
+//
+// Data file recording identifiers of read pages
+//
+struct ReadLog {
+    file: File,
+    reads: Arc<Mutex<Vec<u64>>>,
+}
+
+impl Storage for ReadLog {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        self.reads.lock().unwrap().push(offs / PAGE_SIZE);
+        Storage::read_at(&self.file, buf, offs)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        Storage::write_all_at(&self.file, buf, offs)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Storage::size(&self.file)
+    }
+}
+
+//
+// Build tree with holes left by removed keys, append new keys and return number of non-sequential
+// page reads performed by scan of the appended keys (with cold cache) and size of the data file
+//
+fn appended_keys_locality(files: &TestFiles, allocation: AllocationPolicy) -> (usize, u64) {
+    let conf = || StoreConfig {
+        allocation,
+        ..StoreConfig::default()
+    };
+    let store = files.open_without_wal(conf());
+    fill(&store, 0..N_KEYS, value);
+    // remove alternating chunks of several leaf pages, so that free pages are scattered over the file
+    let mut trans = store.start_transaction();
+    for i in (0..N_KEYS).filter(|i| i / 200 % 2 == 0) {
+        trans.remove(key(i)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    fill(&store, N_KEYS..N_KEYS * 3 / 2, value);
+    drop(store);
+
+    let reads = Arc::new(Mutex::new(Vec::new()));
+    let storage = ReadLog {
+        file: OpenOptions::new().read(true).write(true).open(&files.db).unwrap(),
+        reads: reads.clone(),
+    };
+    let store = Store::open_with_storage(Box::new(storage), None, conf()).unwrap();
+    assert_eq!(verify(&store), N_KEYS as u64);
+    store.trim_cache(0).unwrap();
+    reads.lock().unwrap().clear();
+    let items = store.range_filtered(key(N_KEYS), key(N_KEYS * 3 / 2), |_| true).unwrap();
+    assert_eq!(items.len(), N_KEYS as usize / 2);
+    let reads = reads.lock().unwrap();
+    let jumps = reads.windows(2).filter(|pair| pair[1] != pair[0] + 1).count();
+    (jumps, std::fs::metadata(&files.db).unwrap().len())
+}
+
+#[test]
+fn append_first_places_appended_leaves_sequentially() {
+    let reuse = appended_keys_locality(&TestFiles::new("allocation_reuse_first"), AllocationPolicy::ReuseFirst);
+    let append = appended_keys_locality(
+        &TestFiles::new("allocation_append_first"),
+        AllocationPolicy::AppendFirst { max_size: 1_000_000 },
+    );
+    // scan of appended keys reads pages scattered over holes or contiguous run of pages at the end of file
+    // (except internal pages of B-Tree path)
+    assert!(reuse.0 > 100, "{reuse:?}");
+    assert!(append.0 < 10, "{append:?}");
+    // holes are not reused, so file is larger
+    assert!(append.1 > reuse.1, "{reuse:?} {append:?}");
+
+    // size limit is already reached: free pages are reused
+    let limited = appended_keys_locality(
+        &TestFiles::new("allocation_append_first_limited"),
+        AllocationPolicy::AppendFirst { max_size: 1 },
+    );
+    assert_eq!(limited, reuse);
+}
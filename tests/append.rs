@@ -0,0 +1,253 @@
+mod common;
+
+use common::{fill, key, verify, TestFiles};
+use skv::{Store, StoreConfig};
+
+//
+// Insert ascending keys by `append` or `put` in one transaction
+//
+fn load(store: &Store, n: u32, append: bool) {
+    let mut trans = store.start_transaction();
+    for i in 0..n {
+        if append {
+            trans.append(key(i), i.to_be_bytes()).unwrap();
+        } else {
+            trans.put(key(i), i.to_be_bytes()).unwrap();
+        }
+    }
+    trans.commit().unwrap();
+}
+
+#[test]
+fn append_packs_pages_densely() {
+    const N: u32 = 100_000;
+    let mut leaves = Vec::new();
+    for append in [false, true] {
+        let files = TestFiles::new("append_packs_pages_densely");
+        let store = files.open(StoreConfig::default());
+        load(&store, N, append);
+        assert_eq!(verify(&store), N as u64);
+        assert_eq!(store.get(key(N / 2)).unwrap(), Some((N / 2).to_be_bytes().to_vec()));
+        let summary = store.structure_summary().unwrap();
+        leaves.push(summary.levels.last().unwrap().pages);
+    }
+    // pages filled by `append` are left full (like by `put` of ascending keys): 11 bytes key with its length,
+    // 4 bytes value and 2 bytes offset take 18 bytes, so ~450 items fit in 8kb page
+    assert!(leaves[1] <= leaves[0], "{leaves:?}");
+    assert!(leaves[1] <= (N / 440) as u64, "{leaves:?}");
+}
+
+#[test]
+fn append_rejects_not_greater_key() {
+    let files = TestFiles::new("append_rejects_not_greater_key");
+    let store = files.open(StoreConfig::default());
+    load(&store, 1000, true);
+    let mut trans = store.start_transaction();
+    assert!(trans.append(key(999), b"value").is_err());
+    assert!(trans.append(key(500), b"value").is_err());
+    trans.append(key(1000), b"value").unwrap();
+    assert!(trans.append(key(1000), b"value").is_err());
+    trans.commit().unwrap();
+    drop(trans);
+    assert_eq!(verify(&store), 1001);
+}
+
+#[test]
+#[ignore = "slow: run with `cargo test --release -- --ignored`"]
+fn append_of_million_keys_packs_pages_densely() {
+    const N: u32 = 1_000_000;
+    let mut leaves = Vec::new();
+    for append in [false, true] {
+        let files = TestFiles::new("append_of_million_keys_packs_pages_densely");
+        let store = files.open(StoreConfig::default());
+        load(&store, N, append);
+        assert_eq!(verify(&store), N as u64);
+        leaves.push(store.structure_summary().unwrap().levels.last().unwrap().pages);
+    }
+    assert!(leaves[1] <= leaves[0], "{leaves:?}");
+    assert!(leaves[1] <= (N / 440) as u64, "{leaves:?}");
+}
+
+//
+// Stream of items in the format expected by `Store::create_from_sorted`
+//
+fn sorted_stream(keys: impl Iterator<Item = u32>) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for i in keys {
+        for field in [key(i), i.to_be_bytes().to_vec()] {
+            stream.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            stream.extend_from_slice(&field);
+        }
+    }
+    stream
+}
+
+fn create(files: &TestFiles, stream: &[u8]) -> anyhow::Result<Store> {
+    Store::create_from_sorted(&files.db, Some(&files.log), StoreConfig::default(), stream)
+}
+
+#[test]
+fn store_is_created_from_sorted_stream() {
+    const N: u32 = 100_000;
+    let files = TestFiles::new("store_is_created_from_sorted_stream");
+    let store = create(&files, &sorted_stream(0..N)).unwrap();
+    assert_eq!(verify(&store), N as u64);
+    drop(store);
+    let store = files.open(StoreConfig::default());
+    assert_eq!(verify(&store), N as u64);
+    for i in (0..N).step_by(997) {
+        assert_eq!(store.get(key(i)).unwrap(), Some(i.to_be_bytes().to_vec()));
+    }
+    assert!(store.structure_summary().unwrap().levels.last().unwrap().pages <= (N / 440) as u64);
+    drop(store);
+    // only empty store can be loaded
+    assert!(create(&files, &sorted_stream(N..N + 1)).is_err());
+
+    let files = TestFiles::new("store_is_created_from_empty_stream");
+    assert_eq!(verify(&create(&files, &[]).unwrap()), 0);
+}
+
+#[test]
+fn invalid_sorted_streams_are_rejected() {
+    let duplicate = sorted_stream([0, 1, 2, 2, 3].into_iter());
+    let unsorted = sorted_stream([0, 1, 3, 2].into_iter());
+    let mut truncated = sorted_stream(0..3);
+    truncated.truncate(truncated.len() - 2);
+    let mut huge_key = sorted_stream(0..1);
+    huge_key.extend_from_slice(&u32::MAX.to_be_bytes());
+    for stream in [duplicate, unsorted, truncated, huge_key] {
+        let files = TestFiles::new("invalid_sorted_streams_are_rejected");
+        assert!(create(&files, &stream).is_err());
+    }
+}
+
+#[test]
+#[ignore = "slow: run with `cargo test --release -- --ignored`"]
+fn create_from_sorted_million_keys() {
+    const N: u32 = 1_000_000;
+    let stream = sorted_stream(0..N);
+    let files = TestFiles::new("create_from_sorted_million_keys");
+    let created = create(&files, &stream).unwrap();
+    assert_eq!(verify(&created), N as u64);
+
+    let files = TestFiles::new("create_from_sorted_million_keys_put");
+    let store = files.open(StoreConfig::default());
+    for i in 0..N {
+        store.put(key(i), i.to_be_bytes()).unwrap();
+    }
+    assert_eq!(verify(&store), N as u64);
+    // the same content in pages filled at least as densely as by puts
+    assert_eq!(created.content_hash().unwrap(), store.content_hash().unwrap());
+    let leaves = |store: &Store| store.structure_summary().unwrap().levels.last().unwrap().pages;
+    assert!(leaves(&created) <= leaves(&store));
+}
+
+//
+// Insert ascending keys in batches of the given size by `put_many_sorted` or by `put` of each key
+// in transaction per batch and return number of pages at each level of B-Tree
+//
+fn load_batches(store: &Store, n: u32, batch_size: u32, sorted_batches: bool) -> Vec<u64> {
+    for start in (0..n).step_by(batch_size as usize) {
+        let batch: Vec<_> = (start..(start + batch_size).min(n)).map(|i| (key(i), i.to_be_bytes())).collect();
+        if sorted_batches {
+            store.put_many_sorted(&batch).unwrap();
+        } else {
+            let mut trans = store.start_transaction();
+            for (k, v) in batch {
+                trans.put(k, v).unwrap();
+            }
+            trans.commit().unwrap();
+        }
+    }
+    store.structure_summary().unwrap().levels.iter().map(|level| level.pages).collect()
+}
+
+#[test]
+fn sorted_batches_build_the_same_tree_as_puts() {
+    const N: u32 = 100_000;
+    let files = TestFiles::new("sorted_batches_put");
+    let store = files.open(StoreConfig::default());
+    let put_levels = load_batches(&store, N, 1000, false);
+    let files = TestFiles::new("sorted_batches");
+    let batched = files.open(StoreConfig::default());
+    let batched_levels = load_batches(&batched, N, 1000, true);
+    assert_eq!(batched_levels, put_levels);
+    assert_eq!(batched.content_hash().unwrap(), store.content_hash().unwrap());
+    assert_eq!(verify(&batched), N as u64);
+
+    // batches which are not sorted or overlap with existing keys are inserted one by one
+    batched.put_many_sorted(&[(key(N + 2), "2"), (key(N + 1), "1")]).unwrap();
+    batched.put_many_sorted(&[(key(10), "10"), (key(N + 3), "3")]).unwrap();
+    batched.put_many_sorted(&[(key(N + 4), "4"), (key(N + 4), "4")]).unwrap();
+    batched.put_many_sorted::<Vec<u8>, Vec<u8>>(&[]).unwrap();
+    assert_eq!(verify(&batched), N as u64 + 4);
+    assert_eq!(batched.get(key(10)).unwrap(), Some(b"10".to_vec()));
+    for i in 1..=4 {
+        assert_eq!(batched.get(key(N + i)).unwrap(), Some(i.to_string().into_bytes()));
+    }
+}
+
+#[test]
+#[ignore = "slow: run with `cargo test --release -- --ignored`"]
+fn sorted_batches_of_million_keys_build_the_same_tree_as_puts() {
+    const N: u32 = 1_000_000;
+    let mut trees = Vec::new();
+    for sorted_batches in [false, true] {
+        let files = TestFiles::new("sorted_batches_of_million_keys_build_the_same_tree_as_puts");
+        let store = files.open(StoreConfig::default());
+        let levels = load_batches(&store, N, 10_000, sorted_batches);
+        assert_eq!(verify(&store), N as u64);
+        trees.push((levels, store.content_hash().unwrap()));
+    }
+    assert_eq!(trees[0], trees[1]);
+}
+
+//
+// Insert keys in the specified order in one transaction by insert cursor or by puts
+//
+fn merge_keys(store: &Store, keys: impl Iterator<Item = u32>, by_cursor: bool) {
+    let mut trans = store.start_transaction();
+    if by_cursor {
+        let mut cursor = trans.insert_cursor().unwrap();
+        for i in keys {
+            cursor.insert(key(i), i.to_be_bytes()).unwrap();
+        }
+    } else {
+        for i in keys {
+            trans.put(key(i), i.to_be_bytes()).unwrap();
+        }
+    }
+    trans.commit().unwrap();
+}
+
+#[test]
+fn insert_cursor_merges_sorted_keys() {
+    const N: u32 = 50_000;
+    let mut hashes = Vec::new();
+    for by_cursor in [false, true] {
+        let files = TestFiles::new("insert_cursor_merges_sorted_keys");
+        let store = files.open(StoreConfig::default());
+        // even keys are stored, odd keys are merged in between them, then some keys are updated
+        fill(&store, 0..N, |i| vec![i as u8; 10]);
+        let mut trans = store.start_transaction();
+        for i in (1..N).step_by(2) {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        merge_keys(&store, (1..N).step_by(2), by_cursor);
+        merge_keys(&store, (0..N).step_by(10), by_cursor);
+        // keys which are not sorted and are outside of the tree
+        merge_keys(&store, [N + 10, N, 5, 3, N + 5].into_iter(), by_cursor);
+        assert_eq!(verify(&store), N as u64 + 3);
+        for i in 0..N {
+            let expected = if i % 2 == 1 || i % 10 == 0 { i.to_be_bytes().to_vec() } else { vec![i as u8; 10] };
+            assert_eq!(store.get(key(i)).unwrap(), Some(expected), "key {i}");
+        }
+        hashes.push(store.content_hash().unwrap());
+        drop(store);
+        // cursor inserts are logged and recovered like puts
+        assert_eq!(verify(&files.open(StoreConfig::default())), N as u64 + 3);
+    }
+    assert_eq!(hashes[0], hashes[1]);
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::{open_store, verify};
+
+//
+// Allocator counting allocations, so that test can check that ingestion doesn't copy keys and values
+//
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn ingest_from_borrowed_buffer() {
+    const N: usize = 20000;
+    const KEY_LEN: usize = 12;
+    const VALUE_LEN: usize = 20;
+    // sorted records laid out in one buffer like in memory mapped file
+    let mut input = Vec::with_capacity(N * (KEY_LEN + VALUE_LEN));
+    for i in 0..N {
+        input.extend_from_slice(format!("key{i:09}").as_bytes());
+        input.extend_from_slice(&[i as u8; VALUE_LEN]);
+    }
+    let store = open_store("ingest_from_borrowed_buffer");
+    let mut trans = store.start_transaction();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for record in input.chunks(KEY_LEN + VALUE_LEN) {
+        let (key, value) = record.split_at(KEY_LEN);
+        trans.put(key, value).unwrap();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    trans.commit().unwrap();
+    drop(trans);
+    // keys and values are copied directly to pages: allocations are performed only by page splits
+    assert!(allocations < N / 10, "{allocations} allocations for {N} keys");
+    assert_eq!(verify(&store), N as u64);
+    assert_eq!(store.get(&input[..KEY_LEN]).unwrap(), Some(vec![0u8; VALUE_LEN]));
+}
+
+#[test]
+fn lookup_by_slice_literals() {
+    let store = open_store("lookup_by_slice_literals");
+    store.put(b"alpha", b"1").unwrap();
+    store.put(b"beta", b"2").unwrap();
+    assert_eq!(store.get(b"alpha").unwrap(), Some(b"1".to_vec()));
+    assert!(store.contains_key(b"beta").unwrap());
+    assert!(!store.contains_key(b"gamma").unwrap());
+    // slice of a longer buffer: prefix of existing key is not found
+    assert!(!store.contains_key(&b"alphabet"[..4]).unwrap());
+
+    let mut trans = store.start_transaction();
+    trans.remove(b"alpha").unwrap();
+    trans.put(b"gamma", b"3").unwrap();
+    // transaction sees its own updates, store sees only committed state
+    assert!(!trans.contains_key(b"alpha").unwrap());
+    assert!(trans.contains_key(b"gamma").unwrap());
+    assert!(store.contains_key(b"alpha").unwrap());
+    assert!(!store.contains_key(b"gamma").unwrap());
+    trans.commit().unwrap();
+    drop(trans);
+    assert!(!store.contains_key(b"alpha").unwrap());
+    assert_eq!(store.get(b"gamma").unwrap(), Some(b"3".to_vec()));
+}
+
+#[test]
+fn string_literals_as_keys_and_values() {
+    let store = open_store("string_literals_as_keys_and_values");
+    store.put("apple", "red").unwrap();
+    store.put(String::from("banana"), "yellow").unwrap();
+    // `Key` and `Value` aliases are still accepted
+    let (key, value): (skv::Key, skv::Value) = (b"cherry".to_vec(), b"dark red".to_vec());
+    store.put(&key, &value).unwrap();
+    store.put(key.clone(), value).unwrap();
+
+    assert_eq!(store.get("apple").unwrap(), Some(b"red".to_vec()));
+    assert_eq!(store.get(String::from("banana")).unwrap(), Some(b"yellow".to_vec()));
+    assert_eq!(store.get(&key).unwrap(), Some(b"dark red".to_vec()));
+    assert_eq!(store.get("durian").unwrap(), None);
+
+    let mut trans = store.start_transaction();
+    trans.put("durian", "green").unwrap();
+    trans.remove("apple").unwrap();
+    assert_eq!(trans.get("durian").unwrap(), Some(b"green".to_vec()));
+    assert_eq!(trans.range("a", "c").unwrap(), vec![(b"banana".to_vec(), b"yellow".to_vec())]);
+    trans.commit().unwrap();
+    drop(trans);
+    store.remove("banana").unwrap();
+    assert_eq!(verify(&store), 2);
+    assert_eq!(store.get("apple").unwrap(), None);
+}
@@ -0,0 +1,463 @@
+mod common;
+
+use common::{fill, key, open_store, verify, TestFiles};
+use std::sync::Arc;
+
+use skv::{ConflictPolicy, Store, StoreConfig, UpsertOutcome};
+
+#[test]
+fn empty_key_is_rejected() {
+    let store = open_store("empty_key_is_rejected");
+    assert!(store.put(b"", b"value").is_err());
+    let mut trans = store.start_transaction();
+    assert!(trans.put(b"", b"value").is_err());
+    trans.put(b"k", b"value").unwrap();
+    trans.commit().unwrap();
+    drop(trans);
+    assert_eq!(verify(&store), 1);
+
+    // key prefix doesn't make empty user key valid
+    let files = TestFiles::new("empty_key_is_rejected_with_prefix");
+    let store = files.open(StoreConfig {
+        key_prefix: Some(b"tenant/".to_vec()),
+        ..StoreConfig::default()
+    });
+    assert!(store.put(b"", b"value").is_err());
+}
+
+#[test]
+fn remove_validates_key_like_put() {
+    for write_cache_size in [0, 100] {
+        let files = TestFiles::new("remove_validates_key_like_put");
+        let store = files.open(StoreConfig {
+            key_prefix: Some(b"tenant/".to_vec()),
+            write_cache_size,
+            ..StoreConfig::default()
+        });
+        // key fits in item by itself, but not together with key prefix
+        let long_key = vec![b'k'; 255 - 3];
+        assert!(store.put(&long_key, b"value").is_err());
+        assert!(store.remove(&long_key).is_err());
+        assert!(store.remove(b"").is_err());
+        let mut trans = store.start_transaction();
+        assert!(trans.remove(&long_key).is_err());
+        assert!(trans.remove_returning(&long_key).is_err());
+        assert!(trans.remove(b"").is_err());
+        trans.put(b"k", b"value").unwrap();
+        trans.remove(b"k").unwrap();
+        trans.commit().unwrap();
+    }
+}
+
+#[test]
+fn largest_keys_are_found_through_inf_separators() {
+    let store = open_store("largest_keys_are_found_through_inf_separators");
+    fill(&store, 0..20000, |i| vec![i as u8; 20]);
+    let largest = vec![u8::MAX; 255];
+    store.put(&largest, b"largest").unwrap();
+    store.put([1u8], b"smallest").unwrap();
+
+    let summary = store.structure_summary().unwrap();
+    assert!(summary.height >= 2);
+    // every internal page on the right-most path ends with +inf separator
+    assert!(summary.inf_separators >= (summary.height - 1) as u64);
+    assert_eq!(verify(&store), 20002);
+    assert_eq!(store.get(&largest).unwrap(), Some(b"largest".to_vec()));
+    assert_eq!(store.get([1u8]).unwrap(), Some(b"smallest".to_vec()));
+    assert_eq!(store.get(key(19999)).unwrap(), Some(vec![(19999 % 256) as u8; 20]));
+}
+
+#[test]
+fn empty_tree_is_handled_by_public_entry_points() {
+    let store = open_store("empty_tree_is_handled_by_public_entry_points");
+    // the first time tree is empty because it was never created, the second time because all keys are removed
+    for round in 0..2 {
+        assert_eq!(store.get(key(0)).unwrap(), None, "round {round}");
+        assert!(!store.contains_key(key(0)).unwrap());
+        store.remove(key(0)).unwrap();
+        assert_eq!(verify(&store), 0);
+        assert!(store.range_filtered(key(0), key(100), |_| true).unwrap().is_empty());
+        assert_eq!(store.scan_page(None, 10).unwrap(), (Vec::new(), None));
+        assert_eq!(store.prefix_stats(b"key").unwrap().keys, 0);
+        assert!(store.scrub().unwrap().bad_pages.is_empty());
+        store.structure_summary().unwrap();
+        store.content_hash().unwrap();
+        store.warmup().unwrap();
+        let snapshot = store.snapshot().unwrap();
+        assert_eq!(snapshot.get(key(0)).unwrap(), None);
+        assert!(snapshot.range(key(0), key(100)).unwrap().is_empty());
+        drop(snapshot);
+        let mut trans = store.start_transaction();
+        assert_eq!(trans.verify().unwrap(), 0);
+        assert!(trans.range(key(0), key(100)).unwrap().is_empty());
+        assert_eq!(trans.remove_returning(key(0)).unwrap(), None);
+        trans.commit().unwrap();
+        drop(trans);
+
+        fill(&store, 0..1000, |_| vec![1u8; 10]);
+        let mut trans = store.start_transaction();
+        for i in 0..1000 {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+    }
+}
+
+//
+// Invoke every read and write operation on empty store and check that results are empty
+//
+fn check_empty_store(store: &Store, other: &Store) {
+    assert_eq!(verify(store), 0);
+    assert_eq!(store.get(key(1)).unwrap(), None);
+    assert_eq!(store.get_or(key(1), b"default".to_vec()).unwrap(), b"default");
+    assert!(!store.contains_key(key(1)).unwrap());
+    assert_eq!(store.scan_page(Some(&key(1)), 10).unwrap(), (Vec::new(), None));
+    assert!(store.range_filtered(key(0), key(9), |_| true).unwrap().is_empty());
+    assert_eq!(store.prefix_stats(b"").unwrap().keys, 0);
+    assert!(store.structure_summary().unwrap().levels.len() <= 1);
+    assert_eq!(store.warmup_range(key(0), key(9)).unwrap(), 0);
+    assert!(store.scrub().unwrap().bad_pages.is_empty());
+    assert_eq!(store.content_hash().unwrap(), other.content_hash().unwrap());
+    // merge of empty stores doesn't change anything
+    assert_eq!(store.merge_from(other, ConflictPolicy::KeepExisting).unwrap(), 0);
+
+    // rollback of updates of empty tree leaves store empty and doesn't leak pinned pages
+    let pinned = store.stats().pinned_pages;
+    let mut trans = store.start_transaction();
+    assert!(trans.range(key(0), key(9)).unwrap().is_empty());
+    for i in 0..1000 {
+        trans.put(key(i), [1u8]).unwrap();
+    }
+    trans.rollback().unwrap();
+    drop(trans);
+    assert_eq!(store.stats().pinned_pages, pinned);
+    assert_eq!(verify(store), 0);
+
+    // put and remove restore empty tree
+    store.put(key(1), b"value").unwrap();
+    assert_eq!(store.get(key(1)).unwrap(), Some(b"value".to_vec()));
+    store.remove(key(1)).unwrap();
+    store.remove(key(1)).unwrap();
+    assert_eq!(store.get(key(1)).unwrap(), None);
+    assert_eq!(verify(store), 0);
+}
+
+#[test]
+fn every_operation_on_empty_store() {
+    let other = open_store("every_operation_on_empty_store_other");
+    for with_wal in [true, false] {
+        let files = TestFiles::new("every_operation_on_empty_store");
+        let open = || {
+            if with_wal {
+                files.open(StoreConfig::default())
+            } else {
+                files.open_without_wal(StoreConfig::default())
+            }
+        };
+        // new store
+        let store = open();
+        check_empty_store(&store, &other);
+        // store emptied by removal of all keys
+        fill(&store, 0..5000, |_| vec![0u8; 50]);
+        let mut trans = store.start_transaction();
+        for i in 0..5000 {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        check_empty_store(&store, &other);
+        // emptied store after reopen
+        drop(store);
+        check_empty_store(&open(), &other);
+    }
+}
+
+#[test]
+fn remove_returning_returns_removed_value() {
+    let modes = [
+        StoreConfig::default(),
+        StoreConfig {
+            write_cache_size: 10,
+            ..StoreConfig::default()
+        },
+        StoreConfig {
+            value_log: true,
+            ..StoreConfig::default()
+        },
+        StoreConfig {
+            tombstones: true,
+            ..StoreConfig::default()
+        },
+    ];
+    for conf in modes {
+        let files = TestFiles::new("remove_returning_returns_removed_value");
+        // tombstones of removed keys remain in B-Tree
+        let keys = if conf.tombstones { 1000 } else { 499 };
+        let store = files.open(conf);
+        fill(&store, 0..1000, |i| vec![i as u8; i as usize % 50 + 1]);
+        let mut trans = store.start_transaction();
+        for i in (0..1000).step_by(2) {
+            assert_eq!(trans.remove_returning(key(i)).unwrap(), Some(vec![i as u8; i as usize % 50 + 1]));
+            // key is already removed
+            assert_eq!(trans.remove_returning(key(i)).unwrap(), None);
+        }
+        assert_eq!(trans.remove_returning(key(5000)).unwrap(), None);
+        // value updated by this transaction is returned
+        trans.put(key(1), b"updated").unwrap();
+        assert_eq!(trans.remove_returning(key(1)).unwrap(), Some(b"updated".to_vec()));
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(verify(&store), keys);
+        assert_eq!(store.get(key(0)).unwrap(), None);
+        assert_eq!(store.get(key(3)).unwrap(), Some(vec![3u8; 4]));
+    }
+}
+
+#[test]
+fn put_reporting_distinguishes_insert_and_update() {
+    for write_cache_size in [0, 10] {
+        let files = TestFiles::new("put_reporting_distinguishes_insert_and_update");
+        let store = files.open(StoreConfig {
+            write_cache_size,
+            ..StoreConfig::default()
+        });
+        fill(&store, 0..1000, |i| vec![i as u8; 100]);
+        let mut trans = store.start_transaction();
+        for i in 0..2000 {
+            let outcome = trans.put_reporting(key(i), b"new").unwrap();
+            if i < 1000 {
+                assert_eq!(outcome, UpsertOutcome::Updated { old: vec![i as u8; 100] });
+            } else {
+                assert_eq!(outcome, UpsertOutcome::Inserted);
+            }
+        }
+        // updates of this transaction are reported as well
+        assert_eq!(
+            trans.put_reporting(key(1500), b"newer").unwrap(),
+            UpsertOutcome::Updated { old: b"new".to_vec() }
+        );
+        trans.remove(key(0)).unwrap();
+        assert_eq!(trans.put_reporting(key(0), b"again").unwrap(), UpsertOutcome::Inserted);
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(verify(&store), 2000);
+        assert_eq!(store.get(key(1500)).unwrap(), Some(b"newer".to_vec()));
+    }
+}
+
+#[test]
+fn shared_values_are_cached_until_write() {
+    let files = TestFiles::new("shared_values_are_cached_until_write");
+    let store = files.open(StoreConfig {
+        value_cache_size: 10,
+        ..StoreConfig::default()
+    });
+    fill(&store, 0..100, |i| vec![i as u8; 20]);
+    let first = store.get_shared(key(1)).unwrap().unwrap();
+    assert_eq!(&*first, &[1u8; 20]);
+    assert!(Arc::ptr_eq(&first, &store.get_shared(key(1)).unwrap().unwrap()));
+    assert_eq!(store.get_shared(key(1000)).unwrap(), None);
+
+    // write invalidates cached value
+    store.put(key(1), b"updated").unwrap();
+    let updated = store.get_shared(key(1)).unwrap().unwrap();
+    assert!(!Arc::ptr_eq(&first, &updated));
+    assert_eq!(&*updated, b"updated");
+    assert!(Arc::ptr_eq(&updated, &store.get_shared(key(1)).unwrap().unwrap()));
+    // uncommitted changes are not seen, rolled back transaction leaves value unchanged
+    let mut trans = store.start_transaction();
+    trans.put(key(1), b"uncommitted").unwrap();
+    trans.rollback().unwrap();
+    drop(trans);
+    assert_eq!(&*store.get_shared(key(1)).unwrap().unwrap(), b"updated");
+    store.remove(key(1)).unwrap();
+    assert_eq!(store.get_shared(key(1)).unwrap(), None);
+
+    // overflow of cache doesn't affect returned values
+    for round in 0..2 {
+        for i in 2..100 {
+            assert_eq!(&*store.get_shared(key(i)).unwrap().unwrap(), &[i as u8; 20], "round {round}");
+        }
+    }
+    drop(store);
+
+    // without value cache each read returns new allocation
+    let store = files.open(StoreConfig::default());
+    let value = store.get_shared(key(2)).unwrap().unwrap();
+    assert!(!Arc::ptr_eq(&value, &store.get_shared(key(2)).unwrap().unwrap()));
+}
+
+#[test]
+fn update_value_of_various_sizes() {
+    let store = open_store("update_value_of_various_sizes");
+    fill(&store, 0..10000, |_| vec![1u8; 50]);
+    let mut trans = store.start_transaction();
+    assert!(!trans.update_value(key(10000), b"missing").unwrap());
+    // same size, smaller and larger values (the largest ones don't fit in leaf pages, so they are split)
+    for (i, len) in [50, 10, 0, 100, 1000, 2000].into_iter().enumerate() {
+        for k in (i as u32..10000).step_by(6) {
+            assert!(trans.update_value(key(k), vec![2u8; len]).unwrap());
+        }
+    }
+    let expected = |k: u32| vec![2u8; [50, 10, 0, 100, 1000, 2000][k as usize % 6]];
+    for k in 0..10000 {
+        assert_eq!(trans.get(key(k)).unwrap(), Some(expected(k)), "key {k}");
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    assert_eq!(verify(&store), 10000);
+    for k in 0..10000 {
+        assert_eq!(store.get(key(k)).unwrap(), Some(expected(k)), "key {k}");
+    }
+    assert_eq!(store.get(key(10000)).unwrap(), None);
+}
+
+#[test]
+fn rollback_of_root_split_restores_tree() {
+    for with_wal in [true, false] {
+        let files = TestFiles::new("rollback_of_root_split_restores_tree");
+        let open = |files: &TestFiles| {
+            if with_wal {
+                files.open(StoreConfig::default())
+            } else {
+                files.open_without_wal(StoreConfig::default())
+            }
+        };
+        let store = open(&files);
+        // root is a leaf page, transaction splits it and then grows tree further
+        fill(&store, 0..100, |i| vec![i as u8; 10]);
+        let summary = store.structure_summary().unwrap();
+        assert_eq!(summary.height, 1);
+        let hash = store.content_hash().unwrap();
+        let checked_pages = store.scrub().unwrap().checked_pages;
+        let pinned = store.stats().pinned_pages;
+
+        let mut trans = store.start_transaction();
+        for i in 100..50_000 {
+            trans.put(key(i), vec![i as u8; 10]).unwrap();
+        }
+        assert_eq!(trans.verify().unwrap(), 50_000);
+        trans.rollback().unwrap();
+        drop(trans);
+
+        assert_eq!(store.stats().pinned_pages, pinned, "with_wal={with_wal}");
+        assert_eq!(store.structure_summary().unwrap().height, 1);
+        assert_eq!(store.content_hash().unwrap(), hash);
+        assert_eq!(store.scrub().unwrap().checked_pages, checked_pages);
+        assert_eq!(verify(&store), 100);
+        assert_eq!(store.get(key(100)).unwrap(), None);
+        drop(store);
+
+        // size of the store is restored, so pages allocated by transaction are not leaked:
+        // repeating the transaction produces the same file as without rollback
+        let store = open(&files);
+        assert_eq!(store.structure_summary().unwrap().height, 1);
+        assert_eq!(store.content_hash().unwrap(), hash);
+        fill(&store, 100..50_000, |i| vec![i as u8; 10]);
+        assert_eq!(verify(&store), 50_000);
+        drop(store);
+        let reference = TestFiles::new("rollback_of_root_split_reference");
+        let store = open(&reference);
+        fill(&store, 0..100, |i| vec![i as u8; 10]);
+        fill(&store, 100..50_000, |i| vec![i as u8; 10]);
+        drop(store);
+        assert_eq!(
+            std::fs::metadata(&files.db).unwrap().len(),
+            std::fs::metadata(&reference.db).unwrap().len(),
+            "with_wal={with_wal}"
+        );
+    }
+}
+
+#[test]
+fn streamed_lookups_match_gets() {
+    let store = open_store("streamed_lookups_match_gets");
+    fill(&store, 0..10000, |i| i.to_be_bytes().to_vec());
+    store.remove(key(500)).unwrap();
+    let keys: Vec<Vec<u8>> = (0..20000).step_by(7).map(key).chain([key(500), key(3)]).collect();
+    let mut streamed = Vec::new();
+    store.get_stream(keys.clone(), |k, value| streamed.push((k, value.unwrap())));
+    let expected: Vec<_> = keys.iter().map(|k| (k.clone(), store.get(k).unwrap())).collect();
+    assert_eq!(streamed, expected);
+    // removed key 500 is not found, key 3 is found in addition to multiples of 7
+    assert_eq!(streamed.iter().filter(|(_, value)| value.is_some()).count(), (0..10000).step_by(7).count() + 1);
+
+    // error of one lookup is passed to sink and doesn't stop others
+    let mut results = Vec::new();
+    store.get_stream([key(1), Vec::new(), key(2)], |_, value| results.push(value.is_ok()));
+    assert_eq!(results.len(), 3);
+    assert!(results[0] && results[2]);
+}
+
+#[test]
+fn entry_combinators() {
+    let store = open_store("entry_combinators");
+    store.put(key(1), [1u8]).unwrap();
+    let mut trans = store.start_transaction();
+    let increment = |value: &mut Vec<u8>| value[0] += 1;
+
+    // and_modify of present key, or_insert is not used
+    let entry = trans.entry(key(1)).unwrap();
+    assert_eq!((entry.key(), entry.get()), (&key(1)[..], Some(&vec![1u8])));
+    assert_eq!(entry.and_modify(increment).or_insert([100u8]).unwrap(), [2u8]);
+    // and_modify of missing key does nothing, default is inserted
+    assert_eq!(trans.entry(key(2)).unwrap().and_modify(increment).or_insert([100u8]).unwrap(), [100u8]);
+    // or_insert_with calls default only for missing key
+    assert_eq!(trans.entry(key(3)).unwrap().or_insert_with(|| vec![3u8]).unwrap(), [3u8]);
+    assert_eq!(trans.entry(key(3)).unwrap().or_insert_with(|| panic!("key exists")).unwrap(), [3u8]);
+    // apply saves modification without inserting missing key
+    let mut entry = trans.entry(key(1)).unwrap().and_modify(increment);
+    entry.apply().unwrap();
+    drop(entry);
+    let mut entry = trans.entry(key(4)).unwrap().and_modify(increment);
+    entry.apply().unwrap();
+    drop(entry);
+    assert_eq!(trans.get(key(4)).unwrap(), None);
+    // entry dropped without saving changes nothing
+    drop(trans.entry(key(1)).unwrap().and_modify(increment));
+    assert_eq!(trans.get(key(1)).unwrap(), Some(vec![3u8]));
+    trans.commit().unwrap();
+    drop(trans);
+
+    assert_eq!(store.get(key(1)).unwrap(), Some(vec![3u8]));
+    assert_eq!(store.get(key(2)).unwrap(), Some(vec![100u8]));
+    assert_eq!(store.get(key(3)).unwrap(), Some(vec![3u8]));
+    assert_eq!(verify(&store), 3);
+}
+
+#[test]
+fn get_with_default() {
+    let store = open_store("get_with_default");
+    fill(&store, 0..100, |i| vec![i as u8; 10]);
+    let default = || b"default".to_vec();
+    let calls = std::cell::Cell::new(0);
+    let lazy = || {
+        calls.set(calls.get() + 1);
+        default()
+    };
+    // present key
+    assert_eq!(store.get_or(key(1), default()).unwrap(), vec![1u8; 10]);
+    assert_eq!(store.get_or_else(key(1), lazy).unwrap(), vec![1u8; 10]);
+    assert_eq!(calls.get(), 0);
+    // absent key
+    assert_eq!(store.get_or(key(100), default()).unwrap(), default());
+    assert_eq!(store.get_or_else(key(100), lazy).unwrap(), default());
+    assert_eq!(calls.get(), 1);
+
+    // transaction sees its own updates
+    let mut trans = store.start_transaction();
+    trans.remove(key(1)).unwrap();
+    trans.put(key(100), [100u8]).unwrap();
+    assert_eq!(trans.get_or(key(1), default()).unwrap(), default());
+    assert_eq!(trans.get_or_else(key(1), lazy).unwrap(), default());
+    assert_eq!(calls.get(), 2);
+    assert_eq!(trans.get_or(key(100), default()).unwrap(), [100u8]);
+    assert_eq!(trans.get_or_else(key(100), lazy).unwrap(), [100u8]);
+    assert_eq!(trans.get_or(key(2), default()).unwrap(), vec![2u8; 10]);
+    assert_eq!(calls.get(), 2);
+    trans.rollback().unwrap();
+    drop(trans);
+    assert_eq!(store.get_or(key(1), default()).unwrap(), vec![1u8; 10]);
+    assert_eq!(store.get_or_else(key(100), lazy).unwrap(), default());
+    assert_eq!(calls.get(), 3);
+}
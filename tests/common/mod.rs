@@ -0,0 +1,77 @@
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+use skv::{Store, StoreConfig};
+
+//
+// Files of test store: data file, WAL and value log with the specified name in temporary directory
+//
+pub struct TestFiles {
+    pub db: PathBuf,
+    pub log: PathBuf,
+}
+
+impl TestFiles {
+    //
+    // Paths of files of new test store: files left by previous run of the test are removed
+    //
+    pub fn new(name: &str) -> TestFiles {
+        let dir = std::env::temp_dir().join("skv-tests");
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = TestFiles {
+            db: dir.join(format!("{name}.db")),
+            log: dir.join(format!("{name}.log")),
+        };
+        for path in [&files.db, &files.log, &files.db.with_extension("vlog")] {
+            let _ = std::fs::remove_file(path);
+        }
+        files
+    }
+
+    //
+    // Open store with WAL
+    //
+    pub fn open(&self, conf: StoreConfig) -> Store {
+        Store::open(&self.db, Some(&self.log), conf).unwrap()
+    }
+
+    //
+    // Open store without WAL
+    //
+    pub fn open_without_wal(&self, conf: StoreConfig) -> Store {
+        Store::open(&self.db, None, conf).unwrap()
+    }
+}
+
+//
+// Open new store with WAL and default configuration
+//
+pub fn open_store(name: &str) -> Store {
+    TestFiles::new(name).open(StoreConfig::default())
+}
+
+//
+// Key with the specified number preserving numeric order
+//
+pub fn key(i: u32) -> Vec<u8> {
+    format!("key{i:08}").into_bytes()
+}
+
+//
+// Put keys from the range with values produced by `value` in one transaction
+//
+pub fn fill(store: &Store, keys: std::ops::Range<u32>, value: impl Fn(u32) -> Vec<u8>) {
+    let mut trans = store.start_transaction();
+    for i in keys {
+        trans.put(key(i), value(i)).unwrap();
+    }
+    trans.commit().unwrap();
+}
+
+//
+// Check B-Tree invariants of the last committed state and return number of keys
+//
+pub fn verify(store: &Store) -> u64 {
+    store.verify_cancellable(&std::sync::atomic::AtomicBool::new(false)).unwrap()
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use common::{fill, key, verify, TestFiles};
+use skv::{Storage, Store, StoreConfig};
+
+fn value(i: u32) -> Vec<u8> {
+    vec![i as u8; 100]
+}
+
+#[test]
+fn compaction_shrinks_file_after_deletes() {
+    for with_wal in [true, false] {
+        let files = TestFiles::new("compaction_shrinks_file_after_deletes");
+        let open = || {
+            if with_wal {
+                files.open(StoreConfig::default())
+            } else {
+                files.open_without_wal(StoreConfig::default())
+            }
+        };
+        let store = open();
+        fill(&store, 0..20000, value);
+        // remove every other key and the whole upper half, so that live pages are scattered
+        let mut trans = store.start_transaction();
+        for i in (0..10000).step_by(2).chain(10000..20000) {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        let size = std::fs::metadata(&files.db).unwrap().len();
+        let shrunk = store.compact_in_place().unwrap();
+        assert!(shrunk > 0);
+        let new_size = std::fs::metadata(&files.db).unwrap().len();
+        assert_eq!(size - new_size, shrunk * 8192, "with WAL: {with_wal}");
+        assert!(store.scrub().unwrap().bad_pages.is_empty());
+        // nothing more to compact
+        assert_eq!(store.compact_in_place().unwrap(), 0);
+        drop(store);
+
+        let store = open();
+        assert_eq!(verify(&store), 5000);
+        for i in 0..20000 {
+            let expected = (i < 10000 && i % 2 == 1).then(|| value(i));
+            assert_eq!(store.get(key(i)).unwrap(), expected, "key {i}");
+        }
+        // store continues to grow from the new end of file
+        fill(&store, 20000..30000, value);
+        assert_eq!(verify(&store), 15000);
+    }
+}
+
+//
+// Crash point shared by data file and WAL: mutating operations of both files are counted,
+// and all operations starting from the specified one are lost, like on power failure
+//
+#[derive(Clone, Default)]
+struct CrashPoint(Arc<Mutex<(u64, Option<u64>)>>);
+
+impl CrashPoint {
+    //
+    // Count operation and check whether it should be performed
+    //
+    fn survives(&self) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.0 += 1;
+        state.1.is_none_or(|crash| state.0 <= crash)
+    }
+
+    fn operations(&self) -> u64 {
+        self.0.lock().unwrap().0
+    }
+
+    fn crash_after(&self, n: u64) {
+        let mut state = self.0.lock().unwrap();
+        state.1 = Some(state.0 + n);
+    }
+}
+
+struct CrashingStorage {
+    file: File,
+    crash: CrashPoint,
+}
+
+impl Storage for CrashingStorage {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        Storage::read_at(&self.file, buf, offs)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        if self.crash.survives() {
+            Storage::write_all_at(&self.file, buf, offs)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        if self.crash.survives() {
+            self.file.sync_all()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        if self.crash.survives() {
+            self.file.sync_data()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        if self.crash.survives() {
+            self.file.set_len(size)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Storage::size(&self.file)
+    }
+}
+
+#[test]
+fn crash_during_compaction() {
+    let prepare = |files: &TestFiles| {
+        let crash = CrashPoint::default();
+        let open = |path| CrashingStorage {
+            file: OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap(),
+            crash: crash.clone(),
+        };
+        let store =
+            Store::open_with_storage(Box::new(open(&files.db)), Some(Box::new(open(&files.log))), StoreConfig::default())
+                .unwrap();
+        fill(&store, 0..5000, |i| vec![i as u8; 100]);
+        let mut trans = store.start_transaction();
+        for i in (0..2500).step_by(2).chain(2500..5000) {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        (store, crash)
+    };
+    let files = TestFiles::new("crash_during_compaction");
+    let total = {
+        let (store, crash) = prepare(&files);
+        let before = crash.operations();
+        assert!(store.compact_in_place().unwrap() > 0);
+        crash.operations() - before
+    };
+    for n in (0..total).step_by((total as usize / 20).max(1)) {
+        let files = TestFiles::new("crash_during_compaction");
+        {
+            let (store, crash) = prepare(&files);
+            crash.crash_after(n);
+            store.compact_in_place().unwrap();
+        }
+        // store is either compacted or not, but all keys are preserved
+        let store = files.open(StoreConfig::default());
+        assert!(store.scrub().unwrap().bad_pages.is_empty(), "crash after {n} operations");
+        assert_eq!(verify(&store), 1250, "crash after {n} operations");
+        for i in (1..2500).step_by(2) {
+            assert_eq!(store.get(key(i)).unwrap(), Some(vec![i as u8; 100]));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::{fill, key, verify, TestFiles};
+use skv::{Store, StoreConfig};
+
+const N_KEYS: u32 = 20000;
+
+fn compressed() -> StoreConfig {
+    StoreConfig {
+        page_compression: true,
+        ..StoreConfig::default()
+    }
+}
+
+// values with a lot of redundancy, like typical text or JSON records
+fn value(i: u32) -> Vec<u8> {
+    format!("{{\"id\":{i},\"name\":\"user{i}\",\"status\":\"active\",\"padding\":\"{}\"}}", "x".repeat(100)).into_bytes()
+}
+
+fn check(store: &Store, keys: impl Iterator<Item = u32>) {
+    for i in keys {
+        assert_eq!(store.get(key(i)).unwrap(), Some(value(i)), "key {i}");
+    }
+}
+
+#[test]
+fn compression_reduces_file_size() {
+    let plain = TestFiles::new("compression_plain");
+    let store = plain.open(StoreConfig::default());
+    fill(&store, 0..N_KEYS, value);
+    store.close().unwrap();
+    drop(store);
+
+    let files = TestFiles::new("compression_compressed");
+    let store = files.open(compressed());
+    fill(&store, 0..N_KEYS, value);
+    check(&store, 0..N_KEYS);
+    store.close().unwrap();
+    drop(store);
+
+    let plain_size = std::fs::metadata(&plain.db).unwrap().len();
+    let compressed_size = std::fs::metadata(&files.db).unwrap().len();
+    assert!(
+        compressed_size * 3 < plain_size * 2,
+        "compressed file {compressed_size} is not smaller than 2/3 of plain file {plain_size}"
+    );
+
+    let store = files.open(compressed());
+    assert_eq!(verify(&store), N_KEYS as u64);
+    check(&store, 0..N_KEYS);
+}
+
+#[test]
+fn updated_pages_are_found_after_reopen() {
+    let files = TestFiles::new("compression_updates");
+    let store = files.open_without_wal(compressed());
+    fill(&store, 0..N_KEYS, value);
+    // rewrite pages several times, so that the file contains stale images of them
+    for round in 0..3 {
+        let mut trans = store.start_transaction();
+        for i in (round..N_KEYS).step_by(3) {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        fill(&store, 0..N_KEYS, value);
+    }
+    drop(store);
+    let store = files.open_without_wal(compressed());
+    assert_eq!(verify(&store), N_KEYS as u64);
+    check(&store, 0..N_KEYS);
+}
+
+#[test]
+fn compacted_store_is_reopened() {
+    let files = TestFiles::new("compression_compact");
+    let store = files.open(compressed());
+    fill(&store, 0..N_KEYS, value);
+    let mut trans = store.start_transaction();
+    for i in N_KEYS / 4..N_KEYS {
+        trans.remove(key(i)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    let size = std::fs::metadata(&files.db).unwrap().len();
+    assert!(store.compact_in_place().unwrap() > 0);
+    assert!(std::fs::metadata(&files.db).unwrap().len() < size);
+    drop(store);
+    let store = files.open(compressed());
+    assert_eq!(verify(&store), (N_KEYS / 4) as u64);
+    check(&store, 0..N_KEYS / 4);
+    assert_eq!(store.get(key(N_KEYS / 4)).unwrap(), None);
+}
+
+#[test]
+fn compression_mode_can_not_be_changed() {
+    let files = TestFiles::new("compression_mode");
+    fill(&files.open(compressed()), 0..100, value);
+    assert!(Store::open(&files.db, Some(&files.log), StoreConfig::default()).is_err());
+    let plain = TestFiles::new("compression_mode_plain");
+    fill(&plain.open(StoreConfig::default()), 0..100, value);
+    assert!(Store::open(&plain.db, Some(&plain.log), compressed()).is_err());
+}
+
+#[test]
+fn compressed_store_is_repaired() {
+    let files = TestFiles::new("compression_repair");
+    let store = files.open(compressed());
+    fill(&store, 0..N_KEYS, value);
+    store.close().unwrap();
+    drop(store);
+    let dest = TestFiles::new("compression_repair_dest");
+    let report = Store::repair(&files.db, &dest.db, StoreConfig::default()).unwrap();
+    assert_eq!(report.unreadable_pages, 0);
+    let store = dest.open(StoreConfig::default());
+    assert_eq!(verify(&store), N_KEYS as u64);
+    check(&store, 0..N_KEYS);
+}
+
+#[test]
+fn compressed_store_is_replaced() {
+    let files = TestFiles::new("compression_replace");
+    let store = files.open(compressed());
+    fill(&store, 0..100, |_| b"old".to_vec());
+    let new_files = TestFiles::new("compression_replace_new");
+    fill(&new_files.open(compressed()), 0..N_KEYS, value);
+    // page map should be reconstructed from the new data file
+    store.replace_with(&new_files.db, Some(&new_files.log)).unwrap();
+    assert_eq!(verify(&store), N_KEYS as u64);
+    check(&store, 0..N_KEYS);
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::{fill, key, open_store, verify, TestFiles};
+use skv::{Store, StoreConfig, StoreHandle};
+
+#[test]
+fn concurrent_put_and_get() {
+    const THREADS: u32 = 8;
+    const KEYS: u32 = 2000;
+    let store = Arc::new(open_store("concurrent_put_and_get"));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                // each thread writes its own keys and reads keys of other threads
+                for i in 0..KEYS {
+                    let k = i * THREADS + t;
+                    store.put(key(k), k.to_be_bytes()).unwrap();
+                    let other = key(i * THREADS + (t + 1) % THREADS);
+                    if let Some(value) = store.get(&other).unwrap() {
+                        assert_eq!(value, (i * THREADS + (t + 1) % THREADS).to_be_bytes());
+                    }
+                    assert_eq!(store.get(key(k)).unwrap(), Some(k.to_be_bytes().to_vec()));
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(verify(&store), (THREADS * KEYS) as u64);
+    for k in 0..THREADS * KEYS {
+        assert_eq!(store.get(key(k)).unwrap(), Some(k.to_be_bytes().to_vec()));
+    }
+}
+
+#[test]
+fn concurrent_loads_of_distinct_pages() {
+    const THREADS: u32 = 16;
+    const KEYS: u32 = 50000;
+    let files = TestFiles::new("concurrent_loads_of_distinct_pages");
+    fill(&files.open(StoreConfig::default()), 0..KEYS, |i| vec![i as u8; 200]);
+    let conf = |busy_events| StoreConfig {
+        cache_size: 64, // much smaller than the tree, so that pages are permanently reloaded
+        busy_events,
+        ..StoreConfig::default()
+    };
+    assert!(Store::open(&files.db, Some(&files.log), conf(0)).is_err());
+    for busy_events in [1, 8, 256] {
+        let store = Arc::new(files.open(conf(busy_events)));
+        let (done, finished) = mpsc::channel();
+        let mut threads = Vec::new();
+        for t in 0..THREADS {
+            let store = store.clone();
+            let done = done.clone();
+            threads.push(thread::spawn(move || {
+                // each thread scans its own part of the tree, so that threads wait for loads of different pages,
+                // and also reads keys of the other threads
+                let part = KEYS / THREADS;
+                for i in 0..part {
+                    for k in [t * part + i, (t * part + i * 7919) % KEYS] {
+                        assert_eq!(store.get(key(k)).unwrap(), Some(vec![k as u8; 200]));
+                    }
+                }
+                done.send(()).unwrap();
+            }));
+        }
+        for _ in 0..THREADS {
+            finished
+                .recv_timeout(Duration::from_secs(120))
+                .unwrap_or_else(|_| panic!("readers are blocked with {busy_events} busy events"));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(store.stats().pinned_pages <= 1);
+    }
+}
+
+#[test]
+fn get_does_not_wait_for_write_transaction() {
+    let store = Arc::new(open_store("get_does_not_wait_for_write_transaction"));
+    fill(&store, 0..5000, |_| vec![1u8; 100]);
+    // long-lived transaction modifies (and splits) pages read by other threads
+    let mut trans = store.start_transaction();
+    for i in 0..5000 {
+        trans.put(key(i), vec![2u8; 200]).unwrap();
+    }
+    let (done, finished) = mpsc::channel();
+    let readers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                for i in (t..5000).step_by(4) {
+                    // last committed state is read
+                    assert_eq!(store.get(key(i)).unwrap(), Some(vec![1u8; 100]));
+                }
+                assert_eq!(store.get(key(5000)).unwrap(), None);
+                done.send(()).unwrap();
+            })
+        })
+        .collect();
+    for _ in 0..readers.len() {
+        finished.recv_timeout(Duration::from_secs(60)).expect("reader is blocked by write transaction");
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8; 200]));
+}
+
+#[test]
+fn handles_share_one_store() {
+    const THREADS: u32 = 4;
+    const KEYS: u32 = 1000;
+    let files = TestFiles::new("handles_share_one_store");
+    let handle = StoreHandle::new(files.open(StoreConfig::default()));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let handle = handle.clone();
+            thread::spawn(move || {
+                // write transactions of different handles are serialized
+                for i in (0..KEYS).step_by(100) {
+                    let mut trans = handle.start_transaction();
+                    for k in i..i + 100 {
+                        trans.put(key(k * THREADS + t), [t as u8]).unwrap();
+                    }
+                    trans.commit().unwrap();
+                }
+                handle.handle_count()
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert!(thread.join().unwrap() >= 2);
+    }
+    assert_eq!(handle.handle_count(), 1);
+    // changes made through any handle are visible through others
+    let other = handle.clone();
+    assert_eq!(verify(&other), (THREADS * KEYS) as u64);
+    for k in 0..THREADS * KEYS {
+        assert_eq!(handle.get(key(k)).unwrap(), Some(vec![(k % THREADS) as u8]));
+    }
+    // store is closed when the last handle is dropped
+    drop(handle);
+    assert!(Store::open(&files.db, Some(&files.log), StoreConfig::default()).is_err());
+    drop(other);
+    assert_eq!(verify(&files.open(StoreConfig::default())), (THREADS * KEYS) as u64);
+}
+
+#[test]
+fn long_batch_yields_to_other_writers() {
+    const BATCH: u32 = 100;
+    const MAX_BATCHES: u32 = 1000;
+    let store = open_store("long_batch_yields_to_other_writers");
+    let marker = b"marker".to_vec();
+    thread::scope(|scope| {
+        let (started, start) = mpsc::channel();
+        let (store, marker) = (&store, &marker);
+        let writer = scope.spawn(move || {
+            start.recv().unwrap();
+            // other writer waits for the lock held by the batch
+            let mut trans = store.start_transaction();
+            // it sees progress of the batch committed so far
+            let committed = trans.range(key(0), key(BATCH * MAX_BATCHES)).unwrap().len() as u32;
+            trans.put(marker, committed.to_be_bytes()).unwrap();
+            trans.commit().unwrap();
+            committed
+        });
+        let mut trans = store.start_transaction();
+        let mut batches = 0;
+        while batches < MAX_BATCHES && trans.get(marker).unwrap().is_none() {
+            for i in batches * BATCH..(batches + 1) * BATCH {
+                trans.put(key(i), [1u8]).unwrap();
+            }
+            batches += 1;
+            if batches == 1 {
+                started.send(()).unwrap();
+            }
+            trans = trans.checkpoint_progress().unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        let committed = writer.join().unwrap();
+        assert!(batches < MAX_BATCHES, "other writer made no progress during the batch");
+        assert!(committed > 0 && committed % BATCH == 0 && committed <= batches * BATCH, "{committed} of {batches} batches");
+        assert_eq!(store.get(marker).unwrap(), Some(committed.to_be_bytes().to_vec()));
+        assert_eq!(verify(store), (batches * BATCH + 1) as u64);
+    });
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use common::{fill, key, TestFiles};
+use skv::{Store, StoreConfig};
+
+fn value(i: u32) -> Vec<u8> {
+    vec![i as u8; (i % 100) as usize]
+}
+
+fn open(name: &str) -> Store {
+    TestFiles::new(name).open(StoreConfig::default())
+}
+
+#[test]
+fn content_hash_does_not_depend_on_layout() {
+    const N: u32 = 20000;
+    let ascending = open("content_hash_ascending");
+    fill(&ascending, 0..N, value);
+
+    let descending = open("content_hash_descending");
+    let mut trans = descending.start_transaction();
+    for i in (0..N).rev() {
+        trans.put(key(i), value(i)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+
+    // keys inserted in pseudo-random order with extra keys removed later and compaction
+    let shuffled = open("content_hash_shuffled");
+    let mut trans = shuffled.start_transaction();
+    for i in 0..2 * N {
+        let k = (i * 7919) % (2 * N);
+        trans.put(key(k), if k < N { value(k) } else { b"extra".to_vec() }).unwrap();
+    }
+    for k in N..2 * N {
+        trans.remove(key(k)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    shuffled.compact_in_place().unwrap();
+
+    let hash = ascending.content_hash().unwrap();
+    assert_eq!(descending.content_hash().unwrap(), hash);
+    assert_eq!(shuffled.content_hash().unwrap(), hash);
+    // physical layouts differ
+    let leaves = |store: &Store| store.structure_summary().unwrap().levels.last().unwrap().pages;
+    assert_ne!(leaves(&ascending), leaves(&descending));
+}
+
+#[test]
+fn content_hash_detects_differences() {
+    let store = open("content_hash_detects_differences");
+    fill(&store, 0..1000, value);
+    let hash = store.content_hash().unwrap();
+    assert_eq!(store.content_hash().unwrap(), hash);
+
+    // single changed value, removed key, added key and moved boundary between key and value change the hash
+    store.put(key(500), b"changed").unwrap();
+    assert_ne!(store.content_hash().unwrap(), hash);
+    store.put(key(500), value(500)).unwrap();
+    assert_eq!(store.content_hash().unwrap(), hash);
+    store.remove(key(0)).unwrap();
+    assert_ne!(store.content_hash().unwrap(), hash);
+    store.put(key(0), value(0)).unwrap();
+    store.put(key(1000), b"").unwrap();
+    assert_ne!(store.content_hash().unwrap(), hash);
+    store.remove(key(1000)).unwrap();
+    assert_eq!(store.content_hash().unwrap(), hash);
+
+    let a = open("content_hash_boundary_a");
+    a.put(b"ab", b"c").unwrap();
+    let b = open("content_hash_boundary_b");
+    b.put(b"a", b"bc").unwrap();
+    assert_ne!(a.content_hash().unwrap(), b.content_hash().unwrap());
+}
@@ -0,0 +1,672 @@
+#![cfg(feature = "fault-injection")]
+
+mod common;
+
+use std::fs::OpenOptions;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+
+use common::{fill, key, verify, TestFiles};
+use skv::{FaultInjector, SharedBufferPool, Storage, Store, StoreConfig, StoreError, StoreState};
+
+//
+// Open store on top of fault injector wrapping data file (and WAL if it is specified)
+//
+fn open_injected(files: &TestFiles, with_wal: bool) -> (Store, Arc<FaultInjector>, Option<Arc<FaultInjector>>) {
+    open_injected_with(files, with_wal, StoreConfig::default())
+}
+
+fn open_injected_with(
+    files: &TestFiles,
+    with_wal: bool,
+    conf: StoreConfig,
+) -> (Store, Arc<FaultInjector>, Option<Arc<FaultInjector>>) {
+    let open = |path| OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap();
+    let file = Arc::new(FaultInjector::new(Box::new(open(&files.db))));
+    let log = with_wal.then(|| Arc::new(FaultInjector::new(Box::new(open(&files.log)))));
+    let store = Store::open_with_storage(
+        Box::new(file.clone()),
+        log.clone().map(|log| Box::new(log) as Box<dyn skv::Storage>),
+        conf,
+    )
+    .unwrap();
+    (store, file, log)
+}
+
+#[test]
+fn crash_between_page_writes_without_wal() {
+    let files = TestFiles::new("crash_between_page_writes_without_wal");
+    // number of writes performed by commit of the first transaction
+    let total = {
+        let (store, file, _) = open_injected(&files, false);
+        let before = file.writes();
+        fill(&store, 0..2000, |i| vec![i as u8; 50]);
+        file.writes() - before
+    };
+    assert!(total > 2);
+    for n in 0..total {
+        let files = TestFiles::new("crash_between_page_writes_without_wal");
+        {
+            let (store, file, _) = open_injected(&files, false);
+            file.crash_after_writes(n);
+            fill(&store, 0..2000, |i| vec![i as u8; 50]);
+        }
+        let store = files.open_without_wal(StoreConfig::default());
+        // metadata is written last, so crash leaves store in the state preceding the transaction
+        assert_eq!(verify(&store), 0, "crash after {n} writes");
+        assert_eq!(store.get(key(0)).unwrap(), None);
+    }
+}
+
+#[test]
+fn crash_between_compressed_page_writes() {
+    let conf = || StoreConfig {
+        page_compression: true,
+        ..StoreConfig::default()
+    };
+    let files = TestFiles::new("crash_between_compressed_page_writes");
+    let total = {
+        let (store, file, _) = open_injected_with(&files, true, conf());
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        let before = file.writes();
+        fill(&store, 0..3000, |_| vec![2u8; 50]);
+        file.writes() - before
+    };
+    assert!(total > 2);
+    for n in 0..total {
+        let files = TestFiles::new("crash_between_compressed_page_writes");
+        {
+            let (store, file, log) = open_injected_with(&files, true, conf());
+            fill(&store, 0..1000, |_| vec![1u8; 50]);
+            // writes to the data file are lost in the middle of update of existing pages, WAL survives
+            file.crash_after_writes(n);
+            fill(&store, 0..3000, |_| vec![2u8; 50]);
+            log.unwrap().crash_after_writes(0);
+        }
+        // page map is reconstructed from records (partially written ones are ignored) and WAL is replayed
+        let store = files.open(conf());
+        assert_eq!(verify(&store), 3000, "crash after {n} writes");
+        assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8; 50]));
+    }
+}
+
+#[test]
+fn reordered_metadata_write_without_wal() {
+    let files = TestFiles::new("reordered_metadata_write_without_wal");
+    {
+        let (store, file, _) = open_injected(&files, false);
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        file.cache_writes();
+        fill(&store, 1000..3000, |_| vec![2u8; 50]);
+        // device persists metadata, but not data pages which were not synced
+        file.crash_persisting_last_write().unwrap();
+    }
+    let store = files.open_without_wal(StoreConfig::default());
+    assert_eq!(verify(&store), 3000);
+    assert_eq!(store.get(key(2999)).unwrap(), Some(vec![2u8; 50]));
+}
+
+#[test]
+fn recreated_preallocated_wal_is_detected() {
+    let files = TestFiles::new("recreated_preallocated_wal_is_detected");
+    let conf = || StoreConfig {
+        preallocate_wal: true,
+        ..StoreConfig::default()
+    };
+    {
+        let (store, file, log) = open_injected_with(&files, true, conf());
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        // crash before checkpoint: data file depends on WAL
+        file.crash_after_writes(0);
+        log.unwrap().crash_after_writes(0);
+    }
+    let saved = files.log.with_extension("saved");
+    std::fs::copy(&files.log, &saved).unwrap();
+
+    // WAL is lost and recreated with preallocated size, so its size is not changed
+    let size = std::fs::metadata(&files.log).unwrap().len();
+    std::fs::remove_file(&files.log).unwrap();
+    OpenOptions::new().write(true).create_new(true).open(&files.log).unwrap().set_len(size).unwrap();
+    let err = Store::open(&files.db, Some(&files.log), conf()).err().unwrap();
+    assert!(matches!(err.downcast::<StoreError>().unwrap(), StoreError::WalMissing { .. }));
+
+    // original WAL is replayed
+    std::fs::rename(&saved, &files.log).unwrap();
+    let store = files.open(conf());
+    assert_eq!(verify(&store), 1000);
+}
+
+#[test]
+fn free_list_is_recovered_after_crash() {
+    let files = TestFiles::new("free_list_is_recovered_after_crash");
+    {
+        let (store, file, log) = open_injected(&files, true);
+        fill(&store, 0..3000, |_| vec![1u8; 100]);
+        let mut trans = store.start_transaction();
+        for i in 0..2000 {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+        // crash right after delete which moved pages to free list
+        file.crash_after_writes(0);
+        log.unwrap().crash_after_writes(0);
+    }
+    let store = files.open(StoreConfig::default());
+    let report = store.scrub().unwrap();
+    assert!(report.bad_pages.is_empty(), "bad pages {:?}", report.bad_pages);
+    assert_eq!(verify(&store), 1000);
+
+    // freed pages are reused instead of extending the store
+    let size = std::fs::metadata(&files.db).unwrap().len();
+    fill(&store, 0..500, |_| vec![2u8; 100]);
+    assert_eq!(std::fs::metadata(&files.db).unwrap().len(), size);
+    assert_eq!(verify(&store), 1500);
+    assert!(store.scrub().unwrap().bad_pages.is_empty());
+}
+
+#[test]
+fn recovery_is_reported_when_opened_with_pool() {
+    let files = TestFiles::new("recovery_is_reported_when_opened_with_pool");
+    {
+        let (store, file, log) = open_injected(&files, true);
+        for i in 0..10 {
+            fill(&store, i * 100..(i + 1) * 100, |_| vec![1u8; 50]);
+        }
+        // crash before checkpoint: transactions are recovered from WAL
+        file.crash_after_writes(0);
+        log.unwrap().crash_after_writes(0);
+    }
+    let pool = Arc::new(SharedBufferPool::new(64).unwrap());
+    let (store, report) =
+        Store::open_with_pool_and_report(&files.db, Some(&files.log), StoreConfig::default(), pool).unwrap();
+    assert!(report.transactions > 0);
+    assert_eq!(verify(&store), 1000);
+}
+
+//
+// Keys 0..1000 have value 1, keys 1000..3000 are inserted and all keys are updated to value 2
+// by the second transaction
+//
+fn check_recovered(store: &Store, committed: bool) {
+    if committed {
+        assert_eq!(verify(store), 3000);
+        assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8; 50]));
+        assert_eq!(store.get(key(2999)).unwrap(), Some(vec![2u8; 50]));
+    } else {
+        assert_eq!(verify(store), 1000);
+        assert_eq!(store.get(key(0)).unwrap(), Some(vec![1u8; 50]));
+        assert_eq!(store.get(key(2999)).unwrap(), None);
+    }
+}
+
+#[test]
+fn crash_after_wal_write_before_data_flush() {
+    let files = TestFiles::new("crash_after_wal_write_before_data_flush");
+    let total = {
+        let (store, file, _) = open_injected(&files, true);
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        let before = file.writes();
+        fill(&store, 0..3000, |_| vec![2u8; 50]);
+        file.writes() - before
+    };
+    assert!(total > 2);
+    for n in 0..total {
+        let files = TestFiles::new("crash_after_wal_write_before_data_flush");
+        {
+            let (store, file, log) = open_injected(&files, true);
+            fill(&store, 0..1000, |_| vec![1u8; 50]);
+            // transaction is committed in WAL, but only `n` of its pages reach the data file
+            file.crash_after_writes(n);
+            fill(&store, 0..3000, |_| vec![2u8; 50]);
+            log.unwrap().crash_after_writes(0);
+        }
+        let store = files.open(StoreConfig::default());
+        check_recovered(&store, true);
+    }
+}
+
+#[test]
+fn torn_wal_write_is_not_replayed() {
+    let files = TestFiles::new("torn_wal_write_is_not_replayed");
+    let total = {
+        let (store, _, log) = open_injected(&files, true);
+        let log = log.unwrap();
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        let before = log.writes();
+        fill(&store, 0..3000, |_| vec![2u8; 50]);
+        log.writes() - before
+    };
+    assert!(total > 1);
+    for n in 0..=total {
+        let files = TestFiles::new("torn_wal_write_is_not_replayed");
+        {
+            let (store, file, log) = open_injected(&files, true);
+            fill(&store, 0..1000, |_| vec![1u8; 50]);
+            // crash while transaction is written to WAL: nothing reaches the data file
+            log.unwrap().crash_after_writes(n);
+            file.crash_after_writes(0);
+            fill(&store, 0..3000, |_| vec![2u8; 50]);
+        }
+        let store = files.open(StoreConfig::default());
+        // transaction is committed only by its metadata record which is written last
+        check_recovered(&store, n == total);
+    }
+}
+
+#[test]
+fn crash_during_checkpoint() {
+    let files = TestFiles::new("crash_during_checkpoint");
+    let update = |store: &Store| {
+        let mut trans = store.start_transaction();
+        for i in 0..3000 {
+            trans.put(key(i), vec![2u8; 50]).unwrap();
+        }
+        trans.commit_and_checkpoint().unwrap();
+    };
+    let total = {
+        let (store, file, _) = open_injected(&files, true);
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        let before = file.writes();
+        update(&store);
+        file.writes() - before
+    };
+    assert!(total > 2);
+    for n in 0..total {
+        let files = TestFiles::new("crash_during_checkpoint");
+        {
+            let (store, file, log) = open_injected(&files, true);
+            fill(&store, 0..1000, |_| vec![1u8; 50]);
+            // crash while pages are flushed and WAL end is cleared in metadata
+            file.crash_after_writes(n);
+            update(&store);
+            log.unwrap().crash_after_writes(0);
+        }
+        let store = files.open(StoreConfig::default());
+        check_recovered(&store, true);
+    }
+
+    // WAL restarted by completed checkpoint is overwritten by the next transaction which is torn
+    let files = TestFiles::new("crash_during_checkpoint");
+    {
+        let (store, file, log) = open_injected(&files, true);
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        update(&store);
+        log.unwrap().crash_after_writes(1);
+        file.crash_after_writes(0);
+        fill(&store, 0..10, |_| vec![3u8; 50]);
+    }
+    let store = files.open(StoreConfig::default());
+    check_recovered(&store, true);
+    assert_eq!(store.get(key(9)).unwrap(), Some(vec![2u8; 50]));
+}
+
+//
+// Data file which writes are blocked while gate is closed
+//
+struct GatedStorage {
+    file: Arc<FaultInjector>,
+    closed: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Storage for GatedStorage {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        self.file.read_at(buf, offs)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        let (lock, cvar) = &*self.closed;
+        let _guard = cvar.wait_while(lock.lock().unwrap(), |closed| *closed).unwrap();
+        self.file.write_all_at(buf, offs)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+}
+
+#[test]
+fn background_flush_is_recovered_from_wal() {
+    let files = TestFiles::new("background_flush_is_recovered_from_wal");
+    let open = |path| OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap();
+    let file = Arc::new(FaultInjector::new(Box::new(open(&files.db))));
+    let log = Arc::new(FaultInjector::new(Box::new(open(&files.log))));
+    let closed = Arc::new((Mutex::new(false), Condvar::new()));
+    let gated = GatedStorage {
+        file: file.clone(),
+        closed: closed.clone(),
+    };
+    let conf = StoreConfig {
+        background_flush: true,
+        ..StoreConfig::default()
+    };
+    let store = Store::open_with_storage(Box::new(gated), Some(Box::new(log.clone())), conf).unwrap();
+    fill(&store, 0..100, |_| vec![1u8; 50]);
+
+    // commit doesn't wait for writes to the data file: pages are read from pending images
+    *closed.0.lock().unwrap() = true;
+    let before = file.writes();
+    fill(&store, 0..2000, |_| vec![2u8; 50]);
+    assert_eq!(file.writes(), before);
+    assert_eq!(verify(&store), 2000);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8; 50]));
+
+    // crash before background writer has written anything: WAL is already synced by commit
+    file.crash_after_writes(0);
+    log.crash_after_writes(0);
+    *closed.0.lock().unwrap() = false;
+    closed.1.notify_all();
+    drop(store);
+
+    let store = files.open(StoreConfig::default());
+    assert_eq!(verify(&store), 2000);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8; 50]));
+    assert_eq!(store.get(key(1999)).unwrap(), Some(vec![2u8; 50]));
+}
+
+#[test]
+fn recovery_report_of_clean_and_crashed_open() {
+    let files = TestFiles::new("recovery_report_of_clean_and_crashed_open");
+    let open = || Store::open_with_report(&files.db, Some(&files.log), StoreConfig::default()).unwrap();
+    let seq = {
+        let store = files.open(StoreConfig::default());
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        store.put(key(0), b"last").unwrap();
+        let seq = store.start_transaction().commit().unwrap();
+        seq
+    };
+    // store is closed cleanly: nothing to replay
+    let (store, report) = open();
+    assert_eq!((report.transactions, report.replayed_bytes, report.discarded_bytes), (0, 0, 0));
+    assert!(!report.crc_mismatch);
+    assert_eq!(report.commit_seq, seq);
+    assert_eq!(report.height, store.structure_summary().unwrap().height);
+    drop(store);
+
+    // crash before checkpoint: all transactions are replayed
+    {
+        let (store, file, log) = open_injected(&files, true);
+        for i in 0..5 {
+            fill(&store, i * 100..(i + 1) * 100, |_| vec![2u8; 50]);
+        }
+        file.crash_after_writes(0);
+        log.unwrap().crash_after_writes(0);
+    }
+    let (store, report) = open();
+    assert_eq!(report.transactions, 5);
+    assert!(report.replayed_bytes > 0);
+    assert_eq!(report.discarded_bytes, 0);
+    assert!(!report.crc_mismatch);
+    assert_eq!(report.commit_seq, seq + 5);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8; 50]));
+    drop(store);
+
+    // crash in the middle of writing the last transaction to WAL: its beginning is discarded
+    {
+        let (store, file, log) = open_injected(&files, true);
+        let log = log.unwrap();
+        fill(&store, 0..100, |_| vec![3u8; 50]);
+        file.crash_after_writes(0);
+        log.crash_after_writes(1);
+        fill(&store, 0..3000, |_| vec![4u8; 50]);
+    }
+    let (store, report) = open();
+    assert_eq!(report.transactions, 1);
+    assert!(report.discarded_bytes > 0);
+    assert_eq!(report.commit_seq, seq + 6);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![3u8; 50]));
+    drop(store);
+
+    // corrupted transaction and the rest of WAL are discarded
+    let pos = {
+        let (store, file, log) = open_injected(&files, true);
+        fill(&store, 0..100, |_| vec![5u8; 50]);
+        let pos = store.wal_position();
+        fill(&store, 0..100, |_| vec![6u8; 50]);
+        fill(&store, 0..100, |_| vec![7u8; 50]);
+        file.crash_after_writes(0);
+        log.unwrap().crash_after_writes(0);
+        pos
+    };
+    let log = OpenOptions::new().read(true).write(true).open(&files.log).unwrap();
+    let mut byte = [0u8];
+    Storage::read_exact_at(&log, &mut byte, pos + 100).unwrap();
+    Storage::write_all_at(&log, &[!byte[0]], pos + 100).unwrap();
+    drop(log);
+    let (store, report) = open();
+    assert_eq!(report.transactions, 1);
+    assert!(report.crc_mismatch);
+    assert!(report.discarded_bytes > 0);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![5u8; 50]));
+}
+
+#[test]
+fn transient_write_errors_are_retried() {
+    let conf = |io_retries| StoreConfig {
+        io_retries,
+        ..StoreConfig::default()
+    };
+    for (io_retries, succeeds) in [(0, false), (1, false), (2, true), (5, true)] {
+        let files = TestFiles::new("transient_write_errors_are_retried");
+        let (store, file, log) = open_injected_with(&files, true, conf(io_retries));
+        let log = log.unwrap();
+        fill(&store, 0..100, |_| vec![1u8; 50]);
+        // the first write of the next commit fails twice
+        log.fail_next_writes(2, io::ErrorKind::Interrupted);
+        let before = log.writes();
+        let mut trans = store.start_transaction();
+        trans.put(key(0), b"updated").unwrap();
+        let res = trans.commit();
+        assert_eq!(res.is_ok(), succeeds, "{io_retries} retries: {res:?}");
+        if succeeds {
+            drop(trans);
+            assert!(log.writes() - before >= 3);
+            assert_eq!(store.get(key(0)).unwrap(), Some(b"updated".to_vec()));
+            // data file errors are retried as well
+            file.fail_next_writes(2, io::ErrorKind::WouldBlock);
+            store.put(key(1), b"updated").unwrap();
+        } else {
+            // error is reported without further attempts
+            assert_eq!(log.writes() - before, io_retries as u64 + 1);
+        }
+    }
+
+    // other errors are not retried
+    let files = TestFiles::new("transient_write_errors_are_retried");
+    let (store, _, log) = open_injected_with(&files, true, conf(5));
+    let log = log.unwrap();
+    fill(&store, 0..100, |_| vec![1u8; 50]);
+    log.fail_nth_write(1);
+    let before = log.writes();
+    let mut trans = store.start_transaction();
+    trans.put(key(0), b"updated").unwrap();
+    assert!(trans.commit().is_err());
+    assert_eq!(log.writes() - before, 1);
+}
+
+//
+// Fill store with initial values, then prepare transaction updating half of keys and removing the other half
+// and crash (of both files) before it is committed or rolled back
+//
+fn crash_after_prepare(files: &TestFiles, crash_log_after: Option<u64>) {
+    let (store, file, log) = open_injected(files, true);
+    let log = log.unwrap();
+    fill(&store, 0..1000, |_| vec![1u8; 50]);
+    let mut trans = store.start_transaction();
+    for i in 0..1000 {
+        if i % 2 == 0 {
+            trans.put(key(i), vec![2u8; 50]).unwrap();
+        } else {
+            trans.remove(key(i)).unwrap();
+        }
+    }
+    if let Some(n) = crash_log_after {
+        log.crash_after_writes(n);
+    }
+    file.crash_after_writes(0);
+    let _ = trans.prepare();
+    log.crash_after_writes(0);
+    // drop of transaction rolls it back, but abort record is lost
+}
+
+fn check_initial(store: &Store) {
+    assert_eq!(verify(store), 1000);
+    for i in 0..1000 {
+        assert_eq!(store.get(key(i)).unwrap(), Some(vec![1u8; 50]), "key {i}");
+    }
+}
+
+fn check_prepared(store: &Store) {
+    assert_eq!(verify(store), 500);
+    for i in 0..1000 {
+        let expected = (i % 2 == 0).then(|| vec![2u8; 50]);
+        assert_eq!(store.get(key(i)).unwrap(), expected, "key {i}");
+    }
+}
+
+#[test]
+fn prepared_transaction_is_committed_after_crash() {
+    let files = TestFiles::new("prepared_transaction_is_committed_after_crash");
+    crash_after_prepare(&files, None);
+    let (store, report) = Store::open_with_report(&files.db, Some(&files.log), StoreConfig::default()).unwrap();
+    assert!(report.prepared);
+    // changes of transaction are not visible until it is resolved, and other transactions can not be committed
+    check_initial(&store);
+    assert!(store.put(key(0), b"other").is_err());
+    let mut trans = store.prepared_transaction().unwrap().unwrap();
+    trans.commit_prepared().unwrap();
+    drop(trans);
+    assert!(store.prepared_transaction().unwrap().is_none());
+    check_prepared(&store);
+    store.put(key(1), b"other").unwrap();
+    drop(store);
+
+    let (store, report) = Store::open_with_report(&files.db, Some(&files.log), StoreConfig::default()).unwrap();
+    assert!(!report.prepared);
+    assert_eq!(store.get(key(1)).unwrap(), Some(b"other".to_vec()));
+    store.remove(key(1)).unwrap();
+    check_prepared(&store);
+}
+
+#[test]
+fn prepared_transaction_is_rolled_back_after_crash() {
+    let files = TestFiles::new("prepared_transaction_is_rolled_back_after_crash");
+    crash_after_prepare(&files, None);
+    let store = files.open(StoreConfig::default());
+    let mut trans = store.prepared_transaction().unwrap().unwrap();
+    trans.rollback_prepared().unwrap();
+    drop(trans);
+    check_initial(&store);
+    store.put(key(0), b"other").unwrap();
+    drop(store);
+
+    // rollback is recorded in WAL, so that transaction is not restored again
+    let (store, report) = Store::open_with_report(&files.db, Some(&files.log), StoreConfig::default()).unwrap();
+    assert!(!report.prepared);
+    assert!(store.prepared_transaction().unwrap().is_none());
+    assert_eq!(store.get(key(0)).unwrap(), Some(b"other".to_vec()));
+    store.put(key(0), vec![1u8; 50]).unwrap();
+    check_initial(&store);
+    drop(store);
+
+    // drop of restored transaction rolls it back as well
+    let files = TestFiles::new("prepared_transaction_is_rolled_back_after_crash");
+    crash_after_prepare(&files, None);
+    let store = files.open(StoreConfig::default());
+    drop(store.prepared_transaction().unwrap().unwrap());
+    check_initial(&store);
+    drop(store);
+    let (store, report) = Store::open_with_report(&files.db, Some(&files.log), StoreConfig::default()).unwrap();
+    assert!(!report.prepared);
+    check_initial(&store);
+}
+
+#[test]
+fn torn_prepare_is_not_restored() {
+    let files = TestFiles::new("torn_prepare_is_not_restored");
+    crash_after_prepare(&files, Some(1));
+    let (store, report) = Store::open_with_report(&files.db, Some(&files.log), StoreConfig::default()).unwrap();
+    assert!(!report.prepared);
+    assert!(store.prepared_transaction().unwrap().is_none());
+    check_initial(&store);
+}
+
+#[test]
+fn crash_after_commit_of_prepared_transaction() {
+    let files = TestFiles::new("crash_after_commit_of_prepared_transaction");
+    {
+        let (store, file, log) = open_injected(&files, true);
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        let mut trans = store.start_transaction();
+        for i in 0..1000 {
+            if i % 2 == 0 {
+                trans.put(key(i), vec![2u8; 50]).unwrap();
+            } else {
+                trans.remove(key(i)).unwrap();
+            }
+        }
+        trans.prepare().unwrap();
+        // commit record reaches WAL, but pages are not written to the data file
+        file.crash_after_writes(0);
+        trans.commit_prepared().unwrap();
+        log.unwrap().crash_after_writes(0);
+    }
+    let (store, report) = Store::open_with_report(&files.db, Some(&files.log), StoreConfig::default()).unwrap();
+    assert!(!report.prepared);
+    // initial transaction and prepared transaction are replayed
+    assert_eq!(report.transactions, 2);
+    check_prepared(&store);
+}
+
+#[test]
+fn corrupted_store_rejects_operations_until_reopened() {
+    let files = TestFiles::new("corrupted_store_rejects_operations_until_reopened");
+    let (store, file, _) = open_injected(&files, true);
+    fill(&store, 0..1000, |_| vec![1u8; 50]);
+    assert_eq!(store.state(), StoreState::Opened);
+    // transaction is written to WAL, but write of its pages to the data file fails
+    file.fail_nth_write(1);
+    let mut trans = store.start_transaction();
+    trans.put(key(0), b"updated").unwrap();
+    assert!(trans.commit().is_err());
+    drop(trans);
+    assert_eq!(store.state(), StoreState::Corrupted);
+
+    let corrupted = |res: anyhow::Result<()>, op: &str| {
+        let err = res.expect_err(op);
+        assert!(matches!(err.downcast::<StoreError>(), Ok(StoreError::Corrupted(_))), "{op}");
+    };
+    corrupted(store.get(key(1)).map(|_| ()), "get");
+    corrupted(store.contains_key(key(1)).map(|_| ()), "contains_key");
+    corrupted(store.put(key(1), b"value"), "put");
+    corrupted(store.remove(key(1)), "remove");
+    corrupted(store.range_filtered(key(0), key(10), |_| true).map(|_| ()), "range_filtered");
+    corrupted(store.scan_page(None, 10).map(|_| ()), "scan_page");
+    corrupted(store.verify_cancellable(&Default::default()).map(|_| ()), "verify");
+    corrupted(store.snapshot().map(|_| ()), "snapshot");
+    corrupted(store.warmup().map(|_| ()), "warmup");
+    corrupted(store.content_hash().map(|_| ()), "content_hash");
+    corrupted(store.scrub().map(|_| ()), "scrub");
+    corrupted(store.start_transaction().put(key(1), b"value"), "transaction put");
+    corrupted(store.start_transaction().get(key(1)).map(|_| ()), "transaction get");
+
+    // recovery replays committed transaction from WAL
+    let report = store.try_reopen().unwrap();
+    assert!(report.transactions >= 1);
+    assert_eq!(store.state(), StoreState::Opened);
+    assert_eq!(store.get(key(0)).unwrap(), Some(b"updated".to_vec()));
+    assert_eq!(verify(&store), 1000);
+    store.put(key(1), b"value").unwrap();
+    assert!(store.try_reopen().is_err());
+}
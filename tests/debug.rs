@@ -0,0 +1,31 @@
+mod common;
+
+use common::{fill, open_store};
+
+#[test]
+fn store_debug_summary() {
+    let store = open_store("store_debug_summary");
+    fill(&store, 0..1000, |_| vec![0u8; 100]);
+    let summary = format!("{store:?}");
+    for field in ["Store", "conf", "StoreConfig", "wal: true", "state: Opened", "size", "height", "cached"] {
+        assert!(summary.contains(field), "{field} is missing in {summary}");
+    }
+    // buffer pool is summarized, not dumped
+    assert!(summary.len() < 4096, "{summary}");
+}
+
+#[test]
+fn debug_does_not_wait_for_active_transaction() {
+    let store = open_store("debug_does_not_wait_for_active_transaction");
+    fill(&store, 0..10, |_| vec![0u8; 100]);
+    let mut trans = store.start_transaction();
+    trans.put(b"key", b"value").unwrap();
+    // transaction holds database lock: it is reported instead of blocking
+    let summary = format!("{store:?}");
+    assert!(summary.contains("<locked>"), "{summary}");
+    assert!(format!("{trans:?}").contains("InProgress"));
+    trans.commit().unwrap();
+    assert!(format!("{trans:?}").contains("Committed"));
+    drop(trans);
+    assert!(!format!("{store:?}").contains("<locked>"));
+}
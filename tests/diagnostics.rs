@@ -0,0 +1,46 @@
+#![cfg(feature = "diagnostics")]
+
+mod common;
+
+use common::{fill, key, TestFiles};
+use skv::StoreConfig;
+
+#[test]
+fn dirty_page_report_matches_transaction() {
+    for wal_flush_threshold in [StoreConfig::default().wal_flush_threshold, 10] {
+        let files = TestFiles::new("dirty_page_report_matches_transaction");
+        let store = files.open(StoreConfig {
+            wal_flush_threshold,
+            ..StoreConfig::default()
+        });
+        fill(&store, 0..20000, |_| vec![1u8; 100]);
+        assert_eq!(store.dirty_page_report().unwrap(), Default::default());
+
+        let mut trans = store.start_transaction();
+        // update keys located in different leaf pages
+        for i in (0..20000).step_by(1000) {
+            trans.put(key(i), vec![2u8; 100]).unwrap();
+        }
+        let report = store.dirty_page_report().unwrap();
+        let stats = store.stats();
+        assert_eq!(report.dirtied, report.dirty.len());
+        assert_eq!(report.dirtied, stats.dirty_pages);
+        // each modified leaf page is dirty once
+        assert!(report.dirty.len() >= 20, "{report:?}");
+        let mut unique = report.dirty.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), report.dirty.len());
+        assert!(report.synced.iter().all(|pid| report.dirty.contains(pid)));
+        if wal_flush_threshold == 10 {
+            // pages exceeding threshold are written to WAL, the next one to be written is still dirty
+            assert!(!report.synced.is_empty(), "{report:?}");
+            assert!(report.next_sync.is_none_or(|pid| report.dirty.contains(&pid)));
+        } else {
+            assert!(report.synced.is_empty(), "{report:?}");
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(store.dirty_page_report().unwrap(), Default::default());
+    }
+}
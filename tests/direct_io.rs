@@ -0,0 +1,81 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::io;
+
+use common::{fill, key, verify, TestFiles};
+use skv::{Store, StoreConfig};
+
+const N_KEYS: u32 = 20000;
+
+//
+// Run the same workload on store opened with the specified configuration and return its content:
+// B-Tree is larger than buffer pool, so pages are evicted and read back from the data file
+//
+fn workload(name: &str, conf: impl Fn() -> StoreConfig) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let files = TestFiles::new(name);
+    let store = files.open(conf());
+    fill(&store, 0..N_KEYS, |i| vec![i as u8; 100 + (i % 50) as usize]);
+    let mut trans = store.start_transaction();
+    for i in (0..N_KEYS).step_by(3) {
+        trans.remove(key(i)).unwrap();
+    }
+    for i in (1..N_KEYS).step_by(7) {
+        trans.put(key(i), vec![7u8; 300]).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    store.compact_in_place().unwrap();
+    let hash = store.content_hash().unwrap();
+    store.close().unwrap();
+    drop(store);
+
+    let store = files.open(conf());
+    assert_eq!(store.content_hash().unwrap(), hash);
+    assert!(store.scrub().unwrap().bad_pages.is_empty());
+    let n_keys = verify(&store);
+    let items = store.range_filtered(key(0), key(N_KEYS), |_| true).unwrap();
+    assert_eq!(items.len() as u64, n_keys);
+    items
+}
+
+#[test]
+fn direct_io_results_equal_buffered_results() {
+    let files = TestFiles::new("direct_io_probe");
+    let direct = StoreConfig {
+        direct_io: true,
+        ..StoreConfig::default()
+    };
+    if let Err(err) = Store::open(&files.db, Some(&files.log), direct) {
+        // file system of temporary directory (like tmpfs) rejects O_DIRECT with EINVAL: nothing to compare then,
+        // any other error is a failure
+        let unsupported = err.downcast_ref::<io::Error>().map(io::Error::kind) == Some(io::ErrorKind::InvalidInput);
+        assert!(unsupported, "{err}");
+        return;
+    }
+    for page_compression in [false, true] {
+        let conf = |direct_io| {
+            move || StoreConfig {
+                direct_io,
+                page_compression,
+                cache_size: 64,
+                ..StoreConfig::default()
+            }
+        };
+        let buffered = workload("direct_io_buffered", conf(false));
+        let direct = workload("direct_io_direct", conf(true));
+        assert_eq!(direct, buffered, "page_compression={page_compression}");
+    }
+}
+
+#[test]
+fn direct_io_requires_open_by_path() {
+    let files = TestFiles::new("direct_io_requires_open_by_path");
+    let file = std::fs::File::create(&files.db).unwrap();
+    let conf = StoreConfig {
+        direct_io: true,
+        ..StoreConfig::default()
+    };
+    assert!(Store::open_with_storage(Box::new(file), None, conf).is_err());
+}
@@ -0,0 +1,427 @@
+mod common;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common::{fill, key, verify, TestFiles};
+use skv::{Durability, Storage, Store, StoreConfig, StoreError};
+
+//
+// WAL storage counting syncs
+//
+struct SyncCounter {
+    file: File,
+    syncs: Arc<AtomicUsize>,
+}
+
+impl Storage for SyncCounter {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        Storage::read_at(&self.file, buf, offs)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        Storage::write_all_at(&self.file, buf, offs)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.file.sync_data()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Storage::size(&self.file)
+    }
+}
+
+fn open_file(path: &Path) -> File {
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap()
+}
+
+fn open_counted(files: &TestFiles, conf: StoreConfig) -> (Store, Arc<AtomicUsize>) {
+    let syncs = Arc::new(AtomicUsize::new(0));
+    let log = SyncCounter {
+        file: open_file(&files.log),
+        syncs: syncs.clone(),
+    };
+    let store = Store::open_with_storage(Box::new(open_file(&files.db)), Some(Box::new(log)), conf).unwrap();
+    (store, syncs)
+}
+
+fn commit(store: &Store, i: u32, durability: Durability) {
+    let mut trans = store.start_transaction();
+    trans.set_durability(durability);
+    trans.put(key(i), vec![i as u8; 100]).unwrap();
+    trans.commit().unwrap();
+}
+
+#[test]
+fn batched_transactions_share_sync() {
+    let files = TestFiles::new("batched_transactions_share_sync");
+    let conf = StoreConfig {
+        batched_sync_interval: Duration::from_secs(3600),
+        ..StoreConfig::default()
+    };
+    let (store, syncs) = open_counted(&files, conf);
+
+    let before = syncs.load(Ordering::SeqCst);
+    commit(&store, 0, Durability::Immediate);
+    assert_eq!(syncs.load(Ordering::SeqCst), before + 1);
+
+    for i in 1..10 {
+        commit(&store, i, Durability::Batched);
+    }
+    assert_eq!(syncs.load(Ordering::SeqCst), before + 1);
+
+    // immediate transaction syncs changes of preceding batched transactions
+    commit(&store, 10, Durability::Immediate);
+    assert_eq!(syncs.load(Ordering::SeqCst), before + 2);
+    for i in 0..=10 {
+        assert_eq!(store.get(key(i)).unwrap(), Some(vec![i as u8; 100]));
+    }
+}
+
+#[test]
+fn batched_transaction_is_synced_in_background() {
+    let files = TestFiles::new("batched_transaction_is_synced_in_background");
+    let conf = StoreConfig {
+        batched_sync_interval: Duration::from_millis(20),
+        ..StoreConfig::default()
+    };
+    let (store, syncs) = open_counted(&files, conf);
+
+    let before = syncs.load(Ordering::SeqCst);
+    commit(&store, 0, Durability::Batched);
+    commit(&store, 1, Durability::Batched);
+    // WAL is synced without further commits
+    let start = Instant::now();
+    while syncs.load(Ordering::SeqCst) == before {
+        assert!(start.elapsed() < Duration::from_secs(10), "WAL was not synced");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(syncs.load(Ordering::SeqCst), before + 1);
+
+    // pages of synced transactions are still written to the data file
+    store.sync_batched().unwrap();
+    drop(store);
+    let store = files.open(StoreConfig::default());
+    assert_eq!(store.get(key(1)).unwrap(), Some(vec![1u8; 100]));
+}
+
+#[test]
+fn torn_metadata_write_is_recovered_from_shadow() {
+    let conf = || StoreConfig {
+        meta_double_write: true,
+        ..StoreConfig::default()
+    };
+    let files = TestFiles::new("torn_metadata_write_is_recovered_from_shadow");
+    {
+        let store = files.open_without_wal(conf());
+        fill(&store, 0..1000, |_| vec![1u8; 50]);
+        fill(&store, 1000..2000, |_| vec![2u8; 50]);
+    }
+    // torn write: only the beginning of the primary copy of metadata is overwritten
+    let file = open_file(&files.db);
+    let mut page = vec![0u8; 512];
+    Storage::read_exact_at(&file, &mut page, 0).unwrap();
+    page[4..64].fill(0xA5);
+    Storage::write_all_at(&file, &page[..64], 0).unwrap();
+    drop(file);
+
+    let store = files.open_without_wal(conf());
+    assert_eq!(verify(&store), 2000);
+    assert_eq!(store.get(key(1999)).unwrap(), Some(vec![2u8; 50]));
+}
+
+#[test]
+fn autocommit_writes_are_synced_and_survive_reopen() {
+    let files = TestFiles::new("autocommit_writes_are_synced_and_survive_reopen");
+    {
+        let (store, syncs) = open_counted(&files, StoreConfig::default());
+        for i in 0..10 {
+            // each operation is separate transaction which is durable once it returns
+            let before = syncs.load(Ordering::SeqCst);
+            store.put(key(i), vec![i as u8; 100]).unwrap();
+            assert_eq!(syncs.load(Ordering::SeqCst), before + 1);
+        }
+        let before = syncs.load(Ordering::SeqCst);
+        store.remove(key(0)).unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), before + 1);
+    }
+    let store = files.open(StoreConfig::default());
+    assert_eq!(verify(&store), 9);
+    assert_eq!(store.get(key(0)).unwrap(), None);
+    for i in 1..10 {
+        assert_eq!(store.get(key(i)).unwrap(), Some(vec![i as u8; 100]));
+    }
+}
+
+#[test]
+fn autocommit_composes_with_transactions() {
+    let files = TestFiles::new("autocommit_composes_with_transactions");
+    let store = files.open(StoreConfig::default());
+    fill(&store, 0..100, |_| b"trans".to_vec());
+    store.put(key(50), b"auto").unwrap();
+    store.remove(key(51)).unwrap();
+    store.put(key(100), b"auto").unwrap();
+
+    // explicit transaction sees autocommit changes, autocommit operation sees its changes after commit
+    let mut trans = store.start_transaction();
+    assert_eq!(trans.get(key(50)).unwrap(), Some(b"auto".to_vec()));
+    assert_eq!(trans.get(key(51)).unwrap(), None);
+    trans.put(key(51), b"trans").unwrap();
+    trans.remove(key(100)).unwrap();
+    trans.commit().unwrap();
+    drop(trans);
+    store.remove(key(50)).unwrap();
+
+    // rolled back transaction doesn't affect subsequent autocommit operations
+    let mut trans = store.start_transaction();
+    trans.put(key(200), b"lost").unwrap();
+    trans.rollback().unwrap();
+    drop(trans);
+    store.put(key(201), b"auto").unwrap();
+
+    drop(store);
+    let store = files.open(StoreConfig::default());
+    assert_eq!(verify(&store), 100);
+    assert_eq!(store.get(key(50)).unwrap(), None);
+    assert_eq!(store.get(key(51)).unwrap(), Some(b"trans".to_vec()));
+    assert_eq!(store.get(key(100)).unwrap(), None);
+    assert_eq!(store.get(key(200)).unwrap(), None);
+    assert_eq!(store.get(key(201)).unwrap(), Some(b"auto".to_vec()));
+}
+
+#[test]
+fn commit_sequence_numbers_increase_and_persist() {
+    let files = TestFiles::new("commit_sequence_numbers_increase_and_persist");
+    let commit = |store: &Store, i: u32| {
+        let mut trans = store.start_transaction();
+        trans.put(key(i), [1u8]).unwrap();
+        trans.commit().unwrap()
+    };
+    let last = {
+        let store = files.open(StoreConfig::default());
+        let mut prev = 0;
+        for i in 0..10 {
+            let seq = commit(&store, i);
+            assert!(seq > prev, "{seq} follows {prev}");
+            prev = seq;
+        }
+        // transaction which doesn't change anything is not assigned new number
+        assert_eq!(store.start_transaction().commit().unwrap(), prev);
+        prev
+    };
+    let store = files.open(StoreConfig::default());
+    assert!(commit(&store, 10) > last);
+    drop(store);
+
+    // the same in no-WAL mode
+    let files = TestFiles::new("commit_sequence_numbers_increase_and_persist_without_wal");
+    let store = files.open_without_wal(StoreConfig::default());
+    let last = commit(&store, 0);
+    drop(store);
+    let store = files.open_without_wal(StoreConfig::default());
+    assert!(commit(&store, 1) > last);
+}
+
+#[test]
+fn wal_position_grows_until_checkpoint() {
+    const INTERVAL: u64 = 1024 * 1024;
+    let files = TestFiles::new("wal_position_grows_until_checkpoint");
+    let store = files.open(StoreConfig {
+        checkpoint_interval: INTERVAL,
+        ..StoreConfig::default()
+    });
+    assert!(store.wal_enabled());
+    assert_eq!(store.checkpoint_interval(), INTERVAL);
+    assert_eq!(store.wal_position(), 0);
+    let mut pos = 0;
+    let mut checkpoints = 0;
+    for i in 0..200 {
+        fill(&store, i * 100..(i + 1) * 100, |_| vec![1u8; 100]);
+        let new_pos = store.wal_position();
+        assert!(new_pos < INTERVAL);
+        if new_pos <= pos {
+            // WAL is restarted by checkpoint once it reaches the interval
+            assert_eq!(new_pos, 0);
+            checkpoints += 1;
+        }
+        pos = new_pos;
+    }
+    assert!(checkpoints > 1, "{checkpoints} checkpoints");
+    // read-only operations do not write to WAL
+    assert_eq!(verify(&store), 20000);
+    assert_eq!(store.wal_position(), pos);
+    drop(store);
+
+    let store = files.open_without_wal(StoreConfig::default());
+    assert!(!store.wal_enabled());
+    fill(&store, 0..100, |_| vec![2u8; 100]);
+    assert_eq!(store.wal_position(), 0);
+}
+
+#[test]
+fn preallocated_wal_keeps_commits_durable() {
+    const INTERVAL: u64 = 4 * 1024 * 1024;
+    const COMMITS: u32 = 200;
+    let conf = |preallocate_wal| StoreConfig {
+        preallocate_wal,
+        checkpoint_interval: INTERVAL,
+        ..StoreConfig::default()
+    };
+    for preallocate_wal in [false, true] {
+        let files = TestFiles::new("preallocated_wal_keeps_commits_durable");
+        let (store, syncs) = open_counted(&files, conf(preallocate_wal));
+        if preallocate_wal {
+            assert_eq!(std::fs::metadata(&files.log).unwrap().len(), INTERVAL);
+        }
+        let before = syncs.load(Ordering::SeqCst);
+        for i in 0..COMMITS {
+            commit(&store, i, Durability::Immediate);
+        }
+        // each commit syncs WAL; size of preallocated WAL is not changed, so `sync_data` doesn't lose file metadata
+        assert_eq!(syncs.load(Ordering::SeqCst) - before, COMMITS as usize);
+        let wal_size = std::fs::metadata(&files.log).unwrap().len();
+        if preallocate_wal {
+            assert_eq!(wal_size, INTERVAL);
+        } else {
+            assert!(wal_size < INTERVAL);
+        }
+
+        // image of files at this moment (like after power failure) contains all commits
+        let image = TestFiles::new("preallocated_wal_keeps_commits_durable_image");
+        std::fs::copy(&files.db, &image.db).unwrap();
+        std::fs::copy(&files.log, &image.log).unwrap();
+        let store = image.open(conf(preallocate_wal));
+        assert_eq!(verify(&store), COMMITS as u64);
+        assert_eq!(store.get(key(COMMITS - 1)).unwrap(), Some(vec![(COMMITS - 1) as u8; 100]));
+    }
+}
+
+#[test]
+fn commit_and_checkpoint_leaves_nothing_to_recover() {
+    let files = TestFiles::new("commit_and_checkpoint_leaves_nothing_to_recover");
+    let syncs = Arc::new(AtomicUsize::new(0));
+    let file = SyncCounter {
+        file: open_file(&files.db),
+        syncs: syncs.clone(),
+    };
+    let conf = StoreConfig {
+        batched_sync_interval: Duration::from_secs(3600),
+        ..StoreConfig::default()
+    };
+    let store = Store::open_with_storage(Box::new(file), Some(Box::new(open_file(&files.log))), conf).unwrap();
+    // pages of batched transactions are deferred in memory until the next checkpoint
+    for i in 0..10 {
+        commit(&store, i, Durability::Batched);
+    }
+    assert!(store.wal_position() > 0);
+    let before = syncs.load(Ordering::SeqCst);
+    let mut trans = store.start_transaction();
+    for i in 10..1000 {
+        trans.put(key(i), vec![i as u8; 100]).unwrap();
+    }
+    trans.commit_and_checkpoint().unwrap();
+    drop(trans);
+    assert_eq!(store.wal_position(), 0);
+    assert!(syncs.load(Ordering::SeqCst) > before);
+
+    // data file alone contains all committed transactions
+    let image = TestFiles::new("commit_and_checkpoint_leaves_nothing_to_recover_image");
+    std::fs::copy(&files.db, &image.db).unwrap();
+    let copy = image.open_without_wal(StoreConfig::default());
+    assert_eq!(verify(&copy), 1000);
+    assert_eq!(copy.get(key(0)).unwrap(), Some(vec![0u8; 100]));
+    assert_eq!(copy.get(key(999)).unwrap(), Some(vec![999u32 as u8; 100]));
+    drop(copy);
+
+    // and recovery has nothing to replay
+    drop(store);
+    let (store, report) = Store::open_with_report(&files.db, Some(&files.log), StoreConfig::default()).unwrap();
+    assert_eq!(report.transactions, 0);
+    assert_eq!(verify(&store), 1000);
+}
+
+#[test]
+fn wal_is_shrunk_on_checkpoint_if_requested() {
+    const INTERVAL: u64 = 4 * 1024 * 1024;
+    for (shrink_wal_on_checkpoint, preallocate_wal) in [(false, false), (true, false), (true, true)] {
+        let conf = StoreConfig {
+            shrink_wal_on_checkpoint,
+            preallocate_wal,
+            checkpoint_interval: INTERVAL,
+            ..StoreConfig::default()
+        };
+        let files = TestFiles::new("wal_is_shrunk_on_checkpoint_if_requested");
+        let store = files.open(conf.clone());
+        fill(&store, 0..10000, |_| vec![1u8; 100]);
+        let peak = std::fs::metadata(&files.log).unwrap().len();
+        assert!(store.wal_position() > 0);
+        let mut trans = store.start_transaction();
+        trans.put(key(0), [2u8]).unwrap();
+        trans.commit_and_checkpoint().unwrap();
+        drop(trans);
+        assert_eq!(store.wal_position(), 0);
+        let size = std::fs::metadata(&files.log).unwrap().len();
+        match (shrink_wal_on_checkpoint, preallocate_wal) {
+            // WAL keeps its size including records of the transaction performing checkpoint
+            (false, _) => assert!(size > peak, "WAL size {size}, peak {peak}"),
+            (true, false) => assert_eq!(size, 0),
+            (true, true) => assert_eq!(size, INTERVAL),
+        }
+        // WAL is extended again by the next transactions
+        fill(&store, 10000..11000, |_| vec![3u8; 100]);
+        drop(store);
+        let store = files.open(conf);
+        assert_eq!(verify(&store), 11000);
+        assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8]));
+        assert_eq!(store.get(key(10999)).unwrap(), Some(vec![3u8; 100]));
+    }
+}
+
+#[test]
+fn wal_of_another_store_is_not_replayed() {
+    let files = TestFiles::new("wal_of_another_store_is_not_replayed");
+    let store = files.open(StoreConfig::default());
+    fill(&store, 0..1000, |_| vec![1u8; 100]);
+    store.close().unwrap();
+    drop(store);
+
+    // WAL of another store which was not checkpointed (like after crash)
+    let other = TestFiles::new("wal_of_another_store_is_not_replayed_other");
+    let other_store = other.open(StoreConfig::default());
+    fill(&other_store, 0..500, |_| vec![2u8; 100]);
+    let image = TestFiles::new("wal_of_another_store_is_not_replayed_image");
+    std::fs::copy(&other.db, &image.db).unwrap();
+    std::fs::copy(&other.log, &image.log).unwrap();
+    drop(other_store);
+    let wal_size = std::fs::metadata(&image.log).unwrap().len();
+    assert!(wal_size > 0);
+
+    let err = Store::open(&files.db, Some(&image.log), StoreConfig::default()).err().unwrap();
+    assert!(matches!(err.downcast::<StoreError>().unwrap(), StoreError::WalMismatch));
+    // neither data file nor foreign WAL is changed
+    assert_eq!(std::fs::metadata(&image.log).unwrap().len(), wal_size);
+    let store = files.open(StoreConfig::default());
+    assert_eq!(verify(&store), 1000);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![1u8; 100]));
+    drop(store);
+    // WAL is still replayed with its own data file
+    let store = image.open(StoreConfig::default());
+    assert_eq!(verify(&store), 500);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8; 100]));
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use common::{fill, key, verify, TestFiles};
+use fs2::FileExt;
+use skv::{Store, StoreConfig, StoreError};
+
+fn open_file(path: &Path) -> File {
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).unwrap()
+}
+
+//
+// Anonymous temporary file (like `tempfile::tempfile()`): it is removed from directory right after creation
+//
+fn anonymous_file(path: &Path) -> File {
+    let file = open_file(path);
+    std::fs::remove_file(path).unwrap();
+    file
+}
+
+#[test]
+fn store_is_opened_over_anonymous_files() {
+    let files = TestFiles::new("store_is_opened_over_anonymous_files");
+    let file = anonymous_file(&files.db);
+    let log = anonymous_file(&files.log);
+    let (file_dup, log_dup) = (file.try_clone().unwrap(), log.try_clone().unwrap());
+    let store = Store::open_with_files(file, Some(log), StoreConfig::default()).unwrap();
+    fill(&store, 0..1000, |i| vec![i as u8; 10]);
+    drop(store);
+    assert!(!files.db.exists());
+
+    // store is reopened through duplicated descriptors
+    let store = Store::open_with_files(file_dup, Some(log_dup), StoreConfig::default()).unwrap();
+    assert_eq!(verify(&store), 1000);
+    assert_eq!(store.get(key(999)).unwrap(), Some(vec![999u32 as u8; 10]));
+}
+
+#[test]
+fn files_locked_by_caller_are_accepted() {
+    let files = TestFiles::new("files_locked_by_caller_are_accepted");
+    // caller holds locks through its own descriptors of the same files
+    let (file, log) = (open_file(&files.db), open_file(&files.log));
+    file.lock_exclusive().unwrap();
+    log.lock_exclusive().unwrap();
+    let store = Store::open_with_files(open_file(&files.db), Some(open_file(&files.log)), StoreConfig::default()).unwrap();
+    fill(&store, 0..100, |_| b"value".to_vec());
+    drop(store);
+    let store = Store::open_with_files(open_file(&files.db), None, StoreConfig::default()).unwrap();
+    assert_eq!(verify(&store), 100);
+}
+
+fn open_error(files: &TestFiles, conf: StoreConfig) -> StoreError {
+    let err = Store::open(&files.db, Some(&files.log), conf).err().unwrap();
+    err.downcast::<StoreError>().unwrap()
+}
+
+#[test]
+fn locked_files_are_reported() {
+    let files = TestFiles::new("locked_files_are_reported");
+    drop(files.open(StoreConfig::default()));
+    // lock held through other descriptor is the same as lock of another process
+    let file = open_file(&files.db);
+    file.lock_exclusive().unwrap();
+    assert!(matches!(open_error(&files, StoreConfig::default()), StoreError::Locked { path } if path == files.db));
+    file.unlock().unwrap();
+    let log = open_file(&files.log);
+    log.lock_exclusive().unwrap();
+    assert!(matches!(open_error(&files, StoreConfig::default()), StoreError::Locked { path } if path == files.log));
+    drop(log);
+
+    // the second store of this process is rejected before locking
+    let store = files.open(StoreConfig::default());
+    assert!(matches!(open_error(&files, StoreConfig::default()), StoreError::AlreadyOpen { .. }));
+    drop(store);
+}
+
+#[test]
+fn locking_can_be_disabled() {
+    let files = TestFiles::new("locking_can_be_disabled");
+    let unlocked = || StoreConfig {
+        file_locking: false,
+        ..StoreConfig::default()
+    };
+    fill(&files.open(unlocked()), 0..100, |_| b"value".to_vec());
+    let (file, log) = (open_file(&files.db), open_file(&files.log));
+    file.lock_exclusive().unwrap();
+    log.lock_exclusive().unwrap();
+    let store = files.open(unlocked());
+    assert_eq!(verify(&store), 100);
+    drop(store);
+
+    // two handles of the same files (exclusive access should be guaranteed by the caller)
+    let first = Store::open_with_files(open_file(&files.db), None, unlocked()).unwrap();
+    let second = Store::open_with_files(open_file(&files.db), None, unlocked()).unwrap();
+    assert_eq!(verify(&first), 100);
+    assert_eq!(verify(&second), 100);
+}
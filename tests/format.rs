@@ -0,0 +1,158 @@
+mod common;
+
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+use common::TestFiles;
+use skv::{Store, StoreConfig, StoreError};
+
+const PAGE_SIZE: usize = 8192;
+const PID_SIZE: usize = if cfg!(feature = "large-pages") { 8 } else { 4 };
+// offset of extension area: signature, free, size, root, height, commit sequence number and flags precede it
+const METADATA_EXT_OFFS: usize = 4 + 3 * PID_SIZE + 4 + 8 + 4;
+
+fn open_error(files: &TestFiles) -> StoreError {
+    let err = Store::open(&files.db, Some(&files.log), StoreConfig::default()).err().unwrap();
+    err.downcast::<StoreError>().unwrap()
+}
+
+#[test]
+fn legacy_store_is_rejected() {
+    let files = TestFiles::new("format_legacy");
+    // metadata of store created before signature was introduced: free, size, root and height
+    let mut page = vec![0u8; PAGE_SIZE];
+    for (i, field) in [0u32, 3, 2, 2].into_iter().enumerate() {
+        page[i * 4..i * 4 + 4].copy_from_slice(&field.to_be_bytes());
+    }
+    std::fs::write(&files.db, &page).unwrap();
+    assert!(matches!(open_error(&files), StoreError::UnsupportedFormat { version: 0 }));
+}
+
+#[test]
+fn store_without_format_version_is_rejected() {
+    let files = TestFiles::new("format_unversioned");
+    drop(files.open(StoreConfig::default()));
+    // format version is the first extension field: change its type to unknown one
+    let file = OpenOptions::new().read(true).write(true).open(&files.db).unwrap();
+    let mut tag = [0u8; 2];
+    file.read_exact_at(&mut tag, METADATA_EXT_OFFS as u64).unwrap();
+    assert_eq!(u16::from_be_bytes(tag), 1);
+    file.write_all_at(&u16::MAX.to_be_bytes(), METADATA_EXT_OFFS as u64).unwrap();
+    drop(file);
+    assert!(matches!(open_error(&files), StoreError::UnsupportedFormat { version: 1 }));
+}
+
+#[test]
+fn garbage_is_not_a_store() {
+    let files = TestFiles::new("format_garbage");
+    std::fs::write(&files.db, vec![0xA5u8; PAGE_SIZE]).unwrap();
+    assert!(matches!(open_error(&files), StoreError::NotAStore));
+}
+
+#[test]
+fn inconsistent_root_and_height_are_rejected() {
+    const ROOT_OFFS: u64 = (4 + 2 * PID_SIZE) as u64;
+    const HEIGHT_OFFS: u64 = (4 + 3 * PID_SIZE) as u64;
+    let pid = |pid: u64| pid.to_be_bytes()[8 - PID_SIZE..].to_vec();
+    // (root, height): non-empty root of empty tree, empty root of non-empty tree, root outside the store
+    for (root, height) in [(None, 0u32), (Some(0), 1), (Some(u32::MAX as u64), 1)] {
+        let files = TestFiles::new("format_inconsistent_root");
+        let store = files.open(StoreConfig::default());
+        common::fill(&store, 0..10, |_| b"value".to_vec());
+        drop(store);
+        let file = OpenOptions::new().read(true).write(true).open(&files.db).unwrap();
+        if let Some(root) = root {
+            file.write_all_at(&pid(root), ROOT_OFFS).unwrap();
+        }
+        file.write_all_at(&height.to_be_bytes(), HEIGHT_OFFS).unwrap();
+        drop(file);
+        assert!(
+            matches!(open_error(&files), StoreError::Corrupted(_)),
+            "root {root:?}, height {height}"
+        );
+    }
+}
+
+#[test]
+fn pages_carry_their_type() {
+    const PAGE_TYPE_OFFS: usize = 2;
+    let files = TestFiles::new("format_page_types");
+    let store = files.open(StoreConfig::default());
+    common::fill(&store, 0..10000, |_| vec![1u8; 100]);
+    // free some pages
+    let mut trans = store.start_transaction();
+    for i in 2000..6000 {
+        trans.remove(common::key(i)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    let summary = store.structure_summary().unwrap();
+    assert_eq!(summary.height, 2);
+    drop(store);
+
+    let data = std::fs::read(&files.db).unwrap();
+    let mut counts = [0u64; 4];
+    for page in data.chunks(PAGE_SIZE).skip(1) {
+        counts[page[PAGE_TYPE_OFFS] as usize] += 1;
+    }
+    // (untyped, leaf, internal, free)
+    let n_pages = (data.len() / PAGE_SIZE) as u64 - 1;
+    assert_eq!(counts[0], 0);
+    assert_eq!(counts[1], summary.levels[1].pages);
+    assert_eq!(counts[2], 1);
+    assert!(counts[3] > 0);
+    assert_eq!(counts.iter().sum::<u64>(), n_pages);
+
+    // leaf page misinterpreted as internal and vice versa are detected
+    let root = data.chunks(PAGE_SIZE).skip(1).position(|page| page[PAGE_TYPE_OFFS] == 2).unwrap() + 1;
+    let leaf = data.chunks(PAGE_SIZE).skip(1).position(|page| page[PAGE_TYPE_OFFS] == 1).unwrap() + 1;
+    for (pid, page_type) in [(root, 1u8), (leaf, 2u8)] {
+        let file = OpenOptions::new().read(true).write(true).open(&files.db).unwrap();
+        file.write_all_at(&[page_type], (pid * PAGE_SIZE + PAGE_TYPE_OFFS) as u64).unwrap();
+        drop(file);
+        let store = files.open(StoreConfig::default());
+        assert!(store.scrub().unwrap().bad_pages.iter().any(|bad| *bad as usize == pid));
+        let conf = StoreConfig {
+            verify_on_open: true,
+            ..StoreConfig::default()
+        };
+        drop(store);
+        assert!(matches!(
+            Store::open(&files.db, Some(&files.log), conf).err().unwrap().downcast::<StoreError>().unwrap(),
+            StoreError::Corrupted(_)
+        ));
+        // restore type
+        let file = OpenOptions::new().read(true).write(true).open(&files.db).unwrap();
+        file.write_all_at(&[3 - page_type], (pid * PAGE_SIZE + PAGE_TYPE_OFFS) as u64).unwrap();
+    }
+    assert!(files.open(StoreConfig::default()).scrub().unwrap().bad_pages.is_empty());
+}
+
+#[test]
+fn store_of_other_page_id_width_is_rejected() {
+    let files = TestFiles::new("format_page_id_width");
+    let store = files.open(StoreConfig::default());
+    common::fill(&store, 0..10000, |i| vec![i as u8; 20]);
+    // image of files before checkpoint, so that WAL records are replayed on open
+    let image = TestFiles::new("format_page_id_width_image");
+    std::fs::copy(&files.db, &image.db).unwrap();
+    std::fs::copy(&files.log, &image.log).unwrap();
+    drop(store);
+    let store = image.open(StoreConfig::default());
+    assert_eq!(common::verify(&store), 10000);
+    assert_eq!(store.get(common::key(9999)).unwrap(), Some(vec![9999u32 as u8; 20]));
+    drop(store);
+
+    // signature encodes width of page identifiers
+    let (magic, other_magic, other_width) = match PID_SIZE {
+        4 => (b"SKV\0", b"SKV8", 64),
+        _ => (b"SKV8", b"SKV\0", 32),
+    };
+    let file = OpenOptions::new().read(true).write(true).open(&files.db).unwrap();
+    let mut signature = [0u8; 4];
+    file.read_exact_at(&mut signature, 0).unwrap();
+    assert_eq!(&signature, magic);
+    file.write_all_at(other_magic, 0).unwrap();
+    drop(file);
+    assert!(matches!(open_error(&files), StoreError::PageIdWidthMismatch { width } if width == other_width));
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::{fill, key, TestFiles};
+use skv::StoreConfig;
+
+// 1GB buffer pool
+const CACHE_SIZE: usize = 128 * 1024;
+
+//
+// Resident set size of the process in kilobytes
+//
+fn resident_kb() -> u64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|line| line.starts_with("VmRSS:")).unwrap();
+    line.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn pool_pages_are_allocated_on_first_use() {
+    let files = TestFiles::new("pool_pages_are_allocated_on_first_use");
+    let store = files.open(StoreConfig::default());
+    fill(&store, 0..10000, |_| vec![1u8; 50]);
+    drop(store);
+
+    let before = resident_kb();
+    let store = files.open(StoreConfig {
+        cache_size: CACHE_SIZE,
+        ..StoreConfig::default()
+    });
+    let opened = resident_kb();
+    assert!(opened < before + 64 * 1024, "{before}KB before open, {opened}KB after open");
+    // memory grows with working set: 10000 keys occupy few dozens of pages
+    for i in 0..10000 {
+        assert!(store.get(key(i)).unwrap().is_some());
+    }
+    let touched = resident_kb();
+    assert!(touched < before + 64 * 1024, "{before}KB before open, {touched}KB after reads");
+    assert!(store.stats().cached_pages < 100);
+}
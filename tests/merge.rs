@@ -0,0 +1,52 @@
+mod common;
+
+use common::{fill, key, open_store, verify};
+use skv::ConflictPolicy;
+
+#[test]
+fn merge_disjoint_stores() {
+    let dst = open_store("merge_disjoint_dst");
+    let src = open_store("merge_disjoint_src");
+    fill(&dst, 0..3000, |_| b"dst".to_vec());
+    // more keys than one merge batch
+    fill(&src, 3000..6000, |_| b"src".to_vec());
+    assert_eq!(dst.merge_from(&src, ConflictPolicy::Error).unwrap(), 3000);
+    assert_eq!(verify(&dst), 6000);
+    assert_eq!(dst.get(key(0)).unwrap(), Some(b"dst".to_vec()));
+    assert_eq!(dst.get(key(5999)).unwrap(), Some(b"src".to_vec()));
+    // source store is not changed
+    assert_eq!(verify(&src), 3000);
+}
+
+#[test]
+fn merge_overlapping_stores() {
+    for policy in [ConflictPolicy::Overwrite, ConflictPolicy::KeepExisting, ConflictPolicy::Error] {
+        let dst = open_store("merge_overlapping_dst");
+        let src = open_store("merge_overlapping_src");
+        fill(&dst, 0..2000, |_| b"dst".to_vec());
+        fill(&src, 1000..3000, |_| b"src".to_vec());
+        let merged = dst.merge_from(&src, policy);
+        match policy {
+            ConflictPolicy::Overwrite => {
+                assert_eq!(merged.unwrap(), 2000);
+                assert_eq!(dst.get(key(1000)).unwrap(), Some(b"src".to_vec()));
+            }
+            ConflictPolicy::KeepExisting => {
+                // preserved keys are not counted
+                assert_eq!(merged.unwrap(), 1000);
+                assert_eq!(dst.get(key(1000)).unwrap(), Some(b"dst".to_vec()));
+            }
+            ConflictPolicy::Error => {
+                assert!(merged.is_err());
+                // batch containing conflict is not committed
+                assert_eq!(dst.get(key(1000)).unwrap(), Some(b"dst".to_vec()));
+                assert_eq!(dst.get(key(2999)).unwrap(), None);
+                assert_eq!(verify(&dst), 2000);
+                continue;
+            }
+        }
+        assert_eq!(verify(&dst), 3000);
+        assert_eq!(dst.get(key(999)).unwrap(), Some(b"dst".to_vec()));
+        assert_eq!(dst.get(key(2999)).unwrap(), Some(b"src".to_vec()));
+    }
+}
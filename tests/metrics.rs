@@ -0,0 +1,180 @@
+#![cfg(feature = "metrics")]
+
+mod common;
+
+use common::{key, verify, TestFiles};
+use skv::{Store, StoreConfig};
+
+//
+// Value of metric in Prometheus text exposition format returned by `Store::metrics_text`
+//
+fn metric(store: &Store, name: &str) -> f64 {
+    let text = store.metrics_text();
+    let line = text
+        .lines()
+        .find(|line| line.split(' ').next() == Some(name))
+        .unwrap_or_else(|| panic!("metric {name} is missing in {text}"));
+    line.split(' ').nth(1).unwrap().parse().unwrap()
+}
+
+fn page_accesses(store: &Store) -> f64 {
+    metric(store, "skv_cache_hits_total") + metric(store, "skv_cache_misses_total")
+}
+
+#[test]
+fn write_cache_coalesces_hot_key_updates() {
+    const PUTS: u32 = 10000;
+    const HOT_KEYS: u32 = 5;
+    let mut accesses = Vec::new();
+    for write_cache_size in [0, 100] {
+        let files = TestFiles::new("write_cache_coalesces_hot_key_updates");
+        let store = files.open(StoreConfig {
+            write_cache_size,
+            ..StoreConfig::default()
+        });
+        common::fill(&store, 0..10000, |_| vec![0u8; 20]);
+        let before = page_accesses(&store);
+        let mut trans = store.start_transaction();
+        for i in 0..PUTS {
+            trans.put(key(i % HOT_KEYS * 1000), i.to_be_bytes()).unwrap();
+            // transaction reads its own buffered updates
+            assert_eq!(trans.get(key(i % HOT_KEYS * 1000)).unwrap(), Some(i.to_be_bytes().to_vec()));
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        accesses.push(page_accesses(&store) - before);
+        for k in 0..HOT_KEYS {
+            let last = PUTS - HOT_KEYS + k;
+            assert_eq!(store.get(key(k * 1000)).unwrap(), Some(last.to_be_bytes().to_vec()));
+        }
+        assert_eq!(verify(&store), 10000);
+    }
+    // without cache each put (and get) descends B-Tree, with cache B-Tree is updated once per key
+    assert!(accesses[0] >= PUTS as f64, "{accesses:?}");
+    assert!(accesses[1] * 100.0 < accesses[0], "{accesses:?}");
+}
+
+#[test]
+fn lookups_after_warmup_read_only_leaf_pages() {
+    const N_KEYS: u32 = 100000;
+    const LOOKUPS: u32 = 200;
+    let files = TestFiles::new("lookups_after_warmup_read_only_leaf_pages");
+    let store = files.open(StoreConfig::default());
+    common::fill(&store, 0..N_KEYS, |_| vec![0u8; 20]);
+    let height = store.structure_summary().unwrap().levels.len();
+    assert!(height >= 3, "height {height}");
+    store.close().unwrap();
+    drop(store);
+
+    let mut misses = Vec::new();
+    for warmup in [false, true] {
+        let store = files.open(StoreConfig::default());
+        if warmup {
+            assert!(store.warmup().unwrap() > 0);
+        }
+        let before = metric(&store, "skv_cache_misses_total");
+        for i in 0..LOOKUPS {
+            let k = i * 997 % N_KEYS;
+            assert!(store.get(key(k)).unwrap().is_some(), "key {k}");
+        }
+        misses.push(metric(&store, "skv_cache_misses_total") - before);
+    }
+    // cold lookups load internal pages as well, after warmup at most one leaf page is read per lookup
+    assert!(misses[1] < misses[0], "{misses:?}");
+    assert!(misses[1] <= LOOKUPS as f64, "{misses:?}");
+}
+
+#[test]
+fn metrics_text_reports_workload() {
+    const NAMES: [&str; 9] = [
+        "cache_hits_total",
+        "cache_misses_total",
+        "cache_hit_ratio",
+        "cached_pages",
+        "dirty_pages",
+        "tree_height",
+        "commits_total",
+        "wal_bytes_written_total",
+        "checkpoints_total",
+    ];
+    let files = TestFiles::new("metrics_text_reports_workload");
+    let store = files.open(StoreConfig::default());
+    let text = store.metrics_text();
+    for name in NAMES {
+        let kind = if name.ends_with("_total") { "counter" } else { "gauge" };
+        assert!(text.contains(&format!("# HELP skv_{name} ")), "{text}");
+        assert!(text.contains(&format!("# TYPE skv_{name} {kind}\n")), "{text}");
+    }
+    assert_eq!(metric(&store, "skv_commits_total"), 0.0);
+    assert_eq!(metric(&store, "skv_wal_bytes_written_total"), 0.0);
+    assert_eq!(metric(&store, "skv_cache_hit_ratio"), 1.0);
+
+    // WAL written by each commit is reflected by WAL position until checkpoint
+    let mut wal_bytes = 0;
+    for i in 0..3 {
+        let pos = store.wal_position();
+        common::fill(&store, i * 5000..(i + 1) * 5000, |_| vec![1u8; 100]);
+        wal_bytes += store.wal_position() - pos;
+    }
+    assert_eq!(metric(&store, "skv_commits_total"), 3.0);
+    assert_eq!(metric(&store, "skv_wal_bytes_written_total"), wal_bytes as f64);
+    assert_eq!(metric(&store, "skv_checkpoints_total"), 0.0);
+    let mut trans = store.start_transaction();
+    trans.put(key(0), [2u8]).unwrap();
+    assert!(metric(&store, "skv_dirty_pages") > 0.0);
+    trans.commit_and_checkpoint().unwrap();
+    drop(trans);
+    assert_eq!(metric(&store, "skv_commits_total"), 4.0);
+    assert!(metric(&store, "skv_wal_bytes_written_total") > wal_bytes as f64);
+    assert_eq!(metric(&store, "skv_checkpoints_total"), 1.0);
+    assert_eq!(metric(&store, "skv_dirty_pages"), 0.0);
+
+    // pages are read from the data file after reopen: read-only workload changes only cache counters
+    drop(store);
+    let store = files.open(StoreConfig::default());
+    assert_eq!(verify(&store), 15000);
+    // lookups find pages loaded by traversal in cache
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![2u8]));
+    let hits = metric(&store, "skv_cache_hits_total");
+    let misses = metric(&store, "skv_cache_misses_total");
+    assert!(hits > 0.0 && misses > 0.0, "{hits} hits, {misses} misses");
+    assert_eq!(metric(&store, "skv_cache_hit_ratio"), hits / (hits + misses));
+    let stats = store.stats();
+    assert_eq!(metric(&store, "skv_cached_pages"), stats.cached_pages as f64);
+    assert_eq!(metric(&store, "skv_tree_height"), store.structure_summary().unwrap().height as f64);
+    assert_eq!(metric(&store, "skv_commits_total"), 0.0);
+    assert_eq!(metric(&store, "skv_wal_bytes_written_total"), 0.0);
+}
+
+#[test]
+fn insert_cursor_accesses_fewer_pages_than_puts() {
+    const N: u32 = 20000;
+    let mut accesses = Vec::new();
+    for by_cursor in [false, true] {
+        let files = TestFiles::new("insert_cursor_accesses_fewer_pages_than_puts");
+        let store = files.open(StoreConfig::default());
+        common::fill(&store, 0..N, |_| vec![0u8; 20]);
+        let height = store.structure_summary().unwrap().height;
+        assert!(height >= 2, "height {height}");
+        let before = page_accesses(&store);
+        // keys fall between stored keys
+        let keys = (0..N).map(|i| [key(i), b"+".to_vec()].concat());
+        let mut trans = store.start_transaction();
+        if by_cursor {
+            let mut cursor = trans.insert_cursor().unwrap();
+            for k in keys {
+                cursor.insert(k, [1u8]).unwrap();
+            }
+        } else {
+            for k in keys {
+                trans.put(k, [1u8]).unwrap();
+            }
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        accesses.push(page_accesses(&store) - before);
+        assert_eq!(verify(&store), 2 * N as u64);
+    }
+    // put descends B-Tree for each key, cursor mostly accesses only the leaf
+    assert!(accesses[1] * 1.5 < accesses[0], "{accesses:?}");
+}
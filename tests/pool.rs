@@ -0,0 +1,274 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::{fill, key, verify, TestFiles};
+use skv::{SharedBufferPool, Store, StoreConfig, StoreError};
+
+#[test]
+fn stores_share_bounded_pool() {
+    let pool = Arc::new(SharedBufferPool::new(64).unwrap());
+    let files: Vec<TestFiles> = (0..3).map(|i| TestFiles::new(&format!("stores_share_bounded_pool{i}"))).collect();
+    let stores: Vec<Store> = files
+        .iter()
+        .map(|files| {
+            let (store, report) =
+                Store::open_with_pool_and_report(&files.db, Some(&files.log), StoreConfig::default(), pool.clone())
+                    .unwrap();
+            assert_eq!(report.transactions, 0);
+            store
+        })
+        .collect();
+    assert_eq!(pool.store_count(), 3);
+    for (i, store) in stores.iter().enumerate() {
+        // each store alone needs more pages than the whole pool
+        fill(store, 0..10000, |_| vec![i as u8; 50]);
+    }
+    assert!(pool.allocated_pages() <= pool.size());
+    for (i, store) in stores.iter().enumerate() {
+        assert_eq!(verify(store), 10000);
+        assert_eq!(store.get(key(9999)).unwrap(), Some(vec![i as u8; 50]));
+    }
+    drop(stores);
+    assert_eq!(pool.store_count(), 0);
+}
+
+#[test]
+fn large_transaction_spills_dirty_pages() {
+    let files = TestFiles::new("large_transaction_spills_dirty_pages");
+    let conf = || StoreConfig {
+        cache_size: 32,
+        ..StoreConfig::default()
+    };
+    let store = files.open(conf());
+    let mut trans = store.start_transaction();
+    // ~200 leaf pages: 20 items of 400 bytes fit in page
+    for i in 0..4000 {
+        trans.put(key(i), vec![i as u8; 400]).unwrap();
+        let stats = store.stats();
+        assert!(stats.dirty_pages <= 32 * 3 / 4, "{stats:?}");
+    }
+    let stats = store.stats();
+    assert!(stats.spilled_pages > 150, "{stats:?}");
+    // spilled pages are reloaded from WAL
+    for i in 0..4000 {
+        assert_eq!(trans.get(key(i)).unwrap(), Some(vec![i as u8; 400]));
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    assert_eq!(store.stats().dirty_pages, 0);
+    drop(store);
+    let store = files.open(conf());
+    assert_eq!(verify(&store), 4000);
+    assert_eq!(store.get(key(3999)).unwrap(), Some(vec![3999u32 as u8; 400]));
+}
+
+#[test]
+fn too_small_cache_is_reported() {
+    let files = TestFiles::new("too_small_cache_is_reported");
+    let store = files.open(StoreConfig::default());
+    // long keys reduce fanout of internal pages, so that tree becomes tall
+    let long_key = |i: u32| format!("{i:0200}").into_bytes();
+    let mut trans = store.start_transaction();
+    for i in 0..20000 {
+        trans.put(long_key(i), b"value").unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    let height = store.structure_summary().unwrap().height as usize;
+    assert!(height >= 3);
+    drop(store);
+
+    let open_error = |cache_size| {
+        let conf = StoreConfig {
+            cache_size,
+            ..StoreConfig::default()
+        };
+        let err = Store::open(&files.db, Some(&files.log), conf).err().unwrap();
+        err.downcast::<StoreError>().unwrap()
+    };
+    match open_error(3) {
+        StoreError::CacheTooSmall { cache_size, required } => assert_eq!((cache_size, required), (3, 2 * height + 3)),
+        err => panic!("unexpected error {err}"),
+    }
+    assert!(matches!(open_error(2 * height + 2), StoreError::CacheTooSmall { .. }));
+
+    // cache is enough for the current tree, but not when it grows
+    let store = files.open(StoreConfig {
+        cache_size: 2 * height + 3,
+        ..StoreConfig::default()
+    });
+    assert_eq!(store.get(long_key(19999)).unwrap(), Some(b"value".to_vec()));
+    let mut grown = None;
+    for i in 20000..200000 {
+        if let Err(err) = store.put(long_key(i), b"value") {
+            grown = Some(err.downcast::<StoreError>().unwrap());
+            break;
+        }
+    }
+    assert!(matches!(grown, Some(StoreError::CacheTooSmall { .. })), "{grown:?}");
+}
+
+#[test]
+fn poisoned_buffer_manager_is_reported() {
+    let files = TestFiles::new("poisoned_buffer_manager_is_reported");
+    let store = files.open(StoreConfig {
+        cache_size: 20,
+        ..StoreConfig::default()
+    });
+    fill(&store, 0..5000, |_| vec![1u8; 100]);
+    // hook is called holding buffer manager lock: its panic poisons the lock
+    store
+        .set_eviction_hook(Some(Box::new(|pid, _| panic!("eviction of page {pid}"))))
+        .unwrap();
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        for i in 0..5000 {
+            let _ = store.get(key(i));
+        }
+    }));
+    assert!(res.is_err());
+
+    let is_corrupted = |err: anyhow::Error| matches!(err.downcast::<StoreError>(), Ok(StoreError::Corrupted(_)));
+    assert!(is_corrupted(store.get(key(0)).unwrap_err()));
+    assert!(is_corrupted(store.put(key(0), b"value").unwrap_err()));
+    assert!(store.set_eviction_hook(None).is_err());
+    let mut trans = store.start_transaction();
+    assert!(trans.put(key(0), b"value").is_err());
+    drop(trans);
+    // statistics remain available and store is closed without panic
+    store.stats();
+    drop(store);
+
+    // nothing is lost
+    let store = files.open(StoreConfig::default());
+    assert_eq!(verify(&store), 5000);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![1u8; 100]));
+}
+
+#[test]
+fn poisoned_shared_pool_is_reported_on_open() {
+    let pool = Arc::new(SharedBufferPool::new(20).unwrap());
+    let files = TestFiles::new("poisoned_shared_pool_is_reported_on_open");
+    let open =
+        |files: &TestFiles| Store::open_with_pool(&files.db, Some(&files.log), StoreConfig::default(), pool.clone());
+    let store = open(&files).unwrap();
+    fill(&store, 0..5000, |_| vec![1u8; 100]);
+    store
+        .set_eviction_hook(Some(Box::new(|pid, _| panic!("eviction of page {pid}"))))
+        .unwrap();
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        for i in 0..5000 {
+            let _ = store.get(key(i));
+        }
+    }));
+    assert!(res.is_err());
+    // panic in one store makes buffers of all stores sharing the pool unreliable
+    let other = TestFiles::new("poisoned_shared_pool_is_reported_on_open_other");
+    let err = open(&other).err().unwrap();
+    assert!(matches!(err.downcast::<StoreError>(), Ok(StoreError::Corrupted(_))));
+}
+
+#[test]
+fn trim_cache_retains_pinned_and_dirty_pages() {
+    let files = TestFiles::new("trim_cache_retains_pinned_and_dirty_pages");
+    let store = files.open(StoreConfig {
+        cache_size: 200,
+        ..StoreConfig::default()
+    });
+    fill(&store, 0..20000, |_| vec![1u8; 100]);
+    for i in 0..20000 {
+        store.get(key(i)).unwrap();
+    }
+    assert_eq!(store.stats().cached_pages, 200);
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    {
+        let evicted = evicted.clone();
+        store
+            .set_eviction_hook(Some(Box::new(move |pid, clean| evicted.lock().unwrap().push((pid, clean)))))
+            .unwrap();
+    }
+    // dirty pages of uncommitted transaction are pinned until its end
+    let mut trans = store.start_transaction();
+    for i in (0..20000).step_by(1000) {
+        trans.put(key(i), vec![2u8; 100]).unwrap();
+    }
+    let before = store.stats();
+    assert!(before.dirty_pages >= 20, "{before:?}");
+    evicted.lock().unwrap().clear();
+
+    // target can not be reached: only unpinned pages are evicted
+    let n_evicted = store.trim_cache(0).unwrap();
+    let after = store.stats();
+    assert_eq!(after.cached_pages, after.pinned_pages, "{after:?}");
+    assert_eq!(after.dirty_pages, before.dirty_pages);
+    assert_eq!(n_evicted, before.cached_pages - after.cached_pages);
+    let evicted = std::mem::take(&mut *evicted.lock().unwrap());
+    assert_eq!(evicted.len(), n_evicted);
+    assert!(evicted.iter().all(|(_, clean)| *clean));
+
+    // reachable target
+    for i in 1..20000 {
+        if i % 1000 != 0 {
+            trans.get(key(i)).unwrap();
+        }
+    }
+    let target = after.pinned_pages + 50;
+    assert!(store.stats().cached_pages > target);
+    store.trim_cache(target).unwrap();
+    assert_eq!(store.stats().cached_pages, target);
+    assert_eq!(store.stats().dirty_pages, before.dirty_pages);
+
+    // modifications are not lost
+    for i in 0..20000 {
+        let value = if i % 1000 == 0 { 2u8 } else { 1u8 };
+        assert_eq!(trans.get(key(i)).unwrap(), Some(vec![value; 100]), "key {i}");
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    store.set_eviction_hook(None).unwrap();
+    drop(store);
+    let store = files.open(StoreConfig::default());
+    assert_eq!(verify(&store), 20000);
+    assert_eq!(store.get(key(1000)).unwrap(), Some(vec![2u8; 100]));
+}
+
+#[test]
+fn boundary_cache_sizes_for_tree_heights() {
+    let long_key = |i: u32| format!("{i:0200}").into_bytes();
+    for (n_keys, expected_height) in [(10, 1), (1000, 2), (20000, 3)] {
+        let files = TestFiles::new("boundary_cache_sizes_for_tree_heights");
+        let store = files.open(StoreConfig::default());
+        let mut trans = store.start_transaction();
+        for i in 0..n_keys {
+            trans.put(long_key(i), b"value").unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        let height = store.structure_summary().unwrap().height as usize;
+        assert_eq!(height, expected_height);
+        drop(store);
+
+        let conf = |cache_size| StoreConfig {
+            cache_size,
+            ..StoreConfig::default()
+        };
+        let err = Store::open(&files.db, Some(&files.log), conf(2 * height + 2)).err().unwrap();
+        match err.downcast::<StoreError>().unwrap() {
+            StoreError::CacheTooSmall { required, .. } => assert_eq!(required, 2 * height + 3),
+            err => panic!("unexpected error {err}"),
+        }
+        // the smallest cache is enough to read and update the whole tree of this height
+        let store = files.open(conf(2 * height + 3));
+        for i in (0..n_keys).rev() {
+            assert_eq!(store.get(long_key(i)).unwrap(), Some(b"value".to_vec()));
+        }
+        let mut trans = store.start_transaction();
+        for i in (0..n_keys).step_by(3) {
+            trans.put(long_key(i), b"new value").unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(verify(&store), n_keys as u64);
+        assert_eq!(store.structure_summary().unwrap().height as usize, height);
+    }
+}
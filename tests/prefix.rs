@@ -0,0 +1,105 @@
+mod common;
+
+use common::TestFiles;
+use skv::{PrefixStats, StoreConfig};
+
+fn tenant(prefix: &str) -> StoreConfig {
+    StoreConfig {
+        key_prefix: Some(prefix.as_bytes().to_vec()),
+        ..StoreConfig::default()
+    }
+}
+
+#[test]
+fn keys_are_transparently_namespaced() {
+    let files = TestFiles::new("keys_are_transparently_namespaced");
+    for (prefix, value) in [("a/", b"alice"), ("b/", b"bobby")] {
+        let store = files.open(tenant(prefix));
+        for i in 0..1000u32 {
+            store.put(format!("{i:04}"), value).unwrap();
+        }
+        store.remove("0000").unwrap();
+        assert_eq!(store.get("0001").unwrap(), Some(value.to_vec()));
+    }
+    // keys are stored with prefixes
+    let store = files.open(StoreConfig::default());
+    assert_eq!(store.get("a/0001").unwrap(), Some(b"alice".to_vec()));
+    assert_eq!(store.get("b/0001").unwrap(), Some(b"bobby".to_vec()));
+    assert_eq!(store.get("0001").unwrap(), None);
+    assert_eq!(store.get("a/0000").unwrap(), None);
+    store.put("c/0001", b"carol").unwrap();
+    drop(store);
+
+    // each tenant sees only its own keys, without prefix
+    let store = files.open(tenant("b/"));
+    assert_eq!(store.get("0001").unwrap(), Some(b"bobby".to_vec()));
+    assert_eq!(store.get("a/0001").unwrap(), None);
+    let trans = store.start_transaction();
+    let items = trans.range("0000", "9999").unwrap();
+    assert_eq!(items.len(), 999);
+    assert!(items.iter().all(|(key, value)| key.len() == 4 && value == b"bobby"));
+}
+
+#[test]
+fn scan_can_not_escape_prefix() {
+    let files = TestFiles::new("scan_can_not_escape_prefix");
+    let store = files.open(StoreConfig::default());
+    for prefix in ["a", "b", "b/", "b0", "c/"] {
+        for i in 0..100u32 {
+            store.put(format!("{prefix}{i:02}"), prefix).unwrap();
+        }
+    }
+    drop(store);
+    let store = files.open(tenant("b/"));
+    let trans = store.start_transaction();
+    // the widest possible range covers only keys with prefix
+    let items = trans.range([0u8], [u8::MAX; 64]).unwrap();
+    assert_eq!(items.len(), 100);
+    assert!(items.iter().all(|(_, value)| value == b"b/"));
+    assert_eq!(items[0].0, b"00");
+    drop(trans);
+    let (page, _) = store.scan_page(None, 1000).unwrap();
+    assert_eq!(page.len(), 100);
+    assert!(page.iter().all(|(_, value)| value == b"b/"));
+    let (page, next) = store.scan_page(Some(b"98"), 1000).unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(next, None);
+}
+
+#[test]
+fn prefix_stats_are_exact() {
+    let files = TestFiles::new("prefix_stats_are_exact");
+    let store = files.open(StoreConfig::default());
+    let mut trans = store.start_transaction();
+    // "alice/" keys are 10 bytes with 10 bytes values, "bob/" keys are 8 bytes with 100 bytes values
+    for i in 0..1000u32 {
+        trans.put(format!("alice/{i:04}"), [1u8; 10]).unwrap();
+    }
+    for i in 0..3000u32 {
+        trans.put(format!("bob/{i:04}"), [2u8; 100]).unwrap();
+    }
+    // keys adjacent to the prefixes which should not be counted
+    trans.put("alice0", b"x").unwrap();
+    trans.put("bob", b"x").unwrap();
+    trans.commit().unwrap();
+    drop(trans);
+    store.remove("bob/0000").unwrap();
+
+    let alice = store.prefix_stats(b"alice/").unwrap();
+    let bob = store.prefix_stats(b"bob/").unwrap();
+    assert_eq!((alice.keys, alice.key_bytes, alice.value_bytes), (1000, 10 * 1000, 10 * 1000));
+    assert_eq!((bob.keys, bob.key_bytes, bob.value_bytes), (2999, 8 * 2999, 100 * 2999));
+    // only boundary pages can be shared by the prefixes and adjacent keys
+    let leaves = store.structure_summary().unwrap().levels.last().unwrap().pages;
+    assert!(alice.pages > 0 && bob.pages > 0);
+    assert!((leaves..=leaves + 2).contains(&(alice.pages + bob.pages)), "{alice:?} {bob:?}, {leaves} leaves");
+    assert_eq!(store.prefix_stats(b"carol/").unwrap(), PrefixStats::default());
+    assert_eq!(store.prefix_stats(b"").unwrap().keys, 4001);
+    drop(store);
+
+    // prefix is relative to key prefix of the store, which is not counted in key length
+    let store = files.open(tenant("bob/"));
+    assert_eq!(store.prefix_stats(b"").unwrap(), PrefixStats { key_bytes: 4 * 2999, ..bob });
+    let stats = store.prefix_stats(b"01").unwrap();
+    assert_eq!((stats.keys, stats.key_bytes, stats.value_bytes), (100, 4 * 100, 100 * 100));
+}
@@ -0,0 +1,263 @@
+mod common;
+
+use common::{fill, key, open_store, verify, TestFiles};
+use std::sync::atomic::AtomicBool;
+
+use skv::{StoreConfig, StoreError};
+
+//
+// Numeric suffix of key "<prefix>/<number>"
+//
+fn suffix(key: &[u8]) -> u32 {
+    let pos = key.iter().rposition(|b| *b == b'/').unwrap();
+    std::str::from_utf8(&key[pos + 1..]).unwrap().parse().unwrap()
+}
+
+#[test]
+fn range_filtered_by_numeric_suffix() {
+    let store = open_store("range_filtered_by_numeric_suffix");
+    let mut trans = store.start_transaction();
+    for user in ["alice", "bob", "carol"] {
+        for n in 0..1000u32 {
+            trans.put(format!("{user}/{n}"), n.to_be_bytes()).unwrap();
+        }
+    }
+    trans.commit().unwrap();
+    drop(trans);
+
+    // lexicographic order places "bob/990" between "bob/99" and "bob/991"
+    let items = store.range_filtered("bob/", "bob/\u{7f}", |key| suffix(key) >= 990).unwrap();
+    let mut numbers: Vec<u32> = items.iter().map(|(key, _)| suffix(key)).collect();
+    numbers.sort();
+    assert_eq!(numbers, (990..1000).collect::<Vec<u32>>());
+    for (key, value) in &items {
+        assert!(key.starts_with(b"bob/"));
+        assert_eq!(value[..], suffix(key).to_be_bytes());
+    }
+    assert!(store.range_filtered("bob/", "bob/\u{7f}", |_| false).unwrap().is_empty());
+}
+
+#[test]
+fn range_filtered_filter_can_access_store() {
+    let store = open_store("range_filtered_filter_can_access_store");
+    fill(&store, 0..100, |i| vec![(i % 2) as u8]);
+    let items = store
+        .range_filtered(common::key(0), common::key(100), |key| store.get(key).unwrap().unwrap()[0] == 1)
+        .unwrap();
+    assert_eq!(items.len(), 50);
+    assert!(store.range_filtered(common::key(1), common::key(0), |_| true).is_err());
+}
+
+#[test]
+fn range_start_positioning() {
+    let store = open_store("range_start_positioning");
+    // even keys only, so that odd keys fall between items (and leaf pages)
+    let mut trans = store.start_transaction();
+    for i in (0..20000).step_by(2) {
+        trans.put(key(i), i.to_be_bytes()).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    let trans = store.start_transaction();
+    let first = |start: &[u8], end: &[u8]| trans.range_unchecked(start, end).unwrap().first().map(|(key, _)| key.clone());
+    for i in (0..20000).step_by(2) {
+        // start equal to item and between items
+        assert_eq!(first(&key(i), &key(i + 4)), Some(key(i)));
+        assert_eq!(first(&key(i + 1), &key(i + 4)), (i + 2 < 20000).then(|| key(i + 2)));
+    }
+    // start smaller and larger than all keys
+    assert_eq!(first(b"a", &key(1)), Some(key(0)));
+    assert_eq!(first(b"\0", &key(1)), Some(key(0)));
+    assert_eq!(first(&key(19999), b"z"), None);
+    assert_eq!(first(b"z", b"zz"), None);
+    assert_eq!(trans.range(key(3), key(7)).unwrap().len(), 2);
+    assert_eq!(trans.range(key(4), key(5)).unwrap().len(), 1);
+    assert!(trans.range(key(5), key(6)).unwrap().is_empty());
+}
+
+#[test]
+fn scan_pages_cover_all_keys() {
+    let store = open_store("scan_pages_cover_all_keys");
+    fill(&store, 0..100_000, |i| i.to_be_bytes().to_vec());
+    for limit in [1000, 777, 100_000, 200_000] {
+        let mut after = None;
+        let mut expected = 0u32;
+        loop {
+            let (items, next) = store.scan_page(after.as_deref(), limit).unwrap();
+            assert!(items.len() <= limit);
+            // pages are contiguous and do not overlap
+            for (key, value) in &items {
+                assert_eq!(key, &common::key(expected), "limit {limit}");
+                assert_eq!(value[..], expected.to_be_bytes());
+                expected += 1;
+            }
+            match next {
+                Some(next) => {
+                    assert_eq!(items.len(), limit);
+                    assert_eq!(Some(&next), items.last().map(|(key, _)| key));
+                    after = Some(next);
+                }
+                None => break,
+            }
+        }
+        assert_eq!(expected, 100_000, "limit {limit}");
+    }
+    assert!(store.scan_page(None, 0).is_err());
+}
+
+#[test]
+fn scan_page_sees_commits_between_calls() {
+    let store = open_store("scan_page_sees_commits_between_calls");
+    fill(&store, 0..100, |_| vec![1u8]);
+    let (items, next) = store.scan_page(None, 50).unwrap();
+    assert_eq!(items.len(), 50);
+    // keys inserted after continuation key are returned, keys before it are not
+    store.put(key(10), [2u8]).unwrap();
+    store.put(key(60), [2u8]).unwrap();
+    store.remove(key(70)).unwrap();
+    let (items, next) = store.scan_page(next.as_deref(), 100).unwrap();
+    assert_eq!(next, None);
+    assert_eq!(items.len(), 49);
+    assert_eq!(items[0].0, key(50));
+    assert_eq!(items[10], (key(60), vec![2u8]));
+    assert!(items.iter().all(|(k, _)| k != &key(70)));
+}
+
+#[test]
+fn transaction_range_scan_and_delete() {
+    for write_cache_size in [0, 100] {
+        let files = TestFiles::new("transaction_range_scan_and_delete");
+        let store = files.open(StoreConfig {
+            write_cache_size,
+            ..StoreConfig::default()
+        });
+        fill(&store, 0..10000, |i| vec![(i % 3) as u8; 20]);
+        let mut trans = store.start_transaction();
+        // uncommitted updates are seen by the scan
+        for i in (1000..2000).step_by(2) {
+            trans.put(key(i), [9u8]).unwrap();
+        }
+        trans.remove(key(1001)).unwrap();
+        let items = trans.range(key(1000), key(3000)).unwrap();
+        assert_eq!(items.len(), 1999);
+        assert_eq!(items[0], (key(1000), vec![9u8]));
+        assert_eq!(items[1].0, key(1002));
+        // delete matching items while iterating through the result
+        let mut removed = 0;
+        for (k, v) in items {
+            if v == [9u8] || v == [0u8; 20] {
+                trans.remove(&k).unwrap();
+                removed += 1;
+            }
+        }
+        let left = trans.range(key(1000), key(3000)).unwrap();
+        assert_eq!(left.len(), 1999 - removed);
+        assert!(left.iter().all(|(_, v)| v != &[9u8] && v != &[0u8; 20]));
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(store.range_filtered(key(1000), key(3000), |_| true).unwrap(), left);
+        assert_eq!(verify(&store), (10000 - 1 - removed) as u64);
+    }
+}
+
+#[test]
+fn update_range_doubles_values_in_range() {
+    let value = |i: u32| i.to_be_bytes().to_vec();
+    let store = open_store("update_range_doubles_values_in_range");
+    fill(&store, 0..10000, value);
+    let inserted = [key(5000), b"x".to_vec()].concat();
+    let mut trans = store.start_transaction();
+    // item inserted by the transaction itself is updated too
+    trans.put(&inserted, 1u32.to_be_bytes()).unwrap();
+    let updated = trans
+        .update_range(key(3000), key(7000), |_, v| {
+            let n = u32::from_be_bytes(v[..].try_into().unwrap());
+            // remove every tenth item
+            (n % 10 != 0).then(|| (n * 2).to_be_bytes().to_vec())
+        })
+        .unwrap();
+    assert_eq!(updated, 4001);
+    trans.commit().unwrap();
+    drop(trans);
+    for i in 0..10000u32 {
+        let expected = match i {
+            3000..=6999 if i % 10 == 0 => None,
+            3000..=6999 => Some((i * 2).to_be_bytes().to_vec()),
+            _ => Some(value(i)),
+        };
+        assert_eq!(store.get(key(i)).unwrap(), expected, "key {i}");
+    }
+    assert_eq!(store.get(&inserted).unwrap(), Some(2u32.to_be_bytes().to_vec()));
+    assert_eq!(verify(&store), 10000 - 400 + 1);
+}
+
+#[test]
+fn reversed_ranges_are_rejected() {
+    let store = open_store("reversed_ranges_are_rejected");
+    fill(&store, 0..1000, |i| vec![i as u8]);
+    let is_invalid = |res: anyhow::Result<_>| {
+        matches!(res.err().map(|err| err.downcast::<StoreError>()), Some(Ok(StoreError::InvalidRange)))
+    };
+    let (start, end) = (key(600), key(400));
+    assert!(is_invalid(store.range_filtered(&start, &end, |_| true).map(|_| ())));
+    assert!(is_invalid(store.warmup_range(&start, &end).map(|_| ())));
+    let snapshot = store.snapshot().unwrap();
+    assert!(is_invalid(snapshot.range(&start, &end).map(|_| ())));
+    // reversed range is empty for unchecked variant
+    assert!(snapshot.range_unchecked(&start, &end).unwrap().is_empty());
+    // end of range is exclusive, so range with equal bounds is empty
+    assert!(snapshot.range(&start, &start).unwrap().is_empty());
+    assert!(store.range_filtered(&start, &start, |_| true).unwrap().is_empty());
+    assert_eq!(store.warmup_range(&start, &start).unwrap(), 0);
+    assert_eq!(snapshot.range(&end, &start).unwrap().len(), 200);
+    drop(snapshot);
+
+    let mut trans = store.start_transaction();
+    assert!(is_invalid(trans.range(&start, &end).map(|_| ())));
+    assert!(is_invalid(trans.range_cancellable(&start, &end, &AtomicBool::new(false)).map(|_| ())));
+    assert!(is_invalid(trans.verify_range(&start, &end).map(|_| ())));
+    assert!(is_invalid(trans.update_range(&start, &end, |_, _| None).map(|_| ())));
+    assert!(is_invalid(trans.remove_range_where(&start, &end, |_, _| true).map(|_| ())));
+    assert!(trans.range_unchecked(&start, &end).unwrap().is_empty());
+    // equal bounds
+    assert!(trans.range(&start, &start).unwrap().is_empty());
+    assert!(trans.range_unchecked(&start, &start).unwrap().is_empty());
+    assert!(trans.range_cancellable(&start, &start, &AtomicBool::new(false)).unwrap().is_empty());
+    assert_eq!(trans.update_range(&start, &start, |_, _| None).unwrap(), 0);
+    assert_eq!(trans.remove_range_where(&start, &start, |_, _| true).unwrap(), 0);
+    assert_eq!(trans.verify_range(&start, &start).unwrap(), 0);
+    // normal range: the same items for all range operations
+    assert_eq!(trans.range(&end, &start).unwrap().len(), 200);
+    assert_eq!(trans.verify_range(&end, &start).unwrap(), 200);
+    assert_eq!(trans.update_range(&end, &start, |_, v| Some(v.clone())).unwrap(), 200);
+    assert_eq!(trans.remove_range_where(&end, &start, |_, _| false).unwrap(), 0);
+    assert_eq!(trans.remove_range_where(&end, &start, |k, _| k == &key(599)).unwrap(), 1);
+    assert!(trans.range(key(599), &start).unwrap().is_empty());
+    trans.commit().unwrap();
+    drop(trans);
+    // nothing but the last key of the range is changed
+    assert_eq!(verify(&store), 999);
+    assert_eq!(store.get(&start).unwrap(), Some(vec![600u32 as u8]));
+}
+
+#[test]
+fn remove_range_where_removes_only_matching_items() {
+    let store = open_store("remove_range_where_removes_only_matching_items");
+    fill(&store, 0..10000, |i| i.to_be_bytes().to_vec());
+    let odd = |_: &Vec<u8>, v: &Vec<u8>| u32::from_be_bytes(v[..].try_into().unwrap()) % 2 == 1;
+    let mut trans = store.start_transaction();
+    // end of range is exclusive
+    let removed = trans.remove_range_where(key(2001), key(7001), odd).unwrap();
+    assert_eq!(removed, 2500);
+    // predicate is applied to uncommitted values
+    trans.put(key(8000), 1u32.to_be_bytes()).unwrap();
+    assert_eq!(trans.remove_range_where(key(7001), key(9000), odd).unwrap(), 1000 + 1);
+    trans.commit().unwrap();
+    drop(trans);
+    for i in 0..10000u32 {
+        let removed = i % 2 == 1 && ((2001..7001).contains(&i) || (7001..9000).contains(&i)) || i == 8000;
+        let expected = (!removed).then(|| i.to_be_bytes().to_vec());
+        assert_eq!(store.get(key(i)).unwrap(), expected, "key {i}");
+    }
+    assert_eq!(verify(&store), 10000 - 2500 - 1001);
+}
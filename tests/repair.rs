@@ -0,0 +1,83 @@
+mod common;
+
+use common::{fill, key, verify, TestFiles};
+use skv::{KeyDictionary, Store, StoreConfig};
+
+#[test]
+fn repair_with_key_prefix_and_dictionary() {
+    let conf = || StoreConfig {
+        key_prefix: Some(b"tenant1/".to_vec()),
+        key_dictionary: Some(KeyDictionary::new(b'/', ["users", "orders", "active"]).unwrap()),
+        ..StoreConfig::default()
+    };
+    let key = |i: u32| format!("{}/active/{i:06}", if i.is_multiple_of(2) { "users" } else { "orders" }).into_bytes();
+    let files = TestFiles::new("repair_with_key_prefix_and_dictionary");
+    let store = files.open(conf());
+    let mut trans = store.start_transaction();
+    for i in 0..5000 {
+        trans.put(key(i), i.to_be_bytes()).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    store.close().unwrap();
+    drop(store);
+
+    // key prefix is stripped before key is decoded and prepended again in new store
+    let dest = TestFiles::new("repair_with_key_prefix_and_dictionary_dest");
+    let report = Store::repair(&files.db, &dest.db, conf()).unwrap();
+    assert_eq!(report.keys, 5000);
+    let store = dest.open_without_wal(conf());
+    assert_eq!(verify(&store), 5000);
+    for i in 0..5000 {
+        assert_eq!(store.get(key(i)).unwrap(), Some(i.to_be_bytes().to_vec()), "key {i}");
+    }
+}
+
+#[test]
+fn repair_recovers_leaves_of_corrupted_tree() {
+    const PAGE_SIZE: usize = 8192;
+    const PAGE_TYPE_OFFS: usize = 2;
+    let files = TestFiles::new("repair_recovers_leaves_of_corrupted_tree");
+    let store = files.open(StoreConfig::default());
+    fill(&store, 0..50000, |i| vec![i as u8; 100]);
+    // pages of removed keys are freed: their items should not be restored
+    let mut trans = store.start_transaction();
+    for i in 10000..20000 {
+        trans.remove(key(i)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    let summary = store.structure_summary().unwrap();
+    assert!(summary.height >= 3);
+    store.close().unwrap();
+    drop(store);
+
+    // overwrite all internal pages with garbage
+    let mut data = std::fs::read(&files.db).unwrap();
+    let mut internal = 0;
+    for page in data.chunks_mut(PAGE_SIZE).skip(1) {
+        if page[PAGE_TYPE_OFFS] == 2 {
+            page.fill(0xA5);
+            internal += 1;
+        }
+    }
+    let leaves = summary.levels.last().unwrap().pages;
+    assert_eq!(internal, summary.levels.iter().map(|level| level.pages).sum::<u64>() - leaves);
+    std::fs::write(&files.db, &data).unwrap();
+    assert!(Store::open(&files.db, Some(&files.log), StoreConfig::default())
+        .and_then(|store| store.verify_cancellable(&Default::default()))
+        .is_err());
+
+    let dest = TestFiles::new("repair_recovers_leaves_of_corrupted_tree_dest");
+    let report = Store::repair(&files.db, &dest.db, StoreConfig::default()).unwrap();
+    assert_eq!(report.scanned_pages, (data.len() / PAGE_SIZE - 1) as u64);
+    assert_eq!(report.salvaged_pages, leaves);
+    assert_eq!(report.unreadable_pages, internal);
+    assert_eq!((report.keys, report.duplicates), (40000, 0));
+    let store = dest.open_without_wal(StoreConfig::default());
+    assert_eq!(verify(&store), 40000);
+    for i in 0..50000 {
+        let expected = (!(10000..20000).contains(&i)).then(|| vec![i as u8; 100]);
+        assert_eq!(store.get(key(i)).unwrap(), expected, "key {i}");
+    }
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use common::{fill, key, TestFiles};
+use skv::StoreConfig;
+
+#[test]
+fn replace_with_concurrent_readers() {
+    let conf = || StoreConfig {
+        value_cache_size: 100,
+        ..StoreConfig::default()
+    };
+    let files = TestFiles::new("replace_with_concurrent_readers");
+    let store = files.open(conf());
+    fill(&store, 0..5000, |_| b"old".to_vec());
+    let new_files = TestFiles::new("replace_with_concurrent_readers_new");
+    {
+        let new_store = new_files.open(conf());
+        // different shape of B-Tree: readers using old root in new file would find garbage
+        fill(&new_store, 0..20000, |_| b"new".to_vec());
+    }
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        for t in 0..4u32 {
+            let (store, done) = (&store, &done);
+            s.spawn(move || {
+                let mut i = t;
+                while !done.load(Ordering::Relaxed) {
+                    let k = key(i % 5000);
+                    let value = if t % 2 == 0 {
+                        store.get(&k).unwrap()
+                    } else {
+                        store.get_shared(&k).unwrap().map(|value| value.to_vec())
+                    };
+                    let value = value.unwrap();
+                    assert!(value == b"old" || value == b"new");
+                    i += 7;
+                }
+            });
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store.replace_with(&new_files.db, Some(&new_files.log)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        done.store(true, Ordering::Relaxed);
+    });
+    assert_eq!(store.get(key(0)).unwrap(), Some(b"new".to_vec()));
+    assert_eq!(store.get(key(19999)).unwrap(), Some(b"new".to_vec()));
+    assert!(!new_files.db.exists());
+}
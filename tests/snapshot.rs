@@ -0,0 +1,76 @@
+mod common;
+
+use common::{fill, key, open_store, TestFiles};
+use skv::StoreConfig;
+
+#[test]
+fn snapshot_does_not_block_commits() {
+    let store = open_store("snapshot_does_not_block_commits");
+    fill(&store, 0..1000, |i| format!("v1-{i}").into_bytes());
+    let snapshot = store.snapshot().unwrap();
+    // commits of the same thread are not blocked by snapshot
+    fill(&store, 0..2000, |i| format!("v2-{i}").into_bytes());
+    {
+        let mut trans = store.start_transaction();
+        for i in (0..1000).step_by(2) {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+    }
+    // other snapshot and `get` of the same thread see the new state
+    let other = store.snapshot().unwrap();
+    assert_eq!(store.get(key(1)).unwrap(), Some(b"v2-1".to_vec()));
+    assert_eq!(other.get(key(0)).unwrap(), None);
+    assert_eq!(other.range(key(0), key(2000)).unwrap().len(), 1500);
+
+    assert_eq!(snapshot.get(key(0)).unwrap(), Some(b"v1-0".to_vec()));
+    assert_eq!(snapshot.get(key(1500)).unwrap(), None);
+    let items = snapshot.range(key(0), key(2000)).unwrap();
+    assert_eq!(items.len(), 1000);
+    for (i, (k, v)) in items.iter().enumerate() {
+        assert_eq!(k, &key(i as u32));
+        assert_eq!(v, format!("v1-{i}").as_bytes());
+    }
+}
+
+#[test]
+fn snapshot_with_concurrent_writer() {
+    let files = TestFiles::new("snapshot_with_concurrent_writer");
+    let store = files.open(StoreConfig {
+        cache_size: 64,
+        ..StoreConfig::default()
+    });
+    fill(&store, 0..2000, |i| vec![1u8; 10 + (i % 100) as usize]);
+    let snapshot = store.snapshot().unwrap();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for round in 2..10u8 {
+                fill(&store, 0..2000, |i| vec![round; 10 + (i % 100) as usize]);
+            }
+        })
+        .join()
+        .unwrap();
+    });
+    let items = snapshot.range(key(0), key(2000)).unwrap();
+    assert_eq!(items.len(), 2000);
+    assert!(items.iter().all(|(_, v)| v.iter().all(|b| *b == 1)));
+    drop(snapshot);
+    assert_eq!(store.get(key(0)).unwrap(), Some(vec![9u8; 10]));
+}
+
+#[test]
+fn snapshot_is_invalidated_by_compaction() {
+    let store = open_store("snapshot_is_invalidated_by_compaction");
+    fill(&store, 0..2000, |_| vec![0u8; 100]);
+    {
+        let mut trans = store.start_transaction();
+        for i in 0..1900 {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+    }
+    let snapshot = store.snapshot().unwrap();
+    assert!(store.compact_in_place().unwrap() > 0);
+    assert!(snapshot.get(key(1950)).is_err());
+    assert_eq!(store.snapshot().unwrap().get(key(1950)).unwrap(), Some(vec![0u8; 100]));
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::{fill, key, open_store, verify, TestFiles};
+use skv::{ItemSizeHint, StoreConfig};
+
+#[test]
+fn structure_of_ascending_and_delete_heavy_tree() {
+    let store = open_store("structure_of_ascending_and_delete_heavy_tree");
+    fill(&store, 0..20000, |_| vec![1u8; 20]);
+    let summary = store.structure_summary().unwrap();
+    assert_eq!(summary.levels.len(), summary.height as usize);
+    assert_eq!(summary.levels[0].pages, 1);
+    // only the right-most path of B-Tree ends with +inf separator
+    assert_eq!(summary.inf_separators, summary.height as u64 - 1);
+    let leaves = *summary.levels.last().unwrap();
+    // ascending inserts leave split pages full
+    assert!(leaves.avg_items >= 0.9 * leaves.max_items as f64, "{leaves:?}");
+
+    let mut trans = store.start_transaction();
+    for i in (0..20000).filter(|i| i % 10 != 0) {
+        trans.remove(key(i)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    assert_eq!(verify(&store), 2000);
+    // without merging (`StoreConfig::merge_threshold` is zero by default) pages stay sparse
+    let sparse = *store.structure_summary().unwrap().levels.last().unwrap();
+    assert_eq!(sparse.pages, leaves.pages);
+    assert!(sparse.avg_items <= 0.2 * leaves.avg_items, "{sparse:?}");
+}
+
+#[test]
+fn bulk_load_with_item_size_hint() {
+    const N_KEYS: u32 = 20000;
+    // load descending keys and return number of leaf pages and size of data file
+    let load = |name: &str, item_size_hint: Option<ItemSizeHint>| {
+        let files = TestFiles::new(name);
+        let store = files.open(StoreConfig {
+            item_size_hint,
+            ..StoreConfig::default()
+        });
+        let mut trans = store.start_transaction();
+        for i in (0..N_KEYS).rev() {
+            trans.put(key(i), vec![i as u8; 20]).unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(verify(&store), N_KEYS as u64);
+        let leaves = *store.structure_summary().unwrap().levels.last().unwrap();
+        store.close().unwrap();
+        drop(store);
+        (leaves.pages, std::fs::metadata(&files.db).unwrap().len())
+    };
+    let (plain_pages, plain_size) = load("bulk_load_without_hint", None);
+    let hint = ItemSizeHint {
+        key_len: key(0).len(),
+        value_len: 20,
+    };
+    let (hinted_pages, hinted_size) = load("bulk_load_with_hint", Some(hint));
+    // descending keys leave halves of split pages empty unless split is adjusted by the hint
+    assert!(hinted_pages * 4 < plain_pages * 3, "{hinted_pages} leaves with hint, {plain_pages} without");
+    assert!(hinted_size < plain_size);
+}
+
+#[test]
+fn sparse_pages_are_merged_after_removes() {
+    let load = |name: &str, merge_threshold: f32| {
+        let files = TestFiles::new(name);
+        let store = files.open(StoreConfig {
+            merge_threshold,
+            ..StoreConfig::default()
+        });
+        fill(&store, 0..20000, |_| vec![1u8; 20]);
+        let mut trans = store.start_transaction();
+        for i in (0..20000).filter(|i| i % 10 != 0) {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+        drop(trans);
+        assert_eq!(verify(&store), 2000);
+        for i in (0..20000).step_by(10) {
+            assert_eq!(store.get(key(i)).unwrap(), Some(vec![1u8; 20]), "key {i}");
+        }
+        *store.structure_summary().unwrap().levels.last().unwrap()
+    };
+    let sparse = load("sparse_pages_are_not_merged", 0.0);
+    let merged = load("sparse_pages_are_merged", 0.3);
+    assert!(merged.pages * 2 < sparse.pages, "{} pages merged into {}", sparse.pages, merged.pages);
+    assert!(merged.avg_items > 2.0 * sparse.avg_items);
+    // merged pages are not overfilled
+    assert!(merged.avg_items <= merged.max_items as f64);
+}
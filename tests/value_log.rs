@@ -0,0 +1,144 @@
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+
+use common::{fill, key, verify, TestFiles};
+use skv::{Store, StoreConfig, StoreError};
+
+#[test]
+fn collect_value_log_with_tombstones() {
+    let files = TestFiles::new("collect_value_log_with_tombstones");
+    let conf = || StoreConfig {
+        value_log: true,
+        tombstones: true,
+        ..StoreConfig::default()
+    };
+    let store = files.open(conf());
+    fill(&store, 0..1000, |i| vec![i as u8; 100]);
+    {
+        let mut trans = store.start_transaction();
+        for i in (0..1000).step_by(3) {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+    }
+    fill(&store, 0..1000, |i| vec![(i + 1) as u8; 50]);
+    {
+        let mut trans = store.start_transaction();
+        for i in (0..1000).step_by(2) {
+            trans.remove(key(i)).unwrap();
+        }
+        trans.commit().unwrap();
+    }
+    assert_eq!(store.collect_value_log().unwrap(), 500);
+    for i in 0..1000 {
+        let expected = if i % 2 == 0 { None } else { Some(vec![(i + 1) as u8; 50]) };
+        assert_eq!(store.get(key(i)).unwrap(), expected);
+    }
+    drop(store);
+    let store = files.open(conf());
+    assert_eq!(store.get(key(1)).unwrap(), Some(vec![2u8; 50]));
+    assert_eq!(store.get(key(2)).unwrap(), None);
+}
+
+fn value(i: u32) -> Vec<u8> {
+    // sizes from a few bytes to several pages
+    vec![i as u8; (i as usize * 37) % 30000 + 1]
+}
+
+#[test]
+fn values_round_trip_through_value_log() {
+    let files = TestFiles::new("values_round_trip_through_value_log");
+    let conf = || StoreConfig {
+        value_log: true,
+        ..StoreConfig::default()
+    };
+    let store = files.open(conf());
+    fill(&store, 0..1000, value);
+    for i in 0..1000 {
+        assert_eq!(store.get(key(i)).unwrap(), Some(value(i)), "key {i}");
+    }
+    // overwritten values replace old ones
+    fill(&store, 0..500, |i| value(i + 1));
+    drop(store);
+    let store = files.open(conf());
+    assert_eq!(verify(&store), 1000);
+    for i in 0..1000 {
+        let expected = if i < 500 { value(i + 1) } else { value(i) };
+        assert_eq!(store.get(key(i)).unwrap(), Some(expected), "key {i}");
+    }
+}
+
+#[test]
+fn value_log_space_is_reclaimed_after_deletes() {
+    let files = TestFiles::new("value_log_space_is_reclaimed_after_deletes");
+    let conf = || StoreConfig {
+        value_log: true,
+        ..StoreConfig::default()
+    };
+    let vlog = files.db.with_extension("vlog");
+    let used = || std::fs::metadata(&vlog).unwrap().blocks() * 512;
+    let store = files.open(conf());
+    fill(&store, 0..2000, |i| vec![i as u8; 4000]);
+    let mut trans = store.start_transaction();
+    for i in 100..2000 {
+        trans.remove(key(i)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    let before = used();
+    assert_eq!(store.collect_value_log().unwrap(), 100);
+    // space of removed values is deallocated (file system used by tests supports holes)
+    assert!(used() * 4 < before, "{} bytes used after collection, {before} bytes before", used());
+    drop(store);
+    let store = files.open(conf());
+    assert_eq!(verify(&store), 100);
+    for i in 0..100 {
+        assert_eq!(store.get(key(i)).unwrap(), Some(vec![i as u8; 4000]));
+    }
+    assert_eq!(store.get(key(100)).unwrap(), None);
+}
+
+#[test]
+fn value_length_limit() {
+    let too_long = |store: &Store, len: usize, max: usize| {
+        let err = store.put(key(0), vec![0u8; len]).unwrap_err().downcast::<StoreError>().unwrap();
+        assert!(matches!(err, StoreError::ValueTooLong { len: l, max: m } if l == len && m == max), "{err}");
+    };
+    // (value log, configured limit, effective limit)
+    for (value_log, max_value_len, max) in [
+        (false, None, 2048),
+        (false, Some(100), 100),
+        (true, None, u32::MAX as usize),
+        (true, Some(100_000), 100_000),
+    ] {
+        let files = TestFiles::new("value_length_limit");
+        let store = files.open(StoreConfig {
+            value_log,
+            max_value_len,
+            ..StoreConfig::default()
+        });
+        store.put(key(1), vec![1u8; max.min(1_000_000)]).unwrap();
+        assert_eq!(store.get(key(1)).unwrap().unwrap().len(), max.min(1_000_000));
+        if max < u32::MAX as usize {
+            too_long(&store, max + 1, max);
+            let mut trans = store.start_transaction();
+            assert!(trans.put(key(2), vec![0u8; max + 1]).is_err());
+            assert!(trans.update_value(key(1), vec![0u8; max + 1]).is_err());
+            trans.commit().unwrap();
+        }
+        drop(store);
+        let store = files.open(StoreConfig {
+            value_log,
+            ..StoreConfig::default()
+        });
+        assert_eq!(verify(&store), 1);
+    }
+    // limit can not exceed the length of values which can be stored
+    let files = TestFiles::new("value_length_limit_too_large");
+    let conf = StoreConfig {
+        max_value_len: Some(2049),
+        ..StoreConfig::default()
+    };
+    assert!(Store::open(&files.db, Some(&files.log), conf).is_err());
+}
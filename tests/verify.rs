@@ -0,0 +1,233 @@
+mod common;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use common::{fill, key, verify, TestFiles};
+use skv::{Storage, Store, StoreConfig, StoreError};
+
+const PAGE_SIZE: u64 = 8192;
+
+fn paranoid() -> StoreConfig {
+    StoreConfig {
+        verify_on_open: true,
+        ..StoreConfig::default()
+    }
+}
+
+#[test]
+fn corrupted_page_is_detected_on_open() {
+    let files = TestFiles::new("corrupted_page_is_detected_on_open");
+    fill(&files.open(StoreConfig::default()), 0..10000, |i| vec![i as u8; 100]);
+    // intact store passes verification
+    assert_eq!(verify(&files.open(paranoid())), 10000);
+
+    // overwrite item offsets of B-Tree page at the end of the file
+    let file = OpenOptions::new().read(true).write(true).open(&files.db).unwrap();
+    let pid = file.metadata().unwrap().len() / PAGE_SIZE - 1;
+    Storage::write_all_at(&file, &[0xFF; 64], pid * PAGE_SIZE + 4).unwrap();
+    drop(file);
+
+    let err = Store::open(&files.db, Some(&files.log), paranoid()).err().unwrap();
+    assert!(matches!(err.downcast::<StoreError>().unwrap(), StoreError::Corrupted(_)));
+    // corruption is not detected until the page is accessed without verification
+    let store = files.open(StoreConfig::default());
+    assert!(store.verify_cancellable(&Default::default()).is_err());
+}
+
+//
+// Data file which sets cancellation flag when the specified number of pages is read
+//
+struct CancellingStorage {
+    file: File,
+    reads: Arc<AtomicU64>,
+    cancel_after: Arc<AtomicU64>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Storage for CancellingStorage {
+    fn read_at(&self, buf: &mut [u8], offs: u64) -> io::Result<usize> {
+        if self.reads.fetch_add(1, Ordering::Relaxed) + 1 == self.cancel_after.load(Ordering::Relaxed) {
+            self.cancel.store(true, Ordering::Relaxed);
+        }
+        Storage::read_at(&self.file, buf, offs)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offs: u64) -> io::Result<()> {
+        Storage::write_all_at(&self.file, buf, offs)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Storage::size(&self.file)
+    }
+}
+
+#[test]
+fn cancelled_traversal_terminates_promptly() {
+    const N_KEYS: u32 = 50_000;
+    const CANCEL_AFTER: u64 = 100;
+    let files = TestFiles::new("cancelled_traversal_terminates_promptly");
+    fill(&files.open_without_wal(StoreConfig::default()), 0..N_KEYS, |i| vec![i as u8; 100]);
+    let reads = Arc::new(AtomicU64::new(0));
+    let cancel_after = Arc::new(AtomicU64::new(0));
+    let cancel = Arc::new(AtomicBool::new(false));
+    let storage = CancellingStorage {
+        file: OpenOptions::new().read(true).write(true).open(&files.db).unwrap(),
+        reads: reads.clone(),
+        cancel_after: cancel_after.clone(),
+        cancel: cancel.clone(),
+    };
+    // small cache, so that traversal reads pages from the disk
+    let conf = StoreConfig {
+        cache_size: 100,
+        ..StoreConfig::default()
+    };
+    let store = Store::open_with_storage(Box::new(storage), None, conf).unwrap();
+    let height = store.structure_summary().unwrap().height as u64;
+    let pinned = store.stats().pinned_pages;
+    let is_cancelled = |err: anyhow::Error| matches!(err.downcast::<StoreError>().unwrap(), StoreError::Cancelled);
+
+    // flag is checked before reading any page by verification, range scan locates start of the range before checking it
+    cancel.store(true, Ordering::Relaxed);
+    let start = reads.load(Ordering::Relaxed);
+    assert!(is_cancelled(store.verify_cancellable(&cancel).unwrap_err()));
+    assert_eq!(reads.load(Ordering::Relaxed), start);
+    assert!(is_cancelled(store.start_transaction().range_cancellable(key(0), key(N_KEYS), &cancel).unwrap_err()));
+    assert!(reads.load(Ordering::Relaxed) <= start + height);
+
+    // cancellation in the middle of traversal: no more pages are read after the flag is set
+    cancel.store(false, Ordering::Relaxed);
+    cancel_after.store(reads.load(Ordering::Relaxed) + CANCEL_AFTER, Ordering::Relaxed);
+    assert!(is_cancelled(store.verify_cancellable(&cancel).unwrap_err()));
+    assert_eq!(reads.load(Ordering::Relaxed), cancel_after.load(Ordering::Relaxed));
+    assert_eq!(store.stats().pinned_pages, pinned);
+
+    // range scan checks the flag before reading leaf pages, so internal pages of the path may be read
+    cancel.store(false, Ordering::Relaxed);
+    cancel_after.store(reads.load(Ordering::Relaxed) + CANCEL_AFTER, Ordering::Relaxed);
+    let mut trans = store.start_transaction();
+    assert!(is_cancelled(trans.range_cancellable(key(0), key(N_KEYS), &cancel).unwrap_err()));
+    assert!(reads.load(Ordering::Relaxed) <= cancel_after.load(Ordering::Relaxed) + height);
+    // transaction and store remain usable
+    trans.put(key(N_KEYS), b"value").unwrap();
+    trans.commit().unwrap();
+    drop(trans);
+    assert_eq!(store.stats().pinned_pages, pinned);
+    cancel_after.store(0, Ordering::Relaxed);
+    cancel.store(false, Ordering::Relaxed);
+    assert_eq!(store.verify_cancellable(&cancel).unwrap(), N_KEYS as u64 + 1);
+}
+
+#[test]
+fn scrub_reports_exactly_corrupted_pages() {
+    const PID_SIZE: u64 = if cfg!(feature = "large-pages") { 8 } else { 4 };
+    let files = TestFiles::new("scrub_reports_exactly_corrupted_pages");
+    let store = files.open(StoreConfig::default());
+    fill(&store, 0..10000, |i| vec![i as u8; 100]);
+    assert_eq!(store.structure_summary().unwrap().height, 2);
+    let report = store.scrub().unwrap();
+    assert!(report.bad_pages.is_empty());
+    assert!(report.unreachable_pages.is_empty());
+    drop(store);
+
+    // all pages except metadata and root are leaf pages: corrupt two of them
+    let file = OpenOptions::new().read(true).write(true).open(&files.db).unwrap();
+    let size = file.metadata().unwrap().len() / PAGE_SIZE;
+    assert_eq!(report.checked_pages, size);
+    let mut root = [0u8; 8];
+    Storage::read_exact_at(&file, &mut root[8 - PID_SIZE as usize..], 4 + 2 * PID_SIZE).unwrap();
+    let root = u64::from_be_bytes(root);
+    let bad: Vec<u64> = [1, size / 2, size - 1].into_iter().filter(|pid| *pid != root).take(2).collect();
+    for pid in &bad {
+        Storage::write_all_at(&file, &[0xFF; 64], pid * PAGE_SIZE + 4).unwrap();
+    }
+    drop(file);
+
+    let store = files.open(StoreConfig::default());
+    let cached = store.stats().cached_pages;
+    let report = store.scrub().unwrap();
+    assert_eq!(report.checked_pages, size);
+    assert_eq!(report.bad_pages, bad.iter().map(|pid| *pid as _).collect::<Vec<_>>());
+    // pages are read bypassing buffer cache
+    assert_eq!(store.stats().cached_pages, cached);
+}
+
+#[test]
+fn scrub_checks_unreachable_pages() {
+    const PID_SIZE: usize = if cfg!(feature = "large-pages") { 8 } else { 4 };
+    const PAGE_TYPE_OFFS: usize = 2;
+    const NEXT_FREE_OFFS: usize = 4;
+    let files = TestFiles::new("scrub_checks_unreachable_pages");
+    let store = files.open(StoreConfig::default());
+    fill(&store, 0..10000, |i| vec![i as u8; 100]);
+    let mut trans = store.start_transaction();
+    for i in 2000..6000 {
+        trans.remove(key(i)).unwrap();
+    }
+    trans.commit().unwrap();
+    drop(trans);
+    drop(store);
+
+    let mut data = std::fs::read(&files.db).unwrap();
+    let size = (data.len() as u64 / PAGE_SIZE) as usize;
+    let pid = |offs: usize| {
+        let mut pid = [0u8; 8];
+        pid[8 - PID_SIZE..].copy_from_slice(&data[offs..offs + PID_SIZE]);
+        u64::from_be_bytes(pid) as usize
+    };
+    let mut free_list = vec![pid(4)];
+    while let next @ 1.. = pid(free_list[free_list.len() - 1] * PAGE_SIZE as usize + NEXT_FREE_OFFS) {
+        free_list.push(next);
+    }
+    assert!(free_list.len() > 3, "{free_list:?}");
+    // cut free list after its head, so that the rest of the list is leaked, and damage one of the leaked pages
+    let head = free_list[0] * PAGE_SIZE as usize;
+    data[head + NEXT_FREE_OFFS..head + NEXT_FREE_OFFS + PID_SIZE].fill(0);
+    let damaged = free_list[2];
+    data[damaged * PAGE_SIZE as usize + PAGE_TYPE_OFFS] = 0xFF;
+    std::fs::write(&files.db, &data).unwrap();
+
+    let store = files.open(StoreConfig::default());
+    let report = store.scrub().unwrap();
+    assert_eq!(report.checked_pages, size as u64);
+    let mut leaked: Vec<_> = free_list[1..].iter().map(|pid| *pid as _).collect();
+    leaked.sort_unstable();
+    assert_eq!(report.unreachable_pages, leaked);
+    assert_eq!(report.bad_pages, [damaged as _]);
+    assert_eq!(verify(&store), 6000);
+}
+
+#[test]
+fn verify_range_checks_only_pages_of_range() {
+    let files = TestFiles::new("verify_range_checks_only_pages_of_range");
+    fill(&files.open_without_wal(StoreConfig::default()), 0..50000, |i| vec![i as u8; 20]);
+    // break order of keys in the last leaf page: the largest key becomes smaller than its predecessors
+    let mut data = std::fs::read(&files.db).unwrap();
+    let pos = data.windows(11).position(|w| w == key(49999)).unwrap();
+    data[pos..pos + 11].copy_from_slice(&key(1));
+    std::fs::write(&files.db, &data).unwrap();
+
+    let store = files.open_without_wal(StoreConfig::default());
+    assert!(store.verify_cancellable(&Default::default()).is_err());
+    let trans = store.start_transaction();
+    assert!(trans.verify().is_err());
+    assert_eq!(trans.verify_range(key(0), key(1000)).unwrap(), 1000);
+    assert_eq!(trans.verify_range(key(20000), key(30000)).unwrap(), 10000);
+    assert_eq!(trans.verify_range(key(5), key(5)).unwrap(), 0);
+    assert!(trans.verify_range(key(49000), key(50000)).is_err());
+    assert!(trans.verify_range(key(10), key(5)).is_err());
+}